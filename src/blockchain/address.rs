@@ -0,0 +1,296 @@
+use std::fmt;
+
+use rust_base58::{FromBase58, ToBase58};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::script::{self, ScriptError, ScriptPattern, Stack};
+use blockchain::utils::{ridemp160, sha256};
+
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+/// Destination of a transaction output.
+/// Each variant carries the network prefix it needs, so `Display` always yields the final address string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// Pay to Public Key Hash: version id and hash160 of the public key, base58check encoded
+    P2PKH(u8, [u8; 20]),
+    /// Pay to Script Hash: p2sh version id and hash160 of the redeem script, base58check encoded
+    P2SH(u8, [u8; 20]),
+    /// Pay to Witness Public Key Hash: bech32 hrp and the 20 byte witness program
+    P2WPKH(&'static str, [u8; 20]),
+    /// Pay to Witness Script Hash: bech32 hrp and the 32 byte witness program
+    P2WSH(&'static str, [u8; 32]),
+    /// Pay to Taproot: bech32 hrp and the 32 byte output key, bech32m encoded
+    P2TR(&'static str, [u8; 32]),
+    /// Pay to Public Key: version id and the full public key.
+    /// There is no dedicated address format, so it is displayed as the P2PKH address of the key.
+    P2PK(u8, Vec<u8>),
+}
+
+impl Address {
+    /// Evaluates `script_pubkey` and extracts the address it pays to, the same address callbacks get from
+    /// `EvaluatedTxOut::script`. Scripts are evaluated while parsing, before the block height is known,
+    /// so witness programs are encoded even in blocks before SegWit, where they were still anyone can spend.
+    pub fn from_script(script_pubkey: &[u8], coin: &CoinType) -> Option<Address> {
+        script::eval_from_bytes(script_pubkey, coin).address
+    }
+
+    /// Extracts the address from an already evaluated script stack.
+    /// Returns `Ok(None)` for scripts without an address, like OP_RETURN or multisig outputs.
+    pub fn from_stack(stack: &Stack, coin: &CoinType) -> Result<Option<Address>, ScriptError> {
+        let addr = match stack.pattern {
+            ScriptPattern::Pay2PublicKey => {
+                Some(Address::P2PK(coin.version_id, stack.elements[0].data()?))
+            }
+            ScriptPattern::Pay2PublicKeyHash => Some(Address::P2PKH(
+                coin.version_id,
+                to_array_20(&stack.elements[2].data()?)?,
+            )),
+            ScriptPattern::Pay2ScriptHash => Some(Address::P2SH(
+                coin.p2sh_version,
+                to_array_20(&stack.elements[1].data()?)?,
+            )),
+            ScriptPattern::Pay2WitnessPublicKeyHash => match coin.bech32_hrp {
+                Some(hrp) => Some(Address::P2WPKH(
+                    hrp,
+                    to_array_20(&stack.elements[1].data()?)?,
+                )),
+                None => None,
+            },
+            ScriptPattern::Pay2WitnessScriptHash => match coin.bech32_hrp {
+                Some(hrp) => Some(Address::P2WSH(
+                    hrp,
+                    to_array_32(&stack.elements[1].data()?)?,
+                )),
+                None => None,
+            },
+            ScriptPattern::Pay2Taproot => match coin.bech32_hrp {
+                Some(hrp) => Some(Address::P2TR(hrp, to_array_32(&stack.elements[1].data()?)?)),
+                None => None,
+            },
+            ScriptPattern::Pay2MultiSig => {
+                stack.elements[1].data()?;
+                None
+            }
+            _ => None,
+        };
+        Ok(addr)
+    }
+
+    /// Returns true if this address is encoded from a witness program
+    #[inline]
+    pub fn is_witness(&self) -> bool {
+        match *self {
+            Address::P2WPKH(..) | Address::P2WSH(..) | Address::P2TR(..) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::P2PKH(version, ref h160) => write!(f, "{}", base58check(version, h160)),
+            Address::P2SH(version, ref h160) => write!(f, "{}", base58check(version, h160)),
+            Address::P2PK(version, ref pub_key) => {
+                write!(f, "{}", base58check(version, &ridemp160(&sha256(pub_key))))
+            }
+            Address::P2WPKH(hrp, ref program) => {
+                write!(f, "{}", segwit_encode(hrp, 0, program, BECH32_CONST))
+            }
+            Address::P2WSH(hrp, ref program) => {
+                write!(f, "{}", segwit_encode(hrp, 0, program, BECH32_CONST))
+            }
+            Address::P2TR(hrp, ref program) => {
+                write!(f, "{}", segwit_encode(hrp, 1, program, BECH32M_CONST))
+            }
+        }
+    }
+}
+
+#[inline]
+fn to_array_20(data: &[u8]) -> Result<[u8; 20], ScriptError> {
+    if data.len() != 20 {
+        return Err(ScriptError::InvalidFormat);
+    }
+    let mut arr = [0u8; 20];
+    arr.copy_from_slice(data);
+    Ok(arr)
+}
+
+#[inline]
+fn to_array_32(data: &[u8]) -> Result<[u8; 32], ScriptError> {
+    if data.len() != 32 {
+        return Err(ScriptError::InvalidFormat);
+    }
+    let mut arr = [0u8; 32];
+    arr.copy_from_slice(data);
+    Ok(arr)
+}
+
+/// Takes a version id and a 20 byte hash
+fn base58check(version: u8, h160: &[u8]) -> String {
+    let mut addr = Vec::with_capacity(h160.len() + 5);
+    addr.push(version);
+    addr.extend_from_slice(h160);
+    let checksum = sha256(&sha256(&addr));
+    addr.extend_from_slice(&checksum[0..4]);
+    addr.to_base58()
+}
+
+/// Decodes a base58check encoded P2PKH or P2SH address into its version id and hash160.
+/// Returns None if the address is malformed or its checksum doesn't match.
+pub fn base58check_decode(address: &str) -> Option<(u8, [u8; 20])> {
+    let data = match address.from_base58() {
+        Ok(data) => data,
+        Err(_) => return None,
+    };
+    if data.len() != 25 || sha256(&sha256(&data[0..21]))[0..4] != data[21..25] {
+        return None;
+    }
+    let mut h160 = [0u8; 20];
+    h160.copy_from_slice(&data[1..21]);
+    Some((data[0], h160))
+}
+
+/// Encodes a witness program into a segwit address [BIP173/BIP350]
+fn segwit_encode(hrp: &str, witness_version: u8, program: &[u8], checksum_const: u32) -> String {
+    let mut data = Vec::with_capacity(1 + (program.len() * 8 + 4) / 5 + 6);
+    data.push(witness_version);
+    // Regroup 8 bit bytes into 5 bit groups, the last group is zero padded
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for b in program {
+        acc = (acc << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            data.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        data.push(((acc << (5 - bits)) & 31) as u8);
+    }
+
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    values.extend_from_slice(&[0u8; 6]);
+    let polymod = bech32_polymod(&values) ^ checksum_const;
+    for i in 0..6 {
+        data.push(((polymod >> (5 * (5 - i))) & 31) as u8);
+    }
+
+    let mut addr = String::with_capacity(hrp.len() + 1 + data.len());
+    addr.push_str(hrp);
+    addr.push('1');
+    for d in data {
+        addr.push(BECH32_CHARSET[d as usize] as char);
+    }
+    addr
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut values = Vec::with_capacity(hrp.len() * 2 + 1);
+    values.extend(hrp.bytes().map(|c| c >> 5));
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 31));
+    values
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *v as u32;
+        for i in 0..5 {
+            if (top >> i) & 1 == 1 {
+                chk ^= GEN[i];
+            }
+        }
+    }
+    chk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base58check, base58check_decode, Address};
+    use blockchain::parser::types::{Bitcoin, CoinType, Dogecoin, TestNet3};
+    use blockchain::utils::hex_to_vec;
+
+    #[test]
+    fn test_address_p2wpkh() {
+        // BIP173 test vector
+        let script = hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let addr = Address::from_script(&script, &CoinType::from(Bitcoin)).unwrap();
+        assert_eq!(
+            addr.to_string(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+    }
+
+    #[test]
+    fn test_address_p2wsh() {
+        // BIP173 test vector
+        let script =
+            hex_to_vec("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262");
+        let addr = Address::from_script(&script, &CoinType::from(TestNet3)).unwrap();
+        assert_eq!(
+            addr.to_string(),
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+        );
+    }
+
+    #[test]
+    fn test_address_p2tr() {
+        // BIP350 test vector
+        let script =
+            hex_to_vec("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+        let addr = Address::from_script(&script, &CoinType::from(Bitcoin)).unwrap();
+        assert_eq!(
+            addr.to_string(),
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"
+        );
+    }
+
+    #[test]
+    fn test_address_witness_without_segwit() {
+        // Dogecoin has no bech32 encoding
+        let script = hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        assert_eq!(
+            Address::from_script(&script, &CoinType::from(Dogecoin)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_address_p2sh_version() {
+        // OP_HASH160 e9c3dd0c07aac76179ebc76a6c78d4d67c6c160a OP_EQUAL
+        let script = hex_to_vec("a914e9c3dd0c07aac76179ebc76a6c78d4d67c6c160a87");
+        let addr = Address::from_script(&script, &CoinType::from(Bitcoin)).unwrap();
+        assert_eq!(addr.to_string(), "3P14159f73E4gFr7JterCCQh9QjiTjiZrG");
+        let addr = Address::from_script(&script, &CoinType::from(TestNet3)).unwrap();
+        assert_eq!(addr.to_string(), "2NEZG4p5giVjQt3Uez2Gip9PxMkwtF1Wdi9");
+    }
+
+    #[test]
+    fn test_base58check_decode() {
+        let (version, h160) = base58check_decode("1BitcoinEaterAddressDontSendf59kuE").unwrap();
+        assert_eq!(0x00, version);
+        assert_eq!(
+            "1BitcoinEaterAddressDontSendf59kuE",
+            base58check(version, &h160)
+        );
+        // Broken checksum and invalid characters
+        assert_eq!(
+            None,
+            base58check_decode("1BitcoinEaterAddressDontSendf59kuF")
+        );
+        assert_eq!(
+            None,
+            base58check_decode("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+        );
+    }
+}
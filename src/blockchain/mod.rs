@@ -1,3 +1,4 @@
+pub mod address;
 pub mod parser;
 pub mod proto;
 pub mod utils;
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::hash::BuildHasherDefault;
 use std::io::{Read, Write};
@@ -83,6 +83,60 @@ impl ChainStorage {
         Ok(())
     }
 
+    /// Returns the block the next index run builds upon:
+    /// the genesis block of `coin_type` for an empty storage, otherwise the latest known block.
+    pub fn root_hash(&self, coin_type: &CoinType) -> [u8; 32] {
+        match self.hashes.last() {
+            Some(hash) => *hash,
+            None => coin_type.genesis_hash,
+        }
+    }
+
+    /// Returns the height and hash of the latest processed block, which a resumed scan builds upon
+    pub fn checkpoint(&self) -> Option<(usize, [u8; 32])> {
+        match self.index {
+            0 => None,
+            index => self.hashes.get(index - 1).map(|hash| (index - 1, *hash)),
+        }
+    }
+
+    /// Checks that the checkpoint is still part of the longest chain within `header_map`.
+    /// Fails if the blk files changed since the last scan, e.g. because a reorg replaced the checkpoint block.
+    pub fn verify_checkpoint(
+        &self,
+        header_map: &HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,
+    ) -> OpResult<()> {
+        let (height, hash) = match self.checkpoint() {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(()),
+        };
+        // The checkpoint may be stored in an older blk file than the scanned ones.
+        // Then the latest known block is checked instead, the checkpoint is one of its ancestors.
+        let (height, hash) = match header_map.contains_key(&hash) {
+            true => (height, hash),
+            false => (self.hashes.len() - 1, *transform!(self.hashes.last())),
+        };
+        let reason = if !header_map.contains_key(&hash) {
+            "was not found in the scanned blk files"
+        } else if !ChainBuilder::longest_chain(header_map)
+            .iter()
+            .any(|header| header.hash == hash)
+        {
+            "is no longer part of the longest chain"
+        } else {
+            debug!(target: "chain", "Checkpoint {} at height {} is valid.", utils::arr_to_hex_swapped(&hash), height);
+            return Ok(());
+        };
+        let errbuf = format!(
+            "Block {} at height {} {}, the blk files changed since the last scan.\n\
+             Restart with `--reindex`, or point `--blockchain-dir` and `--chain-storage` to matching data.",
+            utils::arr_to_hex_swapped(&hash),
+            height,
+            reason
+        );
+        Err(OpError::new(OpErrorKind::ValidateError).join_msg(&errbuf))
+    }
+
     /// Loads serialized object and creates a new instance
     pub fn load(path: &Path) -> OpResult<ChainStorage> {
         let mut encoded = String::new();
@@ -139,6 +193,40 @@ impl ChainStorage {
     pub fn len(&self) -> usize {
         self.hashes_len
     }
+
+    /// Compares the block hashes of both storages height by height.
+    /// Blocks link to their predecessor, so both chains differ from the first mismatching height on.
+    pub fn diff(&self, other: &ChainStorage) -> ChainDiff {
+        let common = self
+            .hashes
+            .iter()
+            .zip(other.hashes.iter())
+            .take_while(|&(a, b)| a == b)
+            .count();
+        ChainDiff {
+            common_ancestor: match common {
+                0 => None,
+                n => Some((n - 1, self.hashes[n - 1])),
+            },
+            ours: self.hashes[common..].to_vec(),
+            theirs: other.hashes[common..].to_vec(),
+        }
+    }
+}
+
+/// Fork point and divergent blocks of two chain storages, see `ChainStorage::diff`
+#[derive(Debug, PartialEq)]
+pub struct ChainDiff {
+    pub common_ancestor: Option<(usize, [u8; 32])>, // Height and hash of the latest shared block
+    pub ours: Vec<[u8; 32]>, // Blocks after the common ancestor on the first chain
+    pub theirs: Vec<[u8; 32]>, // Blocks after the common ancestor on the second chain
+}
+
+impl ChainDiff {
+    /// Returns the height of the first divergent block
+    pub fn fork_height(&self) -> usize {
+        self.common_ancestor.map_or(0, |(height, _)| height + 1)
+    }
 }
 
 impl Default for ChainStorage {
@@ -153,25 +241,85 @@ impl Default for ChainStorage {
     }
 }
 
+/// Describes where a block received in ParseMode::FullData belongs to.
+#[derive(Debug, PartialEq)]
+pub enum BlockOrigin {
+    MainChain(usize), // Block is part of the longest chain at the given height
+    Duplicate(usize), // Block of the longest chain which was already received (e.g. from a copied blk file)
+    Stale,            // Block forks off the longest chain (stale or orphan block)
+    Unknown,          // Block is not indexed yet (e.g. written after the last index run)
+}
+
+/// Lookup table to decide whether a block belongs to the longest chain.
+/// Stale blocks are tracked by hash, so blocks building on top of them are recognized as well.
+/// Received blocks of the longest chain are tracked too, so each of them is passed on only once.
+pub struct ChainIndex {
+    heights: HashMap<[u8; 32], usize, BuildHasherDefault<XxHash>>,
+    stales: HashSet<[u8; 32], BuildHasherDefault<XxHash>>,
+    received: HashSet<[u8; 32], BuildHasherDefault<XxHash>>,
+}
+
+impl ChainIndex {
+    pub fn new(storage: &ChainStorage) -> ChainIndex {
+        let mut heights: HashMap<[u8; 32], usize, BuildHasherDefault<XxHash>> = Default::default();
+        heights.reserve(storage.hashes.len());
+        for (height, hash) in storage.hashes.iter().enumerate() {
+            heights.insert(*hash, height);
+        }
+        ChainIndex {
+            heights: heights,
+            stales: Default::default(),
+            received: Default::default(),
+        }
+    }
+
+    /// Classifies the given block header based on the longest chain.
+    /// A block is stale if its predecessor already has a different successor in the longest chain,
+    /// or if its predecessor is a known stale block.
+    /// Blocks of the longest chain are only classified as `MainChain` the first time they are seen.
+    pub fn classify(&mut self, header: &Hashed<BlockHeader>) -> BlockOrigin {
+        if let Some(height) = self.heights.get(&header.hash) {
+            if self.received.insert(header.hash) {
+                return BlockOrigin::MainChain(*height);
+            }
+            return BlockOrigin::Duplicate(*height);
+        }
+        let prev_hash = &header.value.prev_hash;
+        let is_stale = match self.heights.get(prev_hash) {
+            Some(height) => height + 1 < self.heights.len(),
+            None => self.stales.contains(prev_hash),
+        };
+        if is_stale {
+            self.stales.insert(header.hash);
+            BlockOrigin::Stale
+        } else {
+            BlockOrigin::Unknown
+        }
+    }
+}
+
 /// Helper class to sort blocks and determine the longest chain.
 /// The Hashmap consists of <K: BlockHash, V: BlockHeader>
+/// The chain starts at a given root block, chains ending in an unknown block are ignored.
 pub struct ChainBuilder<'a> {
     header_map: &'a HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,
+    root_hash: [u8; 32],
+    rooted: bool, // False if the root block is missing, then any root is accepted
 }
 
 impl<'a> ChainBuilder<'a> {
     /// Returns a Blockchain instance with the longest chain found.
-    /// First element is the genesis block.
+    /// First element is the root block, see `ChainStorage::root_hash()`.
     pub fn extract_blockchain(
         header_map: &HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,
+        root_hash: [u8; 32],
     ) -> OpResult<Vec<Hashed<BlockHeader>>> {
-        // Call our own Iterator implementation for ChainBuilder to traverse over the blockchain
-        let builder = ChainBuilder {
-            header_map: header_map,
-        };
-        let mut chain: Vec<Hashed<BlockHeader>> = builder.into_iter().collect();
-        chain.reverse();
-
+        let rooted = header_map.contains_key(&root_hash);
+        if !rooted {
+            warn!(target: "chain", "Root block {} not found, using the longest chain regardless of its first block.",
+                  utils::arr_to_hex_swapped(&root_hash));
+        }
+        let chain = ChainBuilder::traverse(header_map, root_hash, rooted);
         if chain.is_empty() {
             return Err(OpError::new(OpErrorKind::RuntimeError)
                 .join_msg("extract_blockchain() chain is empty!"));
@@ -183,6 +331,33 @@ impl<'a> ChainBuilder<'a> {
         return Ok(chain);
     }
 
+    /// Returns the longest chain found regardless of its first block, starting with the oldest block.
+    /// Unlike `extract_blockchain()` it doesn't build upon a known block, so it shows which branch won.
+    pub fn longest_chain(
+        header_map: &HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,
+    ) -> Vec<Hashed<BlockHeader>> {
+        if header_map.is_empty() {
+            return Vec::new();
+        }
+        ChainBuilder::traverse(header_map, [0u8; 32], false)
+    }
+
+    fn traverse(
+        header_map: &HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,
+        root_hash: [u8; 32],
+        rooted: bool,
+    ) -> Vec<Hashed<BlockHeader>> {
+        // Call our own Iterator implementation for ChainBuilder to traverse over the blockchain
+        let builder = ChainBuilder {
+            header_map: header_map,
+            root_hash: root_hash,
+            rooted: rooted,
+        };
+        let mut chain: Vec<Hashed<BlockHeader>> = builder.into_iter().collect();
+        chain.reverse();
+        chain
+    }
+
     /// finds all blocks with no successor blocks
     fn find_chain_leafs(&self) -> Vec<Hashed<BlockHeader>> {
         // Create a second hashmap with <K: PrevBlockHash, V: BlockHeader> to store all leafs
@@ -216,9 +391,15 @@ impl<'a> IntoIterator for &'a ChainBuilder<'a> {
         for leaf in leafs {
             let iter = RevBlockIterator {
                 header_map: &self.header_map,
+                root_hash: self.root_hash,
                 last_header: leaf.clone(),
             };
-            let height: usize = iter.count();
+            let (height, root) = iter.fold((0, leaf.hash), |(n, _), header| (n + 1, header.hash));
+            if self.rooted && root != self.root_hash {
+                trace!(target: "chain.iter", "Ignoring leaf {} with unknown root {}",
+                       utils::arr_to_hex_swapped(&leaf.hash), utils::arr_to_hex_swapped(&root));
+                continue;
+            }
             if height > best_height {
                 best_height = height;
                 best_leaf = leaf;
@@ -235,6 +416,7 @@ impl<'a> IntoIterator for &'a ChainBuilder<'a> {
         assert!(best_height > 0);
         RevBlockIterator {
             header_map: &self.header_map,
+            root_hash: self.root_hash,
             last_header: best_leaf,
         }
     }
@@ -244,6 +426,7 @@ impl<'a> IntoIterator for &'a ChainBuilder<'a> {
 /// Starts with the highest block found and goes down to the genesis block.
 pub struct RevBlockIterator<'a> {
     header_map: &'a HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,
+    root_hash: [u8; 32], // Block to stop at, its predecessors are ignored
     last_header: Hashed<BlockHeader>, // Indicates last position set by next()
 }
 
@@ -252,6 +435,11 @@ impl<'a> Iterator for RevBlockIterator<'a> {
 
     /// Returns 'previous' block which matches block.header.prev_hash
     fn next(&mut self) -> Option<Hashed<BlockHeader>> {
+        // Stop at the root: the prev_hash of the genesis block (all zeros) is not a dangling reference
+        // and the blocks before the latest known block are already stored
+        if self.last_header.hash == self.root_hash {
+            return None;
+        }
         let prev_hash = self.last_header.value.prev_hash;
         let prev_header = match self.header_map.get(&prev_hash) {
             Some(header) => Hashed::from(prev_hash, header.clone()),
@@ -265,12 +453,14 @@ impl<'a> Iterator for RevBlockIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::parser::types::{
+        Bitcoin, CoinType, Dogecoin, Litecoin, Myriadcoin, Namecoin, TestNet3,
+    };
     use blockchain::proto::header::BlockHeader;
     use blockchain::proto::Hashed;
     use blockchain::utils;
+    use common::testdir;
     use rustc_serialize::json;
-    use std::env;
     use std::fs;
 
     #[test]
@@ -302,7 +492,7 @@ mod tests {
         assert_eq!(1, chain_storage.latest_blk_idx);
 
         // Serialize storage
-        let pathbuf = env::temp_dir().as_path().join("chain.test.json");
+        let pathbuf = testdir::create("chain.test").join("chain.json");
         chain_storage.serialize(pathbuf.as_path()).unwrap();
 
         // Load storage
@@ -316,7 +506,7 @@ mod tests {
 
         assert_eq!(0, chain_storage.get_cur_height());
         assert_eq!(1, chain_storage.latest_blk_idx);
-        fs::remove_file(pathbuf.as_path()).unwrap();
+        fs::remove_dir_all(pathbuf.parent().unwrap()).unwrap();
 
         chain_storage.consume_next();
         assert_eq!(1, chain_storage.get_cur_height());
@@ -407,4 +597,290 @@ mod tests {
             Err(_) => panic!(),
         };
     }
+
+    /// Creates a child header for the given parent
+    fn child_of(parent: &Hashed<BlockHeader>, nonce: u32) -> Hashed<BlockHeader> {
+        Hashed::double_sha256(BlockHeader::new(
+            0x00000001,
+            parent.hash,
+            [0u8; 32],
+            parent.value.timestamp + 600,
+            0x1d00ffff,
+            nonce,
+        ))
+    }
+
+    #[test]
+    fn chain_index_classifies_synthetic_fork() {
+        // testnet3 genesis block, the fork on top of it is made up
+        let genesis = Hashed::double_sha256(BlockHeader::new(
+            0x00000001,
+            [0u8; 32],
+            utils::hex_to_arr32_swapped(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            ),
+            1296688602,
+            0x1d00ffff,
+            414098458,
+        ));
+        // Small reorg: b1 gets replaced by b2 -> c2 -> d2
+        //   genesis -> a -> b1 -> c1
+        //                -> b2 -> c2 -> d2
+        let a = child_of(&genesis, 1);
+        let b1 = child_of(&a, 2);
+        let c1 = child_of(&b1, 3);
+        let b2 = child_of(&a, 4);
+        let c2 = child_of(&b2, 5);
+        let d2 = child_of(&c2, 6);
+
+        let mut header_map: HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>> =
+            Default::default();
+        for header in vec![&genesis, &a, &b1, &c1, &b2, &c2, &d2] {
+            header_map.insert(header.hash, header.value.clone());
+        }
+        let headers =
+            ChainBuilder::extract_blockchain(&header_map, CoinType::from(TestNet3).genesis_hash)
+                .unwrap();
+
+        let mut chain_storage = ChainStorage::default();
+        chain_storage
+            .extend(headers, &CoinType::from(TestNet3), 0)
+            .unwrap();
+        assert_eq!(4, chain_storage.len());
+
+        let mut index = ChainIndex::new(&chain_storage);
+        assert_eq!(BlockOrigin::MainChain(0), index.classify(&genesis));
+        assert_eq!(BlockOrigin::MainChain(2), index.classify(&b2));
+        assert_eq!(BlockOrigin::MainChain(3), index.classify(&c2));
+        assert_eq!(BlockOrigin::Stale, index.classify(&b1));
+        assert_eq!(BlockOrigin::Stale, index.classify(&c1));
+        // Latest block is not part of the index yet
+        assert_eq!(BlockOrigin::Unknown, index.classify(&d2));
+    }
+
+    #[test]
+    fn chain_storage_diff() {
+        let storage = |hashes: &[u8]| {
+            let hashes: Vec<[u8; 32]> = hashes.iter().map(|&b| [b; 32]).collect();
+            ChainStorage {
+                hashes_len: hashes.len(),
+                hashes: hashes,
+                ..Default::default()
+            }
+        };
+        let ours = storage(&[0, 1, 2, 3]);
+        let theirs = storage(&[0, 1, 4, 5, 6]);
+        let diff = ours.diff(&theirs);
+        assert_eq!(Some((1, [1; 32])), diff.common_ancestor);
+        assert_eq!(2, diff.fork_height());
+        assert_eq!(vec![[2; 32], [3; 32]], diff.ours);
+        assert_eq!(vec![[4; 32], [5; 32], [6; 32]], diff.theirs);
+
+        // A chain which is behind only lacks blocks
+        let diff = ours.diff(&storage(&[0, 1]));
+        assert_eq!(Some((1, [1; 32])), diff.common_ancestor);
+        assert!(diff.theirs.is_empty());
+
+        let diff = ours.diff(&storage(&[7]));
+        assert_eq!(None, diff.common_ancestor);
+        assert_eq!(0, diff.fork_height());
+    }
+
+    #[test]
+    fn chain_storage_verifies_checkpoint() {
+        // testnet3 genesis block
+        let genesis = Hashed::double_sha256(BlockHeader::new(
+            0x00000001,
+            [0u8; 32],
+            utils::hex_to_arr32_swapped(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            ),
+            1296688602,
+            0x1d00ffff,
+            414098458,
+        ));
+        let a = child_of(&genesis, 1);
+        let b1 = child_of(&a, 2);
+        let c1 = child_of(&b1, 3);
+        let mut header_map: HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>> =
+            Default::default();
+        for header in vec![&genesis, &a, &b1, &c1] {
+            header_map.insert(header.hash, header.value.clone());
+        }
+        let mut chain_storage = ChainStorage::default();
+        chain_storage
+            .extend(
+                ChainBuilder::extract_blockchain(&header_map, genesis.hash).unwrap(),
+                &CoinType::from(TestNet3),
+                0,
+            )
+            .unwrap();
+        // Nothing processed yet
+        assert_eq!(None, chain_storage.checkpoint());
+        assert!(chain_storage.verify_checkpoint(&header_map).is_ok());
+        for _ in 0..3 {
+            chain_storage.consume_next();
+        }
+        assert_eq!(Some((2, b1.hash)), chain_storage.checkpoint());
+        assert!(chain_storage.verify_checkpoint(&header_map).is_ok());
+
+        // Reorg replaces the checkpoint b1 by b2 -> c2 -> d2
+        let b2 = child_of(&a, 4);
+        let c2 = child_of(&b2, 5);
+        let d2 = child_of(&c2, 6);
+        for header in vec![&b2, &c2, &d2] {
+            header_map.insert(header.hash, header.value.clone());
+        }
+        assert!(chain_storage.verify_checkpoint(&header_map).is_err());
+
+        // Blk files without the checkpoint or any of its descendants
+        header_map.remove(&b1.hash);
+        assert!(chain_storage.verify_checkpoint(&header_map).is_err());
+    }
+
+    #[test]
+    fn chain_builder_starts_at_genesis() {
+        // (coin, version, merkle root, time, bits, nonce) of the genesis blocks
+        let genesis_blocks = vec![
+            (
+                CoinType::from(Bitcoin),
+                1,
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                1231006505,
+                0x1d00ffff,
+                2083236893,
+            ),
+            (
+                CoinType::from(TestNet3),
+                1,
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                1296688602,
+                0x1d00ffff,
+                414098458,
+            ),
+            (
+                CoinType::from(Namecoin),
+                1,
+                "41c62dbd9068c89a449525e3cd5ac61b20ece28c3c38b3f35b2161f0e6d3cb0d",
+                1303000001,
+                0x1c007fff,
+                0xa21ea192,
+            ),
+            (
+                CoinType::from(Litecoin),
+                1,
+                "97ddfbbae6be97fd6cdf3e7ca13232a3afff2353e29badfab7f73011edd4ced9",
+                1317972665,
+                0x1e0ffff0,
+                2084524493,
+            ),
+            (
+                CoinType::from(Dogecoin),
+                1,
+                "5b2a3f53f605d62c53e62932dac6925e3d74afa5a4b459745c36d42d0ed26a69",
+                1386325540,
+                0x1e0ffff0,
+                99943,
+            ),
+            (
+                CoinType::from(Myriadcoin),
+                2,
+                "3f75db3c18e92f46c21530dc1222e1fddf4ccebbf88e289a6c9dc787fd6469da",
+                1393164995,
+                0x1e0fffff,
+                2092903596,
+            ),
+        ];
+        for (coin_type, version, merkle_root, time, bits, nonce) in genesis_blocks {
+            let genesis = Hashed::double_sha256(BlockHeader::new(
+                version,
+                [0u8; 32],
+                utils::hex_to_arr32_swapped(merkle_root),
+                time,
+                bits,
+                nonce,
+            ));
+            assert_eq!(coin_type.genesis_hash, genesis.hash);
+            let a = child_of(&genesis, 1);
+            let b = child_of(&a, 2);
+
+            // Longer chain of blocks whose parent is missing, e.g. from a later blk file
+            let mut dangling = vec![Hashed::double_sha256(BlockHeader::new(
+                0x00000001,
+                [0xffu8; 32],
+                [0u8; 32],
+                time + 600,
+                0x1d00ffff,
+                3,
+            ))];
+            for nonce in 4..8 {
+                let next = child_of(dangling.last().unwrap(), nonce);
+                dangling.push(next);
+            }
+
+            let mut header_map: HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>> =
+                Default::default();
+            for header in vec![&genesis, &a, &b].into_iter().chain(dangling.iter()) {
+                header_map.insert(header.hash, header.value.clone());
+            }
+            let headers =
+                ChainBuilder::extract_blockchain(&header_map, coin_type.genesis_hash).unwrap();
+            assert_eq!(
+                vec![genesis.hash, a.hash],
+                headers.iter().map(|h| h.hash).collect::<Vec<_>>()
+            );
+
+            let mut chain_storage = ChainStorage::default();
+            chain_storage.extend(headers, &coin_type, 0).unwrap();
+            assert_eq!(a.hash, chain_storage.root_hash(&coin_type));
+        }
+    }
+
+    #[test]
+    fn chain_index_detects_duplicate_blocks() {
+        let genesis = Hashed::double_sha256(BlockHeader::new(
+            0x00000001,
+            [0u8; 32],
+            utils::hex_to_arr32_swapped(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            ),
+            1296688602,
+            0x1d00ffff,
+            414098458,
+        ));
+        let a = child_of(&genesis, 1);
+        let b = child_of(&a, 2);
+        let c = child_of(&b, 3);
+
+        let mut header_map: HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>> =
+            Default::default();
+        for header in vec![&genesis, &a, &b, &c] {
+            header_map.insert(header.hash, header.value.clone());
+        }
+        let headers =
+            ChainBuilder::extract_blockchain(&header_map, CoinType::from(TestNet3).genesis_hash)
+                .unwrap();
+        let mut chain_storage = ChainStorage::default();
+        chain_storage
+            .extend(headers, &CoinType::from(TestNet3), 0)
+            .unwrap();
+
+        // Two copies of the same blk file: genesis -> a -> b -> genesis -> a -> b
+        let mut index = ChainIndex::new(&chain_storage);
+        let origins: Vec<BlockOrigin> = vec![&genesis, &a, &b, &genesis, &a, &b]
+            .into_iter()
+            .map(|header| index.classify(header))
+            .collect();
+        assert_eq!(
+            vec![
+                BlockOrigin::MainChain(0),
+                BlockOrigin::MainChain(1),
+                BlockOrigin::MainChain(2),
+                BlockOrigin::Duplicate(0),
+                BlockOrigin::Duplicate(1),
+                BlockOrigin::Duplicate(2),
+            ],
+            origins
+        );
+    }
 }
@@ -0,0 +1,220 @@
+use std::cmp::Ordering;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::header::BlockHeader;
+
+/// 256 bit target, least significant word first
+type Target = [u64; 4];
+
+/// Decodes the compact `bits` of a header, negative and overflowing targets aren't valid in headers
+fn from_compact(bits: u32) -> Target {
+    let size = (bits >> 24) as usize;
+    let word = (bits & 0x007fffff) as u64;
+    let mut target = [0u64; 4];
+    if size <= 3 {
+        target[0] = word >> (8 * (3 - size));
+        return target;
+    }
+    let shift = 8 * (size - 3);
+    let (limb, offset) = (shift / 64, shift % 64);
+    if limb < 4 {
+        target[limb] |= word << offset;
+    }
+    if offset > 0 && limb + 1 < 4 {
+        target[limb + 1] |= word >> (64 - offset);
+    }
+    target
+}
+
+/// Encodes `target` with a 3 byte mantissa, the lower bytes are truncated
+fn to_compact(target: &Target) -> u32 {
+    let n_bits = match target.iter().rposition(|&w| w != 0) {
+        Some(i) => i * 64 + 64 - target[i].leading_zeros() as usize,
+        None => 0,
+    };
+    let mut size = (n_bits + 7) / 8;
+    let mut compact = if size <= 3 {
+        target[0] << (8 * (3 - size))
+    } else {
+        let shift = 8 * (size - 3);
+        let (limb, offset) = (shift / 64, shift % 64);
+        let mut word = target[limb] >> offset;
+        if offset > 0 && limb + 1 < 4 {
+            word |= target[limb + 1] << (64 - offset);
+        }
+        word & 0x00ffffff
+    } as u32;
+    // The sign bit of the mantissa must not be set
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size as u32) << 24
+}
+
+/// Computes `target * mul / div`, None if the result doesn't fit into 256 bits
+fn mul_div(target: &Target, mul: u64, div: u64) -> Option<Target> {
+    let mut product = [0u64; 5];
+    let mut carry = 0u128;
+    for (i, &word) in target.iter().enumerate() {
+        let v = word as u128 * mul as u128 + carry;
+        product[i] = v as u64;
+        carry = v >> 64;
+    }
+    product[4] = carry as u64;
+
+    let mut rem = 0u128;
+    for word in product.iter_mut().rev() {
+        let v = rem << 64 | *word as u128;
+        *word = (v / div as u128) as u64;
+        rem = v % div as u128;
+    }
+    if product[4] != 0 {
+        return None;
+    }
+    Some([product[0], product[1], product[2], product[3]])
+}
+
+fn cmp(a: &Target, b: &Target) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+/// Returns the bits of the first block of a difficulty period, like `CalculateNextWorkRequired` of Bitcoin Core.
+/// `first_time` is the timestamp of the first and `last_bits` and `last_time` belong to the last block of the previous period.
+pub fn next_bits(
+    last_bits: u32,
+    first_time: u32,
+    last_time: u32,
+    target_timespan: u32,
+    pow_limit_bits: u32,
+) -> u32 {
+    // Retargets are limited to a factor of 4 in either direction
+    let timespan = (last_time as i64 - first_time as i64)
+        .max(target_timespan as i64 / 4)
+        .min(target_timespan as i64 * 4);
+    let pow_limit = from_compact(pow_limit_bits);
+    match mul_div(
+        &from_compact(last_bits),
+        timespan as u64,
+        target_timespan as u64,
+    ) {
+        Some(ref target) if cmp(target, &pow_limit) != Ordering::Greater => to_compact(target),
+        _ => to_compact(&pow_limit),
+    }
+}
+
+/// Recomputes the difficulty at each retarget boundary from the headers of the previous period (--validate-difficulty).
+/// Headers must be passed in height order, boundaries before the first complete period are skipped.
+pub struct DifficultyCheck {
+    interval: usize,           // Blocks between two retargets
+    target_timespan: u32,      // Expected duration of one period in seconds
+    pow_limit_bits: u32,       // Easiest allowed target
+    period_start: Option<u32>, // Timestamp of the first block of the current period
+    last: Option<(u32, u32)>,  // Timestamp and bits of the previous block
+}
+
+impl DifficultyCheck {
+    /// Returns None if the retarget algorithm of the coin is unknown
+    pub fn new(coin: &CoinType) -> Option<DifficultyCheck> {
+        let interval = coin.retarget_interval?;
+        Some(DifficultyCheck {
+            interval: interval,
+            target_timespan: interval as u32 * coin.block_interval,
+            pow_limit_bits: coin.pow_limit_bits,
+            period_start: None,
+            last: None,
+        })
+    }
+
+    /// Checks the header of the block at `height`, returns the expected bits if they don't match
+    pub fn check(&mut self, header: &BlockHeader, height: usize) -> Option<u32> {
+        let mut expected = None;
+        if height % self.interval == 0 {
+            if let (Some(first_time), Some((last_time, last_bits))) = (self.period_start, self.last)
+            {
+                let bits = next_bits(
+                    last_bits,
+                    first_time,
+                    last_time,
+                    self.target_timespan,
+                    self.pow_limit_bits,
+                );
+                if bits != header.bits {
+                    expected = Some(bits);
+                }
+            }
+            self.period_start = Some(header.timestamp);
+        }
+        self.last = Some((header.timestamp, header.bits));
+        expected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_compact, next_bits, to_compact, DifficultyCheck};
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::header::BlockHeader;
+
+    #[test]
+    fn test_compact() {
+        for &bits in &[0x1d00ffff, 0x1b0404cb, 0x17053894, 0x03123456, 0x01120000] {
+            assert_eq!(bits, to_compact(&from_compact(bits)));
+        }
+        assert_eq!([0, 0, 0, 0xffff0000], from_compact(0x1d00ffff));
+        // A mantissa with the sign bit set is stored with one more byte
+        assert_eq!(0x05009234, to_compact(&[0x92340000, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_next_bits() {
+        // First retarget of Bitcoin at block 32256, using blocks 30240 and 32255
+        assert_eq!(
+            0x1d00d86a,
+            next_bits(
+                0x1d00ffff,
+                1261130161,
+                1262152739,
+                14 * 24 * 60 * 60,
+                0x1d00ffff
+            )
+        );
+        // Slow periods never go beyond the limit
+        assert_eq!(
+            0x1d00ffff,
+            next_bits(
+                0x1d00ffff,
+                1231006505,
+                1239006505,
+                14 * 24 * 60 * 60,
+                0x1d00ffff
+            )
+        );
+    }
+
+    #[test]
+    fn test_difficulty_check() {
+        let mut check = DifficultyCheck::new(&CoinType::from(Bitcoin)).unwrap();
+        let header =
+            |timestamp, bits| BlockHeader::new(1, [0u8; 32], [0u8; 32], timestamp, bits, 0);
+
+        // Starts in the middle of a period, so the first boundary can't be checked
+        for height in 1000..2016 {
+            assert_eq!(
+                None,
+                check.check(&header(height as u32 * 600, 0x1d00ffff), height)
+            );
+        }
+        assert_eq!(None, check.check(&header(2016 * 600, 0x1c7fffff), 2016));
+        let time = |height: usize| (2016 * 600 + (height - 2016) * 300) as u32;
+        for height in 2017..4032 {
+            assert_eq!(None, check.check(&header(time(height), 0x1c7fffff), height));
+        }
+        // Half the targeted time between blocks, so the target is halved as well
+        assert_eq!(
+            Some(0x1c3ff7de),
+            check.check(&header(time(4032), 0x1c7fffff), 4032)
+        );
+        assert_eq!(None, check.check(&header(time(4033), 0x1c3ff7de), 4033));
+    }
+}
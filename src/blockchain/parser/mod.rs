@@ -1,24 +1,43 @@
 use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
 use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
 use time;
 use twox_hash::XxHash;
 
+use blockchain::parser::chain::{BlockOrigin, ChainIndex};
+use blockchain::parser::difficulty::DifficultyCheck;
+use blockchain::parser::reorder::ReorderBuffer;
+use blockchain::parser::shards::Shards;
 use blockchain::parser::worker::Worker;
 use blockchain::proto::block::Block;
 use blockchain::proto::header::BlockHeader;
-use blockchain::proto::Hashed;
-use blockchain::utils::blkfile::BlkFile;
+use blockchain::proto::varuint::VarUint;
+use blockchain::proto::{Hashed, ToRaw};
+use blockchain::utils;
+use blockchain::utils::blkfile::{BlkFile, FileFollower};
+use callbacks::Callback;
+use common::memory;
+use common::profile::{self, Phase};
+use common::shutdown;
 use errors::{OpError, OpErrorKind, OpResult};
 
 use ParserOptions;
 
 pub mod chain;
+pub mod difficulty;
+pub mod reorder;
+pub mod shards;
 pub mod types;
 pub mod worker;
 
+/// Number of blocks between two checks of the callback memory usage against `--memory-limit`
+const MEMORY_CHECK_INTERVAL: usize = 100;
+
 /// Specifies ParseMode. The first time the blockchain needs to be indexed,
 /// because we just need the block hashes to determine the longest chain.
 #[derive(Clone, Debug, PartialEq)]
@@ -30,30 +49,89 @@ pub enum ParseMode {
 /// Wrapper to pass different data between threads. Specified by ParseMode
 pub enum ParseResult {
     FullData(Block),
+    Verified(Block, BlockChecks), // Block with the checks which failed on the worker (--parallel-hash)
     Indexing(BlockHeader),
     Complete(String), // contains the name of the finished thread
     Error(OpError),   // Indicates critical error
 }
 
+/// Integrity checks of full blocks, see `--verify-merkle-root` and `--checksum-verify-blocks`.
+/// Run by the dispatcher, or by the workers ahead of dispatching with `--parallel-hash`.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct BlockChecks {
+    pub merkle_root: bool,
+    pub size: bool,
+}
+
+impl BlockChecks {
+    /// Returns the checks enabled in `options`, none of them works without transactions
+    fn enabled(options: &ParserOptions, headers_only: bool) -> BlockChecks {
+        BlockChecks {
+            merkle_root: options.verify_merkle_root && !headers_only,
+            size: options.checksum_verify_blocks && !headers_only,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.merkle_root || self.size
+    }
+
+    /// Runs the checks on `block` and returns the ones which failed
+    pub fn failed(&self, block: &Block) -> BlockChecks {
+        BlockChecks {
+            merkle_root: self.merkle_root && !block.verify_merkle_root(),
+            size: self.size && !block.verify_size(),
+        }
+    }
+
+    /// Describes the first failed check of `block`, if any
+    fn error_msg(&self, block: &Block) -> Option<String> {
+        if !self.any() {
+            return None;
+        }
+        let hash = utils::arr_to_hex_swapped(&block.header.hash);
+        match self.merkle_root {
+            true => Some(format!("Invalid merkle root of block {}", hash)),
+            false => Some(format!(
+                "Size of block {} doesn't match its reserialization",
+                hash
+            )),
+        }
+    }
+}
+
 /// Small struct to hold statistics together
 #[derive(Default)]
 struct WorkerStats {
     pub n_complete_msgs: usize, // Number of complete messages received from workers
     pub n_valid_blocks: u64,    // Number of received results from workers
     pub latest_blk_idx: u32,    // Latest processed blk file index
+    pub n_stale_blocks: u64,    // Number of skipped blocks which are not part of the longest chain
+    pub n_unknown_blocks: u64,  // Number of skipped blocks which are not indexed yet
+    pub n_duplicate_blocks: u64, // Number of skipped blocks which were already received
+    pub n_filtered_txs: u64,    // Number of transactions not matching --txid-prefix
+    pub n_txs: u64,             // Number of transactions passed to the callback
+    pub n_outputs: u64,         // Number of outputs passed to the callback
 }
 
 /// Implements simple thread pool pattern
 pub struct BlockchainParser<'a> {
     //TODO: make the collections for headers and blocks more generic
     unsorted_headers: HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>, /* holds all headers in parse mode HeadersOnly  */
-    unsorted_blocks: HashMap<[u8; 32], Block, BuildHasherDefault<XxHash>>, /* holds all blocks in parse mode FullData      */
+    unsorted_blocks: ReorderBuffer, /* holds all blocks in parse mode FullData      */
     remaining_files: Arc<Mutex<VecDeque<BlkFile>>>, /* Remaining files (shared between all threads) */
+    follower: Option<Arc<Mutex<FileFollower>>>, /* Picks up new blk files while indexing (--follow-new-files) */
     h_workers: Vec<JoinHandle<()>>, /* Worker job handles                           */
     mode: ParseMode,                /* ParseMode (FullData or Indexing)           */
+    headers_only: bool,             /* Blocks arrive without transactions (FullData) */
+    checks: BlockChecks,            /* Checks run by the dispatcher, by the workers with --parallel-hash */
     options: &'a mut ParserOptions, /* struct to hold cli arguments                 */
     chain_storage: chain::ChainStorage, /* Hash storage with the longest chain          */
+    chain_index: Option<ChainIndex>, /* Height lookup for the longest chain (FullData) */
+    difficulty_check: Option<DifficultyCheck>, /* Retarget validation (--validate-difficulty)  */
     stats: WorkerStats,             /* struct for thread management & statistics    */
+    bench_blocks: Vec<(Block, usize)>, /* Blocks and heights kept for --bench-loops     */
+    shards: Option<Shards>,         /* Additional callback instances for --parallel-write */
     t_started: f64,                 /* Start timestamp                              */
 }
 
@@ -66,23 +144,52 @@ impl<'a> BlockchainParser<'a> {
         chain_storage: chain::ChainStorage,
     ) -> Self {
         info!(target: "parser", "Parsing {} blockchain ...", options.coin_type.name);
-        match parse_mode {
+        let chain_index = match parse_mode {
             ParseMode::Indexing => {
                 info!(target: "parser", "Building blockchain index ...");
+                None
             }
             ParseMode::FullData => {
                 info!(target: "parser", "Parsing {} blocks with mode FullData.", chain_storage.remaining());
+                Some(ChainIndex::new(&chain_storage))
             }
         };
+        // Blocks of files written after the index run are unknown in FullData mode, so only the index follows them
+        let follower = match (options.follow_new_files, &parse_mode) {
+            (true, &ParseMode::Indexing) => Some(Arc::new(Mutex::new(FileFollower::new(
+                options.blockchain_dir.clone(),
+                options.scan_depth,
+                &blk_files,
+            )))),
+            _ => None,
+        };
+        let difficulty_check = match (options.validate_difficulty, &parse_mode) {
+            (true, &ParseMode::FullData) => {
+                let check = DifficultyCheck::new(&options.coin_type);
+                if check.is_none() {
+                    warn!(target: "parser", "The retarget algorithm of {} is unknown, ignoring --validate-difficulty.",
+                          options.coin_type.name);
+                }
+                check
+            }
+            _ => None,
+        };
         BlockchainParser {
             unsorted_headers: Default::default(),
-            unsorted_blocks: Default::default(),
+            unsorted_blocks: ReorderBuffer::new(options.reorder_buffer_size),
             remaining_files: Arc::new(Mutex::new(blk_files)),
+            follower: follower,
             h_workers: Vec::with_capacity(options.thread_count as usize),
             mode: parse_mode,
+            headers_only: false,
+            checks: BlockChecks::default(),
             options: options,
             chain_storage: chain_storage,
+            chain_index: chain_index,
+            difficulty_check: difficulty_check,
             stats: Default::default(),
+            bench_blocks: Vec::new(),
+            shards: None,
             t_started: 0.0,
         }
     }
@@ -90,11 +197,46 @@ impl<'a> BlockchainParser<'a> {
     /// Starts all workers. Needs an active mpsc channel
     pub fn start_worker(&mut self, tx_channel: mpsc::SyncSender<ParseResult>) -> OpResult<()> {
         self.t_started = time::precise_time_s();
+        let mut worker_checks = BlockChecks::default();
         if self.mode == ParseMode::FullData {
+            self.headers_only = self.options.callback.headers_only();
+            if self.headers_only {
+                info!(target: "parser", "Callback only needs block headers, skipping all transactions.");
+                if self.options.verify_merkle_root {
+                    warn!(target: "parser", "Merkle roots can't be verified without transactions, ignoring --verify-merkle-root.");
+                }
+                if self.options.checksum_verify_blocks {
+                    warn!(target: "parser", "Block sizes can't be verified without transactions, ignoring --checksum-verify-blocks.");
+                }
+            }
+            let checks = BlockChecks::enabled(self.options, self.headers_only);
+            match self.options.parallel_hash {
+                true if checks.any() => {
+                    info!(target: "parser", "Verifying blocks on the worker threads.");
+                    worker_checks = checks;
+                }
+                true if !self.headers_only => {
+                    warn!(target: "parser", "Nothing to verify, ignoring --parallel-hash. \
+                                             Use it with --verify-merkle-root or --checksum-verify-blocks.");
+                }
+                _ => self.checks = checks,
+            }
+            if let Some(ref prefix) = self.options.txid_prefix {
+                warn!(target: "parser", "Only transactions with txid prefix `{}` are passed to the callback.", prefix);
+            }
             (*self.options.callback).on_start(
                 self.options.coin_type.clone(),
                 self.chain_storage.get_cur_height(),
             );
+            if let Some(ref shard_options) = self.options.parallel_write {
+                info!(target: "parser", "Writing {} shards in parallel.", shard_options.n_shards);
+                self.shards = Some(Shards::start(
+                    &*self.options.callback,
+                    shard_options,
+                    &self.options.coin_type,
+                    self.chain_storage.get_cur_height(),
+                )?);
+            }
         }
 
         // save latest blk file index for resume mode.
@@ -111,7 +253,12 @@ impl<'a> BlockchainParser<'a> {
             let tx = tx_channel.clone();
             let coin_type = self.options.coin_type.clone();
             let remaining_files = self.remaining_files.clone(); // Increment arc
+            let follower = self.follower.clone();
             let mode = self.mode.clone();
+            let headers_only = self.headers_only;
+            let checks = worker_checks;
+            let read_limiter = self.options.read_limiter.clone();
+            let unknown_magic_coin = self.options.unknown_magic_coin.clone();
 
             let rem = remaining_files.lock()?.len();
             if rem == 0 {
@@ -121,7 +268,17 @@ impl<'a> BlockchainParser<'a> {
             // Spawn worker
             let child =
                 thread::Builder::new().name(format!("worker-{}", i)).spawn(
-                    move || match Worker::new(tx, remaining_files, coin_type, mode) {
+                    move || match Worker::new(
+                        tx,
+                        remaining_files,
+                        follower,
+                        coin_type,
+                        mode,
+                        headers_only,
+                        checks,
+                        read_limiter,
+                        unknown_magic_coin,
+                    ) {
                         Ok(mut w) => w.process(),
                         Err(OpError {
                             kind: OpErrorKind::None,
@@ -146,8 +303,13 @@ impl<'a> BlockchainParser<'a> {
         let rx = rx_channel;
         let mut t_last_log = time::precise_time_s();
         let t_measure_frame = 10.0;
+        let mut last_counts = (0, 0); // Transactions and outputs at the last status line
 
         loop {
+            if shutdown::is_interrupted() {
+                return self.on_interrupt();
+            }
+
             // Retrieve data from mpsc channel
             match rx.try_recv() {
                 Ok(result) => {
@@ -166,6 +328,15 @@ impl<'a> BlockchainParser<'a> {
                                 info!(target:"dispatch", "Status: {:6} Blocks added to index. (avg: {:5.2} blocks/sec)",
                                      self.stats.n_valid_blocks, blocks_sec);
                             }
+                            ParseMode::FullData if self.options.show_tx_rate => {
+                                // Rates over the last frame, as block fullness varies a lot over time
+                                let t_frame = now - t_last_log;
+                                info!(target:"dispatch", "Status: {:6} Blocks processed. (left: {:6}, avg: {:5.2} blocks/sec, {:8.1} tx/sec, {:8.1} outputs/sec)",
+                                     self.stats.n_valid_blocks, self.chain_storage.remaining(), blocks_sec,
+                                     (self.stats.n_txs - last_counts.0) as f64 / t_frame,
+                                     (self.stats.n_outputs - last_counts.1) as f64 / t_frame);
+                                last_counts = (self.stats.n_txs, self.stats.n_outputs);
+                            }
                             ParseMode::FullData => {
                                 info!(target:"dispatch", "Status: {:6} Blocks processed. (left: {:6}, avg: {:5.2} blocks/sec)",
                                      self.stats.n_valid_blocks, self.chain_storage.remaining(), blocks_sec);
@@ -178,10 +349,10 @@ impl<'a> BlockchainParser<'a> {
                 Err(mpsc::TryRecvError::Disconnected) => {}
             }
 
-            // Check if the next block is in unsorted HashMap
+            // Check if the next block is in the reorder buffer
             if let Some(next_hash) = self.chain_storage.get_next() {
                 if let Some(block) = self.unsorted_blocks.remove(&next_hash) {
-                    self.on_block(block);
+                    self.on_block(block)?;
                 }
             }
             // Check if all threads are finished
@@ -200,25 +371,25 @@ impl<'a> BlockchainParser<'a> {
     fn dispatch_worker_msg(&mut self, result: ParseResult) -> OpResult<()> {
         match result {
             // If a block arrives in the desired order, pass it to the callback
-            // if not, add it to the unsorted HashMap for later dispatching
+            // if not, add it to the reorder buffer for later dispatching.
+            // Blocks which are not part of the longest chain never reach the callback.
             ParseResult::FullData(block) => {
-                if self.options.verify_merkle_root {
-                    block.verify_merkle_root();
-                }
-
-                if let Some(next_hash) = self.chain_storage.get_next() {
-                    if block.header.hash == next_hash {
-                        self.on_block(block);
-                    } else {
-                        self.unsorted_blocks.insert(block.header.hash, block);
-                    }
-                }
+                let failed = self.checks.failed(&block);
+                self.dispatch_block(block, failed)?;
             }
+            ParseResult::Verified(block, failed) => self.dispatch_block(block, failed)?,
             // Collect headers to built a valid blockchain
             ParseResult::Indexing(header) => {
                 let header = Hashed::double_sha256(header);
-                self.unsorted_headers.insert(header.hash, header.value);
-                self.stats.n_valid_blocks += 1;
+                if self
+                    .unsorted_headers
+                    .insert(header.hash, header.value)
+                    .is_some()
+                {
+                    self.stats.n_duplicate_blocks += 1;
+                } else {
+                    self.stats.n_valid_blocks += 1;
+                }
             }
             // Collect complete messages
             ParseResult::Complete(name) => {
@@ -233,11 +404,163 @@ impl<'a> BlockchainParser<'a> {
         Ok(())
     }
 
+    /// Passes `block` to the callback, buffers it or skips it if it isn't part of the main chain.
+    /// `failed` holds the checks which failed on it, these abort the scan with `--strict`.
+    fn dispatch_block(&mut self, block: Block, failed: BlockChecks) -> OpResult<()> {
+        if self.options.strict {
+            if let Some(msg) = failed.error_msg(&block) {
+                return Err(strict_error(self.options, &block, &msg));
+            }
+        }
+
+        // Every block is classified first, so duplicates never reach the callback
+        let origin = transform!(self.chain_index.as_mut()).classify(&block.header);
+        match origin {
+            BlockOrigin::MainChain(height) => {
+                if self.chain_storage.get_next() == Some(block.header.hash) {
+                    self.on_block(block)?;
+                } else if height > self.chain_storage.get_cur_height() {
+                    // Blocks below the current height were processed by a previous run
+                    if !self.unsorted_blocks.insert(block) {
+                        return Err(self.reorder_buffer_error());
+                    }
+                }
+            }
+            BlockOrigin::Duplicate(height) => {
+                debug!(target: "dispatch", "Skipping duplicate block {} at height {}",
+                       utils::arr_to_hex_swapped(&block.header.hash), height);
+                self.stats.n_duplicate_blocks += 1;
+            }
+            BlockOrigin::Stale => {
+                debug!(target: "dispatch", "Skipping stale block {}",
+                       utils::arr_to_hex_swapped(&block.header.hash));
+                self.stats.n_stale_blocks += 1;
+            }
+            BlockOrigin::Unknown => {
+                trace!(target: "dispatch", "Skipping unindexed block {}",
+                       utils::arr_to_hex_swapped(&block.header.hash));
+                self.stats.n_unknown_blocks += 1;
+            }
+        }
+        Ok(())
+    }
+
     /// Triggers the callback and consumes the current block
-    fn on_block(&mut self, block: Block) {
-        (*self.options.callback).on_block(block, self.chain_storage.get_cur_height());
+    fn on_block(&mut self, mut block: Block) -> OpResult<()> {
+        match self.options.txid_prefix {
+            // Headers only blocks have no transactions to filter
+            Some(ref prefix) if !self.headers_only => {
+                block
+                    .txs
+                    .retain(|tx| utils::starts_with_hex_swapped(&tx.hash, prefix));
+                self.stats.n_filtered_txs += block.tx_count.value - block.txs.len() as u64;
+                block.tx_count = VarUint::from(block.txs.len() as u64);
+            }
+            _ => (),
+        }
+        self.stats.n_txs += block.txs.len() as u64;
+        self.stats.n_outputs += block
+            .txs
+            .iter()
+            .map(|tx| tx.value.out_count.value)
+            .sum::<u64>();
+        let height = self.chain_storage.get_cur_height();
+        self.check_difficulty(&block, height)?;
+        if height % self.options.block_sample != 0 {
+            // Left out by --block-sample
+        } else if self.options.bench_loops.is_some() {
+            // Replayed from memory once all blocks are read
+            self.bench_blocks.push((block, height));
+        } else {
+            let block = match self.shards {
+                Some(ref shards) => shards.dispatch(block, height)?,
+                None => Some(block),
+            };
+            if let Some(block) = block {
+                let t_start = profile::start();
+                (*self.options.callback).on_block(block, height);
+                profile::record(Phase::Callback, t_start);
+            }
+        }
         self.stats.n_valid_blocks += 1;
         self.chain_storage.consume_next();
+
+        match self.options.memory_limit {
+            Some(limit) if self.chain_storage.get_cur_height() % MEMORY_CHECK_INTERVAL == 0 => {
+                self.check_memory(limit)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Compares the bits of `block` to the retarget algorithm, a mismatch aborts with `--strict`
+    fn check_difficulty(&mut self, block: &Block, height: usize) -> OpResult<()> {
+        let expected = match self.difficulty_check {
+            Some(ref mut check) => match check.check(&block.header.value, height) {
+                Some(expected) => expected,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let msg = format!(
+            "Block {} at height {} has difficulty bits {:#010x}, the retarget algorithm expects {:#010x}",
+            utils::arr_to_hex_swapped(&block.header.hash),
+            height,
+            block.header.value.bits,
+            expected
+        );
+        if self.options.strict {
+            return Err(strict_error(self.options, block, &msg));
+        }
+        warn!(target: "dispatch", "{}", msg);
+        Ok(())
+    }
+
+    /// Describes the block which is holding up the reorder buffer
+    fn reorder_buffer_error(&self) -> OpError {
+        let next_hash = match self.chain_storage.get_next() {
+            Some(hash) => utils::arr_to_hex_swapped(&hash),
+            None => String::from("none"),
+        };
+        let msg = format!(
+            "{} blocks ({}) arrived before block {} at height {}, which exceeds --reorder-buffer-size of {}. \
+             The blk files are probably missing this block, try --reindex. \
+             If they are complete, raise --reorder-buffer-size.",
+            self.unsorted_blocks.len(),
+            memory::format_mb(self.unsorted_blocks.n_bytes()),
+            next_hash,
+            self.chain_storage.get_cur_height(),
+            memory::format_mb(self.unsorted_blocks.max_bytes())
+        );
+        OpError::new(OpErrorKind::RuntimeError).join_msg(&msg)
+    }
+
+    /// Lets the callback free memory if its estimated usage approaches `limit` bytes.
+    /// Aborts if the usage still exceeds the limit, which is easier to act on than the OS killing the process.
+    fn check_memory(&mut self, limit: usize) -> OpResult<()> {
+        let callback = &mut self.options.callback;
+        let mut usage = callback.memory_usage();
+        if usage < limit / 10 * 9 {
+            return Ok(());
+        }
+        // Leave some headroom, so the next check doesn't trigger again right away
+        if callback.reduce_memory(limit / 4 * 3) {
+            let reduced = callback.memory_usage();
+            info!(target: "dispatch", "Callback memory usage reached {} of the {} limit, reduced it to {}.",
+                  memory::format_mb(usage), memory::format_mb(limit), memory::format_mb(reduced));
+            usage = reduced;
+        }
+        if usage > limit {
+            let msg = format!(
+                "Callback needs about {} at block {}, which exceeds --memory-limit of {}. \
+                 Aborting before the system runs out of memory.",
+                memory::format_mb(usage),
+                self.chain_storage.get_cur_height(),
+                memory::format_mb(limit)
+            );
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&msg));
+        }
+        Ok(())
     }
 
     /// Internal method whichs gets called if all workers are finished
@@ -249,23 +572,102 @@ impl<'a> BlockchainParser<'a> {
               (self.stats.n_valid_blocks)
                 .checked_div((t_fin - self.t_started) as u64)
                 .unwrap_or(self.stats.n_valid_blocks));
+        if self.stats.n_duplicate_blocks > 0 {
+            warn!(target: "dispatch", "Skipped {} duplicate blocks. Check {:?} for copies of blk files.",
+                  self.stats.n_duplicate_blocks, self.options.blockchain_dir);
+        }
 
         match self.mode {
             ParseMode::FullData => {
+                info!(target: "dispatch", "Skipped {} stale blocks and {} blocks which are not indexed yet.",
+                      self.stats.n_stale_blocks, self.stats.n_unknown_blocks);
+                if let Some(ref prefix) = self.options.txid_prefix {
+                    info!(target: "dispatch", "Skipped {} transactions not matching txid prefix `{}`.",
+                          self.stats.n_filtered_txs, prefix);
+                }
+                if let Some(loops) = self.options.bench_loops {
+                    replay_blocks(&mut *self.options.callback, &self.bench_blocks, loops);
+                }
+                (*self.options.callback)
+                    .on_skipped_blocks(self.stats.n_stale_blocks, self.stats.n_duplicate_blocks);
+                let t_start = profile::start();
                 (*self.options.callback).on_complete(self.chain_storage.get_cur_height());
+                profile::record(Phase::Callback, t_start);
+                self.complete_shards()?;
             }
             _ => (),
         };
+        if let Some(report) = profile::take_report() {
+            info!(target: "dispatch", "{}", report);
+        }
         self.save_chain_state()?;
+        (*self.options.callback).check_result()
+    }
+
+    /// Gets called if the user interrupted the parser.
+    /// Finalizes the callback and saves the chain state, so the next run continues at the current height.
+    fn on_interrupt(&mut self) -> OpResult<()> {
+        let t_elapsed = time::precise_time_s() - self.t_started;
+        let reason = match shutdown::is_timed_out() {
+            true => "Reached --max-runtime",
+            false => "Interrupted",
+        };
+        match self.mode {
+            ParseMode::FullData => {
+                // Finalize callback files before telling the user it is safe to resume
+                let cur_height = self.chain_storage.get_cur_height();
+                (*self.options.callback)
+                    .on_skipped_blocks(self.stats.n_stale_blocks, self.stats.n_duplicate_blocks);
+                (*self.options.callback).on_complete(cur_height);
+                self.complete_shards()?;
+
+                // Keep the blk file index of the previous run, because the next
+                // blocks may be stored in any file after it.
+                self.stats.latest_blk_idx = self.chain_storage.latest_blk_idx;
+                self.save_chain_state()?;
+
+                let last_height = match cur_height {
+                    0 => String::from("none"),
+                    h => (h - 1).to_string(),
+                };
+                info!(target: "dispatch", "{} after {:.2} minutes. Last processed block height: {} ({:.2}% of {} blocks).\n\
+                                           \t-> Chain state saved to {:?}. Run the same command again to continue at block {}.",
+                      reason, t_elapsed / 60.0, last_height,
+                      cur_height as f64 / self.chain_storage.len().max(1) as f64 * 100.0,
+                      self.chain_storage.len(), self.options.chain_storage_path, cur_height);
+            }
+            ParseMode::Indexing => {
+                info!(target: "dispatch", "{} after {:.2} minutes while indexing ({} headers collected). \
+                                           The index was not saved, the next run starts indexing again.",
+                      reason, t_elapsed / 60.0, self.stats.n_valid_blocks);
+            }
+        }
         Ok(())
     }
 
+    /// Finalizes the shards of --parallel-write, if any
+    fn complete_shards(&mut self) -> OpResult<()> {
+        match (self.shards.take(), self.options.parallel_write.as_ref()) {
+            (Some(shards), Some(shard_options)) => {
+                shards.complete(shard_options, self.chain_storage.get_cur_height())
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Searches for the longest chain and writes the hashes t
     fn save_chain_state(&mut self) -> OpResult<usize> {
         info!(target: "dispatch", "Saving block headers as {:?} ...", self.options.chain_storage_path);
+        if self.mode == ParseMode::Indexing && self.options.resume_verify {
+            self.chain_storage
+                .verify_checkpoint(&self.unsorted_headers)?;
+        }
         // Update chain storage
         let headers = match self.mode {
-            ParseMode::Indexing => chain::ChainBuilder::extract_blockchain(&self.unsorted_headers)?,
+            ParseMode::Indexing => chain::ChainBuilder::extract_blockchain(
+                &self.unsorted_headers,
+                self.chain_storage.root_hash(&self.options.coin_type),
+            )?,
             ParseMode::FullData => Vec::new(),
         };
         self.chain_storage
@@ -274,3 +676,525 @@ impl<'a> BlockchainParser<'a> {
             .serialize(self.options.chain_storage_path.as_path())
     }
 }
+
+/// Passes `blocks` `loops` times to the callback for --bench-loops.
+/// Only the time spent in the callback is measured, cloning the blocks for each call is excluded.
+fn replay_blocks<C: Callback + ?Sized>(callback: &mut C, blocks: &[(Block, usize)], loops: usize) {
+    warn!(target: "bench", "Replaying {} blocks {} times. Callbacks see every block repeatedly, so their results are meaningless!",
+          blocks.len(), loops);
+    // The profile should only cover the replay, not reading the blocks
+    profile::take_report();
+    let mut t_total = 0.0;
+    for i in 0..loops {
+        let mut t_loop = 0.0;
+        for &(ref block, height) in blocks {
+            let block = block.clone();
+            let t_start = time::precise_time_s();
+            let t_profile = profile::start();
+            callback.on_block(block, height);
+            profile::record(Phase::Callback, t_profile);
+            t_loop += time::precise_time_s() - t_start;
+        }
+        info!(target: "bench", "Loop {:3}: {:8.3} seconds ({:8.2} blocks/sec)",
+              i + 1, t_loop, blocks.len() as f64 / t_loop);
+        t_total += t_loop;
+    }
+    info!(target: "bench", "Replayed {} blocks {} times in {:.3} seconds (avg: {:.3} seconds per loop).",
+          blocks.len(), loops, t_total, t_total / loops as f64);
+}
+
+/// Writes `block` into `dir` for debugging: the raw bytes as `block-<hash>.bin` and the decoded fields as `block-<hash>.txt`.
+/// Returns the path of the latter.
+fn dump_block(dir: &Path, block: &Block, reason: &str) -> OpResult<PathBuf> {
+    let name = format!("block-{}", utils::arr_to_hex_swapped(&block.header.hash));
+    fs::create_dir_all(dir)?;
+    File::create(dir.join(format!("{}.bin", name)))?.write_all(&block.to_bytes())?;
+
+    let mut text = format!("{}\n\n{:#?}\n", reason, block);
+    for tx in &block.txs {
+        text.push_str(&format!("\n{:#?}\n", tx));
+        for input in &tx.value.inputs {
+            text.push_str(&format!("{:#?}\n", input));
+        }
+        for output in &tx.value.outputs {
+            let address = match output.script.address {
+                Some(ref address) => address.to_string(),
+                None => String::from("none"),
+            };
+            text.push_str(&format!(
+                "{:#?}\npattern: {}, address: {}\n",
+                output.out, output.script.pattern, address
+            ));
+        }
+    }
+    let path = dir.join(format!("{}.txt", name));
+    File::create(&path)?.write_all(text.as_bytes())?;
+    Ok(path)
+}
+
+/// Returns the error for a `--strict` check `block` failed, after dumping it with `--dump-failing-block`
+fn strict_error(options: &ParserOptions, block: &Block, msg: &str) -> OpError {
+    let err = OpError::new(OpErrorKind::ValidateError).join_msg(msg);
+    let dir = match options.dump_failing_block {
+        Some(ref dir) => dir,
+        None => return err,
+    };
+    match dump_block(dir, block, msg) {
+        Ok(path) => tag_err!(err, ", the block was dumped to {:?}", path),
+        Err(e) => {
+            warn!(target: "parser", "Unable to dump the failing block into {:?}: {}", dir, e);
+            err
+        }
+    }
+}
+
+/// Parses `blk_file` on its own and passes all blocks to the callback in the order they are stored.
+/// No chain is built, so stale blocks are included and the height given to the callback is the position in the file.
+pub fn parse_single_file(options: &mut ParserOptions, blk_file: BlkFile) -> OpResult<()> {
+    let t_started = time::precise_time_s();
+    let path = blk_file.path.clone();
+    let headers_only = options.callback.headers_only();
+    warn!(target: "parser", "Parsing {:?} without the rest of the chain. Results of callbacks tracking \
+                             the UTXO set or block heights are meaningless!", path);
+    if options.validate_difficulty {
+        warn!(target: "parser", "Block heights are unknown without the chain, ignoring --validate-difficulty.");
+    }
+
+    let (tx, rx) = mpsc::sync_channel(options.worker_backlog);
+    let remaining_files = Arc::new(Mutex::new(VecDeque::from(vec![blk_file])));
+    let coin_type = options.coin_type.clone();
+    let read_limiter = options.read_limiter.clone();
+    let unknown_magic_coin = options.unknown_magic_coin.clone();
+    let (checks, worker_checks) = match options.parallel_hash {
+        true => (
+            BlockChecks::default(),
+            BlockChecks::enabled(options, headers_only),
+        ),
+        false => (
+            BlockChecks::enabled(options, headers_only),
+            BlockChecks::default(),
+        ),
+    };
+    thread::Builder::new()
+        .name(String::from("worker-0"))
+        .spawn(move || {
+            match Worker::new(
+                tx,
+                remaining_files,
+                None,
+                coin_type,
+                ParseMode::FullData,
+                headers_only,
+                worker_checks,
+                read_limiter,
+                unknown_magic_coin,
+            ) {
+                Ok(mut w) => w.process(),
+                Err(err) => {
+                    error!(target: thread::current().name().unwrap(), "{}", err);
+                }
+            }
+        })?;
+    (*options.callback).on_start(options.coin_type.clone(), 0);
+
+    let (mut n_blocks, mut n_txs, mut n_unordered) = (0, 0, 0);
+    let mut prev_hash = None;
+    let mut time_range: Option<(u32, u32)> = None;
+    let mut end_offset = 0; // End of the last complete block
+    let mut bench_blocks = Vec::new();
+    while !shutdown::is_interrupted() {
+        let (block, failed) = match rx.recv() {
+            Ok(ParseResult::FullData(block)) => {
+                let failed = checks.failed(&block);
+                (block, failed)
+            }
+            Ok(ParseResult::Verified(block, failed)) => (block, failed),
+            Ok(ParseResult::Error(err)) => {
+                (*options.callback).on_complete(n_blocks);
+                return Err(tag_err!(
+                    err,
+                    "{:?} is corrupt after {} valid blocks (byte offset {}).",
+                    path,
+                    n_blocks,
+                    end_offset
+                ));
+            }
+            Ok(ParseResult::Indexing(_)) => continue,
+            Ok(ParseResult::Complete(_)) | Err(_) => break,
+        };
+        if options.strict {
+            if let Some(msg) = failed.error_msg(&block) {
+                (*options.callback).on_complete(n_blocks);
+                return Err(strict_error(options, &block, &msg));
+            }
+        }
+        // Blocks are stored in the order they arrived, which doesn't always match the chain
+        if prev_hash.is_some() && prev_hash != Some(block.header.value.prev_hash) {
+            n_unordered += 1;
+        }
+        let timestamp = block.header.value.timestamp;
+        time_range = match time_range {
+            Some((min, max)) => Some((min.min(timestamp), max.max(timestamp))),
+            None => Some((timestamp, timestamp)),
+        };
+        prev_hash = Some(block.header.hash);
+        end_offset = block.blk_offset + block.blocksize as usize;
+        n_txs += block.tx_count.value;
+
+        if n_blocks % options.block_sample != 0 {
+            // Left out by --block-sample
+        } else if options.bench_loops.is_some() {
+            bench_blocks.push((block, n_blocks));
+        } else {
+            (*options.callback).on_block(block, n_blocks);
+        }
+        n_blocks += 1;
+    }
+    if let Some(loops) = options.bench_loops {
+        replay_blocks(&mut *options.callback, &bench_blocks, loops);
+    }
+    (*options.callback).on_complete(n_blocks);
+
+    let (first, last) = time_range.unwrap_or((0, 0));
+    info!(target: "parser", "Parsed {} blocks with {} transactions from {:?} in {:.2} seconds.\n\
+                             \t-> block timestamps:  {} - {} (unix time)\n\
+                             \t-> out of order:      {} blocks don't build on the block stored before them",
+          n_blocks, n_txs, path, time::precise_time_s() - t_started, first, last, n_unordered);
+    if let Some(report) = profile::take_report() {
+        info!(target: "parser", "{}", report);
+    }
+    (*options.callback).check_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::header::BlockHeader;
+    use blockchain::proto::{Hashed, ToRaw};
+    use blockchain::utils;
+    use callbacks::testsink::TestSink;
+    use common::testdir;
+    use common::writer;
+
+    /// Writes blocks without transactions for `headers` into the blk file `name` in `dir`,
+    /// each followed by `padding` zero bytes counted in its size
+    fn write_blk_file(dir: &Path, name: &str, headers: &[Hashed<BlockHeader>], padding: usize) {
+        let magic = CoinType::from(Bitcoin).magic;
+        let mut file = File::create(dir.join(name)).unwrap();
+        for header in headers {
+            let mut block = header.value.to_bytes();
+            block.push(0); // tx_count
+            block.extend_from_slice(&vec![0u8; padding]);
+            file.write_u32::<LittleEndian>(magic).unwrap();
+            file.write_u32::<LittleEndian>(block.len() as u32).unwrap();
+            file.write_all(&block).unwrap();
+        }
+    }
+
+    /// Returns the bitcoin genesis header followed by three empty children
+    fn chain_headers() -> Vec<Hashed<BlockHeader>> {
+        let genesis = Hashed::double_sha256(BlockHeader::new(
+            0x00000001,
+            [0u8; 32],
+            utils::hex_to_arr32_swapped(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            ),
+            1231006505,
+            0x1d00ffff,
+            2083236893,
+        ));
+        let mut headers = vec![genesis];
+        for nonce in 1..4 {
+            let prev_hash = headers.last().unwrap().hash;
+            headers.push(Hashed::double_sha256(BlockHeader::new(
+                0x00000001,
+                prev_hash,
+                [0u8; 32],
+                1231006505 + nonce * 600,
+                0x1d00ffff,
+                nonce,
+            )));
+        }
+        headers
+    }
+
+    /// Parses the blk files in `dir` and returns the rows recorded by a TestSink
+    fn run_test_sink(dir: &Path, block_sample: usize) -> Vec<String> {
+        let chain_storage = dir.join("chain.json");
+        let args = vec![
+            "rusty-blockparser",
+            "--blockchain-dir",
+            dir.to_str().unwrap(),
+            "--chain-storage",
+            chain_storage.to_str().unwrap(),
+            "simplestats",
+        ];
+        let mut options = ::parse_args(&::build_app().get_matches_from(args)).unwrap();
+        let (sink, rows) = TestSink::with_buffer();
+        options.callback = Box::new(sink);
+        // TestSink doesn't allow sampling, so it's set after the args were checked
+        options.block_sample = block_sample;
+        ::run(&mut options).unwrap();
+        let rows = rows.lock().unwrap().clone();
+        rows
+    }
+
+    #[test]
+    fn test_run_passes_main_chain_to_callback() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.run.test");
+
+        let mut headers = chain_headers();
+        // Stored out of order, the parser has to sort them
+        headers.swap(1, 2);
+        write_blk_file(&dir, "blk00000.dat", &headers, 0);
+        headers.swap(1, 2);
+
+        // The latest block is left for the next run, see ChainBuilder
+        let mut expected = vec![String::from("on_start;0")];
+        for (height, header) in headers.iter().take(3).enumerate() {
+            expected.push(format!(
+                "on_block;{};{};0",
+                height,
+                utils::arr_to_hex_swapped(&header.hash)
+            ));
+        }
+        expected.push(String::from("on_skipped_blocks;0;0"));
+        expected.push(String::from("on_complete;3"));
+        assert_eq!(expected, run_test_sink(&dir, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_skips_duplicate_blocks_across_blk_files() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.duplicate.test");
+
+        // Block 2 is stored in both files, and its first copy waits for block 1 in the reorder buffer
+        // when the second one arrives. It must still reach the callback only once, and be counted
+        // as one duplicate.
+        let headers = chain_headers();
+        let first = vec![headers[0].clone(), headers[2].clone()];
+        write_blk_file(&dir, "blk00000.dat", &first, 0);
+        let second = vec![headers[2].clone(), headers[1].clone(), headers[3].clone()];
+        write_blk_file(&dir, "blk00001.dat", &second, 0);
+
+        let mut expected = vec![String::from("on_start;0")];
+        for (height, header) in headers.iter().take(3).enumerate() {
+            expected.push(format!(
+                "on_block;{};{};0",
+                height,
+                utils::arr_to_hex_swapped(&header.hash)
+            ));
+        }
+        expected.push(String::from("on_skipped_blocks;0;1"));
+        expected.push(String::from("on_complete;3"));
+        assert_eq!(expected, run_test_sink(&dir, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_skips_stale_blocks() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.stale.test");
+
+        // A stale sibling of the block at height 2, stored before it:
+        //   0 -> 1 -> stale
+        //          -> 2 -> 3
+        let headers = chain_headers();
+        let stale = Hashed::double_sha256(BlockHeader::new(
+            0x00000001,
+            headers[1].hash,
+            [0u8; 32],
+            headers[2].value.timestamp,
+            0x1d00ffff,
+            1000,
+        ));
+        let stored = vec![
+            headers[0].clone(),
+            headers[1].clone(),
+            stale,
+            headers[2].clone(),
+            headers[3].clone(),
+        ];
+        write_blk_file(&dir, "blk00000.dat", &stored, 0);
+
+        let mut expected = vec![String::from("on_start;0")];
+        for (height, header) in headers.iter().take(3).enumerate() {
+            expected.push(format!(
+                "on_block;{};{};0",
+                height,
+                utils::arr_to_hex_swapped(&header.hash)
+            ));
+        }
+        expected.push(String::from("on_skipped_blocks;1;0"));
+        expected.push(String::from("on_complete;3"));
+        assert_eq!(expected, run_test_sink(&dir, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_block_sample() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.block_sample.test");
+        let headers = chain_headers();
+        write_blk_file(&dir, "blk00000.dat", &headers, 0);
+
+        // Callbacks with state between blocks are rejected
+        let chain_storage = dir.join("chain.json");
+        let args = vec![
+            "rusty-blockparser",
+            "--blockchain-dir",
+            dir.to_str().unwrap(),
+            "--chain-storage",
+            chain_storage.to_str().unwrap(),
+            "--block-sample",
+            "2",
+            "simplestats",
+        ];
+        assert!(::parse_args(&::build_app().get_matches_from(args)).is_err());
+
+        let mut expected = vec![String::from("on_start;0")];
+        for height in &[0, 2] {
+            expected.push(format!(
+                "on_block;{};{};0",
+                height,
+                utils::arr_to_hex_swapped(&headers[*height].hash)
+            ));
+        }
+        expected.push(String::from("on_skipped_blocks;0;0"));
+        expected.push(String::from("on_complete;3"));
+        assert_eq!(expected, run_test_sink(&dir, 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_txid_prefix() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.txid_prefix.test");
+        let chain_storage = dir.join("chain.json");
+        let args = |callback| {
+            vec![
+                "rusty-blockparser",
+                "--blockchain-dir",
+                dir.to_str().unwrap(),
+                "--chain-storage",
+                chain_storage.to_str().unwrap(),
+                "--txid-prefix",
+                "0a",
+                callback,
+                dir.to_str().unwrap(),
+            ]
+        };
+        // utxodump would miss the outputs spent by the left out transactions
+        match ::parse_args(&::build_app().get_matches_from(args("utxodump"))) {
+            Err(e) => assert!(e.to_string().contains("--txid-prefix")),
+            Ok(_) => panic!("utxodump accepted --txid-prefix"),
+        }
+        drop(::parse_args(&::build_app().get_matches_from(args("csvdump"))).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_spec_paths() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.run_spec.test");
+        // Neither a Windows drive nor a colon within a path splits the spec
+        let out_dir = dir.join("out:btc");
+        let args = vec![
+            "rusty-blockparser",
+            "--run",
+            "bitcoin",
+            "C:\\blocks",
+            out_dir.to_str().unwrap(),
+            "spenddump",
+            "dump-folder-of-single-runs",
+        ];
+        let matches = ::build_app().get_matches_from(args);
+        let values: Vec<&str> = matches.values_of("run").unwrap().collect();
+        let spec = ::RunSpec::from_values(&values).unwrap();
+        assert_eq!(PathBuf::from("C:\\blocks"), spec.blockchain_dir);
+
+        // The callback of the run writes into the run's output dir
+        let options = ::parse_run_args(&matches, &spec).unwrap();
+        assert_eq!(PathBuf::from("C:\\blocks"), options.blockchain_dir);
+        assert_eq!(out_dir.join("chain.json"), options.chain_storage_path);
+        assert!(out_dir.join("spends.csv.tmp").exists());
+        assert!(!Path::new("dump-folder-of-single-runs").exists());
+        drop(options);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_schema_file_written_by_run() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.schema_file.test");
+        let dump_folder = dir.join("dump");
+        fs::create_dir_all(&dump_folder).unwrap();
+        let chain_storage = dir.join("chain.json");
+        let args = |block_sample: &'static str| {
+            vec![
+                "rusty-blockparser",
+                "--blockchain-dir",
+                dir.to_str().unwrap(),
+                "--chain-storage",
+                chain_storage.to_str().unwrap(),
+                "--write-schema-file",
+                "--block-sample",
+                block_sample,
+                "utxodump",
+                dump_folder.to_str().unwrap(),
+            ]
+        };
+        // utxodump doesn't allow sampling, the rejected args leave no schema behind
+        assert!(::parse_args(&::build_app().get_matches_from(args("2"))).is_err());
+        assert!(!dump_folder.join("schema.sql").exists());
+
+        let mut options = ::parse_args(&::build_app().get_matches_from(args("1"))).unwrap();
+        assert!(!dump_folder.join("schema.sql").exists());
+        write_blk_file(&dir, "blk00000.dat", &chain_headers(), 0);
+        ::run(&mut options).unwrap();
+        assert!(dump_folder.join("schema.sql").exists());
+        drop(options);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_hash() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("parser.parallel_hash.test");
+        // The padding doesn't survive a reserialization, so all blocks fail --checksum-verify-blocks
+        write_blk_file(&dir, "blk00000.dat", &chain_headers(), 1);
+
+        let chain_storage = dir.join("chain.json");
+        let run = |strict: bool| {
+            let mut args = vec![
+                "rusty-blockparser",
+                "--blockchain-dir",
+                dir.to_str().unwrap(),
+                "--chain-storage",
+                chain_storage.to_str().unwrap(),
+                "--checksum-verify-blocks",
+                "--parallel-hash",
+            ];
+            if strict {
+                args.push("--strict");
+            }
+            args.push("simplestats");
+            let mut options = ::parse_args(&::build_app().get_matches_from(args)).unwrap();
+            ::run(&mut options)
+        };
+        // The failure found by a worker aborts the scan like one found by the dispatcher
+        let err = run(true).unwrap_err();
+        assert!(err.to_string().contains("reserialization"));
+        let _ = fs::remove_file(&chain_storage);
+        assert!(run(false).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+
+use twox_hash::XxHash;
+
+use blockchain::proto::block::Block;
+
+/// Default cap of the buffer for each worker thread, enough for two blk files of 128 MiB
+pub const DEFAULT_BYTES_PER_THREAD: usize = 256 * 1000 * 1000;
+
+/// Blocks of the longest chain which arrived before their predecessor.
+/// Some reordering is normal, as workers read several blk files at once and blocks aren't stored in height order.
+/// The size is capped, because a missing block would otherwise keep all following blocks in memory.
+pub struct ReorderBuffer {
+    blocks: HashMap<[u8; 32], Block, BuildHasherDefault<XxHash>>,
+    n_bytes: usize,   // Serialized size of all buffered blocks
+    max_bytes: usize, // Cap of n_bytes (--reorder-buffer-size)
+}
+
+impl ReorderBuffer {
+    pub fn new(max_bytes: usize) -> ReorderBuffer {
+        ReorderBuffer {
+            blocks: Default::default(),
+            n_bytes: 0,
+            max_bytes: max_bytes,
+        }
+    }
+
+    /// Buffers `block` until its predecessor was processed.
+    /// Returns false if the buffer exceeds its cap afterwards.
+    pub fn insert(&mut self, block: Block) -> bool {
+        self.n_bytes += block.blocksize as usize;
+        if let Some(replaced) = self.blocks.insert(block.header.hash, block) {
+            self.n_bytes -= replaced.blocksize as usize;
+        }
+        self.n_bytes <= self.max_bytes
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 32]) -> Option<Block> {
+        let block = self.blocks.remove(hash)?;
+        self.n_bytes -= block.blocksize as usize;
+        Some(block)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn n_bytes(&self) -> usize {
+        self.n_bytes
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReorderBuffer;
+    use blockchain::proto::block::Block;
+    use blockchain::proto::header::BlockHeader;
+    use blockchain::proto::varuint::VarUint;
+
+    fn block(nonce: u32, prev_hash: [u8; 32]) -> Block {
+        let header = BlockHeader::new(1, prev_hash, [0u8; 32], 1231006505, 0x1d00ffff, nonce);
+        Block::new(0, 0, 1000, header, VarUint::from(0u64), Vec::new())
+    }
+
+    #[test]
+    fn test_reorder_buffer() {
+        // The first block of the chain is delayed, so all following blocks are buffered
+        let delayed = block(0, [0u8; 32]);
+        let mut hashes = vec![delayed.header.hash];
+        let mut buffer = ReorderBuffer::new(4500);
+        let mut within_cap = Vec::new();
+        for nonce in 1..6 {
+            let next = block(nonce, *hashes.last().unwrap());
+            hashes.push(next.header.hash);
+            within_cap.push(buffer.insert(next));
+        }
+        assert_eq!(vec![true, true, true, true, false], within_cap);
+        assert_eq!(5, buffer.len());
+        assert_eq!(5000, buffer.n_bytes());
+
+        // Once the delayed block was processed, the buffer drains in order
+        for hash in &hashes[1..] {
+            assert_eq!(*hash, buffer.remove(hash).unwrap().header.hash);
+        }
+        assert_eq!(0, buffer.len());
+        assert_eq!(0, buffer.n_bytes());
+    }
+}
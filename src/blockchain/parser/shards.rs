@@ -0,0 +1,195 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use callbacks::Callback;
+use common::profile::{self, Phase};
+use errors::{OpError, OpErrorKind, OpResult};
+
+/// Number of blocks which may be queued for each shard before the dispatcher waits
+const SHARD_BACKLOG: usize = 10;
+
+/// Settings of `--parallel-write`
+pub struct ShardOptions {
+    pub n_shards: usize, // Number of callback instances, including the one of the main thread
+    pub dump_folder: PathBuf, // Dump folder of the main callback, the shards write into sub folders
+    pub keep_shards: bool, // Leaves the shard files in place instead of appending them to the main files
+}
+
+/// Additional instances of an order insensitive callback, each running in its own thread.
+/// Block `height` goes to `height % n_shards`, shard 0 is the callback of the main thread.
+pub struct Shards {
+    senders: Vec<mpsc::SyncSender<(Block, usize)>>,
+    handles: Vec<JoinHandle<Box<Callback + Send>>>,
+    folders: Vec<PathBuf>,
+}
+
+impl Shards {
+    /// Creates the shard instances and calls `on_start` for each of them in its thread
+    pub fn start<C: Callback + ?Sized>(
+        callback: &C,
+        options: &ShardOptions,
+        coin_type: &CoinType,
+        start_height: usize,
+    ) -> OpResult<Shards> {
+        let mut shards = Shards {
+            senders: Vec::with_capacity(options.n_shards - 1),
+            handles: Vec::with_capacity(options.n_shards - 1),
+            folders: Vec::with_capacity(options.n_shards - 1),
+        };
+        for i in 1..options.n_shards {
+            let folder = options.dump_folder.join(format!("shard-{}", i));
+            fs::create_dir_all(&folder)?;
+            let mut shard = match callback.shard(&folder) {
+                Ok(shard) => shard,
+                Err(e) => return Err(tag_err!(e, "Unable to create shard {}", i)),
+            };
+            let (tx, rx) = mpsc::sync_channel::<(Block, usize)>(SHARD_BACKLOG);
+            let coin_type = coin_type.clone();
+            let handle = thread::Builder::new()
+                .name(format!("shard-{}", i))
+                .spawn(move || {
+                    shard.on_start(coin_type, start_height);
+                    for (block, height) in rx {
+                        let t_start = profile::start();
+                        shard.on_block(block, height);
+                        profile::record(Phase::Callback, t_start);
+                    }
+                    shard
+                })?;
+            shards.senders.push(tx);
+            shards.handles.push(handle);
+            shards.folders.push(folder);
+        }
+        Ok(shards)
+    }
+
+    /// Returns the block if it belongs to shard 0, otherwise sends it to its shard
+    #[inline]
+    pub fn dispatch(&self, block: Block, height: usize) -> OpResult<Option<Block>> {
+        let n_shards = self.senders.len() + 1;
+        match height % n_shards {
+            0 => Ok(Some(block)),
+            i => match self.senders[i - 1].send((block, height)) {
+                Ok(()) => Ok(None),
+                Err(_) => Err(OpError::new(OpErrorKind::RuntimeError)
+                    .join_msg(&format!("Shard {} stopped unexpectedly", i))),
+            },
+        }
+    }
+
+    /// Waits for all shards to process their blocks and finalizes them.
+    /// The shard files are appended to the files of the same name in `options.dump_folder` afterwards,
+    /// unless `options.keep_shards` is set.
+    pub fn complete(self, options: &ShardOptions, block_height: usize) -> OpResult<()> {
+        drop(self.senders);
+        for (i, handle) in self.handles.into_iter().enumerate() {
+            let mut shard = match handle.join() {
+                Ok(shard) => shard,
+                Err(_) => {
+                    return Err(OpError::new(OpErrorKind::RuntimeError)
+                        .join_msg(&format!("Shard {} panicked", i + 1)))
+                }
+            };
+            shard.on_complete(block_height);
+        }
+        if options.keep_shards {
+            info!(target: "shards", "Kept the files of {} shards in {:?}.", self.folders.len(), options.dump_folder);
+            return Ok(());
+        }
+        let mut n_files = 0;
+        for folder in &self.folders {
+            n_files += merge_folder(folder, &options.dump_folder)?;
+        }
+        info!(target: "shards", "Appended {} files of {} shards to the files in {:?}. Rows are not in height order.",
+              n_files, self.folders.len(), options.dump_folder);
+        Ok(())
+    }
+}
+
+/// Appends all files in `folder` to the files of the same name in `dump_folder` and removes `folder`.
+/// Files are copied byte for byte, which keeps gzip files valid as multiple members. Only the main files
+/// start with the column names with `--header-row`, shards don't write them.
+fn merge_folder(folder: &Path, dump_folder: &Path) -> OpResult<usize> {
+    let mut n_files = 0;
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        let name = transform!(path.file_name());
+        let mut target = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dump_folder.join(name))?;
+        io::copy(&mut fs::File::open(&path)?, &mut target)?;
+        fs::remove_file(&path)?;
+        n_files += 1;
+    }
+    fs::remove_dir(folder)?;
+    Ok(n_files)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate flate2;
+
+    use std::fs::File;
+    use std::io::Read;
+
+    use self::flate2::read::MultiGzDecoder;
+    use super::{ShardOptions, Shards};
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::fixtures::{block, coinbase, output};
+    use blockchain::utils;
+    use callbacks::coinbasedump::CoinbaseDump;
+    use callbacks::Callback;
+    use common::testdir;
+    use common::writer;
+
+    #[test]
+    fn test_merge_gzip_with_header_row() {
+        let _settings = writer::test_defaults();
+        writer::set_header_row(true);
+        writer::set_gzip(true);
+        let dir = testdir::create("shards.test");
+        let matches =
+            CoinbaseDump::build_subcommand().get_matches_from(vec!["coinbasedump", "unused"]);
+        let mut callback = CoinbaseDump::new(&matches, Some(&dir)).unwrap();
+        let options = ShardOptions {
+            n_shards: 2,
+            dump_folder: dir.clone(),
+            keep_shards: false,
+        };
+
+        let coin = CoinType::from(Bitcoin);
+        callback.on_start(coin.clone(), 0);
+        let shards = Shards::start(&callback, &options, &coin, 0).unwrap();
+        let p2pkh = utils::hex_to_vec("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        for height in 0..4 {
+            let output = output(height as u64 + 1, p2pkh.clone());
+            let block = block(vec![coinbase(Vec::new(), vec![output])]);
+            if let Some(block) = shards.dispatch(block, height).unwrap() {
+                callback.on_block(block, height);
+            }
+        }
+        callback.on_complete(3);
+        shards.complete(&options, 3).unwrap();
+
+        assert!(!dir.join("shard-1").exists());
+        let mut rows = String::new();
+        MultiGzDecoder::new(File::open(dir.join("coinbase-0-3.csv.gz")).unwrap())
+            .read_to_string(&mut rows)
+            .unwrap();
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let expected: Vec<String> = [0, 2, 1, 3]
+            .iter()
+            .map(|h| format!("{};{};{}", h, address, h + 1))
+            .collect();
+        assert_eq!(
+            format!("height;address;value\n{}\n", expected.join("\n")),
+            rows
+        );
+    }
+}
@@ -2,7 +2,9 @@ use std::convert::From;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use blockchain::utils::hex_to_arr32_swapped;
+use rustc_serialize::json;
+
+use blockchain::utils::{arr_to_hex_swapped, hex_to_arr32_swapped};
 use errors::{OpError, OpErrorKind, OpResult};
 
 /// Trait to specify the underlying coin of a blockchain
@@ -13,6 +15,57 @@ pub trait Coin {
     fn version_id(&self) -> u8; // https://en.bitcoin.it/wiki/List_of_address_prefixes
     fn genesis(&self) -> [u8; 32]; // Returns genesis hash
     fn default_folder(&self) -> PathBuf; // Default working directory, for example .bitcoin
+
+    // Version id for P2SH addresses
+    fn p2sh_version(&self) -> u8 {
+        0x05
+    }
+    // Human readable part of bech32 addresses, None if the coin has no SegWit
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        None
+    }
+    // Height at which strict DER signatures became mandatory [BIP66], None if unknown
+    fn bip66_height(&self) -> Option<usize> {
+        None
+    }
+    // Number of blocks until coinbase outputs can be spent
+    fn coinbase_maturity(&self) -> usize {
+        100
+    }
+    // Coinbase maturity of outputs created before a height (height, blocks), None if it never changed
+    fn early_coinbase_maturity(&self) -> Option<(usize, usize)> {
+        None
+    }
+    fn unit_name(&self) -> &'static str {
+        "satoshi" // Smallest unit all values are given in
+    }
+    fn decimals(&self) -> u8 {
+        8 // Number of decimals of one coin in the smallest unit
+    }
+    // Targeted time between two blocks in seconds
+    fn block_interval(&self) -> u32 {
+        600
+    }
+    // Block reward of the genesis block in the smallest unit
+    fn initial_subsidy(&self) -> u64 {
+        50 * 100000000
+    }
+    // Number of blocks between two halvings of the subsidy, None if the coin follows another schedule
+    fn halving_interval(&self) -> Option<usize> {
+        Some(210000)
+    }
+    // Upper bound on any output value or sum of values (MAX_MONEY) in the smallest unit
+    fn max_money(&self) -> u64 {
+        21000000 * 100000000
+    }
+    // Number of blocks between two difficulty adjustments, None if the coin doesn't retarget like Bitcoin
+    fn retarget_interval(&self) -> Option<usize> {
+        None
+    }
+    // Easiest allowed target in compact form
+    fn pow_limit_bits(&self) -> u32 {
+        0x1d00ffff
+    }
 }
 
 // Implemented blockchain types.
@@ -43,6 +96,15 @@ impl Coin for Bitcoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".bitcoin").join("blocks")
     }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("bc")
+    }
+    fn bip66_height(&self) -> Option<usize> {
+        Some(363725)
+    }
+    fn retarget_interval(&self) -> Option<usize> {
+        Some(2016)
+    }
 }
 
 /// Bitcoin testnet3
@@ -62,6 +124,18 @@ impl Coin for TestNet3 {
     fn default_folder(&self) -> PathBuf {
         Path::new(".bitcoin").join("testnet3")
     }
+    fn p2sh_version(&self) -> u8 {
+        0xc4
+    }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("tb")
+    }
+    fn bip66_height(&self) -> Option<usize> {
+        Some(330776)
+    }
+    fn retarget_interval(&self) -> Option<usize> {
+        Some(2016)
+    }
 }
 
 impl Coin for Namecoin {
@@ -80,6 +154,12 @@ impl Coin for Namecoin {
     fn default_folder(&self) -> PathBuf {
         PathBuf::from(".namecoin")
     }
+    fn p2sh_version(&self) -> u8 {
+        0x0d
+    }
+    fn unit_name(&self) -> &'static str {
+        "swartz"
+    }
 }
 
 impl Coin for Litecoin {
@@ -98,6 +178,24 @@ impl Coin for Litecoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".litecoin").join("blocks")
     }
+    fn p2sh_version(&self) -> u8 {
+        0x32
+    }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("ltc")
+    }
+    fn unit_name(&self) -> &'static str {
+        "litoshi"
+    }
+    fn block_interval(&self) -> u32 {
+        150
+    }
+    fn halving_interval(&self) -> Option<usize> {
+        Some(840000)
+    }
+    fn max_money(&self) -> u64 {
+        84000000 * 100000000
+    }
 }
 
 impl Coin for Dogecoin {
@@ -116,6 +214,31 @@ impl Coin for Dogecoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".dogecoin").join("blocks")
     }
+    fn p2sh_version(&self) -> u8 {
+        0x16
+    }
+    fn coinbase_maturity(&self) -> usize {
+        240
+    }
+    fn early_coinbase_maturity(&self) -> Option<(usize, usize)> {
+        Some((145000, 30))
+    }
+    fn unit_name(&self) -> &'static str {
+        "koinu"
+    }
+    fn block_interval(&self) -> u32 {
+        60
+    }
+    // Random rewards until block 145000, then halvings down to a constant 10000 DOGE from block 600000
+    fn initial_subsidy(&self) -> u64 {
+        500000 * 100000000
+    }
+    fn halving_interval(&self) -> Option<usize> {
+        None
+    }
+    fn max_money(&self) -> u64 {
+        10000000000 * 100000000
+    }
 }
 
 impl Coin for Myriadcoin {
@@ -134,6 +257,18 @@ impl Coin for Myriadcoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".myriadcoin").join("blocks")
     }
+    fn block_interval(&self) -> u32 {
+        60
+    }
+    fn initial_subsidy(&self) -> u64 {
+        1000 * 100000000
+    }
+    fn halving_interval(&self) -> Option<usize> {
+        Some(967680)
+    }
+    fn max_money(&self) -> u64 {
+        2000000000 * 100000000
+    }
 }
 
 impl Coin for Unobtanium {
@@ -152,6 +287,19 @@ impl Coin for Unobtanium {
     fn default_folder(&self) -> PathBuf {
         Path::new(".unobtanium").join("blocks")
     }
+    fn block_interval(&self) -> u32 {
+        180
+    }
+    fn initial_subsidy(&self) -> u64 {
+        1 * 100000000
+    }
+    // Doesn't follow a plain halving schedule
+    fn halving_interval(&self) -> Option<usize> {
+        None
+    }
+    fn max_money(&self) -> u64 {
+        250000 * 100000000
+    }
 }
 
 /* TODO: implement X11
@@ -169,6 +317,19 @@ pub struct CoinType {
     pub name: String,
     pub magic: u32,
     pub version_id: u8,
+    pub p2sh_version: u8,
+    pub bech32_hrp: Option<&'static str>,
+    pub bip66_height: Option<usize>,
+    pub coinbase_maturity: usize,
+    pub early_coinbase_maturity: Option<(usize, usize)>,
+    pub unit_name: &'static str,
+    pub decimals: u8,
+    pub block_interval: u32,
+    pub initial_subsidy: u64,
+    pub halving_interval: Option<usize>,
+    pub max_money: u64,
+    pub retarget_interval: Option<usize>,
+    pub pow_limit_bits: u32,
     pub genesis_hash: [u8; 32],
     pub default_folder: PathBuf,
 }
@@ -185,12 +346,116 @@ impl<T: Coin> From<T> for CoinType {
             name: coin.name(),
             magic: coin.magic(),
             version_id: coin.version_id(),
+            p2sh_version: coin.p2sh_version(),
+            bech32_hrp: coin.bech32_hrp(),
+            bip66_height: coin.bip66_height(),
+            coinbase_maturity: coin.coinbase_maturity(),
+            early_coinbase_maturity: coin.early_coinbase_maturity(),
+            unit_name: coin.unit_name(),
+            decimals: coin.decimals(),
+            block_interval: coin.block_interval(),
+            initial_subsidy: coin.initial_subsidy(),
+            halving_interval: coin.halving_interval(),
+            max_money: coin.max_money(),
+            retarget_interval: coin.retarget_interval(),
+            pow_limit_bits: coin.pow_limit_bits(),
             genesis_hash: coin.genesis(),
             default_folder: PathBuf::from(coin.default_folder()),
         }
     }
 }
 
+impl CoinType {
+    /// Returns the number of blocks until a coinbase output created at `height` can be spent
+    #[inline]
+    pub fn coinbase_maturity_at(&self, height: usize) -> usize {
+        match self.early_coinbase_maturity {
+            Some((until, maturity)) if height < until => maturity,
+            _ => self.coinbase_maturity,
+        }
+    }
+
+    /// Returns true if a coinbase output created at `height` can be spent in the block after `tip_height`
+    #[inline]
+    pub fn is_coinbase_mature(&self, height: usize, tip_height: usize) -> bool {
+        tip_height + 1 >= height + self.coinbase_maturity_at(height)
+    }
+
+    /// Returns the subsidy of the block at `height`, None if the coin doesn't follow a plain halving schedule
+    pub fn subsidy(&self, height: usize) -> Option<u64> {
+        let halvings = height / self.halving_interval?;
+        if halvings >= 64 {
+            return Some(0);
+        }
+        Some(self.initial_subsidy >> halvings)
+    }
+
+    /// Returns the name of the supported coin whose blocks start with `magic`
+    pub fn name_of_magic(magic: u32) -> Option<String> {
+        let coins = [
+            CoinType::from(Bitcoin),
+            CoinType::from(TestNet3),
+            CoinType::from(Namecoin),
+            CoinType::from(Litecoin),
+            CoinType::from(Dogecoin),
+            CoinType::from(Myriadcoin),
+            CoinType::from(Unobtanium),
+        ];
+        coins
+            .iter()
+            .find(|coin| coin.magic == magic)
+            .map(|coin| coin.name.clone())
+    }
+
+    /// Returns all parameters as pretty printed JSON, see `print-params`
+    pub fn params_json(&self) -> String {
+        format!("{}", json::as_pretty_json(&CoinParams::new(self)))
+    }
+}
+
+/// Coin parameters as written by `print-params`.
+/// Values are given in the smallest unit, hashes in the usual byte swapped hex notation.
+#[derive(RustcEncodable)]
+struct CoinParams {
+    name: String,
+    magic: String,
+    pubkey_hash_version: u8,
+    script_hash_version: u8,
+    bech32_hrp: Option<String>,
+    genesis_hash: String,
+    bip66_height: Option<usize>,
+    coinbase_maturity: usize,
+    early_coinbase_maturity: Option<(usize, usize)>,
+    block_interval: u32,
+    initial_subsidy: u64,
+    halving_interval: Option<usize>,
+    max_money: u64,
+    unit: String,
+    decimals: u8,
+}
+
+impl CoinParams {
+    fn new(coin: &CoinType) -> CoinParams {
+        CoinParams {
+            name: coin.name.clone(),
+            magic: format!("0x{:08x}", coin.magic),
+            pubkey_hash_version: coin.version_id,
+            script_hash_version: coin.p2sh_version,
+            bech32_hrp: coin.bech32_hrp.map(String::from),
+            genesis_hash: arr_to_hex_swapped(&coin.genesis_hash),
+            bip66_height: coin.bip66_height,
+            coinbase_maturity: coin.coinbase_maturity,
+            early_coinbase_maturity: coin.early_coinbase_maturity,
+            block_interval: coin.block_interval,
+            initial_subsidy: coin.initial_subsidy,
+            halving_interval: coin.halving_interval,
+            max_money: coin.max_money,
+            unit: String::from(coin.unit_name),
+            decimals: coin.decimals,
+        }
+    }
+}
+
 impl FromStr for CoinType {
     type Err = OpError;
     fn from_str(coin_name: &str) -> OpResult<Self> {
@@ -210,3 +475,68 @@ impl FromStr for CoinType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Bitcoin, CoinType, Dogecoin, Litecoin};
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let coin = CoinType::from(Bitcoin);
+        // Spendable by a transaction in block 100 at the earliest
+        assert!(!coin.is_coinbase_mature(0, 98));
+        assert!(coin.is_coinbase_mature(0, 99));
+        assert!(!coin.is_coinbase_mature(500, 598));
+        assert!(coin.is_coinbase_mature(500, 1000));
+
+        // Dogecoin raised the maturity from 30 to 240 blocks at block 145000
+        let coin = CoinType::from(Dogecoin);
+        assert!(!coin.is_coinbase_mature(500, 528));
+        assert!(coin.is_coinbase_mature(500, 529));
+        assert!(coin.is_coinbase_mature(144999, 145028));
+        assert!(!coin.is_coinbase_mature(145000, 145238));
+        assert!(coin.is_coinbase_mature(145000, 145239));
+    }
+
+    #[test]
+    fn test_subsidy() {
+        let coin = CoinType::from(Bitcoin);
+        assert_eq!(Some(5000000000), coin.subsidy(0));
+        assert_eq!(Some(5000000000), coin.subsidy(209999));
+        assert_eq!(Some(2500000000), coin.subsidy(210000));
+        assert_eq!(Some(312500000), coin.subsidy(840000));
+        assert_eq!(Some(0), coin.subsidy(64 * 210000));
+        assert_eq!(None, CoinType::from(Dogecoin).subsidy(0));
+    }
+
+    #[test]
+    fn test_name_of_magic() {
+        assert_eq!(
+            Some(String::from("Litecoin")),
+            CoinType::name_of_magic(0xdbb6c0fb)
+        );
+        assert_eq!(
+            Some(CoinType::from(Bitcoin).name),
+            CoinType::name_of_magic(0xd9b4bef9)
+        );
+        assert_eq!(None, CoinType::name_of_magic(0xe8f3e1e3));
+    }
+
+    #[test]
+    fn test_params_json() {
+        let params = Json::from_str(&CoinType::from(Litecoin).params_json()).unwrap();
+        assert_eq!("0xdbb6c0fb", params["magic"].as_string().unwrap());
+        assert_eq!("ltc", params["bech32_hrp"].as_string().unwrap());
+        assert_eq!(
+            "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2",
+            params["genesis_hash"].as_string().unwrap()
+        );
+        assert_eq!(840000, params["halving_interval"].as_u64().unwrap());
+        assert_eq!(8400000000000000, params["max_money"].as_u64().unwrap());
+
+        let params = Json::from_str(&CoinType::from(Dogecoin).params_json()).unwrap();
+        assert!(params["bech32_hrp"].is_null());
+        assert!(params["halving_interval"].is_null());
+    }
+}
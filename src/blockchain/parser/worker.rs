@@ -1,5 +1,4 @@
 use std::collections::VecDeque;
-use std::fs::File;
 use std::io::{Seek, SeekFrom};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
@@ -9,9 +8,13 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use seek_bufread::BufReader;
 
 use blockchain::parser::types::CoinType;
-use blockchain::parser::{ParseMode, ParseResult};
-use blockchain::utils::blkfile::BlkFile;
+use blockchain::parser::{BlockChecks, ParseMode, ParseResult};
+use blockchain::proto::block::Block;
+use blockchain::proto::varuint::VarUint;
+use blockchain::utils::blkfile::{BlkFile, FileFollower};
 use blockchain::utils::reader::BlockchainRead;
+use blockchain::utils::throttle::{RateLimiter, ThrottledFile};
+use common::profile::{self, Phase};
 use errors::{OpError, OpErrorKind, OpResult};
 
 /// Represents a single Worker. All workers share Vector with remaining files.
@@ -20,26 +23,37 @@ use errors::{OpError, OpErrorKind, OpResult};
 pub struct Worker {
     tx_channel: mpsc::SyncSender<ParseResult>, // SyncSender channel to communicate main thread
     pub remaining_files: Arc<Mutex<VecDeque<BlkFile>>>, // remaining BlkFiles to parse (shared with other threads)
-    pub coin_type: CoinType,                            // Coin type
-    pub blk_file: BlkFile,                              // Current blk file
-    pub reader: BufReader<File>,                        // Reader for the entire blk file content
+    pub follower: Option<Arc<Mutex<FileFollower>>>, // Adds new blk files once the remaining ones are taken (--follow-new-files)
+    pub coin_type: CoinType,                        // Coin type
+    pub blk_file: BlkFile,                          // Current blk file
+    pub reader: BufReader<ThrottledFile>,           // Reader for the entire blk file content
+    pub read_limiter: Option<Arc<RateLimiter>>,     // Shared bandwidth limit of all workers
     pub mode: ParseMode, // Specifies if we should read the whole block data or just the header
-    pub name: String,    // Thread name
+    pub headers_only: bool, // Skips the transactions in FullData mode
+    pub checks: BlockChecks, // Checks run on each block before sending it (--parallel-hash)
+    pub unknown_magic_coin: Option<CoinType>, // Parses blocks with an unknown magic as this coin (--assume-coin-on-unknown)
+    pub accepted_magic: Option<u32>,          // Unknown magic which was already warned about
+    pub name: String,                         // Thread name
 }
 
 impl Worker {
     pub fn new(
         tx_channel: mpsc::SyncSender<ParseResult>,
         remaining_files: Arc<Mutex<VecDeque<BlkFile>>>,
+        follower: Option<Arc<Mutex<FileFollower>>>,
         coin_type: CoinType,
         mode: ParseMode,
+        headers_only: bool,
+        checks: BlockChecks,
+        read_limiter: Option<Arc<RateLimiter>>,
+        unknown_magic_coin: Option<CoinType>,
     ) -> OpResult<Self> {
         let worker_name = String::from(transform!(thread::current().name()));
         // Grab initial blk file
-        match Worker::get_next_file(&remaining_files) {
+        match Worker::get_next_file(&remaining_files, &follower) {
             Ok(file) => {
                 // prepare instance variables
-                let reader = file.get_reader()?;
+                let reader = file.get_reader(read_limiter.clone())?;
                 debug!(
                     target: &worker_name,
                     "Parsing blk{:05}.dat ({:.2} Mb)",
@@ -50,10 +64,16 @@ impl Worker {
                 let w = Worker {
                     tx_channel: tx_channel,
                     remaining_files: remaining_files,
+                    follower: follower,
                     coin_type: coin_type,
                     blk_file: file,
                     reader: reader,
+                    read_limiter: read_limiter,
                     mode: mode,
+                    headers_only: headers_only,
+                    checks: checks,
+                    unknown_magic_coin: unknown_magic_coin,
+                    accepted_magic: None,
                     name: worker_name.clone(),
                 };
                 Ok(w)
@@ -109,6 +129,7 @@ impl Worker {
         match self.maybe_next()? {
             false => Ok(None),
             true => {
+                let t_start = profile::start();
                 // Get metadata for next block
                 let magic = self.reader.read_u32::<LittleEndian>()?;
                 if magic == 0 {
@@ -117,14 +138,12 @@ impl Worker {
                     return Ok(None);
                 }
                 // Verify magic value based on current coin type
-                if magic != self.coin_type.magic {
-                    let err = OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
-                        "Got invalid magic value for {}: 0x{:x}, expected: 0x{:x}",
-                        self.coin_type.name, magic, self.coin_type.magic
-                    ));
-                    return Err(err);
+                let assumed = magic != self.coin_type.magic;
+                if assumed {
+                    self.check_unknown_magic(magic)?;
                 }
-                let result = self.extract_data()?;
+                let result = self.extract_data(assumed)?;
+                profile::record(Phase::Parse, t_start);
                 // Send parsed result to main thread
                 self.tx_channel.send(result)?;
                 Ok(Some(()))
@@ -132,22 +151,70 @@ impl Worker {
         }
     }
 
-    /// Extracts Block or BlockHeader. See ParseMode
-    fn extract_data(&mut self) -> OpResult<ParseResult> {
+    /// Accepts a magic value of no supported coin if `--assume-coin-on-unknown` is set, warning once per value.
+    /// Magic values of other supported coins are always rejected, as they mean the wrong `--coin` was given.
+    fn check_unknown_magic(&mut self, magic: u32) -> OpResult<()> {
+        if self.accepted_magic == Some(magic) {
+            return Ok(());
+        }
+        let msg = match (CoinType::name_of_magic(magic), &self.unknown_magic_coin) {
+            (Some(name), _) => format!(
+                "Got magic value of {} for {}: 0x{:x}, expected: 0x{:x}",
+                name, self.coin_type.name, magic, self.coin_type.magic
+            ),
+            (None, &Some(ref coin)) => {
+                warn!(target: &self.name, "Got unknown magic value 0x{:x} in {:?}, parsing these blocks as {}.",
+                      magic, self.blk_file.path, coin.name);
+                self.accepted_magic = Some(magic);
+                return Ok(());
+            }
+            (None, &None) => format!(
+                "Got invalid magic value for {}: 0x{:x}, expected: 0x{:x}",
+                self.coin_type.name, magic, self.coin_type.magic
+            ),
+        };
+        Err(OpError::new(OpErrorKind::ValidateError).join_msg(&msg))
+    }
+
+    /// Extracts Block or BlockHeader. See ParseMode.
+    /// If `assumed` is set, the block had an unknown magic and is parsed as `unknown_magic_coin`.
+    fn extract_data(&mut self, assumed: bool) -> OpResult<ParseResult> {
         // Collect block metadata
         let blocksize = self.reader.read_u32::<LittleEndian>()?;
         let block_offset = self.reader.position();
 
         // Extract next block
         let result = match self.mode {
+            ParseMode::FullData if self.headers_only => {
+                let header = self.reader.read_block_header()?;
+                let tx_count = VarUint::read_from(&mut self.reader)?;
+                Ok(ParseResult::FullData(Block::new(
+                    self.blk_file.index,
+                    block_offset as usize,
+                    blocksize,
+                    header,
+                    tx_count,
+                    Vec::new(),
+                )))
+            }
             ParseMode::FullData => {
+                let coin_type = match self.unknown_magic_coin {
+                    Some(ref coin) if assumed => coin,
+                    _ => &self.coin_type,
+                };
                 let block = self.reader.read_block(
                     self.blk_file.index,
                     block_offset as usize,
                     blocksize,
-                    self.coin_type.version_id,
+                    coin_type,
                 )?;
-                Ok(ParseResult::FullData(block))
+                match self.checks.any() {
+                    true => {
+                        let failed = self.checks.failed(&block);
+                        Ok(ParseResult::Verified(block, failed))
+                    }
+                    false => Ok(ParseResult::FullData(block)),
+                }
             }
             ParseMode::Indexing => {
                 let header = self.reader.read_block_header()?;
@@ -166,7 +233,7 @@ impl Worker {
         // Check if there are some bytes left in buffer
         if self.reader.position() >= self.blk_file.size {
             // Grab next block or return false if no files are left
-            self.blk_file = match Worker::get_next_file(&self.remaining_files) {
+            self.blk_file = match Worker::get_next_file(&self.remaining_files, &self.follower) {
                 Ok(file) => file,
                 Err(OpError {
                     kind: OpErrorKind::None,
@@ -180,7 +247,7 @@ impl Worker {
                     ))
                 }
             };
-            self.reader = self.blk_file.get_reader()?;
+            self.reader = self.blk_file.get_reader(self.read_limiter.clone())?;
             debug!(target: self.name.as_ref(), "Parsing blk{:05}.dat ({:.2} Mb)",
                       self.blk_file.index,
                       self.blk_file.size as f64 / 1000000.0);
@@ -188,9 +255,18 @@ impl Worker {
         return Ok(true);
     }
 
-    /// Returns next file from shared buffer or None
-    fn get_next_file(files: &Arc<Mutex<VecDeque<BlkFile>>>) -> OpResult<BlkFile> {
+    /// Returns next file from shared buffer or None.
+    /// If the buffer is empty, it is refilled with the blk files which appeared in the meantime if following them.
+    fn get_next_file(
+        files: &Arc<Mutex<VecDeque<BlkFile>>>,
+        follower: &Option<Arc<Mutex<FileFollower>>>,
+    ) -> OpResult<BlkFile> {
         let mut locked = files.lock()?;
+        if locked.is_empty() {
+            if let Some(ref follower) = *follower {
+                locked.append(&mut follower.lock()?.poll()?);
+            }
+        }
         Ok(transform!(locked.pop_front()))
     }
 }
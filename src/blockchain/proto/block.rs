@@ -3,10 +3,11 @@ use std::fmt;
 use blockchain::proto::header::BlockHeader;
 use blockchain::proto::tx::Tx;
 use blockchain::proto::varuint::VarUint;
-use blockchain::proto::Hashed;
+use blockchain::proto::{Hashed, ToRaw};
 use blockchain::utils::{arr_to_hex_swapped, merkle_root};
 
 /// Basic block structure which holds all information
+#[derive(Clone)]
 pub struct Block {
     pub blk_index: u32,
     pub blk_offset: usize,
@@ -56,6 +57,31 @@ impl Block {
         }
         return true;
     }
+
+    /// Reserializes the block and compares its length to the size stored in front of it in the blk file.
+    /// A mismatch means a field was parsed wrongly, e.g. with the wrong width.
+    pub fn verify_size(&self) -> bool {
+        let size = self.to_bytes().len();
+        if size != self.blocksize as usize {
+            warn!(target: "block", "Invalid size of block {}!\n  -> declared:     {}\n  -> reserialized: {}\n",
+                  &arr_to_hex_swapped(&self.header.hash), self.blocksize, size);
+            return false;
+        }
+        return true;
+    }
+}
+
+impl ToRaw for Block {
+    /// Serializes the block as it is stored in blk files, transactions including their witnesses
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.blocksize as usize);
+        bytes.extend_from_slice(&self.header.value.to_bytes());
+        bytes.extend_from_slice(&self.tx_count.to_bytes());
+        for tx in &self.txs {
+            bytes.extend_from_slice(&tx.value.witness_bytes());
+        }
+        bytes
+    }
 }
 
 impl fmt::Debug for Block {
@@ -0,0 +1,69 @@
+//! Builders for the transactions and blocks of unit tests.
+//! Transactions are parsed as Bitcoin, and blocks get a dummy header with the genesis timestamp and bits.
+
+use blockchain::parser::types::{Bitcoin, CoinType};
+use blockchain::proto::block::Block;
+use blockchain::proto::header::BlockHeader;
+use blockchain::proto::tx::{Tx, TxInput, TxOutpoint, TxOutput};
+use blockchain::proto::varuint::VarUint;
+use blockchain::proto::Hashed;
+
+pub fn input(txid: [u8; 32], index: u32, script_sig: Vec<u8>, witness: Vec<Vec<u8>>) -> TxInput {
+    TxInput {
+        outpoint: TxOutpoint {
+            txid: txid,
+            index: index,
+        },
+        script_len: VarUint::from(script_sig.len() as u64),
+        script_sig: script_sig,
+        seq_no: 0xFFFFFFFF,
+        witness: witness,
+    }
+}
+
+/// Input spending `txid:index` without script and witness
+pub fn spend(txid: [u8; 32], index: u32) -> TxInput {
+    input(txid, index, Vec::new(), Vec::new())
+}
+
+pub fn output(value: u64, script_pubkey: Vec<u8>) -> TxOutput {
+    TxOutput {
+        value: value,
+        script_len: VarUint::from(script_pubkey.len() as u64),
+        script_pubkey: script_pubkey,
+    }
+}
+
+pub fn tx(version: u32, inputs: Vec<TxInput>, outputs: Vec<TxOutput>, locktime: u32) -> Tx {
+    Tx::new(
+        version,
+        VarUint::from(inputs.len() as u64),
+        &inputs,
+        VarUint::from(outputs.len() as u64),
+        &outputs,
+        locktime,
+        &CoinType::from(Bitcoin),
+    )
+}
+
+pub fn coinbase(script_sig: Vec<u8>, outputs: Vec<TxOutput>) -> Tx {
+    tx(
+        1,
+        vec![input([0; 32], 0xFFFFFFFF, script_sig, Vec::new())],
+        outputs,
+        0,
+    )
+}
+
+pub fn txid(tx: &Tx) -> [u8; 32] {
+    Hashed::double_sha256(tx.clone()).hash
+}
+
+pub fn block_with(version: u32, prev_hash: [u8; 32], txs: Vec<Tx>) -> Block {
+    let header = BlockHeader::new(version, prev_hash, [0u8; 32], 1231006505, 0x1d00ffff, 0);
+    Block::new(0, 0, 1000, header, VarUint::from(txs.len() as u64), txs)
+}
+
+pub fn block(txs: Vec<Tx>) -> Block {
+    block_with(1, [0u8; 32], txs)
+}
@@ -2,9 +2,14 @@ use blockchain::utils::{arr_to_hex_swapped, sha256};
 use std::fmt;
 
 pub mod block;
+#[cfg(test)]
+pub mod fixtures;
 pub mod header;
 pub mod opcodes;
 pub mod script;
+pub mod signature;
+pub mod sigops;
+pub mod taproot;
 pub mod tx;
 pub mod varuint;
 
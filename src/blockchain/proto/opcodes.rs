@@ -407,8 +407,8 @@ pub enum All {
     /// Does nothing
     OP_NOP10 = 0xb9,
     // Every other opcode acts as OP_RETURN
-    /// Synonym for OP_RETURN
-    OP_RETURN_186 = 0xba,
+    /// OP_CHECKSIGADD in tapscript [BIP342], synonym for OP_RETURN in legacy and witness v0 scripts
+    OP_CHECKSIGADD = 0xba,
     /// Synonym for OP_RETURN
     OP_RETURN_187 = 0xbb,
     /// Synonym for OP_RETURN
@@ -584,7 +584,7 @@ impl All {
             || *self == All::OP_RETURN
             || *self == All::OP_RESERVED1
             || *self == All::OP_RESERVED2
-            || *self as u8 >= All::OP_RETURN_186 as u8
+            || *self as u8 >= All::OP_CHECKSIGADD as u8
         {
             Class::ReturnOp
         // 1 opcode
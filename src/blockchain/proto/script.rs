@@ -2,10 +2,10 @@ use std::convert::From;
 use std::error::{self, Error};
 use std::fmt;
 
-use rust_base58::ToBase58;
-
+use blockchain::address::Address;
+use blockchain::parser::types::CoinType;
 use blockchain::proto::opcodes;
-use blockchain::utils::{self, ridemp160, sha256};
+use blockchain::utils;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ScriptError {
@@ -59,6 +59,18 @@ pub enum ScriptPattern {
     /// Signature script: <sig>[sig][sig...] <redeemScript>
     Pay2ScriptHash,
 
+    /// Pay to Witness Public Key Hash [p2wpkh/BIP141]
+    /// Pubkey script: OP_0 <20 byte witness program>
+    Pay2WitnessPublicKeyHash,
+
+    /// Pay to Witness Script Hash [p2wsh/BIP141]
+    /// Pubkey script: OP_0 <32 byte witness program>
+    Pay2WitnessScriptHash,
+
+    /// Pay to Taproot [p2tr/BIP341]
+    /// Pubkey script: OP_1 <32 byte output key>
+    Pay2Taproot,
+
     /// Sign Multisig script [BIP11]
     //SignMultiSig,
 
@@ -87,6 +99,9 @@ impl fmt::Display for ScriptPattern {
             ScriptPattern::Pay2PublicKey => write!(f, "Pay2PublicKey"),
             ScriptPattern::Pay2PublicKeyHash => write!(f, "Pay2PublicKeyHash"),
             ScriptPattern::Pay2ScriptHash => write!(f, "Pay2ScriptHash"),
+            ScriptPattern::Pay2WitnessPublicKeyHash => write!(f, "Pay2WitnessPublicKeyHash"),
+            ScriptPattern::Pay2WitnessScriptHash => write!(f, "Pay2WitnessScriptHash"),
+            ScriptPattern::Pay2Taproot => write!(f, "Pay2Taproot"),
             ScriptPattern::NotRecognised => write!(f, "NotRecognised"),
             ScriptPattern::Error(ref err) => write!(f, "ScriptError: {}", err),
         }
@@ -162,7 +177,7 @@ impl fmt::Debug for Stack {
 
 #[derive(Clone)]
 pub struct EvaluatedScript {
-    pub address: String,
+    pub address: Option<Address>,
     pub pattern: ScriptPattern,
 }
 
@@ -195,7 +210,8 @@ impl<'a> ScriptEvaluator<'a> {
 
             if data_len > 0 {
                 //print!(" ");
-                if self.ip + data_len > self.n_bytes {
+                // Declared length must not exceed the remaining script bytes
+                if data_len > self.n_bytes - self.ip {
                     //println!("!ip: {}, data_len: {}, n_bytes: {}!", self.ip, data_len, self.n_bytes);
                     return Err(ScriptError::UnexpectedEof);
                 } else {
@@ -228,36 +244,27 @@ impl<'a> ScriptEvaluator<'a> {
             n as usize
         } else {
             match opcode {
-                opcodes::All::OP_PUSHDATA1 => {
-                    if self.ip + 1 > self.n_bytes {
-                        return Err(ScriptError::UnexpectedEof);
-                    }
-                    let val = ScriptEvaluator::read_uint(&self.bytes[self.ip..], 1)?;
-                    self.ip += 1;
-                    val
-                }
-                opcodes::All::OP_PUSHDATA2 => {
-                    if self.ip + 2 > self.n_bytes {
-                        return Err(ScriptError::UnexpectedEof);
-                    }
-                    let val = ScriptEvaluator::read_uint(&self.bytes[self.ip..], 2)?;
-                    self.ip += 2;
-                    val
-                }
-                opcodes::All::OP_PUSHDATA4 => {
-                    if self.ip + 4 > self.n_bytes {
-                        return Err(ScriptError::UnexpectedEof);
-                    }
-                    let val = ScriptEvaluator::read_uint(&self.bytes[self.ip..], 4)?;
-                    self.ip += 4;
-                    val
-                }
+                opcodes::All::OP_PUSHDATA1 => self.read_push_len(1)?,
+                opcodes::All::OP_PUSHDATA2 => self.read_push_len(2)?,
+                opcodes::All::OP_PUSHDATA4 => self.read_push_len(4)?,
                 _ => 0,
             }
         };
         Ok(data_len)
     }
 
+    /// Reads the length prefix of OP_PUSHDATA1/2/4 which follows the opcode at `ip`.
+    /// Moves `ip` to the last byte of the length prefix.
+    #[inline]
+    fn read_push_len(&mut self, size: usize) -> Result<usize, ScriptError> {
+        if self.ip + 1 + size > self.n_bytes {
+            return Err(ScriptError::UnexpectedEof);
+        }
+        let val = ScriptEvaluator::read_uint(&self.bytes[self.ip + 1..], size)?;
+        self.ip += size;
+        Ok(val)
+    }
+
     fn eval_script_pattern(elements: &[StackElement]) -> ScriptPattern {
         // Pay to Public Key Hash (p2pkh)
         let p2pkh = [
@@ -290,6 +297,30 @@ impl<'a> ScriptEvaluator<'a> {
             return ScriptPattern::Pay2ScriptHash;
         }
 
+        // Witness v0 (p2wpkh and p2wsh), distinguished by the program length
+        let witness_v0 = [
+            StackElement::Op(opcodes::All::OP_PUSHBYTES_0),
+            StackElement::Data(Vec::new()),
+        ];
+        if ScriptEvaluator::match_stack_pattern(&elements, &witness_v0) {
+            match elements[1].data().map(|d| d.len()) {
+                Ok(20) => return ScriptPattern::Pay2WitnessPublicKeyHash,
+                Ok(32) => return ScriptPattern::Pay2WitnessScriptHash,
+                _ => {}
+            }
+        }
+
+        // Witness v1 (p2tr)
+        let witness_v1 = [
+            StackElement::Op(opcodes::All::OP_PUSHNUM_1),
+            StackElement::Data(Vec::new()),
+        ];
+        if ScriptEvaluator::match_stack_pattern(&elements, &witness_v1) {
+            if let Ok(32) = elements[1].data().map(|d| d.len()) {
+                return ScriptPattern::Pay2Taproot;
+            }
+        }
+
         // Data output
         // pubkey: OP_RETURN <0 to 40 bytes of data>
         let data_output = [
@@ -358,95 +389,54 @@ impl<'a> ScriptEvaluator<'a> {
     }
 }
 
+/// Disassembles a script into the `OP_DUP OP_HASH160 <hex> ...` form.
+/// The result is cut to at most `max_len` characters followed by `...`.
+/// Scripts which can't be evaluated yield the error message in brackets.
+pub fn disassemble(bytes: &[u8], max_len: usize) -> String {
+    let mut asm = match ScriptEvaluator::new(bytes).eval() {
+        Ok(stack) => format!("{:?}", stack),
+        Err(err) => format!("[{}]", err),
+    };
+    // Only contains ASCII chars, so each char is a single byte
+    if asm.len() > max_len {
+        asm.truncate(max_len);
+        asm.push_str("...");
+    }
+    asm
+}
+
 /// Extracts evaluated address from ScriptPubKey
-pub fn eval_from_bytes(bytes: &[u8], version_id: u8) -> EvaluatedScript {
+pub fn eval_from_bytes(bytes: &[u8], coin: &CoinType) -> EvaluatedScript {
     match ScriptEvaluator::new(bytes).eval() {
-        Ok(stack) => eval_from_stack(stack, version_id),
+        Ok(stack) => eval_from_stack(stack, coin),
         Err(err) => EvaluatedScript {
-            address: String::new(),
+            address: None,
             pattern: ScriptPattern::Error(err),
         },
     }
 }
 
 /// Extracts evaluated address from script stack
-pub fn eval_from_stack(stack: Stack, version_id: u8) -> EvaluatedScript {
-    // Wrap everything in a closure to early catch try!()
-    match (|| -> Result<EvaluatedScript, ScriptError> {
-        let script = match stack.pattern {
-            ref p @ ScriptPattern::Pay2PublicKey => {
-                let pub_key = stack.elements[0].data()?;
-                EvaluatedScript {
-                    address: public_key_to_addr(&pub_key, version_id),
-                    pattern: p.clone(),
-                }
-            }
-            ref p @ ScriptPattern::Pay2PublicKeyHash => {
-                let h160 = stack.elements[2].data()?;
-                EvaluatedScript {
-                    address: hash_160_to_address(&h160, version_id),
-                    pattern: p.clone(),
-                }
-            }
-            ref p @ ScriptPattern::Pay2ScriptHash => {
-                let h160 = stack.elements[1].data()?;
-                EvaluatedScript {
-                    address: hash_160_to_address(&h160, 5),
-                    pattern: p.clone(),
-                }
-            }
-            ScriptPattern::DataOutput(ref data) => EvaluatedScript {
-                address: String::new(),
-                pattern: ScriptPattern::DataOutput(data.clone()),
-            },
-            ref p @ ScriptPattern::Pay2MultiSig => {
-                stack.elements[1].data()?;
-                EvaluatedScript {
-                    address: String::new(),
-                    pattern: p.clone(),
-                }
-            }
-            ref p @ ScriptPattern::NotRecognised => EvaluatedScript {
-                address: String::new(),
-                pattern: p.clone(),
-            },
-            ref p @ _ => EvaluatedScript {
-                address: String::new(),
-                pattern: p.clone(),
-            },
-        };
-        Ok(script)
-    })() {
-        Ok(script) => script,
+pub fn eval_from_stack(stack: Stack, coin: &CoinType) -> EvaluatedScript {
+    match Address::from_stack(&stack, coin) {
+        Ok(address) => EvaluatedScript {
+            address: address,
+            pattern: stack.pattern,
+        },
         Err(e) => EvaluatedScript {
-            address: String::new(),
+            address: None,
             pattern: ScriptPattern::Error(e),
         },
     }
 }
 
-/// Takes full ECDSA public key (65 bytes) and a version id
-fn public_key_to_addr(pub_key: &[u8], version: u8) -> String {
-    let h160 = ridemp160(&sha256(pub_key));
-    hash_160_to_address(&h160, version)
-}
-
-/// Takes 20 byte public key and version id
-fn hash_160_to_address(h160: &[u8], version: u8) -> String {
-    let mut vh160 = Vec::with_capacity(h160.len() + 1);
-    vh160.push(version);
-    vh160.extend_from_slice(&h160);
-
-    let h3 = sha256(&sha256(&vh160));
-
-    let mut addr = Vec::from(vh160);
-    addr.extend_from_slice(&h3[0..4]);
-    addr.to_base58()
-}
-
 #[cfg(test)]
 mod tests {
-    use super::{eval_from_bytes, eval_from_stack, ScriptError, ScriptEvaluator, ScriptPattern};
+    use super::{
+        disassemble, eval_from_bytes, eval_from_stack, ScriptError, ScriptEvaluator, ScriptPattern,
+    };
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::utils;
 
     #[test]
     fn test_bitcoin_script_p2pkh() {
@@ -463,11 +453,29 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv");
+        let script = eval_from_stack(stack, &CoinType::from(Bitcoin));
+        assert_eq!(
+            script.address.unwrap().to_string(),
+            "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"
+        );
         assert_eq!(script.pattern, ScriptPattern::Pay2PublicKeyHash);
     }
 
+    #[test]
+    fn test_disassemble() {
+        let bytes = utils::hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        assert_eq!(
+            disassemble(&bytes, 1000),
+            "OP_DUP OP_HASH160 12ab8dc588ca9d5787dde7eb29569da63c3a238c OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        assert_eq!(disassemble(&bytes, 17), "OP_DUP OP_HASH160...");
+        // Push exceeds the script length
+        assert_eq!(
+            disassemble(&[0x6a, 0x04, 0xde], 1000),
+            "[Script error: Unexpected EOF]"
+        );
+    }
+
     #[test]
     fn test_bitcoin_script_p2pk() {
         // https://blockchain.info/tx/e36f06a8dfe44c3d64be2d3fe56c77f91f6a39da4a5ffc086ecb5db9664e8583
@@ -486,8 +494,11 @@ mod tests {
         assert_eq!("044bca633a91de10df85a63d0a24cb09783148fe0e16c92e937fc4491580c860757148effa0595a955f44078b48ba67fa198782e8bb68115da0daa8fde5301f7f9 OP_CHECKSIG",
             format!("{:?}", stack));
 
-        let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f");
+        let script = eval_from_stack(stack, &CoinType::from(Bitcoin));
+        assert_eq!(
+            script.address.unwrap().to_string(),
+            "1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f"
+        );
         assert_eq!(script.pattern, ScriptPattern::Pay2PublicKey);
     }
 
@@ -535,11 +546,41 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "3P14159f73E4gFr7JterCCQh9QjiTjiZrG");
+        let script = eval_from_stack(stack, &CoinType::from(Bitcoin));
+        assert_eq!(
+            script.address.unwrap().to_string(),
+            "3P14159f73E4gFr7JterCCQh9QjiTjiZrG"
+        );
         assert_eq!(script.pattern, ScriptPattern::Pay2ScriptHash);
     }
 
+    #[test]
+    fn test_bitcoin_script_witness() {
+        // OP_0 20 0x751e76e8199196d454941c45d1b3a323f1433bd6
+        let bytes = utils::hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!(stack.pattern, ScriptPattern::Pay2WitnessPublicKeyHash);
+
+        // OP_0 32 0x1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262
+        let bytes = utils::hex_to_vec(
+            "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+        );
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!(stack.pattern, ScriptPattern::Pay2WitnessScriptHash);
+
+        // OP_1 32 0x79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798
+        let bytes = utils::hex_to_vec(
+            "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        );
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!(stack.pattern, ScriptPattern::Pay2Taproot);
+
+        // Witness programs with an unknown length are not recognised
+        let bytes = utils::hex_to_vec("0010751e76e8199196d454941c45d1b3a323");
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!(stack.pattern, ScriptPattern::NotRecognised);
+    }
+
     #[test]
     fn test_bitcoin_script_data_output() {
         // Raw output script: 6a13636861726c6579206c6f766573206865696469
@@ -555,8 +596,8 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "");
+        let script = eval_from_stack(stack, &CoinType::from(Bitcoin));
+        assert_eq!(script.address, None);
         assert_eq!(
             script.pattern,
             ScriptPattern::DataOutput(String::from("charley loves heidi"))
@@ -575,19 +616,65 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "");
+        let script = eval_from_stack(stack, &CoinType::from(Bitcoin));
+        assert_eq!(script.address, None);
         assert_eq!(script.pattern, ScriptPattern::NotRecognised);
     }
 
     #[test]
     fn test_bitcoin_bogus_script() {
         let bytes = [0x4c, 0xFF, 0x00];
-        let script = eval_from_bytes(&bytes, 0x00);
-        assert_eq!(script.address, "");
+        let script = eval_from_bytes(&bytes, &CoinType::from(Bitcoin));
+        assert_eq!(script.address, None);
         assert_eq!(
             script.pattern,
             ScriptPattern::Error(ScriptError::UnexpectedEof)
         );
     }
+
+    #[test]
+    fn test_bitcoin_script_pushdata() {
+        // OP_PUSHDATA1 2 0xabcd
+        let bytes = [0x4c, 0x02, 0xab, 0xcd];
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!("abcd", format!("{:?}", stack));
+
+        // OP_PUSHDATA2 3 0x010203
+        let bytes = [0x4d, 0x03, 0x00, 0x01, 0x02, 0x03];
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!("010203", format!("{:?}", stack));
+
+        // OP_PUSHDATA4 1 0xff OP_CHECKSIG
+        let bytes = [0x4e, 0x01, 0x00, 0x00, 0x00, 0xff, 0xac];
+        let stack = ScriptEvaluator::new(&bytes).eval().unwrap();
+        assert_eq!("ff OP_CHECKSIG", format!("{:?}", stack));
+    }
+
+    #[test]
+    fn test_bitcoin_script_truncated_pushdata() {
+        let scripts: Vec<&[u8]> = vec![
+            // Direct push of 5 bytes, but only 1 byte left
+            &[0x05, 0x01],
+            // OP_PUSHDATA1 without length byte
+            &[0x4c],
+            // OP_PUSHDATA1 declares 5 bytes, but only 2 bytes left
+            &[0x4c, 0x05, 0x01, 0x02],
+            // OP_PUSHDATA2 with incomplete length
+            &[0x4d, 0x01],
+            // OP_PUSHDATA2 declares 0xffff bytes, but only 1 byte left
+            &[0x4d, 0xff, 0xff, 0x00],
+            // OP_PUSHDATA4 with incomplete length
+            &[0x4e, 0x01, 0x00, 0x00],
+            // OP_PUSHDATA4 declares 0xffffffff bytes, but only 1 byte left
+            &[0x4e, 0xff, 0xff, 0xff, 0xff, 0x00],
+        ];
+        for bytes in scripts {
+            let script = eval_from_bytes(&bytes, &CoinType::from(Bitcoin));
+            assert_eq!(script.address, None);
+            assert_eq!(
+                script.pattern,
+                ScriptPattern::Error(ScriptError::UnexpectedEof)
+            );
+        }
+    }
 }
@@ -0,0 +1,133 @@
+use blockchain::proto::script::{ScriptEvaluator, StackElement};
+
+/// Half of the secp256k1 curve order, the upper bound for low S values
+const HALF_CURVE_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// Extracts everything which looks like an ECDSA signature from a scriptSig.
+/// Pushed data starting with a DER sequence tag and within the signature size range is considered a signature,
+/// this also catches signatures with a broken encoding.
+pub fn extract_signatures(script_sig: &[u8]) -> Vec<Vec<u8>> {
+    match ScriptEvaluator::new(script_sig).eval() {
+        Ok(stack) => stack
+            .elements
+            .into_iter()
+            .filter_map(|e| match e {
+                StackElement::Data(d) => Some(d),
+                StackElement::Op(_) => None,
+            })
+            .filter(|d| d.len() >= 9 && d.len() <= 73 && d[0] == 0x30)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Checks if the signature (including the trailing sighash byte) is strict DER encoded [BIP66]
+pub fn is_strict_der(sig: &[u8]) -> bool {
+    // Format: 0x30 [total-length] 0x02 [R-length] [R] 0x02 [S-length] [S] [sighash]
+    let len = sig.len();
+    if len < 9 || len > 73 {
+        return false;
+    }
+    if sig[0] != 0x30 || sig[1] as usize != len - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= len {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != len {
+        return false;
+    }
+
+    // R must be a positive integer without unnecessary leading zeros
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+
+    // Same for S
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 {
+        return false;
+    }
+    true
+}
+
+/// Checks if S is at most half the curve order [BIP62/BIP146].
+/// Expects a strict DER encoded signature, returns false otherwise.
+pub fn is_low_s(sig: &[u8]) -> bool {
+    if !is_strict_der(sig) {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    let len_s = sig[5 + len_r] as usize;
+    let s = &sig[len_r + 6..len_r + 6 + len_s];
+
+    // Compare big endian values without leading zeros
+    let first = s.iter().position(|b| *b != 0).unwrap_or(s.len());
+    let s = &s[first..];
+    if s.len() != HALF_CURVE_ORDER.len() {
+        return s.len() < HALF_CURVE_ORDER.len();
+    }
+    s <= &HALF_CURVE_ORDER[..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_signatures, is_low_s, is_strict_der};
+    use blockchain::utils::hex_to_vec;
+
+    // scriptSig pushing a single SIGHASH_ALL signature
+    const SCRIPT_SIG: &str = "47304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901";
+
+    #[test]
+    fn test_strict_der_signature() {
+        let sigs = extract_signatures(&hex_to_vec(SCRIPT_SIG));
+        assert_eq!(sigs.len(), 1);
+        assert!(is_strict_der(&sigs[0]));
+        assert!(is_low_s(&sigs[0]));
+    }
+
+    #[test]
+    fn test_non_strict_der_signature() {
+        let mut sig = extract_signatures(&hex_to_vec(SCRIPT_SIG)).remove(0);
+        // Unnecessary leading zero in R
+        let mut padded = vec![0x30, sig[1] + 1, 0x02, sig[3] + 1, 0x00];
+        padded.extend_from_slice(&sig[4..]);
+        assert!(!is_strict_der(&padded));
+        assert!(!is_low_s(&padded));
+
+        // Wrong total length
+        sig[1] += 1;
+        assert!(!is_strict_der(&sig));
+
+        // Too short
+        assert!(!is_strict_der(&[
+            0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01
+        ]));
+    }
+
+    #[test]
+    fn test_high_s_signature() {
+        let sig = extract_signatures(&hex_to_vec(SCRIPT_SIG)).remove(0);
+        let len_r = sig[3] as usize;
+        // Replace S by n - 1, which is above the half curve order
+        let mut high_s = Vec::from(&sig[..len_r + 4]);
+        high_s[1] = (len_r + 2 + 35) as u8;
+        high_s.extend_from_slice(&[0x02, 33, 0x00]);
+        high_s.extend_from_slice(&hex_to_vec(
+            "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364140",
+        ));
+        high_s.push(0x01);
+        assert!(is_strict_der(&high_s));
+        assert!(!is_low_s(&high_s));
+    }
+}
@@ -0,0 +1,228 @@
+use blockchain::proto::opcodes::{self, All};
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::{Tx, TxOutpoint};
+
+/// Legacy and P2SH sigops weigh this much more than witness sigops in the sigop cost [BIP141]
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+/// Sigop cost limit per block since segwit.
+/// Equivalent to the former limit of 20000 legacy and P2SH sigops, as blocks without witnesses cost 4 per sigop.
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80000;
+/// OP_CHECKMULTISIG counts as this many sigops if the number of keys isn't given by the preceding opcode
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+/// Spent output types which change how the sigops of the spending input are counted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpentOutput {
+    ScriptHash,
+    WitnessPubKeyHash,
+    WitnessScriptHash,
+    Other,
+}
+
+impl SpentOutput {
+    pub fn from_pattern(pattern: &ScriptPattern) -> SpentOutput {
+        match *pattern {
+            ScriptPattern::Pay2ScriptHash => SpentOutput::ScriptHash,
+            ScriptPattern::Pay2WitnessPublicKeyHash => SpentOutput::WitnessPubKeyHash,
+            ScriptPattern::Pay2WitnessScriptHash => SpentOutput::WitnessScriptHash,
+            _ => SpentOutput::Other,
+        }
+    }
+}
+
+/// Signature operations of a transaction or block, split by the rule which counts them.
+/// Tapscripts have a sigop budget per input instead, so taproot spends don't add anything [BIP342].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SigOps {
+    pub legacy: usize,  // All input and output scripts, OP_CHECKMULTISIG counts 20
+    pub p2sh: usize,    // Redeem scripts of P2SH spends [BIP16]
+    pub witness: usize, // P2WPKH and witness scripts of P2WSH spends, also nested in P2SH [BIP141]
+}
+
+impl SigOps {
+    /// Counts the sigops of `tx` like Bitcoin Core does for the block limits.
+    /// `spent` returns the type of the output spent by an input, Other if it is unknown.
+    pub fn count<F>(tx: &Tx, mut spent: F) -> SigOps
+    where
+        F: FnMut(&TxOutpoint) -> SpentOutput,
+    {
+        let mut sigops = SigOps::default();
+        for input in &tx.inputs {
+            sigops.legacy += count_script_sigops(&input.script_sig, false);
+        }
+        for output in &tx.outputs {
+            sigops.legacy += count_script_sigops(&output.out.script_pubkey, false);
+        }
+        if tx.is_coinbase() {
+            return sigops;
+        }
+
+        for input in &tx.inputs {
+            let witness_script = input.witness.last().map(|item| &item[..]);
+            match spent(&input.outpoint) {
+                SpentOutput::ScriptHash => {
+                    let redeem_script = match last_push(&input.script_sig) {
+                        Some(script) => script,
+                        None => continue,
+                    };
+                    sigops.p2sh += count_script_sigops(redeem_script, true);
+                    // Nested witness programs are the only push in the script sig
+                    match (redeem_script.first(), redeem_script.len()) {
+                        (Some(&0x00), 22) if redeem_script[1] == 0x14 => sigops.witness += 1,
+                        (Some(&0x00), 34) if redeem_script[1] == 0x20 => {
+                            sigops.witness +=
+                                witness_script.map_or(0, |s| count_script_sigops(s, true))
+                        }
+                        _ => (),
+                    }
+                }
+                SpentOutput::WitnessPubKeyHash => sigops.witness += 1,
+                SpentOutput::WitnessScriptHash => {
+                    sigops.witness += witness_script.map_or(0, |s| count_script_sigops(s, true))
+                }
+                SpentOutput::Other => (),
+            }
+        }
+        sigops
+    }
+
+    pub fn add(&mut self, other: &SigOps) {
+        self.legacy += other.legacy;
+        self.p2sh += other.p2sh;
+        self.witness += other.witness;
+    }
+
+    /// Sigop cost as limited by MAX_BLOCK_SIGOPS_COST
+    pub fn cost(&self) -> usize {
+        (self.legacy + self.p2sh) * WITNESS_SCALE_FACTOR + self.witness
+    }
+}
+
+/// Counts OP_CHECKSIG(VERIFY) and OP_CHECKMULTISIG(VERIFY) in `script`.
+/// If `accurate` is set, a multisig preceded by OP_1 to OP_16 counts as that many sigops, otherwise always as 20.
+/// Like Bitcoin Core, counting stops at the first truncated push.
+pub fn count_script_sigops(script: &[u8], accurate: bool) -> usize {
+    let mut n_sigops = 0;
+    let mut last_op = None;
+    let mut ops = ScriptOps::new(script);
+    while let Some((op, _)) = ops.next_op() {
+        match op {
+            All::OP_CHECKSIG | All::OP_CHECKSIGVERIFY => n_sigops += 1,
+            All::OP_CHECKMULTISIG | All::OP_CHECKMULTISIGVERIFY => {
+                n_sigops += match last_op.map(|op: All| op.classify()) {
+                    Some(opcodes::Class::PushNum(n)) if accurate && n >= 1 => n as usize,
+                    _ => MAX_PUBKEYS_PER_MULTISIG,
+                }
+            }
+            _ => (),
+        }
+        last_op = Some(op);
+    }
+    n_sigops
+}
+
+/// Returns the data of the last push if `script` only consists of pushes, which is the redeem script of a P2SH spend
+fn last_push(script: &[u8]) -> Option<&[u8]> {
+    let mut last = None;
+    let mut ops = ScriptOps::new(script);
+    while let Some((op, data)) = ops.next_op() {
+        if op as u8 > All::OP_PUSHNUM_16 as u8 {
+            return None;
+        }
+        last = Some(data);
+    }
+    match ops.truncated {
+        true => None,
+        false => last,
+    }
+}
+
+/// Iterates over the opcodes of a script along with their pushed data
+struct ScriptOps<'a> {
+    script: &'a [u8],
+    pos: usize,
+    truncated: bool,
+}
+
+impl<'a> ScriptOps<'a> {
+    fn new(script: &'a [u8]) -> ScriptOps<'a> {
+        ScriptOps {
+            script: script,
+            pos: 0,
+            truncated: false,
+        }
+    }
+
+    fn next_op(&mut self) -> Option<(All, &'a [u8])> {
+        let op = All::from(*self.script.get(self.pos)?);
+        self.pos += 1;
+        let push_len = match (op, op.classify()) {
+            (_, opcodes::Class::PushBytes(n)) => n as usize,
+            (All::OP_PUSHDATA1, _) => self.read_len(1)?,
+            (All::OP_PUSHDATA2, _) => self.read_len(2)?,
+            (All::OP_PUSHDATA4, _) => self.read_len(4)?,
+            _ => 0,
+        };
+        if push_len > self.script.len() - self.pos {
+            self.truncated = true;
+            return None;
+        }
+        let data = &self.script[self.pos..self.pos + push_len];
+        self.pos += push_len;
+        Some((op, data))
+    }
+
+    /// Reads the little endian length prefix of OP_PUSHDATA1/2/4
+    fn read_len(&mut self, size: usize) -> Option<usize> {
+        if self.pos + size > self.script.len() {
+            self.truncated = true;
+            return None;
+        }
+        let len = self.script[self.pos..self.pos + size]
+            .iter()
+            .rev()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        self.pos += size;
+        Some(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_script_sigops, last_push};
+    use blockchain::utils;
+
+    #[test]
+    fn test_count_script_sigops() {
+        // P2PKH
+        let script = utils::hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        assert_eq!(1, count_script_sigops(&script, false));
+
+        // 2-of-3 multisig
+        let key = "21031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f";
+        let script = utils::hex_to_vec(&format!("52{}{}{}53ae", key, key, key));
+        assert_eq!(20, count_script_sigops(&script, false));
+        assert_eq!(3, count_script_sigops(&script, true));
+
+        // OP_0 as key count is never accurate
+        let script = utils::hex_to_vec(&format!("00{}00ae", key));
+        assert_eq!(20, count_script_sigops(&script, true));
+
+        // Counting stops at the truncated push, the trailing OP_CHECKSIG is part of its data
+        assert_eq!(
+            1,
+            count_script_sigops(&utils::hex_to_vec("ac4c05acac"), false)
+        );
+    }
+
+    #[test]
+    fn test_last_push() {
+        let script = utils::hex_to_vec("0002abcd03010203");
+        assert_eq!(Some(&[0x01, 0x02, 0x03][..]), last_push(&script));
+        // Not push only
+        assert_eq!(None, last_push(&utils::hex_to_vec("0002abcdac")));
+        // Truncated push
+        assert_eq!(None, last_push(&utils::hex_to_vec("0004abcd")));
+        assert_eq!(None, last_push(&[]));
+    }
+}
@@ -0,0 +1,253 @@
+use blockchain::proto::opcodes;
+use blockchain::proto::script::{ScriptEvaluator, StackElement};
+
+/// First byte of the optional annex, which is the last witness item if present [BIP341]
+const ANNEX_TAG: u8 = 0x50;
+/// Leaf version of tapscript [BIP342], the lowest bit of the control block holds the parity of the output key
+const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+
+/// Spend path of a taproot input
+#[derive(Debug, PartialEq)]
+pub enum TaprootSpend<'a> {
+    /// Schnorr signature of the output key, 65 bytes if followed by a sighash byte
+    KeyPath(&'a [u8]),
+    /// Revealed tapscript and the control block proving that it is committed to in the output key
+    ScriptPath {
+        script: &'a [u8],
+        control_block: &'a [u8],
+    },
+}
+
+/// Decoded witness of an input spending a taproot output [BIP341]
+#[derive(Debug, PartialEq)]
+pub struct TaprootWitness<'a> {
+    pub spend: TaprootSpend<'a>,
+    pub annex: Option<&'a [u8]>,
+}
+
+impl<'a> TaprootWitness<'a> {
+    /// Decodes the witness stack of an input.
+    /// The spent output is not known at this point, so the decision relies on the witness layout:
+    /// a single signature for key path spends, or a tapscript control block as last item for script path spends.
+    /// Returns None for witnesses which can't belong to a taproot spend, like P2WPKH or most P2WSH spends.
+    pub fn decode(witness: &'a [Vec<u8>]) -> Option<TaprootWitness<'a>> {
+        let (items, annex) = match witness.split_last() {
+            Some((last, items)) if !items.is_empty() && last.first() == Some(&ANNEX_TAG) => {
+                (items, Some(&last[..]))
+            }
+            _ => (witness, None),
+        };
+        let spend = match items.split_last() {
+            Some((sig, rest)) if rest.is_empty() => match sig.len() {
+                64 | 65 => TaprootSpend::KeyPath(sig),
+                _ => return None,
+            },
+            Some((control_block, rest)) => {
+                let len = control_block.len();
+                if len < 33
+                    || (len - 33) % 32 != 0
+                    || control_block[0] & 0xfe != TAPSCRIPT_LEAF_VERSION
+                {
+                    return None;
+                }
+                TaprootSpend::ScriptPath {
+                    script: &rest[rest.len() - 1],
+                    control_block: control_block,
+                }
+            }
+            None => return None,
+        };
+        Some(TaprootWitness {
+            spend: spend,
+            annex: annex,
+        })
+    }
+}
+
+/// Returns the sighash type of a schnorr signature, None for 64 byte signatures which imply SIGHASH_DEFAULT [BIP341]
+pub fn sighash_type(sig: &[u8]) -> Option<u8> {
+    match sig.len() {
+        65 => Some(sig[64]),
+        _ => None,
+    }
+}
+
+/// Names a sighash type as returned by `sighash_type`.
+/// An explicit 0x00 byte and undefined types make the signature invalid.
+pub fn sighash_name(sighash: Option<u8>) -> String {
+    let name = match sighash {
+        None => "DEFAULT",
+        Some(0x01) => "ALL",
+        Some(0x02) => "NONE",
+        Some(0x03) => "SINGLE",
+        Some(0x81) => "ALL|ANYONECANPAY",
+        Some(0x82) => "NONE|ANYONECANPAY",
+        Some(0x83) => "SINGLE|ANYONECANPAY",
+        Some(other) => return format!("invalid 0x{:02x}", other),
+    };
+    String::from(name)
+}
+
+/// k-of-n multisig tapscript [BIP342], which replaces OP_CHECKMULTISIG in taproot:
+/// <A pubkey> OP_CHECKSIG <B pubkey> OP_CHECKSIGADD [C pubkey OP_CHECKSIGADD...] <k> OP_NUMEQUAL
+#[derive(Debug, Clone, PartialEq)]
+pub struct TapscriptMultisig {
+    pub required: usize,
+    pub pubkeys: Vec<Vec<u8>>, // 32 byte x-only public keys
+}
+
+impl TapscriptMultisig {
+    /// Decodes `script`, returns None if it is no multisig script
+    pub fn from_script(script: &[u8]) -> Option<TapscriptMultisig> {
+        let stack = match ScriptEvaluator::new(script).eval() {
+            Ok(stack) => stack,
+            Err(_) => return None,
+        };
+        let n_elements = stack.elements.len();
+        if n_elements < 6 || n_elements % 2 != 0 {
+            return None;
+        }
+        let (keys, threshold) = stack.elements.split_at(n_elements - 2);
+
+        let mut pubkeys = Vec::with_capacity(keys.len() / 2);
+        for (i, pair) in keys.chunks(2).enumerate() {
+            let expected = match i {
+                0 => opcodes::All::OP_CHECKSIG,
+                _ => opcodes::All::OP_CHECKSIGADD,
+            };
+            match (&pair[0], &pair[1]) {
+                (&StackElement::Data(ref key), &StackElement::Op(op))
+                    if key.len() == 32 && op == expected =>
+                {
+                    pubkeys.push(key.clone())
+                }
+                _ => return None,
+            }
+        }
+
+        let required = match (&threshold[0], &threshold[1]) {
+            (&StackElement::Op(op), &StackElement::Op(opcodes::All::OP_NUMEQUAL)) => {
+                match op.classify() {
+                    opcodes::Class::PushNum(k) if k > 0 => k as usize,
+                    _ => return None,
+                }
+            }
+            // Thresholds above 16 are pushed as script number
+            (&StackElement::Data(ref num), &StackElement::Op(opcodes::All::OP_NUMEQUAL)) => {
+                read_script_num(num)?
+            }
+            _ => return None,
+        };
+        if required > pubkeys.len() {
+            return None;
+        }
+        Some(TapscriptMultisig {
+            required: required,
+            pubkeys: pubkeys,
+        })
+    }
+}
+
+/// Reads a minimally encoded positive script number of up to 4 bytes
+fn read_script_num(data: &[u8]) -> Option<usize> {
+    match data.last() {
+        Some(last) if data.len() <= 4 && *last != 0 && *last & 0x80 == 0 => Some(
+            data.iter()
+                .rev()
+                .fold(0, |num, byte| (num << 8) | *byte as usize),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sighash_name, sighash_type, TaprootSpend, TaprootWitness, TapscriptMultisig};
+    use blockchain::utils;
+
+    // x-only public keys of the BIP340 test vectors
+    const PUBKEYS: [&str; 3] = [
+        "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659",
+        "dd308afec5777e13121fa72b9cc1b7cc0139715309b086c960e18fd969774eb8",
+    ];
+
+    #[test]
+    fn test_tapscript_multisig() {
+        // <A> OP_CHECKSIG <B> OP_CHECKSIGADD <C> OP_CHECKSIGADD OP_2 OP_NUMEQUAL
+        let script = utils::hex_to_vec(&format!(
+            "20{}ac20{}ba20{}ba529c",
+            PUBKEYS[0], PUBKEYS[1], PUBKEYS[2]
+        ));
+        let multisig = TapscriptMultisig::from_script(&script).unwrap();
+        assert_eq!(2, multisig.required);
+        let pubkeys: Vec<Vec<u8>> = PUBKEYS.iter().map(|k| utils::hex_to_vec(k)).collect();
+        assert_eq!(pubkeys, multisig.pubkeys);
+
+        // Threshold pushed as script number
+        let script = utils::hex_to_vec(&format!(
+            "20{}ac20{}ba20{}ba01039c",
+            PUBKEYS[0], PUBKEYS[1], PUBKEYS[2]
+        ));
+        assert_eq!(3, TapscriptMultisig::from_script(&script).unwrap().required);
+
+        // More signatures required than keys, and OP_CHECKSIGADD in the first place
+        let script = utils::hex_to_vec(&format!("20{}ac20{}ba539c", PUBKEYS[0], PUBKEYS[1]));
+        assert_eq!(None, TapscriptMultisig::from_script(&script));
+        let script = utils::hex_to_vec(&format!("20{}ba20{}ba529c", PUBKEYS[0], PUBKEYS[1]));
+        assert_eq!(None, TapscriptMultisig::from_script(&script));
+    }
+
+    #[test]
+    fn test_taproot_witness_decode() {
+        let sig = vec![0x01; 64];
+        let witness = vec![sig.clone()];
+        assert_eq!(
+            Some(TaprootWitness {
+                spend: TaprootSpend::KeyPath(&sig),
+                annex: None,
+            }),
+            TaprootWitness::decode(&witness)
+        );
+
+        let script = utils::hex_to_vec(&format!("20{}ac", PUBKEYS[0]));
+        let mut control_block = vec![0xc1];
+        control_block.extend(utils::hex_to_vec(PUBKEYS[1]));
+        let annex = vec![0x50, 0x00];
+        let witness = vec![
+            sig.clone(),
+            script.clone(),
+            control_block.clone(),
+            annex.clone(),
+        ];
+        assert_eq!(
+            Some(TaprootWitness {
+                spend: TaprootSpend::ScriptPath {
+                    script: &script,
+                    control_block: &control_block,
+                },
+                annex: Some(&annex[..]),
+            }),
+            TaprootWitness::decode(&witness)
+        );
+
+        // P2WPKH: signature and compressed public key
+        let mut pubkey = vec![0x02];
+        pubkey.extend(utils::hex_to_vec(PUBKEYS[2]));
+        assert_eq!(None, TaprootWitness::decode(&[vec![0x30; 71], pubkey]));
+        assert_eq!(None, TaprootWitness::decode(&[]));
+    }
+
+    #[test]
+    fn test_sighash_type() {
+        let mut sig = vec![0x01; 64];
+        assert_eq!(None, sighash_type(&sig));
+        assert_eq!("DEFAULT", sighash_name(sighash_type(&sig)));
+        sig.push(0x83);
+        assert_eq!(Some(0x83), sighash_type(&sig));
+        assert_eq!("SINGLE|ANYONECANPAY", sighash_name(sighash_type(&sig)));
+        // SIGHASH_DEFAULT must not be given explicitly
+        assert_eq!("invalid 0x00", sighash_name(Some(0x00)));
+        assert_eq!("invalid 0x04", sighash_name(Some(0x04)));
+    }
+}
@@ -4,10 +4,33 @@ use std::fmt;
 use std::hash::BuildHasherDefault;
 use twox_hash::XxHash;
 
+use blockchain::parser::types::CoinType;
 use blockchain::proto::script;
 use blockchain::proto::varuint::VarUint;
 use blockchain::proto::ToRaw;
-use blockchain::utils::{self, arr_to_hex_swapped, le};
+use blockchain::utils::{self, arr_to_hex_swapped, le, sha256};
+use common::profile::{self, Phase};
+
+/// nLockTime values below this threshold are block heights, all others are unix timestamps
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Interpretation of an absolute nLockTime value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockTime {
+    None,
+    Height(u32),
+    Time(u32),
+}
+
+/// Splits nLockTime into block height and unix time locks
+#[inline]
+pub fn locktime_kind(n: u32) -> LockTime {
+    match n {
+        0 => LockTime::None,
+        n if n < LOCKTIME_THRESHOLD => LockTime::Height(n),
+        n => LockTime::Time(n),
+    }
+}
 
 /// Simple transaction struct
 /// Please note: The txid is not stored here. See Hashed.
@@ -29,14 +52,16 @@ impl Tx {
         out_count: VarUint,
         outputs: &[TxOutput],
         tx_locktime: u32,
-        version_id: u8,
+        coin: &CoinType,
     ) -> Self {
         // Evaluate and wrap all outputs to process them later
+        let t_start = profile::start();
         let evaluated_out = outputs
             .iter()
             .cloned()
-            .map(|o| EvaluatedTxOut::eval_script(o, version_id))
+            .map(|o| EvaluatedTxOut::eval_script(o, coin))
             .collect();
+        profile::record(Phase::Classify, t_start);
         Tx {
             tx_version: tx_version,
             in_count: in_count,
@@ -56,29 +81,109 @@ impl Tx {
         return false;
     }
 
+    #[inline]
+    pub fn locktime(&self) -> LockTime {
+        locktime_kind(self.tx_locktime)
+    }
+
+    /// nLockTime is only enforced if at least one input has a non final sequence number
+    #[inline]
+    pub fn is_locktime_enforced(&self) -> bool {
+        self.inputs.iter().any(|input| input.seq_no != 0xFFFFFFFF)
+    }
+
+    /// Returns true if at least one input carries witness data
+    #[inline]
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    /// Computes the witness txid [BIP141], which is the txid for transactions without witness data
+    pub fn wtxid(&self) -> [u8; 32] {
+        sha256(&sha256(&self.witness_bytes()))
+    }
+
+    /// Serializes the transaction including its witnesses [BIP144].
+    /// Marker and flag are only written if at least one input has a witness, as in Bitcoin Core.
+    /// A transaction which set the flag with nothing but empty witness stacks is therefore serialized like a legacy one.
+    pub fn witness_bytes(&self) -> Vec<u8> {
+        if !self.has_witness() {
+            return self.to_bytes();
+        }
+        let mut bytes = self.to_bytes();
+        // Strip locktime, insert marker and flag after the version and append the witnesses
+        bytes.truncate(bytes.len() - 4);
+        bytes.splice(4..4, [0x00, 0x01].iter().cloned());
+        for input in &self.inputs {
+            bytes.extend_from_slice(&VarUint::compact(input.witness.len() as u64).to_bytes());
+            for item in &input.witness {
+                bytes.extend_from_slice(&VarUint::compact(item.len() as u64).to_bytes());
+                bytes.extend_from_slice(item);
+            }
+        }
+        bytes.extend_from_slice(&le::u32_to_array(self.tx_locktime));
+        bytes
+    }
+
+    /// Virtual size in vbytes, which is the weight [BIP141] divided by 4 and rounded up.
+    /// Witness data counts one weight unit per byte, the rest four.
+    pub fn vsize(&self) -> usize {
+        let weight = self.to_bytes().len() * 3 + self.witness_bytes().len();
+        (weight + 3) / 4
+    }
+
+    /// Returns true if all outputs of this non coinbase transaction are OP_RETURN outputs.
+    /// Such transactions only carry data and pay fees without moving any value.
+    pub fn is_data_carrier(&self) -> bool {
+        !self.is_coinbase()
+            && !self.outputs.is_empty()
+            && self.outputs.iter().all(|o| match o.script.pattern {
+                script::ScriptPattern::DataOutput(_) => true,
+                _ => false,
+            })
+    }
+
+    /// Returns the total value of all outputs.
+    /// Valid values are far below the limit of u64, but blk files are untrusted, so the sum saturates instead of overflowing.
+    pub fn output_value(&self) -> u64 {
+        self.outputs
+            .iter()
+            .fold(0u64, |sum, o| sum.saturating_add(o.out.value))
+    }
+
+    /// Returns the fees paid by this transaction.
+    /// Returns `None` if one of the spent outputs is not part of `utxoset`,
+    /// or if the outputs exceed the inputs, which makes the transaction invalid.
     pub fn get_fees(
         &self,
         utxoset: &HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>>,
-    ) -> u64 {
-        let mut sum_out = 0;
+    ) -> Option<u64> {
+        let mut sum_out: u64 = 0;
         for txout in self.outputs.iter() {
-            sum_out += txout.out.value;
+            sum_out = sum_out.checked_add(txout.out.value)?;
         }
-        let mut sum_in = 0;
+        let mut sum_in: u64 = 0;
         for txin in self.inputs.iter() {
             match utxoset.get(&txin.outpoint) {
-                None => {
-                    panic!("spending non-existent coins {}", txin.outpoint);
-                }
+                None => return None,
                 Some((inval, _)) => {
-                    sum_in += *inval;
+                    sum_in = sum_in.checked_add(*inval)?;
                 }
             }
         }
-        sum_in - sum_out
+        sum_in.checked_sub(sum_out)
     }
 }
 
+#[test]
+fn test_locktime_kind() {
+    assert_eq!(locktime_kind(0), LockTime::None);
+    assert_eq!(locktime_kind(1), LockTime::Height(1));
+    assert_eq!(locktime_kind(499_999_999), LockTime::Height(499_999_999));
+    assert_eq!(locktime_kind(500_000_000), LockTime::Time(500_000_000));
+    assert_eq!(locktime_kind(0xFFFFFFFF), LockTime::Time(0xFFFFFFFF));
+}
+
 #[test]
 fn test_get_fees() {
     let mut utxoset : HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>> = Default::default();
@@ -97,6 +202,36 @@ fn test_get_fees() {
     assert_eq!(*h, 1 as usize);
 }
 
+#[test]
+fn test_get_fees_unknown_outpoint() {
+    use blockchain::proto::fixtures::{output, spend, tx};
+
+    let mut utxoset: HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>> =
+        Default::default();
+
+    let tx = tx(1, vec![spend([1; 32], 0)], vec![output(700, Vec::new())], 0);
+    assert_eq!(tx.get_fees(&utxoset), None);
+
+    utxoset.insert(tx.inputs[0].outpoint.clone(), (1000, 1));
+    assert_eq!(tx.get_fees(&utxoset), Some(300));
+}
+
+#[test]
+fn test_is_data_carrier() {
+    use blockchain::proto::fixtures::{output, spend, tx};
+    use blockchain::utils::hex_to_vec;
+
+    let data_output = output(0, hex_to_vec("6a04deadbeef"));
+    let payment_output = output(
+        700,
+        hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac"),
+    );
+    let new_tx = |outputs: Vec<TxOutput>| tx(1, vec![spend([1; 32], 0)], outputs, 0);
+    assert!(new_tx(vec![data_output.clone()]).is_data_carrier());
+    assert!(!new_tx(vec![data_output, payment_output]).is_data_carrier());
+    assert!(!new_tx(Vec::new()).is_data_carrier());
+}
+
 impl fmt::Debug for Tx {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Tx")
@@ -169,6 +304,7 @@ pub struct TxInput {
     pub script_len: VarUint,
     pub script_sig: Vec<u8>,
     pub seq_no: u32,
+    pub witness: Vec<Vec<u8>>, // Witness stack items, not part of the txid serialization
 }
 
 impl ToRaw for TxInput {
@@ -190,6 +326,7 @@ impl fmt::Debug for TxInput {
             .field("script_len", &self.script_len)
             .field("script_sig", &self.script_sig)
             .field("seq_no", &self.seq_no)
+            .field("witness_items", &self.witness.len())
             .finish()
     }
 }
@@ -203,9 +340,9 @@ pub struct EvaluatedTxOut {
 
 impl EvaluatedTxOut {
     #[inline]
-    pub fn eval_script(out: TxOutput, version_id: u8) -> EvaluatedTxOut {
+    pub fn eval_script(out: TxOutput, coin: &CoinType) -> EvaluatedTxOut {
         EvaluatedTxOut {
-            script: script::eval_from_bytes(&out.script_pubkey, version_id),
+            script: script::eval_from_bytes(&out.script_pubkey, coin),
             out: out,
         }
     }
@@ -23,6 +23,16 @@ impl VarUint {
         }
     }
 
+    /// Creates the shortest encoding of `value`, as required for serialization
+    pub fn compact(value: u64) -> VarUint {
+        match value {
+            0x00..=0xfc => VarUint::from(value as u8),
+            0xfd..=0xffff => VarUint::from(value as u16),
+            0x10000..=0xffffffff => VarUint::from(value as u32),
+            _ => VarUint::from(value),
+        }
+    }
+
     pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<VarUint> {
         let first = reader.read_u8()?; // read first length byte
         let vint = match first {
@@ -138,6 +148,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_varuint_compact() {
+        assert_eq!(vec![0xfc], VarUint::compact(0xfc).to_bytes());
+        assert_eq!(vec![0xfd, 0xfd, 0x00], VarUint::compact(0xfd).to_bytes());
+        assert_eq!(
+            vec![0xfe, 0x00, 0x00, 0x01, 0x00],
+            VarUint::compact(0x10000).to_bytes()
+        );
+        assert_eq!(9, VarUint::compact(0x100000000).to_bytes().len());
+    }
+
     #[test]
     fn test_varuint_read() {
         let mut cursor = io::Cursor::new([0xfe, 0x55, 0xa1, 0xae, 0xc6]);
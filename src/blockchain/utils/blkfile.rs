@@ -1,11 +1,14 @@
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::convert::From;
 use std::fs::{self, File, Metadata};
+use std::io::{Seek, SeekFrom};
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use seek_bufread::BufReader;
 
+use blockchain::utils::throttle::{RateLimiter, ThrottledFile};
 use errors::{OpError, OpErrorKind, OpResult};
 
 /// Holds all necessary data about a raw blk file
@@ -14,6 +17,7 @@ pub struct BlkFile {
     pub path: PathBuf, // File path
     pub index: u32,    // Holds Index of blk file. (E.g. blk00000.dat has index 0x00000)
     pub size: u64,     // File size in bytes
+    pub offset: u64, // Position of the first block to read, only set for the grown tail of a followed file
 }
 
 impl BlkFile {
@@ -22,21 +26,71 @@ impl BlkFile {
             path: path,
             index: index,
             size: size,
+            offset: 0,
         }
     }
 
-    /// Returns a BufferedMemoryReader to reduce io wait.
-    pub fn get_reader(&self) -> OpResult<BufReader<File>> {
+    /// Returns a BufferedMemoryReader to reduce io wait, positioned at `offset`.
+    /// Reads are paced by `limiter` if given.
+    pub fn get_reader(
+        &self,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> OpResult<BufReader<ThrottledFile>> {
         let f = File::open(&self.path)?;
-        Ok(BufReader::with_capacity(100000000, f))
+        let mut reader = BufReader::with_capacity(100000000, ThrottledFile::new(f, limiter));
+        if self.offset > 0 {
+            reader.seek(SeekFrom::Start(self.offset))?;
+        }
+        Ok(reader)
     }
 
-    /// Collects all blk*.dat paths in the given directory
-    pub fn from_path(path: PathBuf, min_blk_idx: u32) -> OpResult<VecDeque<BlkFile>> {
-        info!(target: "blkfile", "Reading files from {:?} ...", path);
-        let content = fs::read_dir(path)?;
+    /// Describes the file at `path`. The index is taken from the file name if it has the blk*.dat form, otherwise it is 0.
+    pub fn from_file(path: PathBuf) -> OpResult<BlkFile> {
+        let size = fs::metadata(&path)?.len();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| BlkFile::parse_blk_index(name, "blk", ".dat"))
+            .unwrap_or(0);
+        Ok(BlkFile::new(path, index, size))
+    }
 
+    /// Collects all blk*.dat paths in the given directory.
+    /// Sub directories are scanned up to `depth` levels below, the files are ordered by their index across all of them.
+    pub fn from_path(path: PathBuf, min_blk_idx: u32, depth: usize) -> OpResult<VecDeque<BlkFile>> {
+        info!(target: "blkfile", "Reading files from {:?} ...", path);
         let mut blk_files = Vec::new();
+        let mut visited = HashSet::new();
+        visited.insert(fs::canonicalize(&path)?);
+        BlkFile::collect(&path, min_blk_idx, depth, &mut visited, &mut blk_files)?;
+
+        blk_files.sort_by(|a, b| (a.index, &a.path).cmp(&(b.index, &b.path)));
+        for pair in blk_files.windows(2) {
+            if pair[0].index == pair[1].index {
+                warn!(target: "blkfile", "{:?} and {:?} have the same index, resuming may skip blocks of one of them.",
+                      pair[0].path, pair[1].path);
+            }
+        }
+        trace!(target: "blkfile", "Found {} blk files", blk_files.len());
+        if blk_files.is_empty() {
+            Err(OpError::new(OpErrorKind::RuntimeError).join_msg("No blk files found!"))
+        } else {
+            //blk_files.split_off(2); //just for testing purposes
+            Ok(VecDeque::from_iter(blk_files.into_iter()))
+        }
+    }
+
+    /// Adds the blk files in `dir` to `blk_files`, descending `depth` more levels into sub directories.
+    /// `visited` holds the canonical path of each scanned directory, so symlink loops are not followed.
+    fn collect(
+        dir: &Path,
+        min_blk_idx: u32,
+        depth: usize,
+        visited: &mut HashSet<PathBuf>,
+        blk_files: &mut Vec<BlkFile>,
+    ) -> OpResult<()> {
+        let content = fs::read_dir(dir)?;
+
         let blk_prefix = String::from("blk");
         let blk_ext = String::from(".dat");
 
@@ -50,6 +104,18 @@ impl BlkFile {
                     metadata = fs::metadata(path.clone()).unwrap();
                 }
 
+                if metadata.is_dir() {
+                    if depth == 0 {
+                        continue;
+                    }
+                    if visited.insert(fs::canonicalize(&path)?) {
+                        BlkFile::collect(&path, min_blk_idx, depth - 1, visited, blk_files)?;
+                    } else {
+                        debug!(target: "blkfile", "Skipping {:?}, it was already scanned.", path);
+                    }
+                    continue;
+                }
+
                 let file_name =
                     String::from(transform!(path.as_path().file_name().unwrap().to_str()));
 
@@ -67,15 +133,7 @@ impl BlkFile {
                 warn!(target: "blkfile", "Unable to read blk file!");
             }
         }
-
-        blk_files.sort_by(|a, b| a.path.cmp(&b.path));
-        trace!(target: "blkfile", "Found {} blk files", blk_files.len());
-        if blk_files.is_empty() {
-            Err(OpError::new(OpErrorKind::RuntimeError).join_msg("No blk files found!"))
-        } else {
-            //blk_files.split_off(2); //just for testing purposes
-            Ok(VecDeque::from_iter(blk_files.into_iter()))
-        }
+        Ok(())
     }
 
     /// Identifies blk file and parses index
@@ -92,9 +150,158 @@ impl BlkFile {
     }
 }
 
+/// Picks up blk files which were written after the initial scan of the blockchain directory (--follow-new-files).
+/// Only files with a higher index than all known ones are added, so each file is returned once.
+/// A node appends new blocks to the last file until it is full, so its grown part is returned as well.
+pub struct FileFollower {
+    path: PathBuf,
+    depth: usize,
+    next_idx: u32, // Lowest index of a blk file which wasn't seen yet
+    last: Option<(PathBuf, u32, u64)>, // Path, index and size of the last file that was handed out
+}
+
+impl FileFollower {
+    /// Follows the files after `known`, which are the ones found by `BlkFile::from_path()`
+    pub fn new(path: PathBuf, depth: usize, known: &VecDeque<BlkFile>) -> FileFollower {
+        let last = known
+            .iter()
+            .max_by_key(|f| f.index)
+            .map(|f| (f.path.clone(), f.index, f.size));
+        FileFollower {
+            path: path,
+            depth: depth,
+            next_idx: known.iter().map(|f| f.index + 1).max().unwrap_or(0),
+            last: last,
+        }
+    }
+
+    /// Scans the blockchain directory again and returns the blk files which appeared since the last call.
+    /// If the last file grew in the meantime, the part after its previous size comes first.
+    pub fn poll(&mut self) -> OpResult<VecDeque<BlkFile>> {
+        let mut blk_files = Vec::new();
+        if let Some((ref path, index, ref mut size)) = self.last {
+            let new_size = fs::metadata(path)?.len();
+            if new_size > *size {
+                info!(target: "blkfile", "blk{:05}.dat grew by {} bytes", index, new_size - *size);
+                let mut tail = BlkFile::new(path.clone(), index, new_size);
+                tail.offset = *size;
+                blk_files.push(tail);
+                *size = new_size;
+            }
+        }
+        let mut visited = HashSet::new();
+        visited.insert(fs::canonicalize(&self.path)?);
+        BlkFile::collect(
+            &self.path,
+            self.next_idx,
+            self.depth,
+            &mut visited,
+            &mut blk_files,
+        )?;
+        blk_files.sort_by(|a, b| (a.index, &a.path).cmp(&(b.index, &b.path)));
+        let n_new = blk_files
+            .iter()
+            .filter(|f| f.index >= self.next_idx)
+            .count();
+        if let Some(last) = blk_files.iter().filter(|f| f.index >= self.next_idx).last() {
+            info!(target: "blkfile", "Found {} new blk files up to blk{:05}.dat", n_new, last.index);
+            self.next_idx = last.index + 1;
+            self.last = Some((last.path.clone(), last.index, last.size));
+        }
+        Ok(VecDeque::from_iter(blk_files.into_iter()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use common::testdir;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_from_path_recursive() {
+        use std::os::unix::fs::symlink;
+
+        let root = testdir::create("blkfile.test");
+        fs::create_dir_all(root.join("2009")).unwrap();
+        fs::create_dir_all(root.join("2010/archive")).unwrap();
+        for path in &[
+            "blk00002.dat",
+            "2009/blk00000.dat",
+            "2010/archive/blk00001.dat",
+            "2010/rev00001.dat",
+        ] {
+            File::create(root.join(path)).unwrap();
+        }
+        // Points back to the root, which must not be scanned again
+        symlink(&root, root.join("2010/loop")).unwrap();
+
+        let indices = |depth, min_blk_idx| -> Vec<u32> {
+            BlkFile::from_path(root.clone(), min_blk_idx, depth)
+                .unwrap()
+                .iter()
+                .map(|blk_file| blk_file.index)
+                .collect()
+        };
+        assert_eq!(vec![2], indices(0, 0));
+        assert_eq!(vec![0, 2], indices(1, 0));
+        assert_eq!(vec![0, 1, 2], indices(usize::max_value(), 0));
+        assert_eq!(vec![1, 2], indices(usize::max_value(), 1));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_file_follower() {
+        let root = testdir::create("blkfile.follow.test");
+        File::create(root.join("blk00000.dat")).unwrap();
+
+        let known = BlkFile::from_path(root.clone(), 0, 0).unwrap();
+        let mut follower = FileFollower::new(root.clone(), 0, &known);
+        assert!(follower.poll().unwrap().is_empty());
+
+        // Written while the scan is running
+        File::create(root.join("blk00001.dat")).unwrap();
+        File::create(root.join("blk00002.dat")).unwrap();
+        let indices: Vec<u32> = follower.poll().unwrap().iter().map(|f| f.index).collect();
+        assert_eq!(vec![1, 2], indices);
+        assert!(follower.poll().unwrap().is_empty());
+
+        // Blocks appended to the last file are returned as its tail, the older files are done
+        let append = |name: &str, n_bytes: usize| {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(root.join(name))
+                .unwrap();
+            file.write_all(&vec![0xf9; n_bytes]).unwrap();
+        };
+        append("blk00002.dat", 100);
+        append("blk00001.dat", 10);
+        let tails: Vec<(u32, u64, u64)> = follower
+            .poll()
+            .unwrap()
+            .iter()
+            .map(|f| (f.index, f.offset, f.size))
+            .collect();
+        assert_eq!(vec![(2, 0, 100)], tails);
+        assert!(follower.poll().unwrap().is_empty());
+
+        append("blk00002.dat", 50);
+        File::create(root.join("blk00003.dat")).unwrap();
+        let tails: Vec<(u32, u64, u64)> = follower
+            .poll()
+            .unwrap()
+            .iter()
+            .map(|f| (f.index, f.offset, f.size))
+            .collect();
+        assert_eq!(vec![(2, 100, 150), (3, 0, 0)], tails);
+
+        let mut reader = BlkFile::from_file(root.join("blk00002.dat")).unwrap();
+        reader.offset = 100;
+        assert_eq!(100, reader.get_reader(None).unwrap().position());
+        fs::remove_dir_all(&root).unwrap();
+    }
 
     #[test]
     fn test_parse_blk_index() {
@@ -0,0 +1,98 @@
+use std::hash::{Hash, Hasher};
+
+use twox_hash::XxHash;
+
+/// Fixed size bloom filter for approximate set membership.
+/// Items are never reported as new once inserted, but new items may be falsely reported as known.
+/// The false positive rate grows as the filter fills up.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    n_bits: u64,
+    n_hashes: u64,
+}
+
+impl BloomFilter {
+    /// Creates a filter using `n_bytes` of memory and `n_hashes` bit positions per item
+    pub fn new(n_bytes: usize, n_hashes: u64) -> BloomFilter {
+        let n_words = (n_bytes / 8).max(1);
+        BloomFilter {
+            bits: vec![0; n_words],
+            n_bits: n_words as u64 * 64,
+            n_hashes: n_hashes.max(1),
+        }
+    }
+
+    /// Number of bytes used by the filter
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Inserts `item` and returns true if it was (probably) inserted before
+    pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
+        let (h1, h2) = hash_pair(item);
+        let mut known = true;
+        for i in 0..self.n_hashes {
+            let (word, mask) = self.position(h1, h2, i);
+            if self.bits[word] & mask == 0 {
+                known = false;
+                self.bits[word] |= mask;
+            }
+        }
+        known
+    }
+
+    /// Returns true if `item` was (probably) inserted before, without inserting it
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        let (h1, h2) = hash_pair(item);
+        (0..self.n_hashes).all(|i| {
+            let (word, mask) = self.position(h1, h2, i);
+            self.bits[word] & mask != 0
+        })
+    }
+
+    /// Word index and bit mask of the `i`th position of an item
+    #[inline]
+    fn position(&self, h1: u64, h2: u64, i: u64) -> (usize, u64) {
+        let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.n_bits;
+        ((bit / 64) as usize, 1u64 << (bit % 64))
+    }
+}
+
+/// Derives all positions from two halves of a single hash (Kirsch-Mitzenmacher)
+#[inline]
+fn hash_pair<T: Hash>(item: &T) -> (u64, u64) {
+    let mut hasher = XxHash::default();
+    item.hash(&mut hasher);
+    let hash = hasher.finish();
+    (hash & 0xffffffff, (hash >> 32) | 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    #[test]
+    fn test_bloom_filter_insert() {
+        let mut filter = BloomFilter::new(1024, 4);
+        assert!(!filter.insert(&"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        assert!(filter.insert(&"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        assert!(!filter.insert(&"12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"));
+
+        // Inserted items are always known
+        let items: Vec<u32> = (0..100).collect();
+        for item in &items {
+            filter.insert(item);
+        }
+        assert!(items.iter().all(|item| filter.insert(item)));
+    }
+
+    #[test]
+    fn test_bloom_filter_contains() {
+        let mut filter = BloomFilter::new(1024, 4);
+        assert!(!filter.contains(&"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        // Checking doesn't insert
+        assert!(!filter.insert(&"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        assert!(filter.contains(&"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        assert!(!filter.contains(&"12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"));
+    }
+}
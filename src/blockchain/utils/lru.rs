@@ -0,0 +1,105 @@
+use std::collections::hash_map::Keys;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasherDefault, Hash};
+use std::mem;
+
+use twox_hash::XxHash;
+
+use common::memory;
+
+/// Set holding at most `capacity` items, evicting the least recently inserted or refreshed one.
+/// Evicted items are reported as new when inserted again.
+pub struct LruSet<T: Hash + Eq + Clone> {
+    last_use: HashMap<T, u64, BuildHasherDefault<XxHash>>,
+    queue: VecDeque<(u64, T)>, // Items in order of use, entries with an outdated stamp are skipped
+    capacity: usize,
+    stamp: u64,
+}
+
+impl<T: Hash + Eq + Clone> LruSet<T> {
+    pub fn new(capacity: usize) -> LruSet<T> {
+        LruSet {
+            last_use: Default::default(),
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            stamp: 0,
+        }
+    }
+
+    /// Inserts or refreshes `item` and returns true if it was in the set
+    pub fn insert(&mut self, item: &T) -> bool {
+        self.stamp += 1;
+        let known = self.last_use.insert(item.clone(), self.stamp).is_some();
+        self.queue.push_back((self.stamp, item.clone()));
+        while self.last_use.len() > self.capacity {
+            self.evict();
+        }
+        // Refreshed items leave outdated entries behind
+        if self.queue.len() > 2 * self.capacity {
+            self.compact();
+        }
+        known
+    }
+
+    /// Returns the number of items in the set
+    pub fn len(&self) -> usize {
+        self.last_use.len()
+    }
+
+    /// Iterates over all items in arbitrary order
+    pub fn iter<'a>(&'a self) -> Keys<'a, T, u64> {
+        self.last_use.keys()
+    }
+
+    /// Estimates the memory used by the map and the queue, heap data of the items is not included
+    pub fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(T, u64)>(self.last_use.len())
+            + self.queue.capacity() * mem::size_of::<(u64, T)>()
+    }
+
+    /// Removes the least recently used item
+    fn evict(&mut self) {
+        while let Some((stamp, item)) = self.queue.pop_front() {
+            if self.last_use.get(&item) == Some(&stamp) {
+                self.last_use.remove(&item);
+                return;
+            }
+        }
+    }
+
+    /// Drops the outdated entries of the queue
+    fn compact(&mut self) {
+        let last_use = &self.last_use;
+        self.queue
+            .retain(|&(stamp, ref item)| last_use.get(item) == Some(&stamp));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruSet;
+
+    #[test]
+    fn test_lru_set_evicts_least_recently_used() {
+        let mut set = LruSet::new(2);
+        assert!(!set.insert(&"a"));
+        assert!(!set.insert(&"b"));
+        // Refreshes a, so b is evicted next
+        assert!(set.insert(&"a"));
+        assert!(!set.insert(&"c"));
+        assert_eq!(2, set.len());
+        assert!(set.insert(&"a"));
+        assert!(!set.insert(&"b"));
+        assert!(!set.insert(&"c"));
+    }
+
+    #[test]
+    fn test_lru_set_compacts_queue() {
+        let mut set = LruSet::new(3);
+        for _ in 0..100 {
+            set.insert(&1);
+        }
+        assert_eq!(1, set.len());
+        assert!(set.queue.len() <= 6);
+    }
+}
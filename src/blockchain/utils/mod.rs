@@ -9,8 +9,11 @@ use rustc_serialize::hex::{FromHex, ToHex};
 use blockchain::parser::types::CoinType;
 
 pub mod blkfile;
+pub mod bloom;
+pub mod lru;
 pub mod csv;
 pub mod reader;
+pub mod throttle;
 
 #[inline]
 pub fn ridemp160(data: &[u8]) -> [u8; 20] {
@@ -106,6 +109,20 @@ pub fn arr_to_hex_swapped(data: &[u8]) -> String {
     return hex;
 }
 
+/// Checks if the reversed hex representation of `data` starts with `prefix`.
+/// This compares nibble by nibble, so no string is allocated for each hash.
+#[inline]
+pub fn starts_with_hex_swapped(data: &[u8], prefix: &str) -> bool {
+    if prefix.len() > data.len() * 2 {
+        return false;
+    }
+    prefix.chars().enumerate().all(|(i, c)| {
+        let byte = data[data.len() - 1 - i / 2];
+        let nibble = if i % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+        c.to_digit(16) == Some(nibble as u32)
+    })
+}
+
 #[inline]
 pub fn hex_to_vec(hex_str: &str) -> Vec<u8> {
     hex_str.from_hex().unwrap()
@@ -171,6 +188,21 @@ mod tests {
         assert_eq!(arr_to_hex_swapped(&test), expected);
     }
 
+    #[test]
+    fn test_starts_with_hex_swapped() {
+        let test = hex_to_arr32_swapped(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        );
+        assert!(starts_with_hex_swapped(&test, ""));
+        assert!(starts_with_hex_swapped(&test, "0"));
+        assert!(starts_with_hex_swapped(&test, "000000000019d"));
+        assert!(starts_with_hex_swapped(&test, "000000000019D6"));
+        assert!(!starts_with_hex_swapped(&test, "000000000019e"));
+        assert!(!starts_with_hex_swapped(&test, "1"));
+        assert!(!starts_with_hex_swapped(&test, "x"));
+        assert!(!starts_with_hex_swapped(&test[..1], "6f0"));
+    }
+
     #[test]
     fn test_hex_to_arr32_swapped() {
         let test = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
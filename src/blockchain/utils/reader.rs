@@ -3,6 +3,7 @@ use std::io::{self};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
 use blockchain::proto::header::BlockHeader;
 use blockchain::proto::tx::{Tx, TxInput, TxOutpoint, TxOutput};
@@ -29,11 +30,11 @@ pub trait BlockchainRead: io::Read {
         blk_index: u32,
         blk_offset: usize,
         blocksize: u32,
-        version_id: u8,
+        coin: &CoinType,
     ) -> OpResult<Block> {
         let header = self.read_block_header()?;
         let tx_count = VarUint::read_from(self)?;
-        let txs = self.read_txs(tx_count.value, version_id)?;
+        let txs = self.read_txs(tx_count.value, coin)?;
         Ok(Block::new(
             blk_index, blk_offset, blocksize, header, tx_count, txs,
         ))
@@ -50,7 +51,7 @@ pub trait BlockchainRead: io::Read {
         ))
     }
 
-    fn read_txs(&mut self, tx_count: u64, version_id: u8) -> OpResult<Vec<Tx>> {
+    fn read_txs(&mut self, tx_count: u64, coin: &CoinType) -> OpResult<Vec<Tx>> {
         let mut txs: Vec<Tx> = Vec::with_capacity(tx_count as usize);
         for _ in 0..tx_count {
             let tx_version = self.read_u32::<LittleEndian>()?;
@@ -58,8 +59,11 @@ pub trait BlockchainRead: io::Read {
             let in_count: VarUint;
             if marker == 0x00 {
                 // SegWit hack
-                /*let flag = */
-                self.read_u8()?;
+                let flag = self.read_u8()?;
+                if flag != 0x01 {
+                    return Err(OpError::new(OpErrorKind::RuntimeError)
+                        .join_msg(&format!("Unknown transaction flag: 0x{:02x}", flag)));
+                }
                 in_count = VarUint::read_from(self)?;
             } else {
                 in_count = match marker {
@@ -73,16 +77,19 @@ pub trait BlockchainRead: io::Read {
                     }
                 };
             }
-            let inputs = self.read_tx_inputs(in_count.value)?;
+            let mut inputs = self.read_tx_inputs(in_count.value)?;
             let out_count = VarUint::read_from(self)?;
             let outputs = self.read_tx_outputs(out_count.value)?;
             if marker == 0x00 {
-                // SegWit hack
-                for _ in 0..in_count.value {
+                // Each input is followed by its witness stack, which may be empty for all of them.
+                // Such transactions are kept like legacy ones, the flag isn't part of any hash then.
+                for input in inputs.iter_mut() {
                     let item_count = VarUint::read_from(self)?;
                     for _ in 0..item_count.value {
                         let witness_len = VarUint::read_from(self)?;
-                        let _ = self.read_u8_vec(witness_len.value as u32)?;
+                        input
+                            .witness
+                            .push(self.read_u8_vec(witness_len.value as u32)?);
                     }
                 }
             }
@@ -94,7 +101,7 @@ pub trait BlockchainRead: io::Read {
                 out_count,
                 &outputs,
                 tx_locktime,
-                version_id,
+                coin,
             );
             txs.push(tx);
         }
@@ -122,6 +129,7 @@ pub trait BlockchainRead: io::Read {
                 script_len: script_len,
                 script_sig: script_sig,
                 seq_no: seq_no,
+                witness: Vec::new(),
             };
             inputs.push(input);
         }
@@ -153,13 +161,75 @@ impl<R: io::Read + ?Sized> BlockchainRead for R {}
 #[cfg(test)]
 mod tests {
     use super::*;
-    use blockchain::parser::types::{Bitcoin, Coin};
+    use blockchain::parser::types::Bitcoin;
     use blockchain::proto::script;
-    use blockchain::utils::{arr_to_hex, arr_to_hex_swapped};
+    use blockchain::proto::Hashed;
+    use blockchain::proto::ToRaw;
+    use blockchain::utils::{arr_to_hex, arr_to_hex_swapped, hex_to_vec};
     use byteorder::{LittleEndian, ReadBytesExt};
     use seek_bufread::BufReader;
     use std::io::Cursor;
 
+    #[test]
+    fn test_segwit_tx_witness() {
+        // Signed native P2WPKH example from BIP143, only the second input has a witness
+        let raw_tx = hex_to_vec("01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000");
+        let mut reader = Cursor::new(raw_tx);
+        let tx = reader
+            .read_txs(1, &CoinType::from(Bitcoin))
+            .unwrap()
+            .remove(0);
+
+        assert!(tx.inputs[0].witness.is_empty());
+        assert_eq!(tx.inputs[1].witness.len(), 2);
+        assert_eq!(
+            "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357",
+            arr_to_hex(&tx.inputs[1].witness[1])
+        );
+        assert_eq!(
+            "c36c38370907df2324d9ce9d149d191192f338b37665a82e78e76a12c909b762",
+            arr_to_hex_swapped(&tx.wtxid())
+        );
+        // 233 bytes without and 343 bytes with witness data
+        assert_eq!(261, tx.vsize());
+        let tx = Hashed::double_sha256(tx);
+        assert_eq!(
+            "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609",
+            arr_to_hex_swapped(&tx.hash)
+        );
+    }
+
+    #[test]
+    fn test_segwit_flag_without_witness() {
+        // BIP143 transaction from above with marker and flag but two empty witness stacks
+        let body = "02fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac";
+        let raw_tx = hex_to_vec(&format!("010000000001{}000011000000", body));
+        let raw_len = raw_tx.len() as u64;
+        let mut reader = Cursor::new(raw_tx);
+        let tx = reader
+            .read_txs(1, &CoinType::from(Bitcoin))
+            .unwrap()
+            .remove(0);
+        assert_eq!(raw_len, reader.position());
+        assert!(!tx.has_witness());
+
+        // Canonical serialization drops marker and flag
+        let legacy_tx = hex_to_vec(&format!("01000000{}11000000", body));
+        assert_eq!(legacy_tx, tx.to_bytes());
+        assert_eq!(legacy_tx, tx.witness_bytes());
+        let wtxid = tx.wtxid();
+        let tx = Hashed::double_sha256(tx);
+        assert_eq!(wtxid, tx.hash);
+        assert_eq!(
+            "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609",
+            arr_to_hex_swapped(&tx.hash)
+        );
+
+        // Flags other than 0x01 are reserved
+        let mut reader = Cursor::new(hex_to_vec(&format!("010000000002{}000011000000", body)));
+        assert!(reader.read_txs(1, &CoinType::from(Bitcoin)).is_err());
+    }
+
     #[test]
     fn test_bitcoin_parse_genesis_block() {
         // bitcoin genesis block as raw bytes
@@ -202,7 +272,7 @@ mod tests {
             0x14, 0x17, 0x81, 0xe6, 0x22, 0x94, 0x72, 0x11, 0x66, 0xbf, 0x62, 0x1e, 0x73, 0xa8,
             0x2c, 0xbf, 0x23, 0x42, 0xc8, 0x58, 0xee, 0xac, 0x00, 0x00, 0x00, 0x0,
         ];
-        let inner = Cursor::new(raw_data);
+        let inner = Cursor::new(raw_data.clone());
         let mut reader = BufReader::with_capacity(200, inner);
 
         let blk_id = 0;
@@ -212,7 +282,7 @@ mod tests {
 
         // Parse block
         let block = reader
-            .read_block(blk_id, blk_offset, blocksize, Bitcoin.version_id())
+            .read_block(blk_id, blk_offset, blocksize, &CoinType::from(Bitcoin))
             .unwrap();
 
         // Block Metadata
@@ -268,10 +338,22 @@ mod tests {
         assert_eq!("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
                                 arr_to_hex(&script_pubkey));
         assert_eq!(0x00000000, block.txs[0].value.tx_locktime);
+        // Transactions without witness have the same wtxid and txid
+        assert_eq!(block.txs[0].value.wtxid(), block.txs[0].hash);
+        // Serializing the block gives back the bytes after magic and size
+        assert_eq!(&raw_data[8..8 + 285], &block.to_bytes()[..]);
+        assert!(block.verify_size());
+        // A field parsed with the wrong width changes the reserialized size
+        let mut misparsed = block.clone();
+        misparsed.txs[0].value.inputs[0].script_sig.pop();
+        assert!(!misparsed.verify_size());
 
         assert_eq!(
             "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
-            script::eval_from_bytes(script_pubkey, Bitcoin.version_id()).address
+            script::eval_from_bytes(script_pubkey, &CoinType::from(Bitcoin))
+                .address
+                .unwrap()
+                .to_string()
         );
 
         /******* Genesis block raw data for reference (Most fields are little endian) *******
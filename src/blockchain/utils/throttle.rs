@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::profile::{self, Phase};
+
+/// Largest chunk read at once while throttled, keeps the pacing smooth
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+/// Token bucket shared by all workers to cap the total read bandwidth.
+/// The bucket holds at most one second worth of bytes.
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<(f64, Instant)>, // Available tokens (may be negative), last refill
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec as f64,
+            bucket: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// Blocks until `n_bytes` may be consumed
+    pub fn acquire(&self, n_bytes: usize) {
+        let wait = self.reserve(n_bytes, Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Takes `n_bytes` tokens from the bucket and returns how long the caller has to wait for them.
+    /// Missing tokens are borrowed from the future, so concurrent readers queue up behind each other.
+    fn reserve(&self, n_bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (ref mut tokens, ref mut last_refill) = *bucket;
+        if now > *last_refill {
+            let elapsed = now.duration_since(*last_refill);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            *tokens = (*tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+            *last_refill = now;
+        }
+        *tokens -= n_bytes as f64;
+        if *tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let secs = -*tokens / self.bytes_per_sec;
+        Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+    }
+}
+
+/// File which paces its reads with an optional `RateLimiter`
+pub struct ThrottledFile {
+    inner: File,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl ThrottledFile {
+    pub fn new(inner: File, limiter: Option<Arc<RateLimiter>>) -> ThrottledFile {
+        ThrottledFile {
+            inner: inner,
+            limiter: limiter,
+        }
+    }
+}
+
+impl Read for ThrottledFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let t_start = profile::start();
+        let result = match self.limiter {
+            Some(ref limiter) => {
+                let len = buf.len().min(MAX_CHUNK_SIZE);
+                let n = self.inner.read(&mut buf[..len])?;
+                limiter.acquire(n);
+                Ok(n)
+            }
+            None => self.inner.read(buf),
+        };
+        profile::record(Phase::Read, t_start);
+        result
+    }
+}
+
+impl Seek for ThrottledFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_rate_limiter_reserve() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        // A full bucket allows a burst of one second
+        assert_eq!(limiter.reserve(1000, start), Duration::from_secs(0));
+        assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+        // One second later the debt is paid and 500 tokens are available again
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.reserve(500, later), Duration::from_secs(0));
+        assert_eq!(limiter.reserve(250, later), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::address::Address;
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::bloom::BloomFilter;
+use blockchain::utils::lru::LruSet;
+
+/// Addresses which already received funds
+pub enum SeenAddresses {
+    Exact(HashSet<Address, BuildHasherDefault<XxHash>>),
+    Approximate(BloomFilter), // Overcounts reuse by the false positive rate
+    Bounded(LruSet<Address>), // Forgets the least recently used addresses, undercounts reuse
+}
+
+impl SeenAddresses {
+    /// Creates the set selected by `--approximate <MB>` or `--address-cache-size <N>`, an exact set by default
+    pub fn from_args(matches: &ArgMatches) -> OpResult<SeenAddresses> {
+        if matches.value_of("approximate").is_some() {
+            return match value_t!(matches, "approximate", usize) {
+                Ok(mb) if mb > 0 => Ok(SeenAddresses::Approximate(BloomFilter::new(
+                    mb * 1000000,
+                    4,
+                ))),
+                _ => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--approximate must be a positive number of megabytes")),
+            };
+        }
+        if matches.value_of("address-cache-size").is_some() {
+            return match value_t!(matches, "address-cache-size", usize) {
+                Ok(n) if n > 0 => Ok(SeenAddresses::Bounded(LruSet::new(n))),
+                _ => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--address-cache-size must be a positive number of addresses")),
+            };
+        }
+        Ok(SeenAddresses::Exact(Default::default()))
+    }
+
+    /// Returns true if `address` received funds before
+    #[inline]
+    pub fn insert(&mut self, address: &Address) -> bool {
+        match *self {
+            SeenAddresses::Exact(ref mut set) => !set.insert(address.clone()),
+            SeenAddresses::Approximate(ref mut filter) => filter.insert(address),
+            SeenAddresses::Bounded(ref mut set) => set.insert(address),
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        match *self {
+            SeenAddresses::Exact(ref set) => memory::hash_table_bytes::<Address>(set.len()),
+            SeenAddresses::Approximate(ref filter) => filter.size(),
+            SeenAddresses::Bounded(ref set) => set.memory_usage(),
+        }
+    }
+
+    /// Returns a bloom filter of `n_bytes` holding all tracked addresses, None if already approximate
+    pub fn to_filter(&self, n_bytes: usize) -> Option<BloomFilter> {
+        match *self {
+            SeenAddresses::Exact(ref set) => {
+                let mut filter = BloomFilter::new(n_bytes, 4);
+                for address in set {
+                    filter.insert(address);
+                }
+                Some(filter)
+            }
+            SeenAddresses::Bounded(ref set) => {
+                let mut filter = BloomFilter::new(n_bytes, 4);
+                for address in set.iter() {
+                    filter.insert(address);
+                }
+                Some(filter)
+            }
+            SeenAddresses::Approximate(_) => None,
+        }
+    }
+}
+
+/// Measures address reuse: the share of outputs paying to an address which already received funds.
+/// The reuse rate is written for each interval of blocks, outputs without an address are not counted.
+pub struct AddressReuse {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    seen: SeenAddresses,
+    interval: usize,
+
+    start_height: usize,
+    interval_start: usize,
+    last_height: usize,     // Height of the latest processed block
+    interval_blocks: usize, // Blocks processed in the current interval
+    interval_outputs: u64,
+    interval_reused: u64,
+    n_addresses: u64,
+    out_count: u64,
+    reused_count: u64,
+}
+
+impl AddressReuse {
+    /// Writes the row for the current interval and starts the next one
+    fn write_interval(&mut self, end_height: usize) {
+        let rate = if self.interval_outputs > 0 {
+            self.interval_reused as f64 / self.interval_outputs as f64
+        } else {
+            0.0
+        };
+        let row = format!(
+            "{};{};{};{};{:.6}\n",
+            writer::row_height(self.interval_start),
+            writer::row_height(end_height),
+            self.interval_outputs,
+            self.interval_reused,
+            rate
+        );
+        self.writer.write_all(row.as_bytes()).unwrap();
+        self.interval_start = end_height + 1;
+        self.interval_blocks = 0;
+        self.interval_outputs = 0;
+        self.interval_reused = 0;
+    }
+}
+
+impl Callback for AddressReuse {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("addressreuse")
+            .about("Dumps the share of outputs paying to already used addresses into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("BLOCKS")
+                    .help("Number of blocks per row (default: 1000)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("approximate")
+                    .long("approximate")
+                    .value_name("MB")
+                    .help("Tracks seen addresses in a bloom filter of MB megabytes instead of an exact set. \
+                           Saves memory but slightly overcounts reuse")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("address-cache-size")
+                    .long("address-cache-size")
+                    .value_name("N")
+                    .help("Only remembers the N most recently used addresses. Bounds memory but undercounts reuse of cold addresses")
+                    .conflicts_with("approximate")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let interval = match matches.value_of("interval") {
+            Some(_) => match value_t!(matches, "interval", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--interval must be a positive number of blocks"))
+                }
+            },
+            None => 1000,
+        };
+        let seen = SeenAddresses::from_args(matches)?;
+        match (|| -> OpResult<Self> {
+            let mut cb = AddressReuse {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("addressreuse.csv.tmp"))?,
+                seen: seen,
+                interval: interval,
+                start_height: 0,
+                interval_start: 0,
+                last_height: 0,
+                interval_blocks: 0,
+                interval_outputs: 0,
+                interval_reused: 0,
+                n_addresses: 0,
+                out_count: 0,
+                reused_count: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize AddressReuse with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        self.interval_start = block_height;
+        info!(target: "AddressReuse [on_start]", "Using `AddressReuse` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if self.start_height > 0 {
+            warn!(target: "AddressReuse [on_start]", "Addresses used before block {} are unknown, reuse will be undercounted!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        self.last_height = block_height;
+        self.interval_blocks += 1;
+        for tx in block.txs {
+            for output in &tx.value.outputs {
+                let address = match output.script.address {
+                    Some(ref address) => address,
+                    None => continue,
+                };
+                if self.seen.insert(address) {
+                    self.interval_reused += 1;
+                    self.reused_count += 1;
+                } else {
+                    self.n_addresses += 1;
+                }
+                self.interval_outputs += 1;
+                self.out_count += 1;
+            }
+        }
+        if block_height + 1 - self.interval_start >= self.interval {
+            self.write_interval(block_height);
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        // Last partial interval
+        if self.interval_blocks > 0 {
+            let end_height = self.last_height;
+            self.write_interval(end_height);
+        }
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("addressreuse.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "addressreuse-{}-{}.csv",
+                self.start_height, block_height
+            )),
+        ) {
+            error!(target: "AddressReuse [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        let rate = if self.out_count > 0 {
+            self.reused_count as f64 / self.out_count as f64 * 100.0
+        } else {
+            0.0
+        };
+        info!(target: "AddressReuse [on_complete]", "Done.\nChecked {} outputs with an address:\n\
+                                   \t-> distinct addresses: {:9}\n\
+                                   \t-> reused outputs:     {:9} ({:.2}%)",
+             self.out_count, self.n_addresses, self.reused_count, rate);
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.seen.memory_usage()
+    }
+
+    /// Moves the seen addresses into a bloom filter of `target` bytes, as if `--approximate` was given
+    fn reduce_memory(&mut self, target: usize) -> bool {
+        let filter = match self.seen.to_filter(target) {
+            Some(filter) => filter,
+            None => return false,
+        };
+        warn!(target: "AddressReuse [reduce_memory]", "Switching to a bloom filter of {} to stay within --memory-limit. Reuse will be slightly overcounted from block {} on!",
+              memory::format_mb(target), self.last_height + 1);
+        self.seen = SeenAddresses::Approximate(filter);
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "addressreuse",
+            "addressreuse-<start_height>-<end_height>.csv",
+            &[
+                ("start_height", ColumnType::Integer),
+                ("end_height", ColumnType::Integer),
+                ("outputs", ColumnType::BigInt),
+                ("reused_outputs", ColumnType::BigInt),
+                ("reuse_rate", ColumnType::Real),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::AddressReuse;
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use callbacks::Callback;
+    use common::testdir;
+    use common::writer;
+
+    #[test]
+    fn test_no_blocks() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("addressreuse.test");
+        let matches =
+            AddressReuse::build_subcommand().get_matches_from(vec!["addressreuse", "unused"]);
+        let mut cb = AddressReuse::new(&matches, Some(&dir)).unwrap();
+        cb.on_start(CoinType::from(Bitcoin), 0);
+        cb.on_complete(0);
+
+        let rows = fs::read_to_string(dir.join("addressreuse-0-0.csv")).unwrap();
+        assert_eq!("", rows);
+    }
+}
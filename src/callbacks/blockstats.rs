@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::sigops::{self, SigOps, SpentOutput};
+use blockchain::proto::tx::TxOutpoint;
+
+/// Dumps per block metrics into a CSV file, starting with the signature operations.
+/// Only outputs whose type changes the sigop counting of their spends are kept in memory,
+/// unless `--fees` or `--issuance` need the value of all unspent outputs.
+pub struct BlockStats {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    spent_outputs: HashMap<TxOutpoint, SpentOutput, BuildHasherDefault<XxHash>>, // P2SH and witness v0 outputs
+    utxo_values: Option<HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>>>, // Value and height of all unspent outputs (--fees)
+    fee_columns: bool, // Writes the mean fee and feerate (--fees)
+    issuance: bool,    // Writes the coinbase value against fees and subsidy (--issuance)
+    coin: Option<CoinType>,
+
+    start_height: usize,
+    n_blocks: u64,
+    sigops: SigOps,                   // Sum over all blocks
+    max_cost: Option<(usize, usize)>, // Block height and sigop cost of the block closest to the limit
+    total_fees: u64,                  // Sum over all transactions with known fees
+    total_vsize: u64,
+    n_imbalanced: u64, // Blocks whose coinbase doesn't claim exactly the subsidy and fees
+    unclaimed: i64,    // Sum of the imbalances
+}
+
+/// Fees of the transactions in one block, only transactions whose spent outputs are all known are counted
+#[derive(Default)]
+struct BlockFees {
+    n_txs: u64,
+    n_unknown: u64, // Transactions spending outputs created before the scan
+    fees: u64,
+    vsize: u64,
+}
+
+impl BlockFees {
+    /// Returns the mean fee and the feerate weighted by size as CSV fields, empty if no fee is known
+    fn to_fields(&self) -> String {
+        if self.n_txs == 0 {
+            return String::from(";");
+        }
+        format!(
+            "{:.2};{:.2}",
+            self.fees as f64 / self.n_txs as f64,
+            self.fees as f64 / self.vsize as f64
+        )
+    }
+}
+
+/// Returns the part of `subsidy` and `fees` the coinbase didn't claim, negative if it claimed too much.
+/// Saturates at the limits of i64 for coinbase values no valid block can have.
+fn imbalance(coinbase_value: u64, fees: u64, subsidy: u64) -> i64 {
+    let imbalance = subsidy as i128 + fees as i128 - coinbase_value as i128;
+    imbalance
+        .max(i64::min_value() as i128)
+        .min(i64::max_value() as i128) as i64
+}
+
+impl Callback for BlockStats {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("blockstats")
+            .about("Dumps per block metrics like the sigop count into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("fees")
+                    .long("fees")
+                    .help("Adds the mean fee and the feerate weighted by size, which keeps the value of all unspent outputs in memory"),
+            )
+            .arg(
+                Arg::with_name("issuance")
+                    .long("issuance")
+                    .help("Adds the coinbase value, fees, subsidy and their imbalance, which keeps the value of all unspent outputs in memory"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        match (|| -> OpResult<Self> {
+            let mut cb = BlockStats {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("blockstats.csv.tmp"))?,
+                spent_outputs: Default::default(),
+                utxo_values: if matches.is_present("fees") || matches.is_present("issuance") {
+                    Some(Default::default())
+                } else {
+                    None
+                },
+                fee_columns: matches.is_present("fees"),
+                issuance: matches.is_present("issuance"),
+                coin: None,
+                start_height: 0,
+                n_blocks: 0,
+                sigops: SigOps::default(),
+                max_cost: None,
+                total_fees: 0,
+                total_vsize: 0,
+                n_imbalanced: 0,
+                unclaimed: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize BlockStats with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, coin_type: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        if self.issuance && coin_type.halving_interval.is_none() {
+            warn!(target: "BlockStats [on_start]", "{} doesn't follow a plain halving schedule, the subsidy and imbalance columns are left empty!", coin_type.name);
+        }
+        self.coin = Some(coin_type);
+        info!(target: "BlockStats [on_start]", "Using `BlockStats` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if self.start_height > 0 {
+            warn!(target: "BlockStats [on_start]", "Outputs created before block {} are unknown, P2SH and witness sigops of their spends are not counted!", self.start_height);
+            if self.utxo_values.is_some() {
+                warn!(target: "BlockStats [on_start]", "Transactions spending outputs created before block {} are left out of the fees!", self.start_height);
+            }
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let mut block_sigops = SigOps::default();
+        let mut block_fees = BlockFees::default();
+        for tx in &block.txs {
+            // Outputs are added after counting, but before the next transaction which may spend them
+            let spent_outputs = &mut self.spent_outputs;
+            let tx_sigops = SigOps::count(&tx.value, |outpoint| {
+                spent_outputs.remove(outpoint).unwrap_or(SpentOutput::Other)
+            });
+            block_sigops.add(&tx_sigops);
+
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let spent = SpentOutput::from_pattern(&output.script.pattern);
+                if spent != SpentOutput::Other {
+                    let outpoint = TxOutpoint {
+                        txid: tx.hash,
+                        index: i as u32,
+                    };
+                    self.spent_outputs.insert(outpoint, spent);
+                }
+            }
+
+            if let Some(ref mut utxo_values) = self.utxo_values {
+                if !tx.value.is_coinbase() {
+                    match tx.value.get_fees(utxo_values) {
+                        Some(fees) => {
+                            block_fees.n_txs += 1;
+                            block_fees.fees = block_fees.fees.saturating_add(fees);
+                            block_fees.vsize += tx.value.vsize() as u64;
+                        }
+                        None => block_fees.n_unknown += 1,
+                    }
+                    for input in &tx.value.inputs {
+                        utxo_values.remove(&input.outpoint);
+                    }
+                }
+                for (i, output) in tx.value.outputs.iter().enumerate() {
+                    // OP_RETURN outputs can't be spent
+                    if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                        continue;
+                    }
+                    let outpoint = TxOutpoint {
+                        txid: tx.hash,
+                        index: i as u32,
+                    };
+                    utxo_values.insert(outpoint, (output.out.value, block_height));
+                }
+            }
+        }
+
+        let cost = block_sigops.cost();
+        let mut row = format!(
+            "{};{};{};{};{};{}",
+            writer::row_height(block_height),
+            block.tx_count.value,
+            block_sigops.legacy,
+            block_sigops.p2sh,
+            block_sigops.witness,
+            cost
+        );
+        if self.fee_columns {
+            row.push(';');
+            row.push_str(&block_fees.to_fields());
+        }
+        if self.utxo_values.is_some() {
+            self.total_fees = self.total_fees.saturating_add(block_fees.fees);
+            self.total_vsize += block_fees.vsize;
+        }
+        if self.issuance {
+            let coinbase_value: u64 = block.txs.first().map_or(0, |tx| tx.value.output_value());
+            // Fees are only complete if all spent outputs are known
+            let fees = match block_fees.n_unknown {
+                0 => Some(block_fees.fees),
+                _ => None,
+            };
+            let subsidy = self
+                .coin
+                .as_ref()
+                .and_then(|coin| coin.subsidy(block_height));
+            let fields = match (fees, subsidy) {
+                (Some(fees), Some(subsidy)) => {
+                    let imbalance = imbalance(coinbase_value, fees, subsidy);
+                    if imbalance != 0 {
+                        self.n_imbalanced += 1;
+                        self.unclaimed = self.unclaimed.saturating_add(imbalance);
+                    }
+                    format!("{};{};{}", fees, subsidy, imbalance)
+                }
+                (Some(fees), None) => format!("{};;", fees),
+                (None, Some(subsidy)) => format!(";{};", subsidy),
+                (None, None) => String::from(";;"),
+            };
+            row.push_str(&format!(";{};{}", coinbase_value, fields));
+        }
+        row.push('\n');
+        self.writer.write_all(row.as_bytes()).unwrap();
+
+        self.sigops.add(&block_sigops);
+        self.n_blocks += 1;
+        match self.max_cost {
+            Some((_, max_cost)) if max_cost >= cost => (),
+            _ => self.max_cost = Some((block_height, cost)),
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("blockstats.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "blockstats-{}-{}.csv",
+                self.start_height, block_height
+            )),
+        ) {
+            error!(target: "BlockStats [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "BlockStats [on_complete]", "Done.\nDumped stats of {} blocks:\n\
+                                   \t-> legacy sigops:  {:12}\n\
+                                   \t-> P2SH sigops:    {:12}\n\
+                                   \t-> witness sigops: {:12}",
+             self.n_blocks, self.sigops.legacy, self.sigops.p2sh, self.sigops.witness);
+        if let Some((height, cost)) = self.max_cost {
+            info!(target: "BlockStats [on_complete]", "Highest sigop cost: {} in block {} ({:.2}% of the limit).",
+                  cost, height, cost as f64 / sigops::MAX_BLOCK_SIGOPS_COST as f64 * 100.0);
+        }
+        if self.issuance {
+            info!(target: "BlockStats [on_complete]", "{} blocks don't claim exactly their subsidy and fees, {} in total are unclaimed.",
+                  self.n_imbalanced, self.unclaimed);
+        }
+        if self.utxo_values.is_some() && self.total_vsize > 0 {
+            info!(target: "BlockStats [on_complete]", "Paid {} in fees at a mean feerate of {:.2} per vbyte.",
+                  self.total_fees, self.total_fees as f64 / self.total_vsize as f64);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let n_values = self.utxo_values.as_ref().map_or(0, |values| values.len());
+        memory::hash_table_bytes::<(TxOutpoint, SpentOutput)>(self.spent_outputs.len())
+            + memory::hash_table_bytes::<(TxOutpoint, (u64, usize))>(n_values)
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut columns = vec![
+            ("height", ColumnType::Integer),
+            ("tx_count", ColumnType::BigInt),
+            ("legacy_sigops", ColumnType::Integer),
+            ("p2sh_sigops", ColumnType::Integer),
+            ("witness_sigops", ColumnType::Integer),
+            ("sigop_cost", ColumnType::Integer),
+        ];
+        if self.fee_columns {
+            columns.push(("mean_fee", ColumnType::Real));
+            columns.push(("weighted_feerate", ColumnType::Real));
+        }
+        if self.issuance {
+            columns.push(("coinbase_value", ColumnType::BigInt));
+            columns.push(("total_fees", ColumnType::BigInt));
+            columns.push(("subsidy", ColumnType::BigInt));
+            columns.push(("imbalance", ColumnType::BigInt));
+        }
+        vec![Table::new(
+            "blockstats",
+            "blockstats-<start_height>-<end_height>.csv",
+            &columns,
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::imbalance;
+
+    #[test]
+    fn test_imbalance() {
+        assert_eq!(0, imbalance(5000010000, 10000, 5000000000));
+        // The coinbase may claim less than allowed, like in block 124724
+        assert_eq!(1, imbalance(4999999999, 0, 5000000000));
+        assert_eq!(-1, imbalance(5000000001, 0, 5000000000));
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::address::{self, Address};
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::bloom::BloomFilter;
+use blockchain::utils::{ridemp160, sha256};
+
+/// Lists with at least this many addresses are checked against a bloom filter before the exact lookup
+const PREFILTER_MIN_ADDRESSES: usize = 10000;
+/// Filter size and hash count, which yield a false positive rate of (1 - e^(-11/16))^11
+const BLOOM_BITS_PER_ADDRESS: usize = 16;
+const BLOOM_HASHES: u64 = 11;
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.00046;
+
+/// User supplied list of burn addresses
+struct BurnList {
+    base58: HashMap<(u8, [u8; 20]), String>, // (version id, hash160) of P2PKH and P2SH addresses
+    other: HashSet<String>,                  // Addresses which are compared as strings, e.g. bech32
+    prefilter: Option<BloomFilter>,          // Holds the keys of both maps above
+    approximate: bool, // Only the prefilter is kept and its matches are not confirmed
+    has_other: bool,   // The list contains addresses which are compared as strings
+    n_addresses: usize,
+}
+
+impl BurnList {
+    /// Reads one address per line, empty lines and lines starting with `#` are ignored.
+    /// With `approximate` set, the addresses are only inserted into the prefilter.
+    fn load(path: &Path, approximate: bool) -> OpResult<BurnList> {
+        // The filter must be sized before inserting anything, so the file is read twice
+        let n_addresses = BurnList::read_addresses(path, |_| ())?;
+        let mut prefilter = match approximate || n_addresses >= PREFILTER_MIN_ADDRESSES {
+            true => Some(BloomFilter::new(
+                n_addresses * BLOOM_BITS_PER_ADDRESS / 8,
+                BLOOM_HASHES,
+            )),
+            false => None,
+        };
+        let mut base58 = HashMap::new();
+        let mut other = HashSet::new();
+        let mut has_other = false;
+        BurnList::read_addresses(path, |address| match address::base58check_decode(address) {
+            Some(key) => {
+                if let Some(ref mut filter) = prefilter {
+                    filter.insert(&key);
+                }
+                if !approximate {
+                    base58.insert(key, String::from(address));
+                }
+            }
+            None => {
+                if let Some(ref mut filter) = prefilter {
+                    filter.insert(&address);
+                }
+                if !approximate {
+                    other.insert(String::from(address));
+                }
+                has_other = true;
+            }
+        })?;
+        Ok(BurnList {
+            n_addresses: match approximate {
+                true => n_addresses,
+                false => base58.len() + other.len(),
+            },
+            base58: base58,
+            other: other,
+            prefilter: prefilter,
+            approximate: approximate,
+            has_other: has_other,
+        })
+    }
+
+    /// Calls `f` for every address in the file and returns their number
+    fn read_addresses<F: FnMut(&str)>(path: &Path, mut f: F) -> OpResult<usize> {
+        let mut n_addresses = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let address = line.trim();
+            if address.is_empty() || address.starts_with('#') {
+                continue;
+            }
+            f(address);
+            n_addresses += 1;
+        }
+        Ok(n_addresses)
+    }
+
+    fn len(&self) -> usize {
+        self.n_addresses
+    }
+
+    /// Returns the listed address `address` matches.
+    /// P2PK outputs match the P2PKH address of their key, as they are displayed the same way.
+    #[inline]
+    fn lookup(&self, address: &Address) -> Option<String> {
+        match *address {
+            Address::P2PKH(version, ref h160) | Address::P2SH(version, ref h160) => {
+                self.lookup_base58(&(version, *h160), address)
+            }
+            Address::P2PK(version, ref pub_key) => {
+                self.lookup_base58(&(version, ridemp160(&sha256(pub_key))), address)
+            }
+            _ if self.has_other => {
+                let address = address.to_string();
+                if !self.prefilter_contains(&address) {
+                    return None;
+                }
+                match self.approximate || self.other.contains(&address) {
+                    true => Some(address),
+                    false => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn lookup_base58(&self, key: &(u8, [u8; 20]), address: &Address) -> Option<String> {
+        if !self.prefilter_contains(key) {
+            return None;
+        }
+        match self.approximate {
+            true => Some(address.to_string()),
+            false => self.base58.get(key).cloned(),
+        }
+    }
+
+    /// Returns false if `key` is definitely not listed
+    #[inline]
+    fn prefilter_contains<T: Hash>(&self, key: &T) -> bool {
+        match self.prefilter {
+            Some(ref filter) => filter.contains(key),
+            None => true,
+        }
+    }
+}
+
+/// Dumps all outputs paying to a list of burn addresses.
+/// Coins sent there can never be spent, this quantifies deliberately destroyed supply apart from OP_RETURN outputs.
+pub struct BurnDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    burn_list: Arc<BurnList>, // Shared with the shards of --parallel-write
+    with_timestamp: bool,     // Adds the header time of the block
+
+    start_height: usize,
+    end_height: usize,
+    burned: HashMap<String, (u64, u64)>, // Address (K), (Output count, Value) (V)
+    out_count: u64,
+    burned_value: u64,
+    n_checked: u64, // Outputs with an address, to estimate the false positives of --limit-memory-via-bloom
+}
+
+impl BurnDump {
+    /// Creates the callback writing into `dump_folder`, which differs from the argument for shards.
+    /// Shards leave out the header row, see `Callback::shard`.
+    fn create(
+        dump_folder: &Path,
+        burn_list: Arc<BurnList>,
+        with_timestamp: bool,
+        header_row: bool,
+    ) -> OpResult<Self> {
+        match (|| -> OpResult<Self> {
+            let mut cb = BurnDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("burns.csv.tmp"))?,
+                burn_list: burn_list,
+                with_timestamp: with_timestamp,
+                start_height: 0,
+                end_height: 0,
+                burned: HashMap::new(),
+                out_count: 0,
+                burned_value: 0,
+                n_checked: 0,
+            };
+            if header_row {
+                let schema = cb.schema();
+                cb.writer.write_header(&schema[0])?;
+            }
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize BurnDump with folder: `{:?}`",
+                    dump_folder
+                ))
+            }
+        }
+    }
+}
+
+impl Callback for BurnDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("burndump")
+            .about("Dumps all outputs paying to known burn addresses into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("burn-addresses")
+                    .long("burn-addresses")
+                    .value_name("FILE")
+                    .help("File with one burn address per line, lines starting with # are ignored")
+                    .required(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("limit-memory-via-bloom")
+                    .long("limit-memory-via-bloom")
+                    .help(
+                        "Only keeps a bloom filter of the burn addresses in memory. \
+                           About 0.05% of all outputs are falsely reported as burns",
+                    ),
+            )
+            .arg(
+                Arg::with_name("with-timestamp")
+                    .long("with-timestamp")
+                    .help("Adds the timestamp of the block as last column"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let dump_folder = dump_folder.unwrap();
+        let list_path = Path::new(matches.value_of("burn-addresses").unwrap());
+        let approximate = matches.is_present("limit-memory-via-bloom");
+        let burn_list = match BurnList::load(list_path, approximate) {
+            Ok(list) if list.len() > 0 => list,
+            Ok(_) => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg(&format!("No addresses found in {:?}", list_path)))
+            }
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Unable to load burn addresses from {:?}",
+                    list_path
+                ))
+            }
+        };
+        BurnDump::create(
+            dump_folder,
+            Arc::new(burn_list),
+            matches.is_present("with-timestamp"),
+            true,
+        )
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "BurnDump [on_start]", "Using `BurnDump` with dump folder: {:?}, {} burn addresses and start block {}...",
+              &self.dump_folder, self.burn_list.len(), self.start_height);
+        if self.burn_list.approximate {
+            warn!(target: "BurnDump [on_start]", "Matching against a bloom filter only, about {:.3}% of all outputs will falsely be dumped as burns!",
+                  BLOOM_FALSE_POSITIVE_RATE * 100.0);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let timestamp = block.header.value.timestamp;
+        for tx in block.txs {
+            for output in &tx.value.outputs {
+                let address = match output.script.address {
+                    Some(ref address) => {
+                        self.n_checked += 1;
+                        match self.burn_list.lookup(address) {
+                            Some(address) => address,
+                            None => continue,
+                        }
+                    }
+                    None => continue,
+                };
+                let row = match self.with_timestamp {
+                    true => format!(
+                        "{};{};{};{}\n",
+                        writer::row_height(block_height),
+                        address,
+                        output.out.value,
+                        timestamp
+                    ),
+                    false => format!(
+                        "{};{};{}\n",
+                        writer::row_height(block_height),
+                        address,
+                        output.out.value
+                    ),
+                };
+                self.writer.write_all(row.as_bytes()).unwrap();
+
+                let entry = self.burned.entry(address).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 = entry.1.saturating_add(output.out.value);
+                self.out_count += 1;
+                self.burned_value = self.burned_value.saturating_add(output.out.value);
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("burns.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "burns-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "BurnDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        let mut burned: Vec<(&String, &(u64, u64))> = self.burned.iter().collect();
+        burned.sort_by(|a, b| (b.1).1.cmp(&(a.1).1));
+        let mut details = String::new();
+        for (address, &(count, value)) in burned {
+            details.push_str(&format!(
+                "\n\t-> {}: {:.8} in {} outputs",
+                address,
+                value as f64 * 1E-8,
+                count
+            ));
+        }
+        info!(target: "BurnDump [on_complete]", "Done.\nFound {} outputs burning {:.8} ({} units) at {} of {} addresses:{}",
+             self.out_count, self.burned_value as f64 * 1E-8, self.burned_value,
+             self.burned.len(), self.burn_list.len(), details);
+        if self.burn_list.approximate {
+            info!(target: "BurnDump [on_complete]", "Matches were not confirmed, about {:.0} of the {} found outputs are false positives.",
+                  self.n_checked as f64 * BLOOM_FALSE_POSITIVE_RATE, self.out_count);
+        }
+    }
+
+    fn order_insensitive(&self) -> bool {
+        true
+    }
+
+    fn shard(&self, dump_folder: &Path) -> OpResult<Box<Callback + Send>> {
+        Ok(Box::new(BurnDump::create(
+            dump_folder,
+            Arc::clone(&self.burn_list),
+            self.with_timestamp,
+            false,
+        )?))
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut columns = vec![
+            ("height", ColumnType::Integer),
+            ("address", ColumnType::Text),
+            ("value", ColumnType::BigInt),
+        ];
+        if self.with_timestamp {
+            columns.push(("timestamp", ColumnType::BigInt));
+        }
+        vec![Table::new(
+            "burns",
+            "burns-<start_height>-<end_height>.csv",
+            &columns,
+        )]
+    }
+}
@@ -1,18 +1,22 @@
 extern crate csv;
 
 use std::collections::{HashMap, HashSet};
-use std::fs::{self, File, OpenOptions};
+use std::fs::File;
 use std::hash::{BuildHasherDefault, Hash};
-use std::io::{LineWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use rustc_serialize::json::{self, Decoder, Json};
 use rustc_serialize::Decodable;
 use twox_hash::XxHash;
 
+use callbacks::schema::{ColumnType, Table};
 use callbacks::Callback;
-use errors::{OpError, OpResult};
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
@@ -140,8 +144,8 @@ where
 /// Groups addresses into ownership clusters.
 pub struct Clusterizer {
     dump_folder: PathBuf,
-    utxo_writer: LineWriter<File>,
-    clusterizer_writer: LineWriter<File>,
+    utxo_writer: FileWriter,
+    clusterizer_writer: FileWriter,
     utxo_set: HashMap<TxOutpoint, String, BuildHasherDefault<XxHash>>,
     clusters: DisjointSet<String>,
 
@@ -154,19 +158,6 @@ pub struct Clusterizer {
 }
 
 impl Clusterizer {
-    fn create_writer(path: PathBuf) -> OpResult<LineWriter<File>> {
-        let file = match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-        {
-            Ok(f) => f,
-            Err(err) => return Err(OpError::from(err)),
-        };
-        Ok(LineWriter::new(file))
-    }
-
     /// Serializes clusters to a file.
     fn serialize_clusters(&mut self) -> OpResult<usize> {
         self.clusters.finalize();
@@ -228,21 +219,18 @@ impl Clusterizer {
 
     /// Renames temporary files.
     fn rename_tmp_files(&mut self) -> OpResult<usize> {
-        fs::rename(
+        writer::rename(
             self.dump_folder.as_path().join("clusters.dat.tmp"),
             self.dump_folder.as_path().join("clusters.dat"),
-        )
-        .expect("Unable to rename clusters.dat.tmp file!");
-        fs::rename(
+        )?;
+        self.clusterizer_writer.finalize(
             self.dump_folder.as_path().join("clusters.csv.tmp"),
             self.dump_folder.as_path().join("clusters.csv"),
-        )
-        .expect("Unable to rename clusters.csv.tmp file!");
-        fs::rename(
+        )?;
+        self.utxo_writer.finalize(
             self.dump_folder.as_path().join("utxo.csv.tmp"),
             self.dump_folder.as_path().join("utxo.csv"),
-        )
-        .expect("Unable to rename utxo.csv.tmp file!");
+        )?;
         Ok(3)
     }
 
@@ -307,19 +295,18 @@ impl Callback for Clusterizer {
             )
     }
 
-    fn new(matches: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
     where
         Self: Sized,
     {
-        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
         let max_height = value_t!(matches, "max-height", usize).unwrap_or(0);
         match (|| -> OpResult<Self> {
-            let cb = Clusterizer {
+            let mut cb = Clusterizer {
                 dump_folder: PathBuf::from(dump_folder),
-                clusterizer_writer: Clusterizer::create_writer(
-                    dump_folder.join("clusters.csv.tmp"),
-                )?,
-                utxo_writer: Clusterizer::create_writer(dump_folder.join("utxo.csv.tmp"))?,
+                clusterizer_writer: writer::create(&dump_folder.join("clusters.csv.tmp"))?,
+                // The UTXO set is needed to resume, so it is written even with --summary-only
+                utxo_writer: writer::create_state(&dump_folder.join("utxo.csv.tmp"))?,
                 utxo_set: Default::default(),
                 clusters: {
                     let mut new_clusters: DisjointSet<String> = DisjointSet::new();
@@ -342,6 +329,8 @@ impl Callback for Clusterizer {
                 in_count: 0,
                 out_count: 0,
             };
+            let schema = cb.schema();
+            cb.clusterizer_writer.write_header(&schema[0])?;
             Ok(cb)
         })() {
             Ok(s) => return Ok(s),
@@ -385,11 +374,11 @@ impl Callback for Clusterizer {
                     txid: tx.hash,
                     index: i as u32,
                 };
-                let address = output.script.address.to_owned();
-                if address.is_empty() {
+                let address = match output.script.address {
+                    Some(ref address) => address.to_string(),
                     // Skip non-standard outputs
-                    continue;
-                }
+                    None => continue,
+                };
 
                 trace!(target: "Clusterizer [on_block] [TX outputs]", "Adding UTXO {:#?} to the UTXO set.", tx_outpoint);
                 self.utxo_set.insert(tx_outpoint, address);
@@ -453,7 +442,10 @@ impl Callback for Clusterizer {
         // Write UTXO set to CSV.
         let _ = self.export_utxo_set_to_csv();
         // Rename temporary files.
-        let _ = self.rename_tmp_files();
+        if let Err(e) = self.rename_tmp_files() {
+            error!(target: "Clusterizer [on_complete]", "Unable to rename tmp files: {}", e);
+            return;
+        }
 
         info!(target: "Clusterizer [on_complete]", "Done.\nProcessed all {} blocks:\n\
                                    \t-> clusters:     {:9}\n\
@@ -462,4 +454,32 @@ impl Callback for Clusterizer {
                                    \t-> outputs:      {:9}",
              self.end_height + 1, self.clusters.set_size, self.tx_count, self.in_count, self.out_count);
     }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, String)>(self.utxo_set.len())
+            + memory::hash_table_bytes::<(String, usize)>(self.clusters.map.len())
+            + (self.clusters.parent.len() + self.clusters.rank.len()) * mem::size_of::<usize>()
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![
+            Table::new(
+                "clusters",
+                "clusters.csv",
+                &[
+                    ("address", ColumnType::Text),
+                    ("cluster", ColumnType::BigInt),
+                ],
+            ),
+            Table::new(
+                "utxo",
+                "utxo.csv",
+                &[
+                    ("txid", ColumnType::Hash),
+                    ("indexOut", ColumnType::BigInt),
+                    ("address", ColumnType::Text),
+                ],
+            ),
+        ]
+    }
 }
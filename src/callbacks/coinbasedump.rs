@@ -0,0 +1,190 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+
+/// Dumps the payout addresses of each coinbase transaction.
+/// This reveals where block rewards go and can be used to attribute blocks to mining pools.
+pub struct CoinbaseDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    with_timestamp: bool, // Adds the header time of the block
+
+    start_height: usize,
+    end_height: usize,
+    out_count: u64,
+    unknown_count: u64, // Payouts to scripts without an address
+}
+
+impl CoinbaseDump {
+    /// Creates the callback writing into `dump_folder`, which differs from the argument for shards.
+    /// Shards leave out the header row, see `Callback::shard`.
+    fn create(dump_folder: &Path, with_timestamp: bool, header_row: bool) -> OpResult<Self> {
+        match (|| -> OpResult<Self> {
+            let mut cb = CoinbaseDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("coinbase.csv.tmp"))?,
+                with_timestamp: with_timestamp,
+                start_height: 0,
+                end_height: 0,
+                out_count: 0,
+                unknown_count: 0,
+            };
+            if header_row {
+                let schema = cb.schema();
+                cb.writer.write_header(&schema[0])?;
+            }
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize CoinbaseDump with folder: `{:?}`",
+                    dump_folder
+                ))
+            }
+        }
+    }
+}
+
+impl Callback for CoinbaseDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("coinbasedump")
+            .about("Dumps the payout address and value of all coinbase outputs into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("with-timestamp")
+                    .long("with-timestamp")
+                    .help("Adds the timestamp of the block as last column"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        CoinbaseDump::create(
+            dump_folder.unwrap(),
+            matches.is_present("with-timestamp"),
+            true,
+        )
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "CoinbaseDump [on_start]", "Using `CoinbaseDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let coinbase = match block.txs.first() {
+            Some(tx) if tx.value.is_coinbase() => tx,
+            _ => {
+                warn!(target: "CoinbaseDump [on_block]", "Block {} has no coinbase transaction.", block_height);
+                return;
+            }
+        };
+
+        // The reward may be split over several outputs, each of them gets its own row.
+        for output in &coinbase.value.outputs {
+            // Skip OP_RETURN outputs like the witness commitment, they don't pay anyone
+            if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                continue;
+            }
+            let address = match output.script.address {
+                Some(ref address) => address.to_string(),
+                None => {
+                    self.unknown_count += 1;
+                    String::new()
+                }
+            };
+            let row = match self.with_timestamp {
+                true => format!(
+                    "{};{};{};{}\n",
+                    writer::row_height(block_height),
+                    address,
+                    output.out.value,
+                    block.header.value.timestamp
+                ),
+                false => format!(
+                    "{};{};{}\n",
+                    writer::row_height(block_height),
+                    address,
+                    output.out.value
+                ),
+            };
+            self.writer.write_all(row.as_bytes()).unwrap();
+            self.out_count += 1;
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("coinbase.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "coinbase-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "CoinbaseDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "CoinbaseDump [on_complete]", "Done.\nDumped {} coinbase outputs of {} blocks ({} without address).",
+             self.out_count, self.end_height + 1 - self.start_height, self.unknown_count);
+    }
+
+    fn order_insensitive(&self) -> bool {
+        true
+    }
+
+    fn shard(&self, dump_folder: &Path) -> OpResult<Box<Callback + Send>> {
+        Ok(Box::new(CoinbaseDump::create(
+            dump_folder,
+            self.with_timestamp,
+            false,
+        )?))
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut columns = vec![
+            ("height", ColumnType::Integer),
+            ("address", ColumnType::Text),
+            ("value", ColumnType::BigInt),
+        ];
+        if self.with_timestamp {
+            columns.push(("timestamp", ColumnType::BigInt));
+        }
+        vec![Table::new(
+            "coinbase",
+            "coinbase-<start_height>-<end_height>.csv",
+            &columns,
+        )]
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::Tx;
+use blockchain::utils;
+
+/// Dumps transactions which look like CoinJoins: many inputs and a group of equal valued outputs.
+/// Each participant usually gets one of the equal outputs, plus change of an arbitrary value.
+pub struct CoinJoinDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    min_equal_outputs: usize,
+    min_inputs: u64,
+    with_timestamp: bool, // Adds the header time of the block
+
+    start_height: usize,
+    end_height: usize,
+    tx_count: u64,
+    coinjoin_count: u64,
+    coinjoin_volume: u64, // Value of the equal outputs
+}
+
+impl CoinJoinDump {
+    /// Returns the value and count of the largest group of equal valued outputs.
+    /// Ties go to the higher value, OP_RETURN outputs are ignored.
+    fn equal_outputs(tx: &Tx) -> Option<(u64, usize)> {
+        let mut groups: HashMap<u64, usize> = HashMap::with_capacity(tx.outputs.len());
+        for output in &tx.outputs {
+            if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                continue;
+            }
+            *groups.entry(output.out.value).or_insert(0) += 1;
+        }
+        groups
+            .into_iter()
+            .max_by_key(|&(value, count)| (count, value))
+    }
+}
+
+impl Callback for CoinJoinDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("coinjoindump")
+            .about("Dumps transactions with many inputs and equal valued outputs, which are likely CoinJoins")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("min-equal-outputs")
+                    .long("min-equal-outputs")
+                    .value_name("N")
+                    .help("Minimum number of outputs with the same value (default: 5)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("min-inputs")
+                    .long("min-inputs")
+                    .value_name("M")
+                    .help("Minimum number of inputs (default: 5)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("with-timestamp")
+                    .long("with-timestamp")
+                    .help("Adds the timestamp of the block as last column"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let min_equal_outputs = match matches.value_of("min-equal-outputs") {
+            Some(_) => match value_t!(matches, "min-equal-outputs", usize) {
+                Ok(n) if n >= 2 => n,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--min-equal-outputs must be a number of at least 2"))
+                }
+            },
+            None => 5,
+        };
+        let min_inputs = match matches.value_of("min-inputs") {
+            Some(_) => match value_t!(matches, "min-inputs", u64) {
+                Ok(m) if m > 0 => m,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--min-inputs must be a positive number"))
+                }
+            },
+            None => 5,
+        };
+        match (|| -> OpResult<Self> {
+            let mut cb = CoinJoinDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("coinjoins.csv.tmp"))?,
+                min_equal_outputs: min_equal_outputs,
+                min_inputs: min_inputs,
+                with_timestamp: matches.is_present("with-timestamp"),
+                start_height: 0,
+                end_height: 0,
+                tx_count: 0,
+                coinjoin_count: 0,
+                coinjoin_volume: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize CoinJoinDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "CoinJoinDump [on_start]", "Using `CoinJoinDump` with dump folder: {:?} and start block {}, \
+                                                   matching at least {} equal outputs and {} inputs...",
+              &self.dump_folder, self.start_height, self.min_equal_outputs, self.min_inputs);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        self.tx_count += block.tx_count.value;
+        for tx in &block.txs {
+            if tx.value.in_count.value < self.min_inputs || tx.value.is_coinbase() {
+                continue;
+            }
+            let (value, n_equal) = match CoinJoinDump::equal_outputs(&tx.value) {
+                Some((value, n_equal)) if n_equal >= self.min_equal_outputs && value > 0 => {
+                    (value, n_equal)
+                }
+                _ => continue,
+            };
+            // Participants may contribute several inputs, but rarely get more than one equal output
+            let participants = n_equal.min(tx.value.in_count.value as usize);
+            let mut row = format!(
+                "{};{};{};{}",
+                writer::row_height(block_height),
+                utils::arr_to_hex_swapped(&tx.hash),
+                value,
+                participants
+            );
+            if self.with_timestamp {
+                row.push_str(&format!(";{}", block.header.value.timestamp));
+            }
+            row.push('\n');
+            self.writer.write_all(row.as_bytes()).unwrap();
+            self.coinjoin_count += 1;
+            self.coinjoin_volume = self
+                .coinjoin_volume
+                .saturating_add(value.saturating_mul(n_equal as u64));
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("coinjoins.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "coinjoins-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "CoinJoinDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "CoinJoinDump [on_complete]", "Done.\nFound {} likely CoinJoins in {} transactions ({:.4}%), \
+                                                      mixing {:.8} ({} units) in equal outputs.",
+              self.coinjoin_count, self.tx_count, self.coinjoin_count as f64 / self.tx_count as f64 * 100.0,
+              self.coinjoin_volume as f64 * 1E-8, self.coinjoin_volume);
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut columns = vec![
+            ("height", ColumnType::Integer),
+            ("txid", ColumnType::Hash),
+            ("equal_output_value", ColumnType::BigInt),
+            ("participant_estimate", ColumnType::Integer),
+        ];
+        if self.with_timestamp {
+            columns.push(("timestamp", ColumnType::BigInt));
+        }
+        vec![Table::new(
+            "coinjoins",
+            "coinjoins-<start_height>-<end_height>.csv",
+            &columns,
+        )]
+    }
+}
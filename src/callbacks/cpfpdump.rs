@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Dumps child pays for parent pairs: a transaction spending an output of another transaction in the same block
+/// at a higher feerate, which lets miners include the low fee parent for the combined feerate of both.
+pub struct CpfpDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    utxo_set: HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>>, // Value and height of all unspent outputs
+
+    start_height: usize,
+    end_height: usize,
+    tx_count: u64,
+    n_in_block_pairs: u64, // Pairs of a transaction and its parent in the same block, regardless of the feerate
+    n_pairs: u64,
+}
+
+impl Callback for CpfpDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("cpfpdump")
+            .about("Dumps transactions paying a higher feerate than their parent in the same block (child pays for parent)")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(_: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        match (|| -> OpResult<Self> {
+            let mut cb = CpfpDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("cpfp.csv.tmp"))?,
+                utxo_set: Default::default(),
+                start_height: 0,
+                end_height: 0,
+                tx_count: 0,
+                n_in_block_pairs: 0,
+                n_pairs: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize CpfpDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "CpfpDump [on_start]", "Using `CpfpDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if self.start_height > 0 {
+            warn!(target: "CpfpDump [on_start]", "Outputs created before block {} are unknown, transactions spending them are skipped!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        // Fees and vsize of the transactions of this block
+        let mut block_txs: HashMap<[u8; 32], (u64, usize)> = HashMap::new();
+        for tx in &block.txs {
+            self.tx_count += 1;
+            if !tx.value.is_coinbase() {
+                // Outputs of parents in this block were already added below
+                let fees = tx.value.get_fees(&self.utxo_set);
+                for input in &tx.value.inputs {
+                    self.utxo_set.remove(&input.outpoint);
+                }
+                if let Some(fees) = fees {
+                    let vsize = tx.value.vsize();
+                    let mut parents: Vec<&[u8; 32]> = Vec::new();
+                    for input in &tx.value.inputs {
+                        let txid = &input.outpoint.txid;
+                        if block_txs.contains_key(txid) && !parents.contains(&txid) {
+                            parents.push(txid);
+                        }
+                    }
+                    for parent in parents {
+                        self.n_in_block_pairs += 1;
+                        let (parent_fees, parent_vsize) = block_txs[parent];
+                        // Compares fees / vsize of both without rounding
+                        if fees * parent_vsize as u64 <= parent_fees * vsize as u64 {
+                            continue;
+                        }
+                        let combined_feerate =
+                            (fees + parent_fees) as f64 / (vsize + parent_vsize) as f64;
+                        let row = format!(
+                            "{};{};{:.2}\n",
+                            arr_to_hex_swapped(parent),
+                            arr_to_hex_swapped(&tx.hash),
+                            combined_feerate
+                        );
+                        self.writer.write_all(row.as_bytes()).unwrap();
+                        self.n_pairs += 1;
+                    }
+                    block_txs.insert(tx.hash, (fees, vsize));
+                }
+            }
+
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                // OP_RETURN outputs can't be spent
+                if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                    continue;
+                }
+                let outpoint = TxOutpoint {
+                    txid: tx.hash,
+                    index: i as u32,
+                };
+                self.utxo_set
+                    .insert(outpoint, (output.out.value, block_height));
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("cpfp.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "cpfp-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "CpfpDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "CpfpDump [on_complete]", "Done.\nFound {} transactions spending a parent in the same block among {} transactions \
+                                                  (counted once per parent), {} of them pay a higher feerate than the parent.",
+              self.n_in_block_pairs, self.tx_count, self.n_pairs);
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, (u64, usize))>(self.utxo_set.len())
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "cpfp",
+            "cpfp-<start_height>-<end_height>.csv",
+            &[
+                ("parent_txid", ColumnType::Hash),
+                ("child_txid", ColumnType::Hash),
+                ("combined_feerate", ColumnType::Real),
+            ],
+        )]
+    }
+}
@@ -1,14 +1,16 @@
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 
+use callbacks::schema::{ColumnType, Table};
 use callbacks::Callback;
-use errors::{OpError, OpResult};
+use common::writer::{self, FileWriter};
+use errors::OpResult;
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
+use blockchain::proto::script;
 use blockchain::proto::tx::{EvaluatedTxOut, Tx, TxInput};
 use blockchain::proto::Hashed;
 use blockchain::utils;
@@ -17,10 +19,12 @@ use blockchain::utils;
 pub struct CsvDump {
     // Each structure gets stored in a seperate csv file
     dump_folder: PathBuf,
-    block_writer: BufWriter<File>,
-    tx_writer: BufWriter<File>,
-    txin_writer: BufWriter<File>,
-    txout_writer: BufWriter<File>,
+    block_writer: FileWriter,
+    tx_writer: FileWriter,
+    txin_writer: FileWriter,
+    txout_writer: FileWriter,
+    with_asm: bool,   // Appends the scriptPubKey disassembly to tx_out rows
+    with_wtxid: bool, // Appends the witness txid to transaction rows
 
     start_height: usize,
     end_height: usize,
@@ -29,13 +33,51 @@ pub struct CsvDump {
     out_count: u64,
 }
 
+/// Disassembled scripts longer than this are cut off, mostly affects huge OP_RETURN payloads
+const MAX_ASM_LEN: usize = 1024;
+
 impl CsvDump {
-    fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
-        let file = match File::create(&path) {
-            Ok(f) => f,
-            Err(err) => return Err(OpError::from(err)),
-        };
-        Ok(BufWriter::with_capacity(cap, file))
+    /// Creates the callback writing into `dump_folder`, which differs from the argument for shards.
+    /// Shards leave out the header row, see `Callback::shard`.
+    fn create(
+        dump_folder: &Path,
+        with_asm: bool,
+        with_wtxid: bool,
+        header_row: bool,
+    ) -> OpResult<Self> {
+        match (|| -> OpResult<Self> {
+            let mut cb = CsvDump {
+                dump_folder: PathBuf::from(dump_folder),
+                block_writer: writer::create(&dump_folder.join("blocks.csv.tmp"))?,
+                tx_writer: writer::create(&dump_folder.join("transactions.csv.tmp"))?,
+                txin_writer: writer::create(&dump_folder.join("tx_in.csv.tmp"))?,
+                txout_writer: writer::create(&dump_folder.join("tx_out.csv.tmp"))?,
+                with_asm: with_asm,
+                with_wtxid: with_wtxid,
+                start_height: 0,
+                end_height: 0,
+                tx_count: 0,
+                in_count: 0,
+                out_count: 0,
+            };
+            if header_row {
+                let schema = cb.schema();
+                cb.block_writer.write_header(&schema[0])?;
+                cb.tx_writer.write_header(&schema[1])?;
+                cb.txin_writer.write_header(&schema[2])?;
+                cb.txout_writer.write_header(&schema[3])?;
+            }
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize csvdump with folder: `{:?}`",
+                    dump_folder
+                ))
+            }
+        }
     }
 }
 
@@ -54,38 +96,28 @@ impl Callback for CsvDump {
                     .index(1)
                     .required(true),
             )
+            .arg(
+                Arg::with_name("with-asm")
+                    .long("with-asm")
+                    .help("Adds the disassembled scriptPubKey as last column of tx_out"),
+            )
+            .arg(
+                Arg::with_name("with-wtxid")
+                    .long("with-wtxid")
+                    .help("Adds the witness txid as last column of transactions"),
+            )
     }
 
-    fn new(matches: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
     where
         Self: Sized,
     {
-        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
-        match (|| -> OpResult<Self> {
-            let cap = 4000000;
-            let cb = CsvDump {
-                dump_folder: PathBuf::from(dump_folder),
-                block_writer: CsvDump::create_writer(cap, dump_folder.join("blocks.csv.tmp"))?,
-                tx_writer: CsvDump::create_writer(cap, dump_folder.join("transactions.csv.tmp"))?,
-                txin_writer: CsvDump::create_writer(cap, dump_folder.join("tx_in.csv.tmp"))?,
-                txout_writer: CsvDump::create_writer(cap, dump_folder.join("tx_out.csv.tmp"))?,
-                start_height: 0,
-                end_height: 0,
-                tx_count: 0,
-                in_count: 0,
-                out_count: 0,
-            };
-            Ok(cb)
-        })() {
-            Ok(s) => return Ok(s),
-            Err(e) => {
-                return Err(tag_err!(
-                    e,
-                    "Couldn't initialize csvdump with folder: `{:?}`",
-                    dump_folder.as_path()
-                ))
-            }
-        }
+        CsvDump::create(
+            dump_folder.unwrap(), // Save to unwrap
+            matches.is_present("with-asm"),
+            matches.is_present("with-wtxid"),
+            true,
+        )
     }
 
     fn on_start(&mut self, _: CoinType, block_height: usize) {
@@ -101,16 +133,16 @@ impl Callback for CsvDump {
 
         // serialize transaction
         let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
-        for tx in block.txs {
+        for (tx_index, tx) in block.txs.iter().enumerate() {
             self.tx_writer
-                .write_all(tx.as_csv(&block_hash).as_bytes())
+                .write_all(tx.as_csv(&block_hash, tx_index, self.with_wtxid).as_bytes())
                 .unwrap();
             let txid_str = utils::arr_to_hex_swapped(&tx.hash);
 
             // serialize inputs
-            for input in &tx.value.inputs {
+            for (i, input) in tx.value.inputs.iter().enumerate() {
                 self.txin_writer
-                    .write_all(input.as_csv(&txid_str).as_bytes())
+                    .write_all(input.as_csv(&txid_str, tx_index, i).as_bytes())
                     .unwrap();
             }
             self.in_count += tx.value.in_count.value;
@@ -118,7 +150,11 @@ impl Callback for CsvDump {
             // serialize outputs
             for (i, output) in tx.value.outputs.iter().enumerate() {
                 self.txout_writer
-                    .write_all(output.as_csv(&txid_str, i).as_bytes())
+                    .write_all(
+                        output
+                            .as_csv(&txid_str, tx_index, i, self.with_asm)
+                            .as_bytes(),
+                    )
                     .unwrap();
             }
             self.out_count += tx.value.out_count.value;
@@ -130,16 +166,24 @@ impl Callback for CsvDump {
         self.end_height = block_height;
 
         // Keep in sync with c'tor
-        for f in vec!["blocks", "transactions", "tx_in", "tx_out"] {
+        let writers = vec![
+            ("blocks", &mut self.block_writer),
+            ("transactions", &mut self.tx_writer),
+            ("tx_in", &mut self.txin_writer),
+            ("tx_out", &mut self.txout_writer),
+        ];
+        for (f, writer) in writers {
             // Rename temp files
-            fs::rename(
+            if let Err(e) = writer.finalize(
                 self.dump_folder.as_path().join(format!("{}.csv.tmp", f)),
                 self.dump_folder.as_path().join(format!(
                     "{}-{}-{}.csv",
                     f, self.start_height, self.end_height
                 )),
-            )
-            .expect("Unable to rename tmp file!");
+            ) {
+                error!(target: "callback", "Unable to rename tmp file: {}", e);
+                return;
+            }
         }
 
         info!(target: "callback", "Done.\nDumped all {} blocks:\n\
@@ -148,6 +192,87 @@ impl Callback for CsvDump {
                                    \t-> outputs:      {:9}",
              self.end_height + 1, self.tx_count, self.in_count, self.out_count);
     }
+
+    fn order_insensitive(&self) -> bool {
+        true
+    }
+
+    fn shard(&self, dump_folder: &Path) -> OpResult<Box<Callback + Send>> {
+        Ok(Box::new(CsvDump::create(
+            dump_folder,
+            self.with_asm,
+            self.with_wtxid,
+            false,
+        )?))
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut tx_columns = vec![
+            ("txid", ColumnType::Hash),
+            ("hashBlock", ColumnType::Hash),
+            ("version", ColumnType::BigInt),
+            ("lockTime", ColumnType::BigInt),
+            ("txIndex", ColumnType::Integer),
+        ];
+        if self.with_wtxid {
+            tx_columns.push(("wtxid", ColumnType::Hash));
+        }
+        let mut txout_columns = vec![
+            ("txid", ColumnType::Hash),
+            ("indexOut", ColumnType::Integer),
+            ("value", ColumnType::BigInt),
+            ("scriptPubKey", ColumnType::Text),
+            ("address", ColumnType::Text),
+            ("txIndex", ColumnType::Integer),
+        ];
+        if self.with_asm {
+            txout_columns.push(("scriptPubKeyAsm", ColumnType::Text));
+        }
+        vec![
+            Table::new(
+                "blocks",
+                "blocks-<start_height>-<end_height>.csv",
+                &[
+                    ("block_hash", ColumnType::Hash),
+                    ("height", ColumnType::Integer),
+                    ("version", ColumnType::BigInt),
+                    ("blocksize", ColumnType::BigInt),
+                    ("hashPrev", ColumnType::Hash),
+                    ("hashMerkleRoot", ColumnType::Hash),
+                    ("nTime", ColumnType::BigInt),
+                    ("nBits", ColumnType::BigInt),
+                    ("nNonce", ColumnType::BigInt),
+                ],
+            ),
+            Table::new(
+                "transactions",
+                "transactions-<start_height>-<end_height>.csv",
+                &tx_columns,
+            ),
+            Table::new(
+                "tx_in",
+                "tx_in-<start_height>-<end_height>.csv",
+                &[
+                    ("txid", ColumnType::Hash),
+                    ("hashPrevOut", ColumnType::Hash),
+                    ("indexPrevOut", ColumnType::BigInt),
+                    ("scriptSig", ColumnType::Text),
+                    ("sequence", ColumnType::BigInt),
+                    ("txIndex", ColumnType::Integer),
+                    ("indexIn", ColumnType::Integer),
+                ],
+            ),
+            Table::new(
+                "tx_out",
+                "tx_out-<start_height>-<end_height>.csv",
+                &txout_columns,
+            ),
+        ]
+    }
 }
 
 impl Block {
@@ -157,7 +282,7 @@ impl Block {
         format!(
             "{};{};{};{};{};{};{};{};{}\n",
             &utils::arr_to_hex_swapped(&self.header.hash),
-            &block_height,
+            writer::row_height(block_height),
             &self.header.value.version,
             &self.blocksize,
             &utils::arr_to_hex_swapped(&self.header.value.prev_hash),
@@ -171,44 +296,68 @@ impl Block {
 
 impl Hashed<Tx> {
     #[inline]
-    fn as_csv(&self, block_hash: &str) -> String {
-        // (@txid, @hashBlock, version, lockTime)
+    fn as_csv(&self, block_hash: &str, tx_index: usize, with_wtxid: bool) -> String {
+        // (@txid, @hashBlock, version, lockTime, txIndex[, @wtxid])
+        let wtxid = if with_wtxid {
+            format!(";{}", utils::arr_to_hex_swapped(&self.value.wtxid()))
+        } else {
+            String::new()
+        };
         format!(
-            "{};{};{};{}\n",
+            "{};{};{};{};{}{}\n",
             &utils::arr_to_hex_swapped(&self.hash),
             &block_hash,
             &self.value.tx_version,
-            &self.value.tx_locktime
+            &self.value.tx_locktime,
+            &tx_index,
+            &wtxid
         )
     }
 }
 
 impl TxInput {
     #[inline]
-    fn as_csv(&self, txid: &str) -> String {
-        // (@txid, @hashPrevOut, indexPrevOut, scriptSig, sequence)
+    fn as_csv(&self, txid: &str, tx_index: usize, index: usize) -> String {
+        // (@txid, @hashPrevOut, indexPrevOut, scriptSig, sequence, txIndex, indexIn)
         format!(
-            "{};{};{};{};{}\n",
+            "{};{};{};{};{};{};{}\n",
             &txid,
             &utils::arr_to_hex_swapped(&self.outpoint.txid),
             &self.outpoint.index,
             &utils::arr_to_hex(&self.script_sig),
-            &self.seq_no
+            &self.seq_no,
+            &tx_index,
+            &index
         )
     }
 }
 
 impl EvaluatedTxOut {
     #[inline]
-    fn as_csv(&self, txid: &str, index: usize) -> String {
-        // (@txid, indexOut, value, @scriptPubKey, address)
+    fn as_csv(&self, txid: &str, tx_index: usize, index: usize, with_asm: bool) -> String {
+        // (@txid, indexOut, value, @scriptPubKey, address, txIndex[, scriptPubKeyAsm])
+        // The disassembly only consists of opcode names and hex data, so it never contains the delimiter
+        let asm = if with_asm {
+            format!(
+                ";{}",
+                script::disassemble(&self.out.script_pubkey, MAX_ASM_LEN)
+            )
+        } else {
+            String::new()
+        };
         format!(
-            "{};{};{};{};{}\n",
+            "{};{};{};{};{};{}{}\n",
             &txid,
             &index,
             &self.out.value,
             &utils::arr_to_hex(&self.out.script_pubkey),
-            &self.script.address
+            &self
+                .script
+                .address
+                .as_ref()
+                .map_or(String::new(), |a| a.to_string()),
+            &tx_index,
+            &asm
         )
     }
 }
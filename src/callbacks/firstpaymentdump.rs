@@ -0,0 +1,166 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::addressreuse::SeenAddresses;
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Dumps the first output paying to each address, the moment the address appears on chain.
+/// Later payments are ignored, so a row can be written as soon as an address is seen for the first time.
+pub struct FirstPaymentDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    seen: SeenAddresses,
+
+    start_height: usize,
+    last_height: usize, // Height of the latest processed block
+    n_addresses: u64,
+}
+
+impl Callback for FirstPaymentDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("firstpaymentdump")
+            .about("Dumps the transaction and height of the first payment to each address into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("approximate")
+                    .long("approximate")
+                    .value_name("MB")
+                    .help("Tracks seen addresses in a bloom filter of MB megabytes instead of an exact set. \
+                           Saves memory but misses a few addresses")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("address-cache-size")
+                    .long("address-cache-size")
+                    .value_name("N")
+                    .help("Only remembers the N most recently paid addresses. Bounds memory but dumps cold addresses again when they are paid")
+                    .conflicts_with("approximate")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let seen = SeenAddresses::from_args(matches)?;
+        match (|| -> OpResult<Self> {
+            let mut cb = FirstPaymentDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("firstpayments.csv.tmp"))?,
+                seen: seen,
+                start_height: 0,
+                last_height: 0,
+                n_addresses: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize FirstPaymentDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "FirstPaymentDump [on_start]", "Using `FirstPaymentDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if self.start_height > 0 {
+            warn!(target: "FirstPaymentDump [on_start]", "Addresses paid before block {} are unknown, their next payment is dumped instead!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        self.last_height = block_height;
+        for tx in &block.txs {
+            for output in &tx.value.outputs {
+                let address = match output.script.address {
+                    Some(ref address) => address,
+                    None => continue,
+                };
+                if self.seen.insert(address) {
+                    continue;
+                }
+                let row = format!(
+                    "{};{};{}\n",
+                    address,
+                    arr_to_hex_swapped(&tx.hash),
+                    writer::row_height(block_height)
+                );
+                self.writer.write_all(row.as_bytes()).unwrap();
+                self.n_addresses += 1;
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("firstpayments.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "firstpayments-{}-{}.csv",
+                self.start_height, block_height
+            )),
+        ) {
+            error!(target: "FirstPaymentDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "FirstPaymentDump [on_complete]", "Done.\nDumped the first payment of {} addresses.", self.n_addresses);
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.seen.memory_usage()
+    }
+
+    /// Moves the seen addresses into a bloom filter of `target` bytes, as if `--approximate` was given
+    fn reduce_memory(&mut self, target: usize) -> bool {
+        let filter = match self.seen.to_filter(target) {
+            Some(filter) => filter,
+            None => return false,
+        };
+        warn!(target: "FirstPaymentDump [reduce_memory]", "Switching to a bloom filter of {} to stay within --memory-limit. Some addresses will be missed from block {} on!",
+              memory::format_mb(target), self.last_height + 1);
+        self.seen = SeenAddresses::Approximate(filter);
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "firstpayments",
+            "firstpayments-<start_height>-<end_height>.csv",
+            &[
+                ("address", ColumnType::Text),
+                ("first_txid", ColumnType::Hash),
+                ("first_height", ColumnType::Integer),
+            ],
+        )]
+    }
+}
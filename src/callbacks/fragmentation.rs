@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::tx::TxOutpoint;
+
+/// Returns the histogram bucket of an address holding `utxo_count` outputs.
+/// Bucket `i` holds the counts from `2^i` to `2^(i+1) - 1`.
+fn bucket(utxo_count: u64) -> usize {
+    63 - utxo_count.leading_zeros() as usize
+}
+
+type UtxoSet = HashMap<TxOutpoint, (String, u64), BuildHasherDefault<XxHash>>;
+
+/// Returns the UTXO count and total value of each address
+fn group_by_address(utxo_set: &UtxoSet) -> HashMap<&str, (u64, u64), BuildHasherDefault<XxHash>> {
+    let mut addresses: HashMap<&str, (u64, u64), BuildHasherDefault<XxHash>> = Default::default();
+    for &(ref address, value) in utxo_set.values() {
+        let entry = addresses.entry(address).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 = entry.1.saturating_add(value);
+    }
+    addresses
+}
+
+/// Measures UTXO fragmentation: the number and total value of the unspent outputs held by each address.
+/// Like utxodump, the whole UTXO set is kept in memory, together with the value of each output.
+/// Grouping the final set by address needs another entry per address on top of that.
+pub struct Fragmentation {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    histogram: bool,   // Only write the distribution of UTXO counts per address
+    utxo_set: UtxoSet, // Address and value of all unspent outputs with an address
+
+    start_height: usize,
+    end_height: usize,
+}
+
+impl Callback for Fragmentation {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("fragmentation")
+            .about("Dumps the number and total value of the unspent outputs held by each address")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("histogram")
+                    .long("histogram")
+                    .help("Writes the distribution of UTXO counts per address in power of two buckets instead of one row per address"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        match (|| -> OpResult<Self> {
+            let mut cb = Fragmentation {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("fragmentation.csv.tmp"))?,
+                histogram: matches.is_present("histogram"),
+                utxo_set: Default::default(),
+                start_height: 0,
+                end_height: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize Fragmentation with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "Fragmentation [on_start]", "Using `Fragmentation` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if self.start_height > 0 {
+            warn!(target: "Fragmentation [on_start]", "Outputs created before block {} are unknown and won't be counted!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, _: usize) {
+        for tx in &block.txs {
+            for input in &tx.value.inputs {
+                self.utxo_set.remove(&input.outpoint);
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                // Outputs without an address, including OP_RETURN outputs, can't be grouped
+                let address = match output.script.address {
+                    Some(ref address) => address.to_string(),
+                    None => continue,
+                };
+                let outpoint = TxOutpoint {
+                    txid: tx.hash,
+                    index: i as u32,
+                };
+                self.utxo_set.insert(outpoint, (address, output.out.value));
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        let addresses = group_by_address(&self.utxo_set);
+        let mut buckets: Vec<(u64, u64)> = Vec::new(); // Addresses and total value of each bucket
+        for &(utxo_count, total_value) in addresses.values() {
+            let i = bucket(utxo_count);
+            if buckets.len() <= i {
+                buckets.resize(i + 1, (0, 0));
+            }
+            buckets[i].0 += 1;
+            buckets[i].1 = buckets[i].1.saturating_add(total_value);
+        }
+        if self.histogram {
+            for (i, &(n_addresses, total_value)) in buckets.iter().enumerate() {
+                let row = format!(
+                    "{};{};{};{}\n",
+                    1u64 << i,
+                    (1u64 << i) - 1 + (1u64 << i),
+                    n_addresses,
+                    total_value
+                );
+                self.writer.write_all(row.as_bytes()).unwrap();
+            }
+        } else {
+            for (address, &(utxo_count, total_value)) in &addresses {
+                let row = format!("{};{};{}\n", address, utxo_count, total_value);
+                self.writer.write_all(row.as_bytes()).unwrap();
+            }
+        }
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("fragmentation.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "fragmentation-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "Fragmentation [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        let mut details = String::new();
+        for (i, &(n_addresses, _)) in buckets.iter().enumerate() {
+            details.push_str(&format!(
+                "\n\t-> {:>10} .. {:<10} UTXOs: {:12} addresses",
+                1u64 << i,
+                (1u64 << i) - 1 + (1u64 << i),
+                n_addresses
+            ));
+        }
+        info!(target: "Fragmentation [on_complete]", "Done.\n{} unspent outputs held by {} addresses:{}",
+              self.utxo_set.len(), addresses.len(), details);
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, (String, u64))>(self.utxo_set.len())
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let columns: &[(&str, ColumnType)] = if self.histogram {
+            &[
+                ("min_utxo_count", ColumnType::BigInt),
+                ("max_utxo_count", ColumnType::BigInt),
+                ("n_addresses", ColumnType::BigInt),
+                ("total_value", ColumnType::BigInt),
+            ]
+        } else {
+            &[
+                ("address", ColumnType::Text),
+                ("utxo_count", ColumnType::BigInt),
+                ("total_value", ColumnType::BigInt),
+            ]
+        };
+        vec![Table::new(
+            "fragmentation",
+            "fragmentation-<start_height>-<end_height>.csv",
+            columns,
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bucket;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(0, bucket(1));
+        assert_eq!(1, bucket(2));
+        assert_eq!(1, bucket(3));
+        assert_eq!(2, bucket(4));
+        assert_eq!(9, bucket(1023));
+        assert_eq!(10, bucket(1024));
+        assert_eq!(63, bucket(u64::max_value()));
+    }
+}
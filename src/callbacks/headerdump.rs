@@ -0,0 +1,175 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::ToRaw;
+use blockchain::utils;
+
+/// Dumps the block headers of the longest chain in height order.
+/// Only the headers are read from the blk files, which makes this much faster than a full scan.
+pub struct HeaderDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    csv: bool, // Decoded fields instead of the raw 80 byte headers
+
+    start_height: usize,
+    end_height: usize,
+    n_headers: u64,
+}
+
+impl HeaderDump {
+    fn file_extension(&self) -> &'static str {
+        match self.csv {
+            true => "csv",
+            false => "bin",
+        }
+    }
+}
+
+impl Callback for HeaderDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("headerdump")
+            .about("Dumps all block headers without parsing the transactions")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the header file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Concatenated 80 byte headers or a CSV file of the decoded fields (default: raw)")
+                    .possible_values(&["raw", "csv"])
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let csv = matches.value_of("format") == Some("csv");
+        match (|| -> OpResult<Self> {
+            let path = dump_folder.join("headers.tmp");
+            let mut cb = HeaderDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: match csv {
+                    true => writer::create(&path)?,
+                    false => writer::create_binary(&path)?,
+                },
+                csv: csv,
+                start_height: 0,
+                end_height: 0,
+                n_headers: 0,
+            };
+            if let Some(table) = cb.schema().first() {
+                cb.writer.write_header(table)?;
+            }
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize HeaderDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "HeaderDump [on_start]", "Using `HeaderDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let header = &block.header.value;
+        if self.csv {
+            let row = format!(
+                "{};{};{};{};{};{};{};{}\n",
+                writer::row_height(block_height),
+                utils::arr_to_hex_swapped(&block.header.hash),
+                header.version,
+                utils::arr_to_hex_swapped(&header.prev_hash),
+                utils::arr_to_hex_swapped(&header.merkle_root),
+                header.timestamp,
+                header.bits,
+                header.nonce
+            );
+            self.writer.write_all(row.as_bytes()).unwrap();
+        } else {
+            self.writer.write_all(&header.to_bytes()).unwrap();
+        }
+        self.n_headers += 1;
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        let path = self.dump_folder.as_path().join(format!(
+            "headers-{}-{}.{}",
+            self.start_height,
+            self.end_height,
+            self.file_extension()
+        ));
+        if let Err(e) = self
+            .writer
+            .finalize(self.dump_folder.as_path().join("headers.tmp"), path)
+        {
+            error!(target: "HeaderDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "HeaderDump [on_complete]", "Done.\nDumped {} block headers.", self.n_headers);
+    }
+
+    fn headers_only(&self) -> bool {
+        true
+    }
+
+    fn allows_block_sampling(&self) -> bool {
+        true
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        if !self.csv {
+            return Vec::new();
+        }
+        vec![Table::new(
+            "headers",
+            "headers-<start_height>-<end_height>.csv",
+            &[
+                ("height", ColumnType::Integer),
+                ("block_hash", ColumnType::Hash),
+                ("version", ColumnType::BigInt),
+                ("hashPrev", ColumnType::Hash),
+                ("hashMerkleRoot", ColumnType::Hash),
+                ("nTime", ColumnType::BigInt),
+                ("nBits", ColumnType::BigInt),
+                ("nNonce", ColumnType::BigInt),
+            ],
+        )]
+    }
+}
@@ -1,18 +1,48 @@
+pub mod addressreuse;
+pub mod blockstats;
+pub mod burndump;
 pub mod clusterizer;
+pub mod coinbasedump;
+pub mod coinjoindump;
+pub mod cpfpdump;
 pub mod csvdump;
+pub mod firstpaymentdump;
+pub mod fragmentation;
+pub mod headerdump;
+pub mod outputcountdump;
+pub mod protocoldump;
+pub mod realizedcap;
+#[cfg(test)]
+mod robustness;
+pub mod schema;
+pub mod scripthashdump;
+pub mod spam;
+pub mod spenddump;
 pub mod stats;
+pub mod taprootadoption;
+#[cfg(test)]
+pub mod testsink;
+pub mod txdepthdump;
 pub mod txodump;
+pub mod utxodelta;
 pub mod utxodump;
+pub mod verifysnapshot;
+pub mod versionrolling;
+pub mod watchaddresses;
+
+use std::path::Path;
 
 use clap::{App, ArgMatches};
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
-use errors::OpResult;
+use callbacks::schema::Table;
+use errors::{OpError, OpErrorKind, OpResult};
 
 /// Implement this trait for a custom Callback.
 /// The parser ensures that the blocks arrive in the correct order.
 /// At this stage the main chain is already determined and orphans/stales are removed.
+/// Skipped stale and duplicate blocks are counted and passed to `on_skipped_blocks` once parsing is complete.
 /// Note: These callbacks are only triggered with ParseMode::FullData.
 /// (The first run to determine longest chain is running in ParseMode::Indexing)
 pub trait Callback {
@@ -22,8 +52,10 @@ pub trait Callback {
     where
         Self: Sized;
 
-    /// Instantiates callback
-    fn new(matches: &ArgMatches) -> OpResult<Self>
+    /// Instantiates callback.
+    /// `dump_folder` is where its files go: the `dump-folder` argument, or the OUTDIR of the current `--run`.
+    /// It is None for callbacks without a dump folder.
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
     where
         Self: Sized;
 
@@ -35,4 +67,65 @@ pub trait Callback {
 
     /// Gets called if the dispatcher has finished and all blocks are handled
     fn on_complete(&mut self, block_height: usize);
+
+    /// Gets called right before `on_complete` with the number of stale blocks and of duplicate copies
+    /// of main chain blocks which were skipped, none of them were passed to `on_block`. Not called with `--single-file`.
+    fn on_skipped_blocks(&mut self, _n_stale_blocks: u64, _n_duplicate_blocks: u64) {}
+
+    /// Returns an error if the callback found the parsed data to be invalid, e.g. a failed verification.
+    /// Gets checked once `on_complete` returned, the run then fails with this error.
+    fn check_result(&self) -> OpResult<()> {
+        Ok(())
+    }
+
+    /// Returns true if the callback only needs the block headers.
+    /// The transactions are skipped while reading, so blocks arrive with an empty `txs`.
+    fn headers_only(&self) -> bool {
+        false
+    }
+
+    /// Estimated number of bytes held by the callback, mostly for its UTXO set.
+    /// Compared against `--memory-limit` every few blocks.
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// Gets called if `memory_usage` approaches `--memory-limit`.
+    /// Callbacks which can degrade, e.g. by evicting old outputs, should free memory until
+    /// their usage is below `target` bytes and return true. The parser aborts if the usage still exceeds the limit.
+    fn reduce_memory(&mut self, _target: usize) -> bool {
+        false
+    }
+
+    /// Returns true if the rows written for a block don't depend on any other block.
+    /// Such callbacks may be split into shards with `--parallel-write`, see `shard`.
+    fn order_insensitive(&self) -> bool {
+        false
+    }
+
+    /// Creates another instance with the same arguments, which writes its files into `dump_folder`.
+    /// Each shard sees only every n-th block, and its files are appended to the ones of the same name at the end.
+    /// The files are appended byte for byte, so shards must not write a header row.
+    fn shard(&self, _dump_folder: &Path) -> OpResult<Box<Callback + Send>> {
+        Err(OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg("The callback can't be split into shards"))
+    }
+
+    /// Returns true if the rows of a block stay the same when other blocks are skipped,
+    /// i.e. no state is carried between blocks besides totals. Required by `--block-sample`.
+    fn allows_block_sampling(&self) -> bool {
+        self.order_insensitive()
+    }
+
+    /// Returns true if the rows of a transaction don't depend on any other transaction,
+    /// i.e. no outputs are followed to their spends. Required by `--txid-prefix`.
+    fn allows_tx_filter(&self) -> bool {
+        false
+    }
+
+    /// Describes the columns of all CSV files written by this callback.
+    /// Used to generate the DDL for `--write-schema-file`.
+    fn schema(&self) -> Vec<Table> {
+        Vec::new()
+    }
 }
@@ -0,0 +1,209 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Default of --max-outputs-per-tx
+const DEFAULT_MAX_OUTPUTS: u64 = 100;
+/// Default of --top
+const DEFAULT_TOP: usize = 10;
+
+/// Dumps transactions with more outputs than --max-outputs-per-tx, which are mostly batch payouts of exchanges and pools or spam.
+/// The transactions with the most outputs are logged at the end.
+pub struct OutputCountDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    max_outputs: u64,
+    top: usize,
+    largest: BinaryHeap<Reverse<(u64, usize, [u8; 32])>>, // Output count, height and txid of the top transactions
+
+    start_height: usize,
+    end_height: usize,
+    tx_count: u64,
+    n_flagged: u64,
+}
+
+impl OutputCountDump {
+    /// Keeps the transaction if it is among the `top` ones with the most outputs
+    fn track_largest(&mut self, out_count: u64, height: usize, txid: [u8; 32]) {
+        if self.top == 0 {
+            return;
+        }
+        if self.largest.len() < self.top {
+            self.largest.push(Reverse((out_count, height, txid)));
+        } else if let Some(&Reverse((min_count, _, _))) = self.largest.peek() {
+            if out_count > min_count {
+                self.largest.pop();
+                self.largest.push(Reverse((out_count, height, txid)));
+            }
+        }
+    }
+}
+
+impl Callback for OutputCountDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("outputcountdump")
+            .about("Dumps transactions with an unusually high number of outputs, like batch payouts or spam")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("max-outputs-per-tx")
+                    .long("max-outputs-per-tx")
+                    .value_name("N")
+                    .help("Dumps transactions with more than N outputs (default: 100)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("top")
+                    .long("top")
+                    .value_name("N")
+                    .help("Logs the N transactions with the most outputs at the end (default: 10)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let max_outputs = match matches.value_of("max-outputs-per-tx") {
+            Some(_) => match value_t!(matches, "max-outputs-per-tx", u64) {
+                Ok(n) => n,
+                Err(e) => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg(&format!("Invalid --max-outputs-per-tx: {}", e)))
+                }
+            },
+            None => DEFAULT_MAX_OUTPUTS,
+        };
+        let top = match matches.value_of("top") {
+            Some(_) => match value_t!(matches, "top", usize) {
+                Ok(n) => n,
+                Err(e) => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg(&format!("Invalid --top: {}", e)))
+                }
+            },
+            None => DEFAULT_TOP,
+        };
+        match (|| -> OpResult<Self> {
+            let mut cb = OutputCountDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("outputcounts.csv.tmp"))?,
+                max_outputs: max_outputs,
+                top: top,
+                largest: BinaryHeap::with_capacity(top + 1),
+                start_height: 0,
+                end_height: 0,
+                tx_count: 0,
+                n_flagged: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize OutputCountDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "OutputCountDump [on_start]", "Using `OutputCountDump` with dump folder: {:?} and start block {}, flagging transactions with more than {} outputs...",
+              &self.dump_folder, self.start_height, self.max_outputs);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        self.tx_count += block.tx_count.value;
+        for tx in &block.txs {
+            let out_count = tx.value.out_count.value;
+            self.track_largest(out_count, block_height, tx.hash);
+            if out_count <= self.max_outputs {
+                continue;
+            }
+            let row = format!(
+                "{};{};{}\n",
+                writer::row_height(block_height),
+                arr_to_hex_swapped(&tx.hash),
+                out_count
+            );
+            self.writer.write_all(row.as_bytes()).unwrap();
+            self.n_flagged += 1;
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("outputcounts.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "outputcounts-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "OutputCountDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        let mut details = String::new();
+        for &Reverse((out_count, height, txid)) in self.largest.clone().into_sorted_vec().iter() {
+            details.push_str(&format!(
+                "\n\t-> {:8} outputs in block {:7}, txid: {}",
+                out_count,
+                height,
+                arr_to_hex_swapped(&txid)
+            ));
+        }
+        info!(target: "OutputCountDump [on_complete]", "Done.\n{} of {} transactions have more than {} outputs. Most outputs:{}",
+              self.n_flagged, self.tx_count, self.max_outputs, details);
+    }
+
+    fn allows_block_sampling(&self) -> bool {
+        true
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "outputcounts",
+            "outputcounts-<start_height>-<end_height>.csv",
+            &[
+                ("height", ColumnType::Integer),
+                ("txid", ColumnType::Hash),
+                ("output_count", ColumnType::BigInt),
+            ],
+        )]
+    }
+}
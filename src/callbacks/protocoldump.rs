@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::utils;
+
+/// Markers of meta-protocols which are always detected, more can be added with `--markers`
+const BUILTIN_MARKERS: &[(&str, &str)] = &[
+    ("Omni", "6f6d6e69"),                 // "omni"
+    ("Counterparty", "434e545250525459"), // "CNTRPRTY", only unobfuscated payloads
+    ("Stacks", "5832"),                   // "X2", Stacks 2.0 mainnet
+];
+
+/// Identifies a meta-protocol by the prefix of its OP_RETURN payload: `<protocol>;<hex prefix>`
+#[derive(Debug, PartialEq)]
+pub struct ProtocolMarker {
+    protocol: String,
+    prefix: Vec<u8>,
+}
+
+impl FromStr for ProtocolMarker {
+    type Err = OpError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| {
+            OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Invalid protocol marker `{}`: {}", s, msg))
+        };
+        let fields: Vec<&str> = s.split(';').map(|f| f.trim()).collect();
+        if fields.len() != 2 || fields[0].is_empty() {
+            return Err(invalid("expected <protocol>;<hex prefix>"));
+        }
+        let prefix = fields[1];
+        if prefix.is_empty() || prefix.len() % 2 != 0 || !prefix.chars().all(|c| c.is_digit(16)) {
+            return Err(invalid("the prefix must be hex encoded"));
+        }
+        Ok(ProtocolMarker {
+            protocol: String::from(fields[0]),
+            prefix: utils::hex_to_vec(prefix),
+        })
+    }
+}
+
+/// Returns the data pushed by an `OP_RETURN <data>` script
+fn op_return_payload(script: &[u8]) -> Option<&[u8]> {
+    let (&op, rest) = script.split_first()?;
+    if op != 0x6a {
+        return None;
+    }
+    let (&push, rest) = rest.split_first()?;
+    let (len, data) = match push {
+        0x01..=0x4b => (push as usize, rest),
+        0x4c => (*rest.first()? as usize, &rest[1..]),
+        0x4d if rest.len() >= 2 => (rest[0] as usize | (rest[1] as usize) << 8, &rest[2..]),
+        _ => return None,
+    };
+    data.get(..len)
+}
+
+/// Returns the part of `payload` to dump, the application data after the marker with `strip_prefix`
+fn dumped_payload<'a>(payload: &'a [u8], marker: &ProtocolMarker, strip_prefix: bool) -> &'a [u8] {
+    match strip_prefix {
+        true => &payload[marker.prefix.len()..],
+        false => payload,
+    }
+}
+
+/// Tags transactions whose OP_RETURN payload starts with the marker of a known meta-protocol.
+pub struct ProtocolDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    markers: Vec<ProtocolMarker>,
+    strip_prefix: bool, // Dumps the payloads without their marker
+    counts: Vec<u64>,   // Tagged transactions of each marker
+
+    start_height: usize,
+    end_height: usize,
+    tx_count: u64,
+}
+
+impl ProtocolDump {
+    /// Reads one marker per line, empty lines and lines starting with `#` are ignored
+    fn load_markers(path: &Path) -> OpResult<Vec<ProtocolMarker>> {
+        let mut markers = Vec::new();
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match ProtocolMarker::from_str(line) {
+                Ok(marker) => markers.push(marker),
+                Err(e) => return Err(tag_err!(e, " in {:?} line {}", path, i + 1)),
+            }
+        }
+        Ok(markers)
+    }
+
+    /// Returns the index of the longest marker `payload` starts with
+    fn find_marker(&self, payload: &[u8]) -> Option<usize> {
+        self.markers
+            .iter()
+            .enumerate()
+            .filter(|&(_, marker)| payload.starts_with(&marker.prefix))
+            .max_by_key(|&(_, marker)| marker.prefix.len())
+            .map(|(i, _)| i)
+    }
+}
+
+impl Callback for ProtocolDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("protocoldump")
+            .about("Dumps transactions of meta-protocols like Omni, Counterparty and Stacks, detected by their OP_RETURN prefix")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("markers")
+                    .long("markers")
+                    .value_name("FILE")
+                    .help("Adds the markers in FILE, one `<protocol>;<hex prefix>` per line")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("strip-prefix")
+                    .long("strip-prefix")
+                    .help("Dumps the payloads without the protocol marker, e.g. without `omni` (6f6d6e69) for Omni, \
+                           `CNTRPRTY` (434e545250525459) for Counterparty or `X2` (5832) for Stacks"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let mut markers = Vec::new();
+        for &(protocol, prefix) in BUILTIN_MARKERS {
+            markers.push(ProtocolMarker::from_str(&format!(
+                "{};{}",
+                protocol, prefix
+            ))?);
+        }
+        if let Some(path) = matches.value_of("markers") {
+            markers.extend(ProtocolDump::load_markers(Path::new(path))?);
+        }
+        match (|| -> OpResult<Self> {
+            let mut cb = ProtocolDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("protocols.csv.tmp"))?,
+                counts: vec![0; markers.len()],
+                markers: markers,
+                strip_prefix: matches.is_present("strip-prefix"),
+                start_height: 0,
+                end_height: 0,
+                tx_count: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize ProtocolDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "ProtocolDump [on_start]", "Using `ProtocolDump` with dump folder: {:?} and start block {}, detecting {} protocol markers...",
+              &self.dump_folder, self.start_height, self.markers.len());
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        self.tx_count += block.tx_count.value;
+        for tx in &block.txs {
+            // A transaction is tagged with its first OP_RETURN output carrying a marker
+            for output in &tx.value.outputs {
+                match output.script.pattern {
+                    ScriptPattern::DataOutput(_) => (),
+                    _ => continue,
+                }
+                let payload = match op_return_payload(&output.out.script_pubkey) {
+                    Some(payload) => payload,
+                    None => continue,
+                };
+                let i = match self.find_marker(payload) {
+                    Some(i) => i,
+                    None => continue,
+                };
+                let marker = &self.markers[i];
+                let row = format!(
+                    "{};{};{};{}\n",
+                    writer::row_height(block_height),
+                    utils::arr_to_hex_swapped(&tx.hash),
+                    marker.protocol,
+                    utils::arr_to_hex(dumped_payload(payload, marker, self.strip_prefix))
+                );
+                self.writer.write_all(row.as_bytes()).unwrap();
+                self.counts[i] += 1;
+                break;
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("protocols.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "protocols-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "ProtocolDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        let mut details = String::new();
+        for (marker, count) in self.markers.iter().zip(&self.counts) {
+            details.push_str(&format!("\n\t-> {:16} {:12}", marker.protocol, count));
+        }
+        info!(target: "ProtocolDump [on_complete]", "Done.\nTagged transactions out of {}:{}", self.tx_count, details);
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "protocols",
+            "protocols-<start_height>-<end_height>.csv",
+            &[
+                ("height", ColumnType::Integer),
+                ("txid", ColumnType::Hash),
+                ("protocol", ColumnType::Text),
+                ("payload_hex", ColumnType::Text),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dumped_payload, op_return_payload, ProtocolMarker};
+    use blockchain::utils;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_op_return_payload() {
+        // Omni simple send of 1 USDT
+        let script = utils::hex_to_vec("6a146f6d6e69000000000000001f0000000005f5e100");
+        assert_eq!(
+            Some(&utils::hex_to_vec("6f6d6e69000000000000001f0000000005f5e100")[..]),
+            op_return_payload(&script)
+        );
+        // Truncated push
+        let script = utils::hex_to_vec("6a4c0558320102");
+        assert_eq!(None, op_return_payload(&script));
+        let script = utils::hex_to_vec("6a4c03583201");
+        assert_eq!(Some(&[0x58, 0x32, 0x01][..]), op_return_payload(&script));
+        assert_eq!(None, op_return_payload(&utils::hex_to_vec("6a")));
+        assert_eq!(None, op_return_payload(&utils::hex_to_vec("76a914")));
+    }
+
+    #[test]
+    fn test_protocol_marker() {
+        let marker = ProtocolMarker::from_str("Omni; 6f6d6e69").unwrap();
+        assert_eq!("Omni", marker.protocol);
+        assert_eq!(b"omni".to_vec(), marker.prefix);
+
+        assert!(ProtocolMarker::from_str("Omni").is_err());
+        assert!(ProtocolMarker::from_str(";6f6d6e69").is_err());
+        assert!(ProtocolMarker::from_str("Omni;6f6d6e6").is_err());
+        assert!(ProtocolMarker::from_str("Omni;omni").is_err());
+    }
+
+    #[test]
+    fn test_dumped_payload() {
+        let marker = ProtocolMarker::from_str("Stacks;5832").unwrap();
+        let payload = utils::hex_to_vec("58325b0102");
+        assert_eq!(&payload[..], dumped_payload(&payload, &marker, false));
+        assert_eq!(
+            &[0x5b, 0x01, 0x02][..],
+            dumped_payload(&payload, &marker, true)
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::spam::SpamFilter;
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::TxOutpoint;
+
+/// Dumps value and creation height of each unspent output.
+/// This is the raw input for realized cap computations, which can be joined against a price series.
+pub struct RealizedCap {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    utxo_set: HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>>, // TxOutpoint (K), (Coin Value, Blockheight) (V)
+    block_times: Vec<u32>, // Block timestamps indexed by (block_height - start_height)
+    with_timestamp: bool,
+    exclude_immature: bool,
+    coin_type: CoinType,
+    recent_coinbase: VecDeque<(usize, Vec<TxOutpoint>)>, // Coinbase outputs of the blocks within the maturity window
+    spam_filter: Option<SpamFilter>,
+    spam_outputs: HashSet<TxOutpoint, BuildHasherDefault<XxHash>>, // Unspent outputs which are part of the UTXO set, but not dumped
+
+    start_height: usize,
+    end_height: usize,
+    last_height: usize, // Height of the latest processed block
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+}
+
+impl RealizedCap {
+    /// Removes the coinbase outputs which are not spendable after the latest block from the UTXO set.
+    /// Returns their count and value.
+    fn remove_immature_coinbase(&mut self) -> (usize, u64) {
+        let (mut count, mut value) = (0, 0);
+        for &(height, ref outpoints) in &self.recent_coinbase {
+            if self.coin_type.is_coinbase_mature(height, self.last_height) {
+                continue;
+            }
+            for outpoint in outpoints {
+                if let Some((v, _)) = self.utxo_set.remove(outpoint) {
+                    count += 1;
+                    value = v.saturating_add(value);
+                }
+            }
+        }
+        (count, value)
+    }
+}
+
+impl Callback for RealizedCap {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("realizedcap")
+            .about("Dumps value and creation height of all unspent outputs into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("with-timestamp")
+                    .long("with-timestamp")
+                    .help("Adds the timestamp of the creating block as third column"),
+            )
+            .arg(
+                Arg::with_name("exclude-immature")
+                    .long("exclude-immature")
+                    .help("Leaves out coinbase outputs which can't be spent yet"),
+            )
+            .arg(
+                Arg::with_name("spam-filter")
+                    .long("spam-filter")
+                    .value_name("FILE")
+                    .help("Leaves out outputs matching the spam signatures in FILE, one `<pattern>;<value>[;<script prefix>]` per line")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let spam_filter = match matches.value_of("spam-filter") {
+            Some(path) => Some(SpamFilter::load(Path::new(path))?),
+            None => None,
+        };
+        match (|| -> OpResult<Self> {
+            let mut cb = RealizedCap {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("realizedcap.csv.tmp"))?,
+                utxo_set: Default::default(),
+                block_times: Vec::new(),
+                with_timestamp: matches.is_present("with-timestamp"),
+                exclude_immature: matches.is_present("exclude-immature"),
+                coin_type: CoinType::default(),
+                recent_coinbase: VecDeque::new(),
+                spam_filter: spam_filter,
+                spam_outputs: Default::default(),
+                start_height: 0,
+                end_height: 0,
+                last_height: 0,
+                tx_count: 0,
+                in_count: 0,
+                out_count: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize RealizedCap with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, coin_type: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        self.coin_type = coin_type;
+        info!(target: "RealizedCap [on_start]", "Using `RealizedCap` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if self.start_height > 0 {
+            warn!(target: "RealizedCap [on_start]", "Outputs created before block {} are unknown, the UTXO set will be incomplete!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        debug!(target: "RealizedCap [on_block]", "Block: {}.", block_height);
+        self.block_times.push(block.header.value.timestamp);
+        self.last_height = block_height;
+
+        for tx in block.txs {
+            self.in_count += tx.value.in_count.value;
+            self.out_count += tx.value.out_count.value;
+
+            // Transaction inputs
+            for input in &tx.value.inputs {
+                self.utxo_set.remove(&input.outpoint);
+                self.spam_outputs.remove(&input.outpoint);
+            }
+
+            let is_coinbase = self.exclude_immature && tx.value.is_coinbase();
+            let mut coinbase_outpoints = Vec::new();
+
+            // Transaction outputs
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                // OP_RETURN outputs are unspendable and never part of the UTXO set
+                if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                    continue;
+                }
+                let tx_outpoint = TxOutpoint {
+                    txid: tx.hash,
+                    index: i as u32,
+                };
+                if is_coinbase {
+                    coinbase_outpoints.push(tx_outpoint.clone());
+                }
+                if let Some(ref mut filter) = self.spam_filter {
+                    if filter.exclude(output) {
+                        self.spam_outputs.insert(tx_outpoint.clone());
+                    }
+                }
+                self.utxo_set
+                    .insert(tx_outpoint, (output.out.value, block_height));
+            }
+            if is_coinbase {
+                self.recent_coinbase
+                    .push_back((block_height, coinbase_outpoints));
+            }
+        }
+        // Older coinbase outputs are mature for every later block
+        while let Some(&(height, _)) = self.recent_coinbase.front() {
+            if !self.coin_type.is_coinbase_mature(height, block_height) {
+                break;
+            }
+            self.recent_coinbase.pop_front();
+        }
+        self.tx_count += block.tx_count.value;
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+        if self.exclude_immature {
+            let (count, value) = self.remove_immature_coinbase();
+            info!(target: "RealizedCap [on_complete]", "Excluded {} immature coinbase outputs worth {:.8} ({} units).",
+                  count, value as f64 * 1E-8, value);
+        }
+
+        // Rows are written straight from the UTXO set to avoid another copy in memory
+        let (mut n_spam, mut spam_value) = (0, 0);
+        for (outpoint, &(value, height)) in &self.utxo_set {
+            if self.spam_outputs.contains(outpoint) {
+                n_spam += 1;
+                spam_value = value.saturating_add(spam_value);
+                continue;
+            }
+            let row = if self.with_timestamp {
+                let timestamp = self.block_times[height - self.start_height];
+                format!("{};{};{}\n", value, writer::row_height(height), timestamp)
+            } else {
+                format!("{};{}\n", value, writer::row_height(height))
+            };
+            self.writer.write_all(row.as_bytes()).unwrap();
+        }
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("realizedcap.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "realizedcap-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "RealizedCap [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        if let Some(ref filter) = self.spam_filter {
+            info!(target: "RealizedCap [on_complete]", "Excluded {} unspent spam outputs worth {:.8} ({} units), {} were created in total.",
+                  n_spam, spam_value as f64 * 1E-8, spam_value, filter.n_excluded);
+        }
+        info!(target: "RealizedCap [on_complete]", "Done.\nDumped {} unspent outputs of {} blocks:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}",
+             self.utxo_set.len() - n_spam, self.end_height + 1, self.tx_count, self.in_count, self.out_count);
+    }
+
+    // Evicting outputs would misstate the realized cap, so there is no reduce_memory
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, (u64, usize))>(self.utxo_set.len())
+            + memory::hash_table_bytes::<TxOutpoint>(self.spam_outputs.len())
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut columns = vec![
+            ("value", ColumnType::BigInt),
+            ("creation_height", ColumnType::Integer),
+        ];
+        if self.with_timestamp {
+            columns.push(("creation_timestamp", ColumnType::BigInt));
+        }
+        vec![Table::new(
+            "realizedcap",
+            "realizedcap-<start_height>-<end_height>.csv",
+            &columns,
+        )]
+    }
+}
@@ -0,0 +1,255 @@
+//! Feeds every callback blocks with structurally valid but unusual transactions, none of which may cause a panic.
+//! Blk files are untrusted input, so a callback has to skip what it can't make sense of instead of aborting the scan.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use callbacks::addressreuse::AddressReuse;
+use callbacks::blockstats::BlockStats;
+use callbacks::burndump::BurnDump;
+use callbacks::clusterizer::Clusterizer;
+use callbacks::coinbasedump::CoinbaseDump;
+use callbacks::coinjoindump::CoinJoinDump;
+use callbacks::cpfpdump::CpfpDump;
+use callbacks::csvdump::CsvDump;
+use callbacks::firstpaymentdump::FirstPaymentDump;
+use callbacks::fragmentation::Fragmentation;
+use callbacks::headerdump::HeaderDump;
+use callbacks::outputcountdump::OutputCountDump;
+use callbacks::protocoldump::ProtocolDump;
+use callbacks::realizedcap::RealizedCap;
+use callbacks::scripthashdump::ScriptHashDump;
+use callbacks::spenddump::SpendDump;
+use callbacks::stats::SimpleStats;
+use callbacks::taprootadoption::TaprootAdoption;
+use callbacks::txdepthdump::TxDepthDump;
+use callbacks::txodump::TXODump;
+use callbacks::utxodelta::UTXODelta;
+use callbacks::utxodump::UTXODump;
+use callbacks::verifysnapshot::VerifySnapshot;
+use callbacks::versionrolling::VersionRolling;
+use callbacks::watchaddresses::WatchAddresses;
+use callbacks::Callback;
+use common::testdir;
+use common::writer;
+
+use blockchain::parser::types::{Bitcoin, CoinType};
+use blockchain::proto::block::Block;
+use blockchain::proto::fixtures::{block_with, coinbase, input, output, tx, txid};
+use blockchain::utils;
+
+/// Address of the P2PKH output of the first block, which is watched by `watchaddresses`
+const WATCHED_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+/// Blocks whose transactions are parseable, but break the assumptions of valid consensus data
+fn unusual_blocks() -> Vec<Block> {
+    let mut p2pkh = utils::hex_to_vec("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+    let mut p2tr = vec![0x51, 0x20];
+    p2tr.extend_from_slice(&[7; 32]);
+    let first_coinbase = coinbase(
+        vec![0x03, 0x00, 0x00, 0x00],
+        vec![
+            output(50 * 100000000, p2pkh.clone()),
+            output(u64::max_value(), p2tr.clone()),
+            output(u64::max_value(), vec![0x6a, 0x04, 0x6f, 0x6d, 0x6e, 0x69]),
+            output(0, Vec::new()),
+            output(1, vec![0x4c]),             // OP_PUSHDATA1 without length
+            output(1, vec![0x4e, 0xff, 0xff]), // OP_PUSHDATA4 with a truncated length
+            output(1, vec![0x51, 0xae]),       // 1-of-0 bare multisig
+            output(1, vec![0x00, 0x05, 1, 2, 3, 4, 5]), // witness v0 program of a wrong length
+        ],
+    );
+    let funding = txid(&first_coinbase);
+
+    // No coinbase at all and an empty block
+    let empty = block_with(1, [1; 32], Vec::new());
+
+    let mut control_block = vec![0xc0; 33];
+    control_block.extend_from_slice(&[0; 32]);
+    let spends = vec![
+        // Non-coinbase first transaction, spending outputs which don't exist
+        tx(
+            2,
+            vec![
+                input(funding, 99, Vec::new(), Vec::new()),
+                input([9; 32], 0, Vec::new(), Vec::new()),
+            ],
+            vec![output(1, p2pkh.clone())],
+            0xFFFFFFFF,
+        ),
+        // Spends the huge outputs, the watched address and the same outpoint twice
+        tx(
+            0xFFFFFFFF,
+            vec![
+                input(funding, 0, vec![0x4c], Vec::new()),
+                input(funding, 1, Vec::new(), vec![vec![0; 65]]),
+                input(funding, 2, Vec::new(), Vec::new()),
+                input(funding, 3, Vec::new(), Vec::new()),
+                input(funding, 3, Vec::new(), Vec::new()),
+            ],
+            vec![
+                output(u64::max_value(), p2tr.clone()),
+                output(2, Vec::new()),
+            ],
+            499999999,
+        ),
+        // No inputs and no outputs
+        tx(1, Vec::new(), Vec::new(), 0),
+        // A coinbase input in a later transaction
+        coinbase(Vec::new(), vec![output(1, p2pkh.clone())]),
+        // Unusual witnesses: empty items, a lone annex, a script path spend with an empty script
+        tx(
+            2,
+            vec![
+                input(funding, 4, Vec::new(), vec![Vec::new(), Vec::new()]),
+                input(funding, 5, Vec::new(), vec![vec![0x50]]),
+                input(
+                    funding,
+                    6,
+                    Vec::new(),
+                    vec![Vec::new(), control_block, vec![0x50, 1]],
+                ),
+                input(funding, 7, Vec::new(), vec![vec![0; 1000]; 100]),
+            ],
+            vec![output(0, vec![0x6a]), output(1, p2tr)],
+            1,
+        ),
+    ];
+    p2pkh.truncate(3);
+    let last = block_with(
+        0xFFFFFFFF,
+        [2; 32],
+        vec![
+            coinbase(Vec::new(), vec![output(0, p2pkh)]),
+            coinbase(vec![0xff; 100], Vec::new()),
+        ],
+    );
+    vec![
+        block_with(1, [0; 32], vec![first_coinbase]),
+        empty,
+        block_with(0x20000000 | 0x1fffe000, [3; 32], spends),
+        last,
+    ]
+}
+
+/// Runs a callback created from `args` over `blocks`, returns the panic message if it panicked
+fn feed<C: Callback>(args: &[&str], blocks: Vec<Block>) -> Option<String> {
+    let matches = C::build_subcommand().get_matches_from(args);
+    let dump_folder = matches.value_of("dump-folder").map(Path::new);
+    let mut callback = C::new(&matches, dump_folder).unwrap();
+    let n_blocks = blocks.len();
+    let result = panic::catch_unwind(AssertUnwindSafe(move || {
+        callback.on_start(CoinType::from(Bitcoin), 0);
+        for (height, block) in blocks.into_iter().enumerate() {
+            callback.on_block(block, height);
+        }
+        callback.on_complete(n_blocks);
+    }));
+    match result {
+        Ok(()) => None,
+        Err(e) => Some(match e.downcast_ref::<String>() {
+            Some(msg) => msg.clone(),
+            None => e
+                .downcast_ref::<&str>()
+                .map_or(String::from("unknown panic"), |msg| String::from(*msg)),
+        }),
+    }
+}
+
+#[test]
+fn test_callbacks_survive_unusual_blocks() {
+    let _settings = writer::test_defaults();
+    let folder = testdir::create("robustness.test");
+    let dir = folder.to_str().unwrap();
+    let burn_list = folder.join("burn.txt");
+    fs::write(&burn_list, format!("# watched\n{}\n", WATCHED_ADDRESS)).unwrap();
+    let burn_list = burn_list.to_str().unwrap();
+    let snapshot: PathBuf = folder.join("snapshot.csv");
+    fs::write(&snapshot, "").unwrap();
+    let snapshot = snapshot.to_str().unwrap();
+    // clusterizer writes a utxo.csv of its own, which utxodump would load to resume
+    let utxo_folder = folder.join("utxodump");
+    fs::create_dir(&utxo_folder).unwrap();
+    let utxo_dir = utxo_folder.to_str().unwrap();
+
+    let runs: Vec<(&str, fn(&[&str], Vec<Block>) -> Option<String>, Vec<&str>)> = vec![
+        ("addressreuse", feed::<AddressReuse>, vec![dir]),
+        (
+            "blockstats",
+            feed::<BlockStats>,
+            vec![dir, "--fees", "--issuance"],
+        ),
+        (
+            "burndump",
+            feed::<BurnDump>,
+            vec![dir, "--burn-addresses", burn_list],
+        ),
+        ("clusterizer", feed::<Clusterizer>, vec![dir]),
+        ("coinbasedump", feed::<CoinbaseDump>, vec![dir]),
+        ("coinjoindump", feed::<CoinJoinDump>, vec![dir]),
+        ("cpfpdump", feed::<CpfpDump>, vec![dir]),
+        (
+            "csvdump",
+            feed::<CsvDump>,
+            vec![dir, "--with-asm", "--with-wtxid"],
+        ),
+        ("firstpaymentdump", feed::<FirstPaymentDump>, vec![dir]),
+        ("fragmentation", feed::<Fragmentation>, vec![dir]),
+        (
+            "headerdump",
+            feed::<HeaderDump>,
+            vec![dir, "--format", "csv"],
+        ),
+        ("outputcountdump", feed::<OutputCountDump>, vec![dir]),
+        ("protocoldump", feed::<ProtocolDump>, vec![dir]),
+        ("realizedcap", feed::<RealizedCap>, vec![dir]),
+        ("scripthashdump", feed::<ScriptHashDump>, vec![dir]),
+        (
+            "simplestats",
+            feed::<SimpleStats>,
+            vec![
+                "--check-signatures",
+                "--check-tapscripts",
+                "--check-taproot",
+                "--separate-data-carriers",
+            ],
+        ),
+        ("spenddump", feed::<SpendDump>, vec![dir]),
+        ("taprootadoption", feed::<TaprootAdoption>, vec![dir]),
+        ("txdepthdump", feed::<TxDepthDump>, vec![dir]),
+        (
+            "txodump",
+            feed::<TXODump>,
+            vec![
+                dir,
+                "--lifecycle",
+                "--separate-data-carriers",
+                "--resolve-addresses",
+            ],
+        ),
+        ("utxodelta", feed::<UTXODelta>, vec![dir]),
+        ("utxodump", feed::<UTXODump>, vec![utxo_dir]),
+        ("verify-snapshot", feed::<VerifySnapshot>, vec![snapshot]),
+        ("versionrolling", feed::<VersionRolling>, vec![dir]),
+        (
+            "watchaddresses",
+            feed::<WatchAddresses>,
+            vec!["--address", WATCHED_ADDRESS, "--emit", "balances"],
+        ),
+    ];
+    let mut panics = Vec::new();
+    for (name, run, args) in runs {
+        let mut argv = vec![name];
+        argv.extend(args);
+        if let Some(msg) = run(&argv, unusual_blocks()) {
+            panics.push(format!("{}: {}", name, msg));
+        }
+    }
+    fs::remove_dir_all(&folder).unwrap();
+    assert!(
+        panics.is_empty(),
+        "Callbacks panicked:\n{}",
+        panics.join("\n")
+    );
+}
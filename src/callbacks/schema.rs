@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use rustc_serialize::json;
+
+use blockchain::parser::types::CoinType;
+use common::writer;
+use errors::{OpError, OpErrorKind, OpResult};
+
+/// Type of a column in the CSV output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Integer, // Heights and indices which fit into a signed 32 bit integer
+    BigInt,  // Amounts and unsigned 32 bit header/tx fields
+    Hash,    // 32 byte hash as 64 hex characters
+    Real,
+    Text,
+}
+
+/// SQL dialect of the generated DDL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    Postgres,
+    Sqlite,
+}
+
+impl FromStr for Dialect {
+    type Err = OpError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(Dialect::Postgres),
+            "sqlite" => Ok(Dialect::Sqlite),
+            n => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Unknown schema dialect `{}`", n))),
+        }
+    }
+}
+
+impl Dialect {
+    fn type_name(&self, ty: ColumnType) -> &'static str {
+        match (*self, ty) {
+            (Dialect::Postgres, ColumnType::Integer) => "INTEGER",
+            (Dialect::Postgres, ColumnType::BigInt) => "BIGINT",
+            (Dialect::Postgres, ColumnType::Hash) => "CHAR(64)",
+            (Dialect::Postgres, ColumnType::Real) => "DOUBLE PRECISION",
+            (Dialect::Postgres, ColumnType::Text) => "TEXT",
+            (Dialect::Sqlite, ColumnType::Integer) | (Dialect::Sqlite, ColumnType::BigInt) => {
+                "INTEGER"
+            }
+            (Dialect::Sqlite, ColumnType::Real) => "REAL",
+            (Dialect::Sqlite, ColumnType::Hash) | (Dialect::Sqlite, ColumnType::Text) => "TEXT",
+        }
+    }
+}
+
+/// Describes the columns of a CSV file written by a callback
+pub struct Table {
+    pub name: &'static str,
+    pub file: &'static str, // File name pattern, as documented in the README
+    pub columns: Vec<(&'static str, ColumnType)>,
+}
+
+impl Table {
+    pub fn new(
+        name: &'static str,
+        file: &'static str,
+        columns: &[(&'static str, ColumnType)],
+    ) -> Table {
+        Table {
+            name: name,
+            file: file,
+            columns: Vec::from(columns),
+        }
+    }
+
+    /// Generates the `CREATE TABLE` statement for this table
+    pub fn to_ddl(&self, dialect: Dialect) -> String {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|&(name, ty)| format!("    {} {}", name, dialect.type_name(ty)))
+            .collect();
+        format!(
+            "-- Loaded from {}\nCREATE TABLE {} (\n{}\n);\n",
+            self.file,
+            self.name,
+            columns.join(",\n")
+        )
+    }
+}
+
+/// Writes the DDL for all tables into `path`
+pub fn write_ddl(tables: &[Table], dialect: Dialect, path: &Path) -> OpResult<()> {
+    let mut file = File::create(path)?;
+    let delimiter = match writer::delimiter() {
+        b'\t' => String::from("tab"),
+        c => format!("`{}`", c as char),
+    };
+    writeln!(file, "-- Columns are separated by {}", delimiter)?;
+    for table in tables {
+        write!(file, "\n{}", table.to_ddl(dialect))?;
+    }
+    Ok(())
+}
+
+/// Describes the written files for consumers which don't know the selected coin
+#[derive(RustcEncodable)]
+struct Manifest {
+    coin: String,
+    unit: String, // Smallest unit of the coin, all values and fees are integers in this unit
+    decimals: u8, // One coin equals 10^decimals units
+    tables: Vec<ManifestTable>,
+}
+
+#[derive(RustcEncodable)]
+struct ManifestTable {
+    name: String,
+    file: String,
+    columns: Vec<String>,
+}
+
+impl Manifest {
+    fn new(coin: &CoinType, tables: &[Table]) -> Manifest {
+        Manifest {
+            coin: coin.name.clone(),
+            unit: String::from(coin.unit_name),
+            decimals: coin.decimals,
+            tables: tables
+                .iter()
+                .map(|table| ManifestTable {
+                    name: String::from(table.name),
+                    file: String::from(table.file),
+                    columns: table
+                        .columns
+                        .iter()
+                        .map(|&(c, _)| String::from(c))
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Writes a JSON manifest with the value unit of `coin` and all tables into `path`
+pub fn write_manifest(coin: &CoinType, tables: &[Table], path: &Path) -> OpResult<()> {
+    let mut file = File::create(path)?;
+    writeln!(
+        file,
+        "{}",
+        json::as_pretty_json(&Manifest::new(coin, tables))
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColumnType, Dialect, Manifest, Table};
+    use blockchain::parser::types::{CoinType, Litecoin};
+    use rustc_serialize::json;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_table_to_ddl() {
+        let table = Table::new(
+            "coinbase",
+            "coinbase-<start_height>-<end_height>.csv",
+            &[
+                ("txid", ColumnType::Hash),
+                ("height", ColumnType::Integer),
+                ("value", ColumnType::BigInt),
+                ("address", ColumnType::Text),
+            ],
+        );
+        assert_eq!(
+            table.to_ddl(Dialect::Postgres),
+            "-- Loaded from coinbase-<start_height>-<end_height>.csv\n\
+             CREATE TABLE coinbase (\n    txid CHAR(64),\n    height INTEGER,\n    \
+             value BIGINT,\n    address TEXT\n);\n"
+        );
+        assert_eq!(
+            table.to_ddl(Dialect::Sqlite),
+            "-- Loaded from coinbase-<start_height>-<end_height>.csv\n\
+             CREATE TABLE coinbase (\n    txid TEXT,\n    height INTEGER,\n    \
+             value INTEGER,\n    address TEXT\n);\n"
+        );
+        assert!(Dialect::from_str("mysql").is_err());
+    }
+
+    #[test]
+    fn test_manifest_encode() {
+        let tables = [Table::new(
+            "burns",
+            "burns-<start_height>-<end_height>.csv",
+            &[
+                ("height", ColumnType::Integer),
+                ("value", ColumnType::BigInt),
+            ],
+        )];
+        let manifest = Manifest::new(&CoinType::from(Litecoin), &tables);
+        assert_eq!(
+            json::encode(&manifest).unwrap(),
+            "{\"coin\":\"Litecoin\",\"unit\":\"litoshi\",\"decimals\":8,\"tables\":[{\"name\":\"burns\",\
+             \"file\":\"burns-<start_height>-<end_height>.csv\",\"columns\":[\"height\",\"value\"]}]}"
+        );
+    }
+}
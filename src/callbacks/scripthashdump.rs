@@ -0,0 +1,153 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::utils::{arr_to_hex_swapped, sha256};
+
+/// Dumps the Electrum scripthash of every output, which is how Electrum servers index address histories.
+/// Unlike addresses, a scripthash exists for any pubkey script, standard or not.
+pub struct ScriptHashDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+
+    start_height: usize,
+    end_height: usize,
+    out_count: u64,
+}
+
+/// SHA256 of the pubkey script in reversed hex, as used by the Electrum protocol
+pub fn script_hash(script_pubkey: &[u8]) -> String {
+    arr_to_hex_swapped(&sha256(script_pubkey))
+}
+
+impl Callback for ScriptHashDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("scripthashdump")
+            .about("Dumps the Electrum scripthash and value of all outputs into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(_: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        match (|| -> OpResult<Self> {
+            let mut cb = ScriptHashDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("scripthashes.csv.tmp"))?,
+                start_height: 0,
+                end_height: 0,
+                out_count: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize ScriptHashDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "ScriptHashDump [on_start]", "Using `ScriptHashDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+    }
+
+    fn on_block(&mut self, block: Block, _: usize) {
+        for tx in &block.txs {
+            let txid = arr_to_hex_swapped(&tx.hash);
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                // Electrum servers don't index OP_RETURN outputs, they can't be spent
+                if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                    continue;
+                }
+                let row = format!(
+                    "{};{};{};{}\n",
+                    script_hash(&output.out.script_pubkey),
+                    txid,
+                    i,
+                    output.out.value
+                );
+                self.writer.write_all(row.as_bytes()).unwrap();
+                self.out_count += 1;
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("scripthashes.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "scripthashes-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "ScriptHashDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "ScriptHashDump [on_complete]", "Done.\nDumped the scripthashes of {} outputs.", self.out_count);
+    }
+
+    fn allows_tx_filter(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "scripthashes",
+            "scripthashes-<start_height>-<end_height>.csv",
+            &[
+                ("scripthash", ColumnType::Hash),
+                ("txid", ColumnType::Hash),
+                ("indexOut", ColumnType::Integer),
+                ("value", ColumnType::BigInt),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::script_hash;
+    use blockchain::utils;
+
+    #[test]
+    fn test_script_hash() {
+        // P2PKH of 1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa, the example of the Electrum protocol docs
+        let script = utils::hex_to_vec("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac");
+        assert_eq!(
+            "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161",
+            script_hash(&script)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::EvaluatedTxOut;
+use blockchain::utils;
+use errors::{OpError, OpErrorKind, OpResult};
+
+/// Outputs of a known spam campaign: `<pattern>;<value>[;<script prefix>]`.
+/// The pattern is a name as listed by simplestats, e.g. `Pay2MultiSig` or `DataOutput` for OP_RETURN,
+/// the value is given in units and the optional prefix as hex of the pubkey script. `*` matches anything.
+#[derive(Debug, PartialEq)]
+pub struct SpamSignature {
+    pattern: Option<ScriptPattern>,
+    value: Option<u64>,
+    script_prefix: Vec<u8>,
+}
+
+impl FromStr for SpamSignature {
+    type Err = OpError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| {
+            OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Invalid spam signature `{}`: {}", s, msg))
+        };
+        let fields: Vec<&str> = s.split(';').map(|f| f.trim()).collect();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(invalid("expected <pattern>;<value>[;<script prefix>]"));
+        }
+        let pattern = match fields[0] {
+            "*" => None,
+            "DataOutput" => Some(ScriptPattern::DataOutput(String::new())),
+            "Pay2MultiSig" => Some(ScriptPattern::Pay2MultiSig),
+            "Pay2PublicKey" => Some(ScriptPattern::Pay2PublicKey),
+            "Pay2PublicKeyHash" => Some(ScriptPattern::Pay2PublicKeyHash),
+            "Pay2ScriptHash" => Some(ScriptPattern::Pay2ScriptHash),
+            "Pay2WitnessPublicKeyHash" => Some(ScriptPattern::Pay2WitnessPublicKeyHash),
+            "Pay2WitnessScriptHash" => Some(ScriptPattern::Pay2WitnessScriptHash),
+            "Pay2Taproot" => Some(ScriptPattern::Pay2Taproot),
+            "NotRecognised" => Some(ScriptPattern::NotRecognised),
+            _ => return Err(invalid("unknown pattern")),
+        };
+        let value = match fields[1] {
+            "*" => None,
+            value => match value.parse::<u64>() {
+                Ok(value) => Some(value),
+                Err(_) => return Err(invalid("the value must be a number of units")),
+            },
+        };
+        let script_prefix = match fields.get(2) {
+            None | Some(&"*") => Vec::new(),
+            Some(prefix) => {
+                if prefix.len() % 2 != 0 || !prefix.chars().all(|c| c.is_digit(16)) {
+                    return Err(invalid("the script prefix must be hex encoded"));
+                }
+                utils::hex_to_vec(prefix)
+            }
+        };
+        Ok(SpamSignature {
+            pattern: pattern,
+            value: value,
+            script_prefix: script_prefix,
+        })
+    }
+}
+
+impl SpamSignature {
+    #[inline]
+    fn matches(&self, output: &EvaluatedTxOut) -> bool {
+        let pattern_matches = match (&self.pattern, &output.script.pattern) {
+            (&None, _) => true,
+            // The pushed data differs between outputs of the same campaign
+            (&Some(ScriptPattern::DataOutput(_)), &ScriptPattern::DataOutput(_)) => true,
+            (&Some(ref pattern), actual) => pattern == actual,
+        };
+        pattern_matches
+            && self.value.map_or(true, |value| value == output.out.value)
+            && output.out.script_pubkey.starts_with(&self.script_prefix)
+    }
+}
+
+/// Signatures loaded with `--spam-filter`, along with the number and value of the outputs they matched
+pub struct SpamFilter {
+    signatures: Vec<SpamSignature>,
+    pub n_excluded: u64,
+    pub excluded_value: u64,
+}
+
+impl SpamFilter {
+    /// Reads one signature per line, empty lines and lines starting with `#` are ignored
+    pub fn load(path: &Path) -> OpResult<SpamFilter> {
+        let mut signatures = Vec::new();
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match SpamSignature::from_str(line) {
+                Ok(signature) => signatures.push(signature),
+                Err(e) => return Err(tag_err!(e, " in {:?} line {}", path, i + 1)),
+            }
+        }
+        if signatures.is_empty() {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("No spam signatures found in {:?}", path)));
+        }
+        Ok(SpamFilter {
+            signatures: signatures,
+            n_excluded: 0,
+            excluded_value: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    /// Returns true if `output` matches a signature and counts it as excluded
+    #[inline]
+    pub fn exclude(&mut self, output: &EvaluatedTxOut) -> bool {
+        if !self.signatures.iter().any(|s| s.matches(output)) {
+            return false;
+        }
+        self.n_excluded += 1;
+        self.excluded_value += output.out.value;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpamSignature;
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::tx::{EvaluatedTxOut, TxOutput};
+    use blockchain::proto::varuint::VarUint;
+    use blockchain::utils;
+    use std::str::FromStr;
+
+    fn output(value: u64, script: &str) -> EvaluatedTxOut {
+        let script_pubkey = utils::hex_to_vec(script);
+        let out = TxOutput {
+            value: value,
+            script_len: VarUint::from(script_pubkey.len() as u64),
+            script_pubkey: script_pubkey,
+        };
+        EvaluatedTxOut::eval_script(out, &CoinType::from(Bitcoin))
+    }
+
+    #[test]
+    fn test_spam_signature() {
+        let p2pkh = output(1, "76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        let op_return = output(0, "6a0461626364");
+
+        let signature = SpamSignature::from_str("Pay2PublicKeyHash;1").unwrap();
+        assert!(signature.matches(&p2pkh));
+        assert!(!signature.matches(&op_return));
+        assert!(!SpamSignature::from_str("Pay2PublicKeyHash;2")
+            .unwrap()
+            .matches(&p2pkh));
+
+        // The pushed data of OP_RETURN outputs is ignored, unless a script prefix is given
+        assert!(SpamSignature::from_str("DataOutput;*")
+            .unwrap()
+            .matches(&op_return));
+        assert!(SpamSignature::from_str("*;0;6a0461")
+            .unwrap()
+            .matches(&op_return));
+        assert!(!SpamSignature::from_str("*;0;6a0462")
+            .unwrap()
+            .matches(&op_return));
+
+        assert!(SpamSignature::from_str("Pay2Nothing;1").is_err());
+        assert!(SpamSignature::from_str("Pay2PublicKeyHash;0.1").is_err());
+        assert!(SpamSignature::from_str("Pay2PublicKeyHash;1;abc").is_err());
+        assert!(SpamSignature::from_str("Pay2PublicKeyHash").is_err());
+    }
+}
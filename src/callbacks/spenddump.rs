@@ -0,0 +1,172 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Returns the rows of all spends within `block` in transaction and input order, coinbase inputs spend nothing
+fn spend_rows(block: &Block, block_height: usize) -> Vec<String> {
+    let mut rows = Vec::new();
+    for tx in block.txs.iter().filter(|tx| !tx.value.is_coinbase()) {
+        let spending_txid = arr_to_hex_swapped(&tx.hash);
+        for input in &tx.value.inputs {
+            rows.push(format!(
+                "{};{};{};{}\n",
+                writer::row_height(block_height),
+                arr_to_hex_swapped(&input.outpoint.txid),
+                input.outpoint.index,
+                spending_txid
+            ));
+        }
+    }
+    rows
+}
+
+/// Dumps every spent outpoint along with the spending transaction, the join table between funding and spending transactions.
+/// Unlike `txodump` it needs no values and thus no UTXO set, so it is cheap and keeps no state between blocks.
+pub struct SpendDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+
+    start_height: usize,
+    end_height: usize,
+    n_spends: u64,
+}
+
+impl Callback for SpendDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("spenddump")
+            .about("Dumps each spent outpoint and the transaction spending it into a CSV file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(_: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        match (|| -> OpResult<Self> {
+            let mut cb = SpendDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("spends.csv.tmp"))?,
+                start_height: 0,
+                end_height: 0,
+                n_spends: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize SpendDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "SpendDump [on_start]", "Using `SpendDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        for row in spend_rows(&block, block_height) {
+            self.writer.write_all(row.as_bytes()).unwrap();
+            self.n_spends += 1;
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("spends.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "spends-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "SpendDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "SpendDump [on_complete]", "Done.\nDumped {} spends.", self.n_spends);
+    }
+
+    fn allows_block_sampling(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "spends",
+            "spends-<start_height>-<end_height>.csv",
+            &[
+                ("spend_height", ColumnType::Integer),
+                ("funding_txid", ColumnType::Hash),
+                ("funding_index", ColumnType::BigInt),
+                ("spending_txid", ColumnType::Hash),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spend_rows;
+    use blockchain::proto::fixtures::{self, block, output, spend};
+    use blockchain::proto::tx::Tx;
+    use blockchain::utils::arr_to_hex_swapped;
+
+    fn tx(outpoints: &[([u8; 32], u32)]) -> Tx {
+        let inputs = outpoints
+            .iter()
+            .map(|&(txid, index)| spend(txid, index))
+            .collect();
+        fixtures::tx(1, inputs, vec![output(50, Vec::new())], 0)
+    }
+
+    #[test]
+    fn test_spend_rows() {
+        let coinbase = tx(&[([0; 32], 0xFFFFFFFF)]);
+        let spending = tx(&[([1; 32], 3), ([2; 32], 0)]);
+        let block = block(vec![coinbase, spending]);
+
+        let spending_txid = arr_to_hex_swapped(&block.txs[1].hash);
+        let expected: Vec<String> = [([1u8; 32], 3), ([2u8; 32], 0)]
+            .iter()
+            .map(|&(txid, index)| {
+                format!(
+                    "5;{};{};{}\n",
+                    arr_to_hex_swapped(&txid),
+                    index,
+                    spending_txid
+                )
+            })
+            .collect();
+        assert_eq!(expected, spend_rows(&block, 5));
+    }
+}
@@ -1,22 +1,61 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::BuildHasherDefault;
 use std::io::Write;
+use std::path::Path;
 
-use clap::{App, ArgMatches, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use twox_hash::XxHash;
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::{self, Block};
 use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::signature;
+use blockchain::proto::taproot::{self, TaprootSpend, TaprootWitness, TapscriptMultisig};
+use blockchain::proto::tx::{LockTime, Tx, TxOutpoint};
 use blockchain::utils;
 
+use callbacks::spam::SpamFilter;
 use callbacks::Callback;
-use errors::OpResult;
+use common::memory;
+use errors::{OpError, OpErrorKind, OpResult};
+
+/// Default of --taproot-interval, about two weeks of blocks
+const DEFAULT_TAPROOT_INTERVAL: usize = 2016;
+
+/// Taproot spends within an interval of blocks
+#[derive(Default, Debug, PartialEq)]
+struct TaprootUsage {
+    n_key_path: u64,
+    n_script_path: u64,
+    n_annex: u64,
+    /// Key path spends by sighash type, None for the implicit SIGHASH_DEFAULT
+    n_sighash: BTreeMap<Option<u8>, u64>,
+}
+
+impl TaprootUsage {
+    fn add(&mut self, witness: &TaprootWitness) {
+        match witness.spend {
+            TaprootSpend::KeyPath(sig) => {
+                self.n_key_path += 1;
+                *self
+                    .n_sighash
+                    .entry(taproot::sighash_type(sig))
+                    .or_insert(0) += 1;
+            }
+            TaprootSpend::ScriptPath { .. } => self.n_script_path += 1,
+        }
+        if witness.annex.is_some() {
+            self.n_annex += 1;
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct SimpleStats {
     //X coin_type: CoinType,
     n_valid_blocks: u64,
+    n_stale_blocks: u64,     // Skipped by the dispatcher, see on_skipped_blocks
+    n_duplicate_blocks: u64, // Skipped by the dispatcher, see on_skipped_blocks
     block_sizes: Vec<u32>,
 
     n_tx: u64,
@@ -24,6 +63,14 @@ pub struct SimpleStats {
     n_tx_outputs: u64,
     n_tx_total_fee: u64,
     n_tx_total_volume: u64,
+    /// Transactions with only OP_RETURN outputs
+    n_tx_data_carriers: u64,
+    /// Fees paid by data carriers, only collected with --separate-data-carriers
+    n_tx_data_carrier_fees: u64,
+    /// Data carriers spending outputs which are not in `utxo_values`
+    n_tx_data_carriers_unknown_fee: u64,
+    /// Value and height of all unspent outputs, only kept with --separate-data-carriers
+    utxo_values: Option<HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>>>,
 
     /// Largest transaction (value, height, txid)
     tx_largest: (u64, usize, [u8; 32]),
@@ -33,9 +80,47 @@ pub struct SimpleStats {
     /// (block_height, txid)
     tx_first_occs: HashMap<ScriptPattern, (usize, [u8; 32], u32), BuildHasherDefault<XxHash>>,
 
+    /// Transactions with a block height [0] and unix time [1] locktime
+    n_tx_locktime: [u64; 2],
+    /// Of those, the ones with only final inputs which don't enforce the locktime
+    n_tx_locktime_unenforced: [u64; 2],
+
+    /// Transactions per version (count, first and last block_height, first txid).
+    /// Version 2 enabled relative locktimes [BIP68], so the switch from version 1 tracks its adoption.
+    n_tx_versions: BTreeMap<i32, (u64, usize, usize, [u8; 32])>,
+
     /// Time stats
     t_between_blocks: Vec<u32>,
     last_timestamp: u32,
+
+    /// Signature encoding stats, only collected with --check-signatures
+    check_signatures: bool,
+    list_signature_txids: bool,
+    bip66_height: Option<usize>,
+    n_sigs: u64,
+    /// Counts before [0] and after [1] BIP66 activation
+    n_sigs_non_der: [u64; 2],
+    n_sigs_high_s: [u64; 2],
+    /// Transactions with non strict DER signatures (block_height, txid)
+    non_der_txids: Vec<(usize, [u8; 32])>,
+
+    /// Tapscript stats, only collected with --check-tapscripts
+    check_tapscripts: bool,
+    list_tapscript_multisig: bool,
+    n_script_path_spends: u64,
+    /// Multisig tapscripts by (k, n)
+    n_tapscript_multisig: BTreeMap<(usize, usize), u64>,
+    /// Revealed multisig tapscripts (block_height, txid, input index, script)
+    tapscript_multisigs: Vec<(usize, [u8; 32], usize, TapscriptMultisig)>,
+
+    /// Taproot spend stats, only collected with --check-taproot
+    check_taproot: bool,
+    taproot_interval: usize,
+    /// Usage by the first block height of each interval
+    taproot_usage: BTreeMap<usize, TaprootUsage>,
+
+    /// Outputs matching a known spam signature are left out of the output, volume and type stats
+    spam_filter: Option<SpamFilter>,
 }
 
 impl SimpleStats {
@@ -62,6 +147,259 @@ impl SimpleStats {
             *counter += 1;
         }
     }
+
+    /// Counts the version of a transaction, which is signed in Bitcoin Core
+    #[inline]
+    fn process_tx_version(&mut self, tx: &Tx, block_height: usize, txid: [u8; 32]) {
+        let first_seen = (0, block_height, block_height, txid);
+        let entry = self
+            .n_tx_versions
+            .entry(tx.tx_version as i32)
+            .or_insert(first_seen);
+        entry.0 += 1;
+        entry.2 = block_height;
+    }
+
+    /// Checks the encoding of all input signatures.
+    /// Signatures in the witness are not covered.
+    fn process_tx_signatures(&mut self, tx: &Tx, block_height: usize, txid: [u8; 32]) {
+        let era = match self.bip66_height {
+            Some(height) if block_height >= height => 1,
+            _ => 0,
+        };
+        let mut has_non_der = false;
+        for input in &tx.inputs {
+            for sig in signature::extract_signatures(&input.script_sig) {
+                self.n_sigs += 1;
+                if !signature::is_strict_der(&sig) {
+                    self.n_sigs_non_der[era] += 1;
+                    has_non_der = true;
+                } else if !signature::is_low_s(&sig) {
+                    self.n_sigs_high_s[era] += 1;
+                }
+            }
+        }
+        if has_non_der && self.list_signature_txids {
+            self.non_der_txids.push((block_height, txid));
+        }
+    }
+
+    /// Decodes the revealed tapscripts of all script path spends
+    fn process_tx_tapscripts(&mut self, tx: &Tx, block_height: usize, txid: [u8; 32]) {
+        for (i, input) in tx.inputs.iter().enumerate() {
+            let script = match TaprootWitness::decode(&input.witness) {
+                Some(TaprootWitness {
+                    spend: TaprootSpend::ScriptPath { script, .. },
+                    ..
+                }) => script,
+                _ => continue,
+            };
+            self.n_script_path_spends += 1;
+            if let Some(multisig) = TapscriptMultisig::from_script(script) {
+                *self
+                    .n_tapscript_multisig
+                    .entry((multisig.required, multisig.pubkeys.len()))
+                    .or_insert(0) += 1;
+                if self.list_tapscript_multisig {
+                    self.tapscript_multisigs
+                        .push((block_height, txid, i, multisig));
+                }
+            }
+        }
+    }
+
+    /// Counts the spend path, annex and sighash type of all taproot inputs
+    fn process_tx_taproot(&mut self, tx: &Tx, block_height: usize) {
+        for input in &tx.inputs {
+            let witness = match TaprootWitness::decode(&input.witness) {
+                Some(witness) => witness,
+                None => continue,
+            };
+            let interval_start = block_height - block_height % self.taproot_interval;
+            self.taproot_usage
+                .entry(interval_start)
+                .or_insert_with(Default::default)
+                .add(&witness);
+        }
+    }
+
+    /// Appends the locktime stats to the report
+    fn write_locktime_stats(&self, buffer: &mut Vec<u8>) {
+        writeln!(buffer, "Locktime:").unwrap();
+        let n_none = self.n_tx - self.n_tx_locktime[0] - self.n_tx_locktime[1];
+        writeln!(
+            buffer,
+            "   -> no locktime:\t\t{} ({:.2}%)",
+            n_none,
+            (n_none as f64 / self.n_tx as f64) * 100.00
+        )
+        .unwrap();
+        for (i, name) in ["block height", "unix time"].iter().enumerate() {
+            writeln!(
+                buffer,
+                "   -> {}:\t\t{} ({:.2}%), {} not enforced",
+                name,
+                self.n_tx_locktime[i],
+                (self.n_tx_locktime[i] as f64 / self.n_tx as f64) * 100.00,
+                self.n_tx_locktime_unenforced[i]
+            )
+            .unwrap();
+        }
+        writeln!(buffer, "").unwrap();
+    }
+
+    /// Appends the transaction version stats to the report
+    fn write_version_stats(&self, buffer: &mut Vec<u8>) {
+        writeln!(buffer, "Transaction Versions:").unwrap();
+        for (&version, &(count, first_height, last_height, ref txid)) in &self.n_tx_versions {
+            // Version 3 (TRUC) is standard since Bitcoin Core 28.0, all others are anomalies
+            let nonstandard = version < 1 || version > 3;
+            writeln!(
+                buffer,
+                "   -> version {}:\t\t{} ({:.2}%), blocks #{} to #{}{}",
+                version,
+                count,
+                (count as f64 / self.n_tx as f64) * 100.00,
+                first_height,
+                last_height,
+                if nonstandard { ", nonstandard" } else { "" }
+            )
+            .unwrap();
+            if nonstandard {
+                writeln!(
+                    buffer,
+                    "        first seen in block #{}, txid: {}",
+                    first_height,
+                    utils::arr_to_hex_swapped(txid)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(buffer, "").unwrap();
+    }
+
+    /// Appends the signature encoding stats to the report
+    fn write_signature_stats(&self, buffer: &mut Vec<u8>) {
+        writeln!(buffer, "Signatures:").unwrap();
+        writeln!(buffer, "   -> checked signatures:\t{}", self.n_sigs).unwrap();
+        match self.bip66_height {
+            Some(height) => {
+                // Violations before the activation were valid at the time
+                writeln!(
+                    buffer,
+                    "   -> non strict DER:\t\t{} before BIP66 (block #{}), {} after",
+                    self.n_sigs_non_der[0], height, self.n_sigs_non_der[1]
+                )
+                .unwrap();
+                writeln!(
+                    buffer,
+                    "   -> high S:\t\t\t{} before BIP66 (block #{}), {} after",
+                    self.n_sigs_high_s[0], height, self.n_sigs_high_s[1]
+                )
+                .unwrap();
+            }
+            None => {
+                writeln!(
+                    buffer,
+                    "   -> non strict DER:\t\t{} (BIP66 activation unknown)",
+                    self.n_sigs_non_der[0]
+                )
+                .unwrap();
+                writeln!(buffer, "   -> high S:\t\t\t{}", self.n_sigs_high_s[0]).unwrap();
+            }
+        }
+        for &(height, ref txid) in &self.non_der_txids {
+            writeln!(
+                buffer,
+                "        non strict DER in block #{}, txid: {}",
+                height,
+                utils::arr_to_hex_swapped(txid)
+            )
+            .unwrap();
+        }
+        writeln!(buffer, "").unwrap();
+    }
+
+    /// Appends the tapscript stats to the report
+    fn write_tapscript_stats(&self, buffer: &mut Vec<u8>) {
+        writeln!(buffer, "Tapscripts:").unwrap();
+        writeln!(
+            buffer,
+            "   -> script path spends:\t{}",
+            self.n_script_path_spends
+        )
+        .unwrap();
+        for (&(k, n), count) in &self.n_tapscript_multisig {
+            writeln!(buffer, "   -> {}-of-{} multisig:\t\t{}", k, n, count).unwrap();
+        }
+        for &(height, ref txid, index, ref multisig) in &self.tapscript_multisigs {
+            let pubkeys: Vec<String> = multisig
+                .pubkeys
+                .iter()
+                .map(|k| utils::arr_to_hex(k))
+                .collect();
+            writeln!(
+                buffer,
+                "        {}-of-{} multisig in block #{}, txid: {}, input: {}\n            pubkeys: {}",
+                multisig.required,
+                multisig.pubkeys.len(),
+                height,
+                utils::arr_to_hex_swapped(txid),
+                index,
+                pubkeys.join(", ")
+            )
+            .unwrap();
+        }
+        writeln!(buffer, "").unwrap();
+    }
+
+    /// Appends the taproot stats of each interval to the report
+    fn write_taproot_stats(&self, buffer: &mut Vec<u8>) {
+        writeln!(buffer, "Taproot spends:").unwrap();
+        let mut total = TaprootUsage::default();
+        for (&start, usage) in &self.taproot_usage {
+            writeln!(
+                buffer,
+                "   -> blocks #{} to #{}:\tkey path {}, script path {}, with annex {}",
+                start,
+                start + self.taproot_interval - 1,
+                usage.n_key_path,
+                usage.n_script_path,
+                usage.n_annex
+            )
+            .unwrap();
+            let sighashes: Vec<String> = usage
+                .n_sighash
+                .iter()
+                .map(|(&sighash, count)| format!("{} {}", taproot::sighash_name(sighash), count))
+                .collect();
+            writeln!(buffer, "        key path sighash: {}", sighashes.join(", ")).unwrap();
+
+            total.n_key_path += usage.n_key_path;
+            total.n_script_path += usage.n_script_path;
+            total.n_annex += usage.n_annex;
+            for (&sighash, count) in &usage.n_sighash {
+                *total.n_sighash.entry(sighash).or_insert(0) += count;
+            }
+        }
+        writeln!(
+            buffer,
+            "   -> total:\t\t\tkey path {}, script path {}, with annex {}",
+            total.n_key_path, total.n_script_path, total.n_annex
+        )
+        .unwrap();
+        for (&sighash, count) in &total.n_sighash {
+            writeln!(
+                buffer,
+                "   -> sighash {}:\t\t{} ({:.2}%)",
+                taproot::sighash_name(sighash),
+                count,
+                (*count as f64 / total.n_key_path as f64) * 100.00
+            )
+            .unwrap();
+        }
+        writeln!(buffer, "").unwrap();
+    }
 }
 
 impl Callback for SimpleStats {
@@ -73,17 +411,100 @@ impl Callback for SimpleStats {
             .about("Shows various Blockchain stats")
             .version("0.1")
             .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("check-signatures")
+                    .long("check-signatures")
+                    .help("Counts input signatures which are not strict DER encoded or have a high S value"),
+            )
+            .arg(
+                Arg::with_name("list-signature-txids")
+                    .long("list-signature-txids")
+                    .requires("check-signatures")
+                    .help("Lists all transactions with non strict DER signatures"),
+            )
+            .arg(
+                Arg::with_name("check-tapscripts")
+                    .long("check-tapscripts")
+                    .help("Counts taproot script path spends and k-of-n multisig tapscripts"),
+            )
+            .arg(
+                Arg::with_name("list-tapscript-multisig")
+                    .long("list-tapscript-multisig")
+                    .requires("check-tapscripts")
+                    .help("Lists all multisig tapscripts with their public keys"),
+            )
+            .arg(
+                Arg::with_name("check-taproot")
+                    .long("check-taproot")
+                    .help("Counts taproot key and script path spends, annexes and the sighash types of key path signatures"),
+            )
+            .arg(
+                Arg::with_name("taproot-interval")
+                    .long("taproot-interval")
+                    .value_name("BLOCKS")
+                    .requires("check-taproot")
+                    .help("Reports the taproot spends in intervals of BLOCKS blocks (default: 2016)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("spam-filter")
+                    .long("spam-filter")
+                    .value_name("FILE")
+                    .help("Excludes outputs matching the spam signatures in FILE, one `<pattern>;<value>[;<script prefix>]` per line")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("separate-data-carriers")
+                    .long("separate-data-carriers")
+                    .help("Reports the fees of transactions with only OP_RETURN outputs separately from the total tx fees, \
+                           which keeps the value of all unspent outputs in memory"),
+            )
     }
 
-    fn new(_: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches, _: Option<&Path>) -> OpResult<Self>
     where
         Self: Sized,
     {
-        Ok(Default::default())
+        let spam_filter = match matches.value_of("spam-filter") {
+            Some(path) => Some(SpamFilter::load(Path::new(path))?),
+            None => None,
+        };
+        let taproot_interval = match matches.value_of("taproot-interval") {
+            Some(_) => match value_t!(matches, "taproot-interval", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--taproot-interval must be a positive number of blocks"))
+                }
+            },
+            None => DEFAULT_TAPROOT_INTERVAL,
+        };
+        Ok(SimpleStats {
+            spam_filter: spam_filter,
+            check_signatures: matches.is_present("check-signatures"),
+            list_signature_txids: matches.is_present("list-signature-txids"),
+            check_tapscripts: matches.is_present("check-tapscripts"),
+            list_tapscript_multisig: matches.is_present("list-tapscript-multisig"),
+            check_taproot: matches.is_present("check-taproot"),
+            taproot_interval: taproot_interval,
+            utxo_values: if matches.is_present("separate-data-carriers") {
+                Some(Default::default())
+            } else {
+                None
+            },
+            ..Default::default()
+        })
     }
 
-    fn on_start(&mut self, _: CoinType, _: usize) {
+    fn on_start(&mut self, coin_type: CoinType, block_height: usize) {
         info!(target: "callback", "Executing SimpleStats ...");
+        if self.utxo_values.is_some() && block_height > 0 {
+            warn!(target: "SimpleStats [on_start]", "Data carriers spending outputs created before block {} are left out of their fees!", block_height);
+        }
+        if let Some(ref filter) = self.spam_filter {
+            info!(target: "callback", "Excluding outputs matching {} spam signatures.", filter.len());
+        }
+        self.bip66_height = coin_type.bip66_height;
     }
 
     fn on_block(&mut self, block: Block, block_height: usize) {
@@ -94,27 +515,91 @@ impl Callback for SimpleStats {
         for tx in block.txs {
             // Collect fee rewards
             if tx.value.is_coinbase() {
-                self.n_tx_total_fee += tx.value.outputs[0]
-                    .out
-                    .value
-                    .checked_sub(block::get_base_reward(block_height as u64))
-                    .unwrap_or_default();
+                let fee = tx.value.outputs.first().map_or(0, |o| {
+                    o.out
+                        .value
+                        .checked_sub(block::get_base_reward(block_height as u64))
+                        .unwrap_or_default()
+                });
+                self.n_tx_total_fee = self.n_tx_total_fee.saturating_add(fee);
+            }
+
+            if tx.value.is_data_carrier() {
+                self.n_tx_data_carriers += 1;
+            }
+            if let Some(ref mut utxo_values) = self.utxo_values {
+                if tx.value.is_data_carrier() {
+                    match tx.value.get_fees(utxo_values) {
+                        Some(fees) => {
+                            self.n_tx_data_carrier_fees =
+                                self.n_tx_data_carrier_fees.saturating_add(fees)
+                        }
+                        None => self.n_tx_data_carriers_unknown_fee += 1,
+                    }
+                }
+                if !tx.value.is_coinbase() {
+                    for input in &tx.value.inputs {
+                        utxo_values.remove(&input.outpoint);
+                    }
+                }
+                for (i, output) in tx.value.outputs.iter().enumerate() {
+                    // OP_RETURN outputs can't be spent
+                    if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                        continue;
+                    }
+                    let outpoint = TxOutpoint {
+                        txid: tx.hash,
+                        index: i as u32,
+                    };
+                    utxo_values.insert(outpoint, (output.out.value, block_height));
+                }
             }
 
             self.n_tx_inputs += tx.value.in_count.value;
             self.n_tx_outputs += tx.value.out_count.value;
 
+            let kind = match tx.value.locktime() {
+                LockTime::None => None,
+                LockTime::Height(_) => Some(0),
+                LockTime::Time(_) => Some(1),
+            };
+            if let Some(kind) = kind {
+                self.n_tx_locktime[kind] += 1;
+                if !tx.value.is_locktime_enforced() {
+                    self.n_tx_locktime_unenforced[kind] += 1;
+                }
+            }
+
+            self.process_tx_version(&tx.value, block_height, tx.hash);
+
+            if self.check_signatures && !tx.value.is_coinbase() {
+                self.process_tx_signatures(&tx.value, block_height, tx.hash);
+            }
+            if self.check_tapscripts && !tx.value.is_coinbase() {
+                self.process_tx_tapscripts(&tx.value, block_height, tx.hash);
+            }
+            if self.check_taproot && !tx.value.is_coinbase() {
+                self.process_tx_taproot(&tx.value, block_height);
+            }
+
             let mut i = 0;
             let mut tx_value = 0;
             for o in tx.value.outputs {
+                if let Some(ref mut filter) = self.spam_filter {
+                    if filter.exclude(&o) {
+                        self.n_tx_outputs -= 1;
+                        i += 1;
+                        continue;
+                    }
+                }
                 self.process_tx_pattern(o.script.pattern, block_height, tx.hash, i);
-                tx_value += o.out.value;
+                tx_value = o.out.value.saturating_add(tx_value);
                 i += 1;
             }
             if tx_value > self.tx_largest.0 {
                 self.tx_largest = (tx_value, block_height, tx.hash);
             }
-            self.n_tx_total_volume += tx_value;
+            self.n_tx_total_volume = self.n_tx_total_volume.saturating_add(tx_value);
         }
 
         // Save time between blocks
@@ -130,6 +615,11 @@ impl Callback for SimpleStats {
         self.last_timestamp = block.header.value.timestamp;
     }
 
+    fn on_skipped_blocks(&mut self, n_stale_blocks: u64, n_duplicate_blocks: u64) {
+        self.n_stale_blocks = n_stale_blocks;
+        self.n_duplicate_blocks = n_duplicate_blocks;
+    }
+
     fn on_complete(&mut self, _: usize) {
         let mut buffer = Vec::with_capacity(4096);
         {
@@ -140,6 +630,18 @@ impl Callback for SimpleStats {
                 self.n_valid_blocks
             )
             .unwrap();
+            writeln!(
+                &mut buffer,
+                "   -> skipped stale blocks:\t{}",
+                self.n_stale_blocks
+            )
+            .unwrap();
+            writeln!(
+                &mut buffer,
+                "   -> skipped duplicates:\t{}",
+                self.n_duplicate_blocks
+            )
+            .unwrap();
             writeln!(&mut buffer, "   -> total transactions:\t{}", self.n_tx).unwrap();
             writeln!(
                 &mut buffer,
@@ -153,13 +655,34 @@ impl Callback for SimpleStats {
                 self.n_tx_outputs
             )
             .unwrap();
+            // The total fees are derived from the coinbase, data carrier fees are taken out of them
+            let tx_fees = self
+                .n_tx_total_fee
+                .saturating_sub(self.n_tx_data_carrier_fees);
             writeln!(
                 &mut buffer,
                 "   -> total tx fees:\t\t{:.8} ({} units)",
-                self.n_tx_total_fee as f64 * 1E-8,
-                self.n_tx_total_fee
+                tx_fees as f64 * 1E-8,
+                tx_fees
+            )
+            .unwrap();
+            writeln!(
+                &mut buffer,
+                "   -> data carrier txs:\t\t{} ({:.2}%)",
+                self.n_tx_data_carriers,
+                (self.n_tx_data_carriers as f64 / self.n_tx as f64) * 100.00
             )
             .unwrap();
+            if self.utxo_values.is_some() {
+                writeln!(
+                    &mut buffer,
+                    "   -> data carrier fees:\t{:.8} ({} units, {} txs unknown)",
+                    self.n_tx_data_carrier_fees as f64 * 1E-8,
+                    self.n_tx_data_carrier_fees,
+                    self.n_tx_data_carriers_unknown_fee
+                )
+                .unwrap();
+            }
             writeln!(
                 &mut buffer,
                 "   -> total volume:\t\t{:.8} ({} units)",
@@ -167,6 +690,16 @@ impl Callback for SimpleStats {
                 self.n_tx_total_volume
             )
             .unwrap();
+            if let Some(ref filter) = self.spam_filter {
+                writeln!(
+                    &mut buffer,
+                    "   -> excluded spam outputs:\t{} worth {:.8} ({} units)",
+                    filter.n_excluded,
+                    filter.excluded_value as f64 * 1E-8,
+                    filter.excluded_value
+                )
+                .unwrap();
+            }
         }
         writeln!(&mut buffer, "").unwrap();
         {
@@ -246,6 +779,105 @@ impl Callback for SimpleStats {
             )
             .unwrap();
         }
+        self.write_locktime_stats(&mut buffer);
+        self.write_version_stats(&mut buffer);
+        if self.check_signatures {
+            self.write_signature_stats(&mut buffer);
+        }
+        if self.check_tapscripts {
+            self.write_tapscript_stats(&mut buffer);
+        }
+        if self.check_taproot {
+            self.write_taproot_stats(&mut buffer);
+        }
         info!(target: "simplestats", "\n\n{}", String::from_utf8_lossy(&buffer));
     }
+
+    fn memory_usage(&self) -> usize {
+        let n_values = self.utxo_values.as_ref().map_or(0, |values| values.len());
+        memory::hash_table_bytes::<(TxOutpoint, (u64, usize))>(n_values)
+    }
+
+    /// The fees of data carriers need the values of all spent outputs
+    fn allows_tx_filter(&self) -> bool {
+        self.utxo_values.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimpleStats, TaprootUsage};
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::block::get_base_reward;
+    use blockchain::proto::fixtures::{block, coinbase, output, spend, tx, txid};
+    use blockchain::proto::taproot::TaprootWitness;
+    use callbacks::Callback;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_data_carrier_fees() {
+        let p2pk = vec![0x51];
+        let funding = coinbase(
+            Vec::new(),
+            vec![output(100, p2pk.clone()), output(1000, p2pk.clone())],
+        );
+        let funding_txid = txid(&funding);
+        let txs = vec![
+            coinbase(
+                Vec::new(),
+                vec![output(get_base_reward(1) + 300, p2pk.clone())],
+            ),
+            tx(
+                1,
+                vec![spend(funding_txid, 0)],
+                vec![output(0, vec![0x6a, 0x02, 0xbe, 0xef])],
+                0,
+            ),
+            tx(1, vec![spend(funding_txid, 1)], vec![output(800, p2pk)], 0),
+        ];
+        let blocks = vec![block(vec![funding]), block(txs)];
+
+        let matches = SimpleStats::build_subcommand()
+            .get_matches_from(vec!["simplestats", "--separate-data-carriers"]);
+        let mut stats = SimpleStats::new(&matches, None).unwrap();
+        stats.on_start(CoinType::from(Bitcoin), 0);
+        for (height, block) in blocks.into_iter().enumerate() {
+            stats.on_block(block, height);
+        }
+        assert_eq!(1, stats.n_tx_data_carriers);
+        assert_eq!(100, stats.n_tx_data_carrier_fees);
+        assert_eq!(0, stats.n_tx_data_carriers_unknown_fee);
+        assert_eq!(300, stats.n_tx_total_fee);
+    }
+
+    #[test]
+    fn test_taproot_usage() {
+        let mut usage = TaprootUsage::default();
+        let default_sig = vec![0x01; 64];
+        let mut all_sig = default_sig.clone();
+        all_sig.push(0x01);
+        let annex = vec![0x50];
+        let control_block = vec![0xc0; 33];
+        for witness in &[
+            vec![default_sig.clone()],
+            vec![default_sig.clone(), annex.clone()],
+            vec![all_sig],
+            vec![default_sig, vec![0x51], control_block],
+        ] {
+            usage.add(&TaprootWitness::decode(witness).unwrap());
+        }
+
+        let mut n_sighash = BTreeMap::new();
+        n_sighash.insert(None, 2);
+        n_sighash.insert(Some(0x01), 1);
+        assert_eq!(
+            TaprootUsage {
+                n_key_path: 3,
+                n_script_path: 1,
+                n_annex: 1,
+                n_sighash: n_sighash,
+            },
+            usage
+        );
+    }
 }
@@ -0,0 +1,307 @@
+use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::taproot::{TaprootSpend, TaprootWitness};
+use blockchain::proto::tx::TxOutpoint;
+
+/// Default of --interval, about two weeks of blocks
+const DEFAULT_INTERVAL: usize = 2016;
+
+/// Taproot outputs created and spent within an interval of blocks
+#[derive(Default, Debug, PartialEq)]
+struct Adoption {
+    n_created: u64,
+    n_key_path: u64,
+    n_script_path: u64,
+}
+
+/// Tracks the unspent taproot outputs, as only the spent output tells if an input is a taproot spend at all
+#[derive(Default)]
+struct TaprootOutputs {
+    unspent: HashSet<TxOutpoint, BuildHasherDefault<XxHash>>,
+    n_unknown: u64, // Spends of taproot outputs whose witness has neither a key nor a script path layout
+}
+
+impl TaprootOutputs {
+    /// Adds the taproot outputs created and spent in `block` to `adoption`.
+    /// Spends count towards the interval of the spending block, no matter when the output was created.
+    fn add_block(&mut self, block: &Block, adoption: &mut Adoption) {
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                for input in &tx.value.inputs {
+                    if !self.unspent.remove(&input.outpoint) {
+                        continue;
+                    }
+                    match TaprootWitness::decode(&input.witness) {
+                        Some(TaprootWitness {
+                            spend: TaprootSpend::KeyPath(_),
+                            ..
+                        }) => adoption.n_key_path += 1,
+                        Some(TaprootWitness {
+                            spend: TaprootSpend::ScriptPath { .. },
+                            ..
+                        }) => adoption.n_script_path += 1,
+                        None => self.n_unknown += 1,
+                    }
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if output.script.pattern != ScriptPattern::Pay2Taproot {
+                    continue;
+                }
+                self.unspent.insert(TxOutpoint {
+                    txid: tx.hash,
+                    index: i as u32,
+                });
+                adoption.n_created += 1;
+            }
+        }
+    }
+}
+
+/// Dumps the taproot adoption per interval of blocks: the created P2TR outputs and the key and script path spends.
+/// The spend path of an output is only known once it is spent, so unspent outputs show up in the created column only.
+pub struct TaprootAdoption {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    interval: usize,
+    outputs: TaprootOutputs,
+    current: Option<(usize, Adoption)>, // Start height and counts of the interval of the latest block
+
+    start_height: usize,
+    end_height: usize,
+    total: Adoption,
+}
+
+impl TaprootAdoption {
+    /// Writes the row of the current interval and adds it to the totals
+    fn write_interval(&mut self) {
+        let (interval_start, adoption) = match self.current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        let row = format!(
+            "{};{};{};{}\n",
+            writer::row_height(interval_start),
+            adoption.n_created,
+            adoption.n_key_path,
+            adoption.n_script_path
+        );
+        self.writer.write_all(row.as_bytes()).unwrap();
+        self.total.n_created += adoption.n_created;
+        self.total.n_key_path += adoption.n_key_path;
+        self.total.n_script_path += adoption.n_script_path;
+    }
+}
+
+impl Callback for TaprootAdoption {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("taprootadoption")
+            .about("Dumps the created taproot outputs and their key and script path spends per interval of blocks")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("BLOCKS")
+                    .help("Writes a row every BLOCKS blocks (default: 2016)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let interval = match matches.value_of("interval") {
+            Some(_) => match value_t!(matches, "interval", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--interval must be a positive number of blocks"))
+                }
+            },
+            None => DEFAULT_INTERVAL,
+        };
+        match (|| -> OpResult<Self> {
+            let mut cb = TaprootAdoption {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("taprootadoption.csv.tmp"))?,
+                interval: interval,
+                outputs: Default::default(),
+                current: None,
+                start_height: 0,
+                end_height: 0,
+                total: Default::default(),
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize TaprootAdoption with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "TaprootAdoption [on_start]", "Using `TaprootAdoption` with dump folder: {:?}, start block {} and an interval of {} blocks...",
+              &self.dump_folder, self.start_height, self.interval);
+        if self.start_height > 0 {
+            warn!(target: "TaprootAdoption [on_start]", "Taproot outputs created before block {} are unknown, their spends are not counted!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let interval_start = block_height - block_height % self.interval;
+        if self.current.as_ref().map(|&(start, _)| start) != Some(interval_start) {
+            self.write_interval();
+            self.current = Some((interval_start, Default::default()));
+        }
+        let outputs = &mut self.outputs;
+        if let Some((_, ref mut adoption)) = self.current {
+            outputs.add_block(&block, adoption);
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+        // The last interval is usually incomplete
+        self.write_interval();
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("taprootadoption.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "taprootadoption-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "TaprootAdoption [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        if self.outputs.n_unknown > 0 {
+            warn!(target: "TaprootAdoption [on_complete]", "{} spends of taproot outputs have neither a key nor a script path witness and were not counted.",
+                  self.outputs.n_unknown);
+        }
+        info!(target: "TaprootAdoption [on_complete]", "Done.\nDumped the taproot adoption of {} blocks:\n\
+                                   \t-> P2TR outputs created: {:9}\n\
+                                   \t-> key path spends:      {:9}\n\
+                                   \t-> script path spends:   {:9}\n\
+                                   \t-> still unspent:        {:9}",
+              self.end_height - self.start_height, self.total.n_created, self.total.n_key_path,
+              self.total.n_script_path, self.outputs.unspent.len());
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<TxOutpoint>(self.outputs.unspent.len())
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "taprootadoption",
+            "taprootadoption-<start_height>-<end_height>.csv",
+            &[
+                ("interval_height", ColumnType::Integer),
+                ("p2tr_created", ColumnType::BigInt),
+                ("keypath_spends", ColumnType::BigInt),
+                ("scriptpath_spends", ColumnType::BigInt),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adoption, TaprootOutputs};
+    use blockchain::proto::fixtures::{self, block, input, output, txid};
+    use blockchain::proto::tx::{Tx, TxOutpoint};
+
+    fn tx(inputs: &[(TxOutpoint, Vec<Vec<u8>>)], script_pubkey: Vec<u8>) -> Tx {
+        let inputs = inputs
+            .iter()
+            .map(|&(ref outpoint, ref witness)| {
+                input(outpoint.txid, outpoint.index, Vec::new(), witness.clone())
+            })
+            .collect();
+        fixtures::tx(1, inputs, vec![output(50, script_pubkey)], 0)
+    }
+
+    fn outpoint(tx: &Tx) -> TxOutpoint {
+        TxOutpoint {
+            txid: txid(tx),
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn test_taproot_outputs() {
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend_from_slice(&[7; 32]);
+        let funding = |b: u8| TxOutpoint {
+            txid: [b; 32],
+            index: 0,
+        };
+        let first = tx(&[(funding(1), Vec::new())], p2tr.clone());
+        let second = tx(&[(funding(2), Vec::new())], p2tr);
+
+        let mut outputs = TaprootOutputs::default();
+        let mut adoption = Adoption::default();
+        outputs.add_block(&block(vec![first.clone(), second.clone()]), &mut adoption);
+        assert_eq!(2, adoption.n_created);
+        assert_eq!(2, outputs.unspent.len());
+
+        let control_block = vec![0xc0; 33];
+        let key_path = tx(&[(outpoint(&first), vec![vec![0; 64]])], Vec::new());
+        let script_path = tx(
+            &[(outpoint(&second), vec![vec![0x51], control_block])],
+            Vec::new(),
+        );
+        // Looks like a key path spend, but spends no taproot output
+        let unrelated = tx(&[(outpoint(&key_path), vec![vec![0; 64]])], Vec::new());
+
+        let mut adoption = Adoption::default();
+        outputs.add_block(
+            &block(vec![key_path, script_path, unrelated]),
+            &mut adoption,
+        );
+        let expected = Adoption {
+            n_created: 0,
+            n_key_path: 1,
+            n_script_path: 1,
+        };
+        assert_eq!(expected, adoption);
+        assert!(outputs.unspent.is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use clap::{App, ArgMatches, SubCommand};
+
+use callbacks::Callback;
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Records every call as a row in memory, so tests can assert on what the parser passed to its callback.
+/// Rows are `on_start;height`, `on_block;height;block_hash;tx_count`, `on_skipped_blocks;n_stale;n_duplicate` and `on_complete;height`.
+pub struct TestSink {
+    rows: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestSink {
+    /// Returns the sink and the buffer it writes to, which stays readable after the sink was moved into the parser
+    pub fn with_buffer() -> (TestSink, Arc<Mutex<Vec<String>>>) {
+        let rows = Arc::new(Mutex::new(Vec::new()));
+        (TestSink { rows: rows.clone() }, rows)
+    }
+
+    fn push(&self, row: String) {
+        self.rows.lock().unwrap().push(row);
+    }
+}
+
+impl Callback for TestSink {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("testsink").about("Records all callback invocations in memory")
+    }
+
+    fn new(_: &ArgMatches, _: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(TestSink::with_buffer().0)
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.push(format!("on_start;{}", block_height));
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        self.push(format!(
+            "on_block;{};{};{}",
+            block_height,
+            arr_to_hex_swapped(&block.header.hash),
+            block.tx_count.value
+        ));
+    }
+
+    fn on_skipped_blocks(&mut self, n_stale_blocks: u64, n_duplicate_blocks: u64) {
+        self.push(format!(
+            "on_skipped_blocks;{};{}",
+            n_stale_blocks, n_duplicate_blocks
+        ));
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.push(format!("on_complete;{}", block_height));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Returns the in-block depth of each transaction of `block`: 0 if it spends no output created in the same block,
+/// otherwise one more than the deepest parent it spends from. Parents always come before their children.
+fn in_block_depths(block: &Block) -> Vec<usize> {
+    let mut depths: HashMap<[u8; 32], usize, BuildHasherDefault<XxHash>> = Default::default();
+    let mut result = Vec::with_capacity(block.txs.len());
+    for tx in &block.txs {
+        let depth = match tx.value.is_coinbase() {
+            true => 0,
+            false => tx
+                .value
+                .inputs
+                .iter()
+                .filter_map(|input| depths.get(&input.outpoint.txid))
+                .map(|parent_depth| parent_depth + 1)
+                .max()
+                .unwrap_or(0),
+        };
+        depths.insert(tx.hash, depth);
+        result.push(depth);
+    }
+    result
+}
+
+/// Dumps the in-block dependency depth of each transaction, the longest chain of ancestors in the same block it spends from.
+/// Shows how miners order dependent transactions and how common long in-block chains are, e.g. for CPFP and package relay.
+pub struct TxDepthDump {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+
+    start_height: usize,
+    end_height: usize,
+    tx_count: u64,
+    n_dependent: u64, // Transactions spending an output of the same block
+    deepest: Option<(usize, usize, [u8; 32])>, // Depth, height and txid of the deepest transaction
+}
+
+impl Callback for TxDepthDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("txdepthdump")
+            .about("Dumps the longest chain of ancestors in the same block of each transaction")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+    }
+
+    fn new(_: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        match (|| -> OpResult<Self> {
+            let mut cb = TxDepthDump {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("txdepth.csv.tmp"))?,
+                start_height: 0,
+                end_height: 0,
+                tx_count: 0,
+                n_dependent: 0,
+                deepest: None,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize TxDepthDump with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "TxDepthDump [on_start]", "Using `TxDepthDump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        for (tx, depth) in block.txs.iter().zip(in_block_depths(&block)) {
+            let row = format!(
+                "{};{};{}\n",
+                writer::row_height(block_height),
+                arr_to_hex_swapped(&tx.hash),
+                depth
+            );
+            self.writer.write_all(row.as_bytes()).unwrap();
+            self.tx_count += 1;
+            if depth == 0 {
+                continue;
+            }
+            self.n_dependent += 1;
+            if self
+                .deepest
+                .map_or(true, |(max_depth, _, _)| depth > max_depth)
+            {
+                self.deepest = Some((depth, block_height, tx.hash));
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("txdepth.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "txdepth-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "TxDepthDump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        let deepest = match self.deepest {
+            Some((depth, height, txid)) => format!(
+                "depth {} in block {}, txid: {}",
+                depth,
+                height,
+                arr_to_hex_swapped(&txid)
+            ),
+            None => String::from("none"),
+        };
+        info!(target: "TxDepthDump [on_complete]", "Done.\n{} of {} transactions spend an output of the same block. Deepest: {}",
+              self.n_dependent, self.tx_count, deepest);
+    }
+
+    fn allows_block_sampling(&self) -> bool {
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "txdepth",
+            "txdepth-<start_height>-<end_height>.csv",
+            &[
+                ("height", ColumnType::Integer),
+                ("txid", ColumnType::Hash),
+                ("in_block_depth", ColumnType::Integer),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::in_block_depths;
+    use blockchain::proto::fixtures::{self, block, output, spend, txid as hash};
+    use blockchain::proto::tx::Tx;
+
+    fn tx(parents: &[[u8; 32]]) -> Tx {
+        let inputs = parents.iter().map(|&txid| spend(txid, 0)).collect();
+        fixtures::tx(1, inputs, vec![output(50, Vec::new())], 0)
+    }
+
+    #[test]
+    fn test_in_block_depths() {
+        let first = tx(&[[1; 32]]);
+        let second = tx(&[hash(&first)]);
+        let third = tx(&[hash(&second)]);
+        // Spends from both ends of the chain, the deeper parent counts
+        let fourth = tx(&[hash(&first), hash(&third)]);
+        let unrelated = tx(&[[2; 32]]);
+        let block = block(vec![first, second, third, fourth, unrelated]);
+        assert_eq!(vec![0, 1, 2, 3, 0], in_block_depths(&block));
+    }
+}
@@ -1,44 +1,123 @@
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
+use std::collections::{BTreeMap, HashMap};
 use std::hash::BuildHasherDefault;
-use std::io::{LineWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use twox_hash::XxHash;
 
+use callbacks::schema::{ColumnType, Table};
 use callbacks::Callback;
-use errors::{OpError, OpResult};
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
 
+use blockchain::address::Address;
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
-use blockchain::proto::tx::TxOutpoint;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::taproot::{TaprootSpend, TaprootWitness};
+use blockchain::proto::tx::{TxInput, TxOutpoint};
 use blockchain::proto::ToRaw;
 
+/// What is kept of an unspent output with an address for --resolve-addresses
+enum StoredScript {
+    Address(Address),
+    Type(ScriptPattern), // Pubkey scripts above --max-stored-script-bytes
+}
+
+/// Returns how `input` spends an output of type `pattern`, for --lifecycle
+fn spend_type(pattern: &ScriptPattern, input: &TxInput) -> &'static str {
+    match *pattern {
+        ScriptPattern::Pay2Taproot => match TaprootWitness::decode(&input.witness) {
+            Some(TaprootWitness {
+                spend: TaprootSpend::KeyPath(_),
+                ..
+            }) => "taproot_key_path",
+            Some(TaprootWitness {
+                spend: TaprootSpend::ScriptPath { .. },
+                ..
+            }) => "taproot_script_path",
+            None => "witness_v1",
+        },
+        ScriptPattern::Pay2ScriptHash if !input.witness.is_empty() => "nested_witness_v0",
+        ScriptPattern::Pay2ScriptHash => "p2sh",
+        ScriptPattern::Pay2WitnessPublicKeyHash | ScriptPattern::Pay2WitnessScriptHash => {
+            "witness_v0"
+        }
+        _ => "legacy",
+    }
+}
+
+/// Returns the age in blocks of an output created at `utxo_height` and spent at `spend_height`.
+/// None if the output is younger than its spend, which only happens with corrupt or mixed up state.
+fn coinage(spend_height: usize, utxo_height: usize) -> Option<usize> {
+    spend_height.checked_sub(utxo_height)
+}
+
 /// Dumps the UTXO set into a CSV file
 pub struct TXODump {
     dump_folder: PathBuf,
-    txo_writer: LineWriter<File>,
+    txo_writer: FileWriter,
     utxo_set: HashMap<TxOutpoint, (u64, usize), BuildHasherDefault<XxHash>>, // TxOutpoint (K), (Coin Value, Blockheight) (V)
+    addresses: HashMap<TxOutpoint, StoredScript, BuildHasherDefault<XxHash>>, // Addresses of the unspent outputs if resolve_addresses is set
+    resolve_addresses: bool, // Adds the address of the spent output to each row
+    max_stored_script_bytes: Option<usize>, // Only the type of longer pubkey scripts is stored
+    script_types: HashMap<TxOutpoint, ScriptPattern, BuildHasherDefault<XxHash>>, // Types of the unspent outputs if lifecycle is set
+    lifecycle: bool, // Adds the creation height, both script types and the fee share of the spent output to each row
+    n_type_only: u64,
     start_height: usize,
     end_height: usize,
+    group_by_height: bool, // Writes the block height once per block instead of on every row
+    with_timestamp: bool,  // Adds the header time of the spending block
+    last_height: Option<usize>, // Height of the latest written height header line
+    max_output_age: Option<usize>, // Outputs older than this are evicted from the UTXO set
+    n_evicted: u64,
+    n_evicted_spent: u64, // Inputs spending an evicted (or otherwise unknown) output
+    n_invalid_coinage: u64, // Inputs spending an output created after the spending block
+    n_missing_spent: u64, // Inputs spending an output which was never created
+    separate_data_carriers: bool, // Keeps OP_RETURN only transactions out of the dump
+    n_data_carriers: u64,
+    data_carrier_fees: u64,
     tx_count: u64,
     in_count: u64,
     out_count: u64,
 }
 
+/// Number of blocks between two sweeps over the UTXO set when `--max-output-age-tracking` is set
+const EVICTION_INTERVAL: usize = 1000;
+
 impl TXODump {
-    fn create_writer(path: PathBuf) -> OpResult<LineWriter<File>> {
-        let file = match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-        {
-            Ok(f) => f,
-            Err(err) => return Err(OpError::from(err)),
-        };
-        Ok(LineWriter::new(file))
+    /// Removes all outputs created before `min_height` from the UTXO set
+    fn evict_outputs(&mut self, min_height: usize) {
+        let before = self.utxo_set.len();
+        self.utxo_set
+            .retain(|_, &mut (_, height)| height >= min_height);
+        if self.resolve_addresses {
+            let utxo_set = &self.utxo_set;
+            self.addresses
+                .retain(|outpoint, _| utxo_set.contains_key(outpoint));
+        }
+        if self.lifecycle {
+            let utxo_set = &self.utxo_set;
+            self.script_types
+                .retain(|outpoint, _| utxo_set.contains_key(outpoint));
+        }
+        let n_evicted = before - self.utxo_set.len();
+        self.n_evicted += n_evicted as u64;
+        debug!(target: "TXODump [evict_outputs]", "Evicted {} outputs created before block {}.", n_evicted, min_height);
+    }
+
+    /// Approximate memory used by a UTXO set of `n_outputs` entries, assuming they all have an address
+    fn utxo_bytes(&self, n_outputs: usize) -> usize {
+        let mut bytes = memory::hash_table_bytes::<(TxOutpoint, (u64, usize))>(n_outputs);
+        if self.resolve_addresses {
+            bytes += memory::hash_table_bytes::<(TxOutpoint, StoredScript)>(n_outputs);
+        }
+        if self.lifecycle {
+            bytes += memory::hash_table_bytes::<(TxOutpoint, ScriptPattern)>(n_outputs);
+        }
+        bytes
     }
 
     /// Load the UTXO set from an existing CSV file
@@ -77,24 +156,108 @@ impl Callback for TXODump {
                     .index(1)
                     .required(true),
             )
+            .arg(
+                Arg::with_name("group-by-height")
+                    .long("group-by-height")
+                    .help("Writes a `#<block_height>` line once per block, followed by rows without the block_height column"),
+            )
+            .arg(
+                Arg::with_name("with-timestamp")
+                    .long("with-timestamp")
+                    .help("Adds the timestamp of the spending block as last column, or to the `#<block_height>` lines"),
+            )
+            .arg(
+                Arg::with_name("max-output-age-tracking")
+                    .long("max-output-age-tracking")
+                    .value_name("BLOCKS")
+                    .help("Evicts outputs older than BLOCKS from the UTXO set to bound memory usage. \
+                           Spends of evicted outputs are not dumped!"),
+            )
+            .arg(
+                Arg::with_name("separate-data-carriers")
+                    .long("separate-data-carriers")
+                    .help("Doesn't dump spends of transactions with only OP_RETURN outputs, \
+                           their count and fees are reported separately"),
+            )
+            .arg(
+                Arg::with_name("resolve-addresses")
+                    .long("resolve-addresses")
+                    .help("Adds the address of the spent output to each row. \
+                           Keeps the addresses of all unspent outputs in memory"),
+            )
+            .arg(
+                Arg::with_name("max-stored-script-bytes")
+                    .long("max-stored-script-bytes")
+                    .value_name("BYTES")
+                    .requires("resolve-addresses")
+                    .help("Only keeps the script type of outputs whose pubkey script is longer than BYTES, \
+                           which is written instead of their address")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("lifecycle")
+                    .long("lifecycle")
+                    .help("Adds the creation height, the script type, how it was spent and its share of the fee to each row. \
+                           Keeps the script type of all unspent outputs in memory"),
+            )
     }
 
-    fn new(matches: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
     where
         Self: Sized,
     {
-        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let max_output_age = if matches.is_present("max-output-age-tracking") {
+            match value_t!(matches, "max-output-age-tracking", usize) {
+                Ok(max_age) => Some(max_age),
+                Err(e) => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg(&format!("Invalid --max-output-age-tracking: {}", e)))
+                }
+            }
+        } else {
+            None
+        };
+        let max_stored_script_bytes = match matches.value_of("max-stored-script-bytes") {
+            Some(_) => match value_t!(matches, "max-stored-script-bytes", usize) {
+                Ok(bytes) => Some(bytes),
+                Err(e) => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg(&format!("Invalid --max-stored-script-bytes: {}", e)))
+                }
+            },
+            None => None,
+        };
         match (|| -> OpResult<Self> {
-            let cb = TXODump {
+            let mut cb = TXODump {
                 dump_folder: PathBuf::from(dump_folder),
-                txo_writer: TXODump::create_writer(dump_folder.join("txo.csv.tmp"))?,
+                txo_writer: writer::create(&dump_folder.join("txo.csv.tmp"))?,
                 utxo_set: Default::default(),
+                addresses: Default::default(),
+                resolve_addresses: matches.is_present("resolve-addresses"),
+                max_stored_script_bytes: max_stored_script_bytes,
+                script_types: Default::default(),
+                lifecycle: matches.is_present("lifecycle"),
+                n_type_only: 0,
                 start_height: 0,
                 end_height: 0,
+                group_by_height: matches.is_present("group-by-height"),
+                with_timestamp: matches.is_present("with-timestamp"),
+                last_height: None,
+                max_output_age: max_output_age,
+                n_evicted: 0,
+                n_evicted_spent: 0,
+                n_invalid_coinage: 0,
+                n_missing_spent: 0,
+                separate_data_carriers: matches.is_present("separate-data-carriers"),
+                n_data_carriers: 0,
+                data_carrier_fees: 0,
                 tx_count: 0,
                 in_count: 0,
                 out_count: 0,
             };
+            let schema = cb.schema();
+            cb.txo_writer.write_header(&schema[0])?;
             Ok(cb)
         })() {
             Ok(s) => return Ok(s),
@@ -111,6 +274,9 @@ impl Callback for TXODump {
     fn on_start(&mut self, _: CoinType, block_height: usize) {
         self.start_height = block_height;
         info!(target: "TXODump [on_start]", "Using `TXODump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+        if let Some(max_age) = self.max_output_age {
+            warn!(target: "TXODump [on_start]", "Evicting outputs older than {} blocks. Spends of older outputs will be missing in the dump!", max_age);
+        }
         match self.load_utxo_set() {
             Ok(utxo_count) => {
                 info!(target: "TXODump [on_start]", "Loaded {} UTXOs.", utxo_count);
@@ -123,32 +289,155 @@ impl Callback for TXODump {
 
     fn on_block(&mut self, block: Block, block_height: usize) {
         debug!(target: "TXODump [on_block]", "Block: {}.", block_height);
+        let timestamp = block.header.value.timestamp;
 
-        for tx in block.txs {
+        for (tx_index, tx) in block.txs.into_iter().enumerate() {
             self.in_count += tx.value.in_count.value;
             self.out_count += tx.value.out_count.value;
 
-
             // Transaction inputs
             if !tx.value.is_coinbase() {
-                let feerate = tx.value.get_fees(&self.utxo_set) / tx.value.to_bytes().len() as u64;
-                for input in &tx.value.inputs {
+                // Outputs exceeding the max age are treated as evicted even if the
+                // periodic sweep didn't catch them yet. This keeps the dump deterministic.
+                if let Some(max_age) = self.max_output_age {
+                    for input in &tx.value.inputs {
+                        let expired = match self.utxo_set.get(&input.outpoint) {
+                            Some(&(_, utxo_height)) => coinage(block_height, utxo_height)
+                                .map_or(false, |age| age > max_age),
+                            None => false,
+                        };
+                        if expired {
+                            self.utxo_set.remove(&input.outpoint);
+                            self.addresses.remove(&input.outpoint);
+                            self.script_types.remove(&input.outpoint);
+                            self.n_evicted += 1;
+                        }
+                    }
+                }
+
+                // The fee is unknown if one of the spent outputs was evicted,
+                // so no row is written for the whole transaction.
+                let fees = tx.value.get_fees(&self.utxo_set);
+                // Data carriers pay fees for the block space of their payload, not for moving value.
+                // Their spent outputs are still removed from the UTXO set below.
+                let data_carrier = self.separate_data_carriers && tx.value.is_data_carrier();
+                if data_carrier {
+                    self.n_data_carriers += 1;
+                    self.data_carrier_fees = self
+                        .data_carrier_fees
+                        .saturating_add(fees.unwrap_or_default());
+                }
+                let feerate = match fees {
+                    Some(_) if data_carrier => None,
+                    Some(fees) => Some(fees / tx.value.to_bytes().len() as u64),
+                    None => None,
+                };
+                // The fee is split over the inputs in proportion to their value
+                let sum_in = fees
+                    .unwrap_or_default()
+                    .saturating_add(tx.value.output_value());
+                for (input_index, input) in tx.value.inputs.iter().enumerate() {
                     let tx_outpoint = &input.outpoint;
 
                     trace!(target: "TXODump [on_block] [TX inputs]", "Removing {:#?} from UTXO set.", tx_outpoint);
+                    let (utxo_val, utxo_height) = match self.utxo_set.remove(tx_outpoint) {
+                        Some(utxo) => utxo,
+                        None => {
+                            // Outputs may also be evicted to stay within --memory-limit
+                            if self.max_output_age.is_none() && self.n_evicted == 0 {
+                                warn!(target: "TXODump [on_block]", "Skipping the spend of {} at height {}, which was never created!",
+                                      tx_outpoint, block_height);
+                                self.n_missing_spent += 1;
+                                continue;
+                            }
+                            self.n_evicted_spent += 1;
+                            continue;
+                        }
+                    };
+                    // Outputs without an address have an empty address column
+                    let address = match self.resolve_addresses {
+                        true => Some(match self.addresses.remove(tx_outpoint) {
+                            Some(StoredScript::Address(address)) => address.to_string(),
+                            Some(StoredScript::Type(pattern)) => pattern.to_string(),
+                            None => String::new(),
+                        }),
+                        false => None,
+                    };
+                    let script_type = match self.lifecycle {
+                        true => self.script_types.remove(tx_outpoint),
+                        false => None,
+                    };
+                    let feerate = match feerate {
+                        Some(feerate) => feerate,
+                        None => continue,
+                    };
                     // Write TXOStat
-                    let (utxo_val, utxo_height) = self.utxo_set.get(&tx_outpoint).unwrap();
-                    let coinage = block_height - utxo_height;
-                    self.txo_writer
-                        .write_all(
-                            format!(
-                                "{};{};{};{}\n",
-                                block_height, coinage, feerate, utxo_val
-                            )
-                            .as_bytes(),
+                    let coinage = match coinage(block_height, utxo_height) {
+                        Some(coinage) => coinage,
+                        None => {
+                            warn!(target: "TXODump [on_block]", "Skipping the spend of {} at height {}, which was created later at height {}!",
+                                  tx_outpoint, block_height, utxo_height);
+                            self.n_invalid_coinage += 1;
+                            continue;
+                        }
+                    };
+                    let mut row = if self.group_by_height {
+                        if self.last_height != Some(block_height) {
+                            let header = match self.with_timestamp {
+                                true => {
+                                    format!("#{};{}\n", writer::row_height(block_height), timestamp)
+                                }
+                                false => format!("#{}\n", writer::row_height(block_height)),
+                            };
+                            self.txo_writer.write_all(header.as_bytes()).unwrap();
+                            self.last_height = Some(block_height);
+                        }
+                        format!(
+                            "{};{};{};{};{};{}",
+                            coinage, feerate, utxo_val, tx_index, input_index, tx_outpoint.index
+                        )
+                    } else {
+                        format!(
+                            "{};{};{};{};{};{};{}",
+                            writer::row_height(block_height),
+                            coinage,
+                            feerate,
+                            utxo_val,
+                            tx_index,
+                            input_index,
+                            tx_outpoint.index
                         )
-                        .unwrap();
-                    self.utxo_set.remove(tx_outpoint);
+                    };
+                    if self.lifecycle {
+                        // Outputs created before the scan started have no known type
+                        let (script_type, spend_type) = match script_type {
+                            Some(ref pattern) => (pattern.to_string(), spend_type(pattern, input)),
+                            None => (String::new(), ""),
+                        };
+                        let fee_share = match sum_in {
+                            0 => 0,
+                            _ => {
+                                (fees.unwrap_or_default() as u128 * utxo_val as u128
+                                    / sum_in as u128) as u64
+                            }
+                        };
+                        row.push_str(&format!(
+                            ";{};{};{};{}",
+                            writer::row_height(utxo_height),
+                            script_type,
+                            spend_type,
+                            fee_share
+                        ));
+                    }
+                    if let Some(address) = address {
+                        row.push(';');
+                        row.push_str(&address);
+                    }
+                    if self.with_timestamp && !self.group_by_height {
+                        row.push_str(&format!(";{}", timestamp));
+                    }
+                    row.push('\n');
+                    self.txo_writer.write_all(row.as_bytes()).unwrap();
                 }
             }
 
@@ -161,24 +450,207 @@ impl Callback for TXODump {
                 let coin_value = output.out.value;
 
                 trace!(target: "TXODump [on_block] [TX outputs]", "Adding UTXO {:#?} to the UTXO set.", tx_outpoint);
+                if self.resolve_addresses {
+                    if let Some(ref address) = output.script.address {
+                        let stored = match self.max_stored_script_bytes {
+                            Some(max_bytes) if output.out.script_pubkey.len() > max_bytes => {
+                                self.n_type_only += 1;
+                                StoredScript::Type(output.script.pattern.clone())
+                            }
+                            _ => StoredScript::Address(address.clone()),
+                        };
+                        self.addresses.insert(tx_outpoint.clone(), stored);
+                    }
+                }
+                match output.script.pattern {
+                    // OP_RETURN outputs can't be spent
+                    ScriptPattern::DataOutput(_) => (),
+                    ref pattern if self.lifecycle => {
+                        self.script_types
+                            .insert(tx_outpoint.clone(), pattern.clone());
+                    }
+                    _ => (),
+                }
                 self.utxo_set.insert(tx_outpoint, (coin_value, block_height));
             }
         }
         self.tx_count += block.tx_count.value;
+
+        if let Some(max_age) = self.max_output_age {
+            if block_height % EVICTION_INTERVAL == 0 && block_height > max_age {
+                self.evict_outputs(block_height - max_age);
+            }
+        }
     }
 
     fn on_complete(&mut self, _block_height: usize) {
         // Rename temp files
-        fs::rename(
+        if let Err(e) = self.txo_writer.finalize(
             self.dump_folder.as_path().join("txo.csv.tmp"),
             self.dump_folder.as_path().join("txo.csv"),
-        )
-        .expect("Unable to rename tmp file!");
+        ) {
+            error!(target: "TXODump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
 
         info!(target: "TXODump [on_complete]", "Done.\nDumped all {} blocks:\n\
                                    \t-> transactions: {:9}\n\
                                    \t-> inputs:       {:9}\n\
                                    \t-> outputs:      {:9}",
              self.end_height + 1, self.tx_count, self.in_count, self.out_count);
+        if self.max_output_age.is_some() || self.n_evicted > 0 {
+            info!(target: "TXODump [on_complete]", "Evicted {} outputs, {} spends of evicted outputs were not dumped.",
+                  self.n_evicted, self.n_evicted_spent);
+        }
+        if self.n_invalid_coinage > 0 {
+            warn!(target: "TXODump [on_complete]", "Skipped {} spends of outputs created after the spending block.", self.n_invalid_coinage);
+        }
+        if self.n_missing_spent > 0 {
+            warn!(target: "TXODump [on_complete]", "Skipped {} spends of outputs which were never created.", self.n_missing_spent);
+        }
+        if self.separate_data_carriers {
+            info!(target: "TXODump [on_complete]", "Skipped {} data carrier transactions paying {:.8} ({} units) in fees.",
+                  self.n_data_carriers, self.data_carrier_fees as f64 * 1E-8, self.data_carrier_fees);
+        }
+        if let Some(max_bytes) = self.max_stored_script_bytes {
+            info!(target: "TXODump [on_complete]", "Stored only the script type of {} outputs with pubkey scripts above {} bytes.",
+                  self.n_type_only, max_bytes);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, (u64, usize))>(self.utxo_set.len())
+            + memory::hash_table_bytes::<(TxOutpoint, StoredScript)>(self.addresses.len())
+            + memory::hash_table_bytes::<(TxOutpoint, ScriptPattern)>(self.script_types.len())
+    }
+
+    /// Evicts the oldest outputs, like `--max-output-age-tracking` does
+    fn reduce_memory(&mut self, target: usize) -> bool {
+        let mut n_outputs: BTreeMap<usize, usize> = BTreeMap::new();
+        for &(_, height) in self.utxo_set.values() {
+            *n_outputs.entry(height).or_insert(0) += 1;
+        }
+        // Oldest height which can be kept
+        let mut remaining = self.utxo_set.len();
+        let mut min_height = None;
+        for (&height, &count) in &n_outputs {
+            if self.utxo_bytes(remaining) <= target {
+                min_height = Some(height);
+                break;
+            }
+            remaining -= count;
+        }
+        let min_height = match min_height {
+            Some(height) if Some(&height) == n_outputs.keys().next() => return false,
+            Some(height) => height,
+            None => match n_outputs.keys().next_back() {
+                Some(height) => height + 1,
+                None => return false,
+            },
+        };
+        if self.n_evicted == 0 {
+            warn!(target: "TXODump [reduce_memory]", "Evicting outputs to stay within --memory-limit. Spends of evicted outputs will be missing in the dump!");
+        }
+        self.evict_outputs(min_height);
+        true
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        let mut columns = vec![
+            ("block_height", ColumnType::Integer),
+            ("coinage", ColumnType::Integer),
+            ("feerate", ColumnType::BigInt),
+            ("value", ColumnType::BigInt),
+            ("txIndex", ColumnType::Integer),
+            ("indexIn", ColumnType::Integer),
+            ("indexPrevOut", ColumnType::BigInt),
+        ];
+        // The height is only written in the `#<block_height>` header lines
+        if self.group_by_height {
+            columns.remove(0);
+        }
+        if self.lifecycle {
+            columns.push(("creation_height", ColumnType::Integer));
+            columns.push(("script_type", ColumnType::Text));
+            columns.push(("spend_type", ColumnType::Text));
+            columns.push(("fee_share", ColumnType::BigInt));
+        }
+        if self.resolve_addresses {
+            columns.push(("address", ColumnType::Text));
+        }
+        if self.with_timestamp && !self.group_by_height {
+            columns.push(("timestamp", ColumnType::BigInt));
+        }
+        vec![Table::new("txo", "txo.csv", &columns)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coinage, spend_type};
+    use blockchain::proto::script::ScriptPattern;
+    use blockchain::proto::tx::{TxInput, TxOutpoint};
+    use blockchain::proto::varuint::VarUint;
+
+    fn input(witness: Vec<Vec<u8>>) -> TxInput {
+        TxInput {
+            outpoint: TxOutpoint {
+                txid: [0u8; 32],
+                index: 0,
+            },
+            script_len: VarUint::from(0u64),
+            script_sig: Vec::new(),
+            seq_no: 0xffffffff,
+            witness: witness,
+        }
+    }
+
+    #[test]
+    fn test_coinage() {
+        assert_eq!(Some(0), coinage(0, 0));
+        assert_eq!(Some(100), coinage(100, 0));
+        // A spend before the creation must not underflow
+        assert_eq!(None, coinage(99, 100));
+    }
+
+    #[test]
+    fn test_spend_type() {
+        let sig = vec![0u8; 72];
+        let pubkey = vec![2u8; 33];
+        assert_eq!(
+            "legacy",
+            spend_type(&ScriptPattern::Pay2PublicKeyHash, &input(Vec::new()))
+        );
+        assert_eq!(
+            "p2sh",
+            spend_type(&ScriptPattern::Pay2ScriptHash, &input(Vec::new()))
+        );
+        assert_eq!(
+            "nested_witness_v0",
+            spend_type(
+                &ScriptPattern::Pay2ScriptHash,
+                &input(vec![sig.clone(), pubkey.clone()])
+            )
+        );
+        assert_eq!(
+            "witness_v0",
+            spend_type(
+                &ScriptPattern::Pay2WitnessPublicKeyHash,
+                &input(vec![sig, pubkey])
+            )
+        );
+        assert_eq!(
+            "taproot_key_path",
+            spend_type(&ScriptPattern::Pay2Taproot, &input(vec![vec![0u8; 64]]))
+        );
+        let mut control_block = vec![0xc0];
+        control_block.extend_from_slice(&[1u8; 32]);
+        assert_eq!(
+            "taproot_script_path",
+            spend_type(
+                &ScriptPattern::Pay2Taproot,
+                &input(vec![vec![0u8; 64], vec![0x51], control_block])
+            )
+        );
     }
 }
@@ -0,0 +1,195 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::Callback;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::utils;
+
+/// Writes a journal of all UTXO set changes.
+/// Each block starts with a `#<block_height>` line, followed by a `+txid:index:value` line
+/// for each created and a `-txid:index` line for each spent output, in transaction order.
+/// Replaying the journal from the start height rebuilds the UTXO set at any later height.
+pub struct UTXODelta {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    blocks_per_file: Option<usize>, // Starts a new journal file after this many blocks
+
+    start_height: usize,
+    file_start_height: usize, // First block of the current journal file
+    n_files: usize,
+    created_count: u64,
+    spent_count: u64,
+}
+
+impl UTXODelta {
+    /// Renames the current journal file to its final name containing the covered height range
+    fn finalize_file(&mut self, end_height: usize) {
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("utxodelta.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "utxodelta-{}-{}.csv",
+                self.file_start_height, end_height
+            )),
+        ) {
+            error!(target: "UTXODelta [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+        self.n_files += 1;
+    }
+}
+
+impl Callback for UTXODelta {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("utxodelta")
+            .about("Writes the created and spent outputs of each block into a journal file")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the journal files")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("blocks-per-file")
+                    .long("blocks-per-file")
+                    .value_name("N")
+                    .help("Starts a new journal file every N blocks (default: a single file)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let blocks_per_file = if matches.is_present("blocks-per-file") {
+            match value_t!(matches, "blocks-per-file", usize) {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--blocks-per-file must be a positive number of blocks"))
+                }
+            }
+        } else {
+            None
+        };
+        match (|| -> OpResult<Self> {
+            let cb = UTXODelta {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("utxodelta.csv.tmp"))?,
+                blocks_per_file: blocks_per_file,
+                start_height: 0,
+                file_start_height: 0,
+                n_files: 0,
+                created_count: 0,
+                spent_count: 0,
+            };
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize UTXODelta with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        self.file_start_height = block_height;
+        info!(target: "UTXODelta [on_start]", "Using `UTXODelta` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        if let Some(n) = self.blocks_per_file {
+            if block_height - self.file_start_height >= n {
+                self.finalize_file(block_height - 1);
+                self.writer = writer::create(&self.dump_folder.join("utxodelta.csv.tmp"))
+                    .expect("Unable to create journal file!");
+                self.file_start_height = block_height;
+            }
+        }
+
+        self.writer
+            .write_all(format!("#{}\n", writer::row_height(block_height)).as_bytes())
+            .unwrap();
+        for tx in block.txs {
+            if !tx.value.is_coinbase() {
+                for input in &tx.value.inputs {
+                    let row = format!(
+                        "-{}:{}\n",
+                        utils::arr_to_hex_swapped(&input.outpoint.txid),
+                        input.outpoint.index
+                    );
+                    self.writer.write_all(row.as_bytes()).unwrap();
+                    self.spent_count += 1;
+                }
+            }
+
+            let txid = utils::arr_to_hex_swapped(&tx.hash);
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                // OP_RETURN outputs are unspendable and never part of the UTXO set
+                if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                    continue;
+                }
+                let row = format!("+{}:{}:{}\n", txid, i, output.out.value);
+                self.writer.write_all(row.as_bytes()).unwrap();
+                self.created_count += 1;
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.finalize_file(block_height);
+
+        info!(target: "UTXODelta [on_complete]", "Done.\nWrote {} journal files for blocks {} to {}:\n\
+                                   \t-> created outputs: {:9}\n\
+                                   \t-> spent outputs:   {:9}",
+             self.n_files, self.start_height, block_height, self.created_count, self.spent_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::UTXODelta;
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::fixtures::block;
+    use callbacks::Callback;
+    use common::testdir;
+    use common::writer;
+
+    #[test]
+    fn test_height_origin() {
+        let _settings = writer::test_defaults();
+        writer::set_height_origin(1);
+        let dir = testdir::create("utxodelta.test");
+        let matches = UTXODelta::build_subcommand().get_matches_from(vec!["utxodelta", "unused"]);
+        let mut cb = UTXODelta::new(&matches, Some(&dir)).unwrap();
+        cb.on_start(CoinType::from(Bitcoin), 0);
+        for height in 0..2 {
+            cb.on_block(block(Vec::new()), height);
+        }
+        cb.on_complete(1);
+
+        assert!(!dir.join("utxodelta.csv.tmp").exists());
+        let journal = fs::read_to_string(dir.join("utxodelta-0-1.csv")).unwrap();
+        assert_eq!("#-1\n#0\n", journal);
+    }
+}
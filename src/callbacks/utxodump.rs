@@ -1,14 +1,16 @@
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
+use std::collections::{HashMap, HashSet};
 use std::hash::BuildHasherDefault;
-use std::io::{LineWriter, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use twox_hash::XxHash;
 
+use callbacks::schema::{ColumnType, Table};
 use callbacks::Callback;
-use errors::{OpError, OpResult};
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
@@ -16,61 +18,133 @@ use blockchain::proto::tx::TxOutpoint;
 use blockchain::utils::csv::CsvFile;
 use blockchain::utils::{arr_to_hex_swapped, hex_to_arr32_swapped};
 
-/// Dumps the UTXO set into a CSV file
+/// Unspent output as written to `utxo.csv`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utxo {
+    pub address: String, // Empty for outputs without one
+    pub value: u64,
+    pub height: usize, // Absolute height of the creating block, regardless of --height-origin
+}
+
+pub type UtxoSet = HashMap<TxOutpoint, Utxo, BuildHasherDefault<XxHash>>;
+
+/// Dumps the UTXO set into a CSV file.
+/// Immature coinbase outputs are always part of it, unlike with `realizedcap --exclude-immature`,
+/// because `utxo.csv` is loaded again to resume and must hold every unspent output.
 pub struct UTXODump {
     dump_folder: PathBuf,
-    utxo_writer: LineWriter<File>,
-    utxo_set: HashMap<TxOutpoint, String, BuildHasherDefault<XxHash>>,
+    utxo_writer: FileWriter,
+    utxo_set: UtxoSet,
 
     start_height: usize,
     end_height: usize,
     tx_count: u64,
     in_count: u64,
     out_count: u64,
+    anomalies: SpendAnomalies,
 }
 
-impl UTXODump {
-    fn create_writer(path: PathBuf) -> OpResult<LineWriter<File>> {
-        let file = match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&path)
-        {
-            Ok(f) => f,
-            Err(err) => return Err(OpError::from(err)),
+/// Reads a `utxo.csv` written by UTXODump
+pub fn load_utxo_csv(path: &Path) -> OpResult<UtxoSet> {
+    let mut csv_file = match CsvFile::new(path.to_owned(), b';') {
+        Ok(idx) => idx,
+        Err(e) => return Err(tag_err!(e, "Unable to load UTXO CSV file {:?}!", path)),
+    };
+
+    let mut utxo_set = UtxoSet::default();
+    for (i, record) in csv_file.reader.records().enumerate() {
+        let invalid = || {
+            OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                "Invalid row {} in UTXO CSV file {:?}",
+                i + 1,
+                path
+            ))
         };
-        Ok(LineWriter::new(file))
+        let record = record.map_err(|_| invalid())?;
+        if record.len() < 5 || record[0].len() != 64 || !record[0].chars().all(|c| c.is_digit(16)) {
+            return Err(invalid());
+        }
+        let tx_outpoint = TxOutpoint {
+            txid: hex_to_arr32_swapped(&record[0]),
+            index: record[1].parse::<u32>().map_err(|_| invalid())?,
+        };
+        let utxo = Utxo {
+            address: record[2].to_owned(),
+            value: record[3].parse::<u64>().map_err(|_| invalid())?,
+            height: record[4].parse::<usize>().map_err(|_| invalid())?,
+        };
+        utxo_set.insert(tx_outpoint, utxo);
     }
+    Ok(utxo_set)
+}
 
-    /// Load the UTXO set from an existing CSV file
-    fn load_utxo_set(&mut self) -> OpResult<usize> {
-        debug!(target: "UTXODump [load_utxo_set]", "Loading UTXO set...");
+/// Inputs of invalid blocks which don't spend an unspent output
+#[derive(Default, Debug, PartialEq)]
+pub struct SpendAnomalies {
+    pub double_spent: u64, // Outpoint was already spent by an earlier input of the same block
+    pub missing: u64,      // Outpoint isn't in the UTXO set at all
+}
 
-        let csv_file_path = self.dump_folder.join("utxo.csv");
-        let csv_file_path_string = csv_file_path.as_path().to_str().unwrap();
-        let mut csv_file = match CsvFile::new(csv_file_path.to_owned(), b';') {
-            Ok(idx) => idx,
-            Err(e) => {
-                return Err(tag_err!(
-                    e,
-                    "Unable to load UTXO CSV file {}!",
-                    csv_file_path_string
-                ))
+impl SpendAnomalies {
+    pub fn total(&self) -> u64 {
+        self.double_spent + self.missing
+    }
+
+    fn add(&mut self, other: &SpendAnomalies) {
+        self.double_spent += other.double_spent;
+        self.missing += other.missing;
+    }
+}
+
+/// Removes the outputs spent by `block` from `utxo_set` and adds the ones it creates.
+/// Inputs spending an output which isn't unspent are counted in the returned anomalies.
+pub fn apply_block(utxo_set: &mut UtxoSet, block: &Block, block_height: usize) -> SpendAnomalies {
+    let mut anomalies = SpendAnomalies::default();
+    let mut spent: HashSet<&TxOutpoint, BuildHasherDefault<XxHash>> = Default::default();
+    for tx in &block.txs {
+        trace!(target: "UTXODump [on_block]", "tx_id: {}.", arr_to_hex_swapped(&tx.hash));
+
+        // Transaction inputs
+        if !tx.value.is_coinbase() {
+            for input in &tx.value.inputs {
+                trace!(target: "UTXODump [on_block] [TX inputs]", "Removing {:#?} from UTXO set.", input.outpoint);
+                // The input is spent, remove it from the UTXO set
+                if utxo_set.remove(&input.outpoint).is_some() {
+                    spent.insert(&input.outpoint);
+                } else if spent.contains(&input.outpoint) {
+                    warn!(target: "UTXODump [apply_block]", "Double spend in block {}: tx {} spends {}:{} again!",
+                          arr_to_hex_swapped(&block.header.hash), arr_to_hex_swapped(&tx.hash),
+                          arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index);
+                    anomalies.double_spent += 1;
+                } else {
+                    debug!(target: "UTXODump [apply_block]", "Tx {} spends unknown output {}:{}.",
+                           arr_to_hex_swapped(&tx.hash), arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index);
+                    anomalies.missing += 1;
+                }
             }
-        };
+        }
 
-        for record in csv_file.reader.records().map(|r| r.unwrap()) {
+        // Transaction outputs
+        for (i, output) in tx.value.outputs.iter().enumerate() {
             let tx_outpoint = TxOutpoint {
-                txid: hex_to_arr32_swapped(&record[0]),
-                index: record[1].parse::<u32>().unwrap(),
+                txid: tx.hash,
+                index: i as u32,
+            };
+            let address = match output.script.address {
+                Some(ref address) => address.to_string(),
+                None => String::new(),
             };
 
-            self.utxo_set.insert(tx_outpoint, record[2].to_owned());
+            trace!(target: "UTXODump [on_block] [TX outputs]", "Adding UTXO {:#?} to the UTXO set.", tx_outpoint);
+            let utxo = Utxo {
+                address: address,
+                value: output.out.value,
+                height: block_height,
+            };
+            utxo_set.insert(tx_outpoint, utxo);
         }
-        debug!(target: "UTXODump [load_utxo_set]", "Done.");
-        Ok(self.utxo_set.len())
     }
+    anomalies
 }
 
 impl Callback for UTXODump {
@@ -90,21 +164,28 @@ impl Callback for UTXODump {
             )
     }
 
-    fn new(matches: &ArgMatches) -> OpResult<Self>
+    fn new(_: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
     where
         Self: Sized,
     {
-        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
         match (|| -> OpResult<Self> {
+            // Resuming from a truncated set would silently corrupt it, so a malformed file is an error
+            let utxo_path = dump_folder.join("utxo.csv");
+            let utxo_set = match utxo_path.exists() {
+                true => load_utxo_csv(&utxo_path)?,
+                false => UtxoSet::default(),
+            };
             let cb = UTXODump {
                 dump_folder: PathBuf::from(dump_folder),
-                utxo_writer: UTXODump::create_writer(dump_folder.join("utxo.csv.tmp"))?,
-                utxo_set: Default::default(),
+                utxo_writer: writer::create_state(&dump_folder.join("utxo.csv.tmp"))?,
+                utxo_set: utxo_set,
                 start_height: 0,
                 end_height: 0,
                 tx_count: 0,
                 in_count: 0,
                 out_count: 0,
+                anomalies: SpendAnomalies::default(),
             };
             Ok(cb)
         })() {
@@ -122,63 +203,37 @@ impl Callback for UTXODump {
     fn on_start(&mut self, _: CoinType, block_height: usize) {
         self.start_height = block_height;
         info!(target: "UTXODump [on_start]", "Using `UTXODump` with dump folder: {:?} and start block {}...", &self.dump_folder, self.start_height);
-        match self.load_utxo_set() {
-            Ok(utxo_count) => {
-                info!(target: "UTXODump [on_start]", "Loaded {} UTXOs.", utxo_count);
-            }
-            Err(_) => {
-                info!(target: "UTXODump [on_start]", "No previous UTXO loaded.");
-            }
+        match self.utxo_set.len() {
+            0 => info!(target: "UTXODump [on_start]", "No previous UTXO loaded."),
+            n => info!(target: "UTXODump [on_start]", "Loaded {} UTXOs.", n),
         }
     }
 
     fn on_block(&mut self, block: Block, block_height: usize) {
         debug!(target: "UTXODump [on_block]", "Block: {}.", block_height);
 
-        for tx in block.txs {
-            let txid_str = arr_to_hex_swapped(&tx.hash);
-            trace!(target: "UTXODump [on_block]", "tx_id: {}.", txid_str);
+        for tx in &block.txs {
             self.in_count += tx.value.in_count.value;
             self.out_count += tx.value.out_count.value;
-
-            // Transaction inputs
-            for input in &tx.value.inputs {
-                let tx_outpoint = TxOutpoint {
-                    txid: input.outpoint.txid,
-                    index: input.outpoint.index,
-                };
-
-                trace!(target: "UTXODump [on_block] [TX inputs]", "Removing {:#?} from UTXO set.", tx_outpoint);
-                // The input is spent, remove it from the UTXO set
-                self.utxo_set.remove(&tx_outpoint);
-            }
-
-            // Transaction outputs
-            for (i, output) in tx.value.outputs.iter().enumerate() {
-                let tx_outpoint = TxOutpoint {
-                    txid: tx.hash,
-                    index: i as u32,
-                };
-                let address = output.script.address.to_owned();
-
-                trace!(target: "UTXODump [on_block] [TX outputs]", "Adding UTXO {:#?} to the UTXO set.", tx_outpoint);
-                self.utxo_set.insert(tx_outpoint, address);
-            }
         }
+        let anomalies = apply_block(&mut self.utxo_set, &block, block_height);
+        self.anomalies.add(&anomalies);
         self.tx_count += block.tx_count.value;
     }
 
     fn on_complete(&mut self, block_height: usize) {
         self.end_height = block_height;
 
-        for (tx_outpoint, address) in self.utxo_set.iter() {
+        for (tx_outpoint, utxo) in self.utxo_set.iter() {
             self.utxo_writer
                 .write_all(
                     format!(
-                        "{};{};{}\n",
+                        "{};{};{};{};{}\n",
                         arr_to_hex_swapped(&tx_outpoint.txid),
                         tx_outpoint.index,
-                        address
+                        utxo.address,
+                        utxo.value,
+                        utxo.height
                     )
                     .as_bytes(),
                 )
@@ -186,16 +241,157 @@ impl Callback for UTXODump {
         }
 
         // Rename temp files
-        fs::rename(
+        if let Err(e) = self.utxo_writer.finalize(
             self.dump_folder.as_path().join("utxo.csv.tmp"),
             self.dump_folder.as_path().join("utxo.csv"),
-        )
-        .expect("Unable to rename tmp file!");
+        ) {
+            error!(target: "UTXODump [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
 
         info!(target: "UTXODump [on_complete]", "Done.\nDumped all {} blocks:\n\
                                    \t-> transactions: {:9}\n\
                                    \t-> inputs:       {:9}\n\
                                    \t-> outputs:      {:9}",
              self.end_height + 1, self.tx_count, self.in_count, self.out_count);
+        if self.anomalies.total() > 0 {
+            warn!(target: "UTXODump [on_complete]", "{} inputs didn't spend an unspent output:\n\
+                                   \t-> double spent within a block: {:9}\n\
+                                   \t-> unknown outputs:             {:9}",
+                  self.anomalies.total(), self.anomalies.double_spent, self.anomalies.missing);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, Utxo)>(self.utxo_set.len())
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "utxo",
+            "utxo.csv",
+            &[
+                ("txid", ColumnType::Hash),
+                ("indexOut", ColumnType::BigInt),
+                ("address", ColumnType::Text),
+                ("value", ColumnType::BigInt),
+                ("height", ColumnType::Integer),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{apply_block, SpendAnomalies, UTXODump, Utxo, UtxoSet};
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::fixtures::{self, block, output, spend};
+    use blockchain::proto::tx::{Tx, TxOutpoint};
+    use callbacks::Callback;
+    use common::testdir;
+    use common::writer::{self, Overwrite};
+
+    fn tx(outpoints: &[TxOutpoint], n_outputs: u8) -> Tx {
+        let inputs = outpoints
+            .iter()
+            .map(|outpoint| spend(outpoint.txid, outpoint.index))
+            .collect();
+        let outputs = vec![output(50, Vec::new()); n_outputs as usize];
+        fixtures::tx(1, inputs, outputs, 0)
+    }
+
+    #[test]
+    fn test_apply_block_detects_double_spend() {
+        let funding = TxOutpoint {
+            txid: [1; 32],
+            index: 0,
+        };
+        let mut utxo_set = UtxoSet::default();
+        let utxo = Utxo {
+            address: String::new(),
+            value: 50,
+            height: 0,
+        };
+        utxo_set.insert(funding.clone(), utxo);
+
+        let coinbase = tx(
+            &[TxOutpoint {
+                txid: [0u8; 32],
+                index: 0xFFFFFFFF,
+            }],
+            1,
+        );
+        // Both transactions spend the funding output, the second one also an output that never existed
+        let first = tx(&[funding.clone()], 2);
+        let unknown = TxOutpoint {
+            txid: [2; 32],
+            index: 7,
+        };
+        let second = tx(&[funding.clone(), unknown], 1);
+        let anomalies = apply_block(&mut utxo_set, &block(vec![coinbase, first, second]), 1);
+
+        assert_eq!(
+            SpendAnomalies {
+                double_spent: 1,
+                missing: 1,
+            },
+            anomalies
+        );
+        assert!(!utxo_set.contains_key(&funding));
+        assert_eq!(4, utxo_set.len());
+    }
+
+    #[test]
+    fn test_resume_from_utxo_csv() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("utxodump.test");
+        let matches = UTXODump::build_subcommand().get_matches_from(vec!["utxodump", "unused"]);
+
+        let mut cb = UTXODump::new(&matches, Some(&dir)).unwrap();
+        cb.on_start(CoinType::from(Bitcoin), 0);
+        let funding = tx(&[], 2);
+        let spending = tx(
+            &[TxOutpoint {
+                txid: fixtures::txid(&funding),
+                index: 0,
+            }],
+            1,
+        );
+        cb.on_block(block(vec![funding]), 0);
+        cb.on_block(block(vec![spending]), 1);
+        cb.on_complete(2);
+
+        // Values and heights survive the round trip through utxo.csv
+        let resumed = UTXODump::new(&matches, Some(&dir)).unwrap();
+        assert_eq!(cb.utxo_set, resumed.utxo_set);
+        let mut heights: Vec<usize> = resumed.utxo_set.values().map(|utxo| utxo.height).collect();
+        heights.sort();
+        assert_eq!(vec![0, 1], heights);
+        drop(resumed);
+
+        // A malformed file is rejected before the scan starts
+        fs::write(dir.join("utxo.csv"), "not;a;utxo\n").unwrap();
+        assert!(UTXODump::new(&matches, Some(&dir)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_clobber() {
+        let _settings = writer::test_defaults();
+        writer::set_overwrite(Overwrite::NoClobber);
+        let dir = testdir::create("utxodump.no_clobber.test");
+        fs::write(dir.join("utxo.csv"), "").unwrap();
+        let matches = UTXODump::build_subcommand().get_matches_from(vec!["utxodump", "unused"]);
+
+        // The existing file is kept without a panic, the new rows stay in the temp file
+        let mut cb = UTXODump::new(&matches, Some(&dir)).unwrap();
+        cb.on_start(CoinType::from(Bitcoin), 0);
+        cb.on_block(block(vec![tx(&[], 1)]), 0);
+        cb.on_complete(1);
+        assert_eq!("", fs::read_to_string(dir.join("utxo.csv")).unwrap());
+        assert!(dir.join("utxo.csv.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
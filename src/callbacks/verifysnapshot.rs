@@ -0,0 +1,294 @@
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::utxodump::{self, Utxo, UtxoSet};
+use callbacks::Callback;
+use common::memory;
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Divergences of each kind which are listed in detail, the rest is only counted
+const MAX_LISTED: usize = 20;
+
+/// Recomputes the UTXO set from genesis and compares it against a `utxo.csv` written by utxodump.
+/// The outpoints, addresses, values and heights must all match, otherwise the run fails.
+pub struct VerifySnapshot {
+    snapshot_path: PathBuf,
+    snapshot: UtxoSet,
+    utxo_set: UtxoSet,
+    height: Option<usize>, // Blocks above are ignored, defaults to the last parsed block
+
+    start_height: usize,
+    last_height: Option<usize>, // Height of the latest applied block
+    failure: Option<String>,    // Why the snapshot couldn't be verified, set by on_complete
+}
+
+impl VerifySnapshot {
+    fn list(kind: &str, outpoints: &[(&TxOutpoint, String)], count: usize) {
+        if count == 0 {
+            return;
+        }
+        let mut details = String::new();
+        for &(outpoint, ref detail) in outpoints {
+            details.push_str(&format!(
+                "\n\t-> {}:{} {}",
+                arr_to_hex_swapped(&outpoint.txid),
+                outpoint.index,
+                detail
+            ));
+        }
+        if count > outpoints.len() {
+            details.push_str(&format!("\n\t-> ... and {} more", count - outpoints.len()));
+        }
+        error!(target: "VerifySnapshot [on_complete]", "{} {}:{}", count, kind, details);
+    }
+
+    fn describe(utxo: &Utxo) -> String {
+        format!(
+            "`{}` with value {} from block {}",
+            utxo.address, utxo.value, utxo.height
+        )
+    }
+
+    /// Compares the recomputed UTXO set against the snapshot, returns the number of divergences
+    fn compare(&self, last_height: usize) -> usize {
+        let (mut missing, mut n_missing) = (Vec::new(), 0);
+        let (mut mismatched, mut n_mismatched) = (Vec::new(), 0);
+        for (outpoint, utxo) in &self.utxo_set {
+            match self.snapshot.get(outpoint) {
+                None => {
+                    n_missing += 1;
+                    if missing.len() < MAX_LISTED {
+                        missing.push((outpoint, VerifySnapshot::describe(utxo)));
+                    }
+                }
+                Some(expected) if expected != utxo => {
+                    n_mismatched += 1;
+                    if mismatched.len() < MAX_LISTED {
+                        let detail = format!(
+                            "{} in the snapshot, {} recomputed",
+                            VerifySnapshot::describe(expected),
+                            VerifySnapshot::describe(utxo)
+                        );
+                        mismatched.push((outpoint, detail));
+                    }
+                }
+                Some(_) => (),
+            }
+        }
+        let (mut unexpected, mut n_unexpected) = (Vec::new(), 0);
+        for (outpoint, utxo) in &self.snapshot {
+            if !self.utxo_set.contains_key(outpoint) {
+                n_unexpected += 1;
+                if unexpected.len() < MAX_LISTED {
+                    unexpected.push((outpoint, VerifySnapshot::describe(utxo)));
+                }
+            }
+        }
+
+        let n_divergences = n_missing + n_mismatched + n_unexpected;
+        if n_divergences == 0 {
+            return 0;
+        }
+        error!(target: "VerifySnapshot [on_complete]", "The snapshot diverges from the {} unspent outputs recomputed up to block {}:",
+               self.utxo_set.len(), last_height);
+        VerifySnapshot::list(
+            "unspent outputs are missing in the snapshot",
+            &missing,
+            n_missing,
+        );
+        VerifySnapshot::list(
+            "outputs in the snapshot are spent or don't exist",
+            &unexpected,
+            n_unexpected,
+        );
+        VerifySnapshot::list(
+            "outputs have a different address, value or height",
+            &mismatched,
+            n_mismatched,
+        );
+        n_divergences
+    }
+}
+
+impl Callback for VerifySnapshot {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("verify-snapshot")
+            .about("Recomputes the UTXO set from genesis and compares it against a utxo.csv written by utxodump")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("snapshot")
+                    .help("utxo.csv to verify")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("height")
+                    .long("height")
+                    .value_name("HEIGHT")
+                    .help("Height of the last block included in the snapshot (default: the last parsed block)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches, _: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let snapshot_path = PathBuf::from(matches.value_of("snapshot").unwrap());
+        let height = match matches.value_of("height") {
+            Some(_) => match value_t!(matches, "height", usize) {
+                Ok(height) => Some(height),
+                Err(_) => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--height must be a block height"))
+                }
+            },
+            None => None,
+        };
+        Ok(VerifySnapshot {
+            snapshot: utxodump::load_utxo_csv(&snapshot_path)?,
+            snapshot_path: snapshot_path,
+            utxo_set: UtxoSet::default(),
+            height: height,
+            start_height: 0,
+            last_height: None,
+            failure: None,
+        })
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "VerifySnapshot [on_start]", "Verifying {} unspent outputs of {:?}...", self.snapshot.len(), self.snapshot_path);
+        if self.start_height > 0 {
+            error!(target: "VerifySnapshot [on_start]", "The scan starts at block {}, but the UTXO set must be recomputed from genesis. \
+                                                         Use --reindex or a fresh --chain-storage.", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        if self.start_height > 0 || self.height.map_or(false, |height| block_height > height) {
+            return;
+        }
+        utxodump::apply_block(&mut self.utxo_set, &block, block_height);
+        self.last_height = Some(block_height);
+    }
+
+    fn on_complete(&mut self, _: usize) {
+        let failure = if self.start_height > 0 {
+            String::from("Skipped the verification, the scan didn't start at genesis.")
+        } else {
+            match (self.last_height, self.height) {
+                (None, _) => String::from("No blocks were parsed, nothing to verify."),
+                (Some(last_height), Some(height)) if last_height < height => format!(
+                    "The chain storage ends at block {}, before the snapshot height {}.",
+                    last_height, height
+                ),
+                (Some(last_height), _) => match self.compare(last_height) {
+                    0 => {
+                        info!(target: "VerifySnapshot [on_complete]", "Done.\nThe snapshot matches the {} unspent outputs recomputed up to block {}.",
+                              self.utxo_set.len(), last_height);
+                        return;
+                    }
+                    n => format!(
+                        "The snapshot {:?} diverges from the recomputed UTXO set ({} differences).",
+                        self.snapshot_path, n
+                    ),
+                },
+            }
+        };
+        error!(target: "VerifySnapshot [on_complete]", "{}", failure);
+        self.failure = Some(failure);
+    }
+
+    fn check_result(&self) -> OpResult<()> {
+        match self.failure {
+            Some(ref failure) => Err(OpError::new(OpErrorKind::ValidateError).join_msg(failure)),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, Utxo)>(self.snapshot.len() + self.utxo_set.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::VerifySnapshot;
+    use blockchain::parser::types::{Bitcoin, CoinType};
+    use blockchain::proto::block::Block;
+    use blockchain::proto::fixtures::{self, block, output, spend};
+    use callbacks::utxodump::UTXODump;
+    use callbacks::Callback;
+    use common::testdir;
+    use common::writer;
+
+    fn blocks() -> Vec<Block> {
+        let funding = fixtures::coinbase(
+            vec![1],
+            vec![output(50, Vec::new()), output(25, Vec::new())],
+        );
+        let inputs = vec![spend(fixtures::txid(&funding), 0)];
+        let spending = fixtures::tx(1, inputs, vec![output(40, Vec::new())], 0);
+        vec![block(vec![funding]), block(vec![spending])]
+    }
+
+    fn feed<C: Callback>(callback: &mut C, start_height: usize) {
+        callback.on_start(CoinType::from(Bitcoin), start_height);
+        for (height, block) in blocks().into_iter().enumerate() {
+            callback.on_block(block, height);
+        }
+        callback.on_complete(2);
+    }
+
+    fn verify(snapshot: &Path, args: &[&str], start_height: usize) -> VerifySnapshot {
+        let mut argv = vec!["verify-snapshot", snapshot.to_str().unwrap()];
+        argv.extend(args);
+        let matches = VerifySnapshot::build_subcommand().get_matches_from(argv);
+        let mut cb = VerifySnapshot::new(&matches, None).unwrap();
+        feed(&mut cb, start_height);
+        cb
+    }
+
+    #[test]
+    fn test_verify_snapshot() {
+        let _settings = writer::test_defaults();
+        let dir = testdir::create("verifysnapshot.test");
+        let matches = UTXODump::build_subcommand().get_matches_from(vec!["utxodump", "unused"]);
+        feed(&mut UTXODump::new(&matches, Some(&dir)).unwrap(), 0);
+        let snapshot = dir.join("utxo.csv");
+
+        assert!(verify(&snapshot, &[], 0).check_result().is_ok());
+        // Block 1 spent an output which is unspent at height 0
+        assert!(verify(&snapshot, &["--height", "0"], 0)
+            .check_result()
+            .is_err());
+        // The UTXO set can only be recomputed from genesis
+        assert!(verify(&snapshot, &[], 1).check_result().is_err());
+
+        // A different value of the same outpoint is a divergence
+        let rows = fs::read_to_string(&snapshot).unwrap();
+        assert!(rows.contains(";25;0\n"));
+        fs::write(&snapshot, rows.replace(";25;0\n", ";26;0\n")).unwrap();
+        let err = verify(&snapshot, &[], 0).check_result().unwrap_err();
+        assert!(err.to_string().contains("(1 differences)"));
+
+        // So is a different height
+        fs::write(&snapshot, rows.replace(";25;0\n", ";25;1\n")).unwrap();
+        assert!(verify(&snapshot, &[], 0).check_result().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use callbacks::schema::{ColumnType, Table};
+use callbacks::Callback;
+use common::memory;
+use common::writer::{self, FileWriter};
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+
+/// Version bits which may be rolled by miners without affecting soft fork signalling [BIP320]
+const DEFAULT_ROLLING_MASK: u32 = 0x1fffe000;
+/// Top three bits of versions following the version bits scheme [BIP9]
+const VERSIONBITS_TOP_MASK: u32 = 0xe0000000;
+const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
+
+/// Returns the bits of `version` within `mask`, None for versions which don't follow BIP9.
+/// Earlier versions like 1 to 4 have no bits to roll.
+fn rolled_bits(version: u32, mask: u32) -> Option<u32> {
+    if version & VERSIONBITS_TOP_MASK != VERSIONBITS_TOP_BITS {
+        return None;
+    }
+    Some(version & mask)
+}
+
+/// Detects version rolling (overt ASICBoost): miners varying the version bits reserved by BIP320 as additional nonce space.
+/// A block is flagged if at least --min-rolled-bits of the rolling mask are set and they differ from the previous
+/// block of the same miner, as constant bits are rather a signal than rolling. Miners are told apart by the first
+/// address their coinbase pays to.
+pub struct VersionRolling {
+    dump_folder: PathBuf,
+    writer: FileWriter,
+    mask: u32,
+    min_rolled_bits: u32,
+    miner_check: bool, // Requires the rolled bits to change between blocks of the same miner
+    last_rolled: HashMap<String, u32>, // Rolled bits of the latest block of each miner
+
+    start_height: usize,
+    end_height: usize,
+    n_blocks: u64,
+    n_versionbits: u64, // Blocks with a BIP9 version
+    n_rolling: u64,
+}
+
+impl VersionRolling {
+    /// Returns the first address the coinbase of `block` pays to
+    fn miner(block: &Block) -> Option<String> {
+        let coinbase = block.txs.first()?;
+        coinbase
+            .value
+            .outputs
+            .iter()
+            .filter(|output| match output.script.pattern {
+                ScriptPattern::DataOutput(_) => false,
+                _ => true,
+            })
+            .filter_map(|output| output.script.address.as_ref())
+            .map(|address| address.to_string())
+            .next()
+    }
+
+    /// Decides if `block` likely used version rolling and remembers its bits for the next block of its miner
+    fn is_rolling(&mut self, block: &Block) -> bool {
+        let rolled = match rolled_bits(block.header.value.version, self.mask) {
+            Some(rolled) => rolled,
+            None => return false,
+        };
+        self.n_versionbits += 1;
+        let changed = match self.miner_check {
+            true => match VersionRolling::miner(block) {
+                Some(miner) => match self.last_rolled.insert(miner, rolled) {
+                    Some(previous) => previous != rolled,
+                    None => false,
+                },
+                None => false,
+            },
+            false => true,
+        };
+        changed && rolled.count_ones() >= self.min_rolled_bits
+    }
+}
+
+impl Callback for VersionRolling {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("versionrolling")
+            .about("Dumps the version of each block and whether it likely used version rolling (ASICBoost)")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("dump-folder")
+                    .help("Folder to store the CSV file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("rolling-mask")
+                    .long("rolling-mask")
+                    .value_name("HEX")
+                    .help("Version bits which may be rolled (default: 1fffe000 of BIP320)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("min-rolled-bits")
+                    .long("min-rolled-bits")
+                    .value_name("N")
+                    .help("Minimum number of set bits within the rolling mask (default: 1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("no-miner-check")
+                    .long("no-miner-check")
+                    .help("Flags all blocks with rolled bits, even if they match the previous block of the same miner"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, dump_folder: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let ref dump_folder = PathBuf::from(dump_folder.unwrap());
+        let mask = match matches.value_of("rolling-mask") {
+            Some(hex) => match u32::from_str_radix(hex.trim_start_matches("0x"), 16) {
+                Ok(mask) if mask != 0 => mask,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg("--rolling-mask must be a nonzero 32 bit hex value"))
+                }
+            },
+            None => DEFAULT_ROLLING_MASK,
+        };
+        let min_rolled_bits = match matches.value_of("min-rolled-bits") {
+            Some(_) => match value_t!(matches, "min-rolled-bits", u32) {
+                Ok(n) if n >= 1 && n <= mask.count_ones() => n,
+                _ => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                        "--min-rolled-bits must be between 1 and the number of bits in the rolling mask",
+                    ))
+                }
+            },
+            None => 1,
+        };
+        match (|| -> OpResult<Self> {
+            let mut cb = VersionRolling {
+                dump_folder: PathBuf::from(dump_folder),
+                writer: writer::create(&dump_folder.join("versionrolling.csv.tmp"))?,
+                mask: mask,
+                min_rolled_bits: min_rolled_bits,
+                miner_check: !matches.is_present("no-miner-check"),
+                last_rolled: HashMap::new(),
+                start_height: 0,
+                end_height: 0,
+                n_blocks: 0,
+                n_versionbits: 0,
+                n_rolling: 0,
+            };
+            let schema = cb.schema();
+            cb.writer.write_header(&schema[0])?;
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => {
+                return Err(tag_err!(
+                    e,
+                    "Couldn't initialize VersionRolling with folder: `{:?}`",
+                    dump_folder.as_path()
+                ))
+            }
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "VersionRolling [on_start]", "Using `VersionRolling` with dump folder: {:?} and start block {}, rolling mask {:#010x}...",
+              &self.dump_folder, self.start_height, self.mask);
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let rolling = self.is_rolling(&block);
+        let row = format!(
+            "{};{:#010x};{}\n",
+            writer::row_height(block_height),
+            block.header.value.version,
+            rolling as u8
+        );
+        self.writer.write_all(row.as_bytes()).unwrap();
+        self.n_blocks += 1;
+        if rolling {
+            self.n_rolling += 1;
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        // Rename temp files
+        if let Err(e) = self.writer.finalize(
+            self.dump_folder.as_path().join("versionrolling.csv.tmp"),
+            self.dump_folder.as_path().join(format!(
+                "versionrolling-{}-{}.csv",
+                self.start_height, self.end_height
+            )),
+        ) {
+            error!(target: "VersionRolling [on_complete]", "Unable to rename tmp file: {}", e);
+            return;
+        }
+
+        info!(target: "VersionRolling [on_complete]", "Done.\nDumped the versions of {} blocks:\n\
+                                   \t-> BIP9 versions:          {:9}\n\
+                                   \t-> likely version rolling: {:9}",
+              self.n_blocks, self.n_versionbits, self.n_rolling);
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(String, u32)>(self.last_rolled.len())
+    }
+
+    /// The miner check compares each block to the previous one of its miner
+    fn allows_block_sampling(&self) -> bool {
+        !self.miner_check
+    }
+
+    /// The miner check reads the miner from the coinbase transaction
+    fn allows_tx_filter(&self) -> bool {
+        !self.miner_check
+    }
+
+    fn schema(&self) -> Vec<Table> {
+        vec![Table::new(
+            "versionrolling",
+            "versionrolling-<start_height>-<end_height>.csv",
+            &[
+                ("height", ColumnType::Integer),
+                ("version", ColumnType::Text),
+                ("likely_version_rolling", ColumnType::Integer),
+            ],
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rolled_bits, DEFAULT_ROLLING_MASK};
+
+    #[test]
+    fn test_rolled_bits() {
+        // Versions before BIP9 have nothing to roll
+        assert_eq!(None, rolled_bits(4, DEFAULT_ROLLING_MASK));
+        // Signalling taproot (bit 2) is outside of the BIP320 mask
+        assert_eq!(Some(0), rolled_bits(0x20000004, DEFAULT_ROLLING_MASK));
+        assert_eq!(
+            Some(0x07ff6000),
+            rolled_bits(0x27ff6000, DEFAULT_ROLLING_MASK)
+        );
+        assert_eq!(None, rolled_bits(0x60000000, DEFAULT_ROLLING_MASK));
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::BuildHasherDefault;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::Callback;
+use common::memory;
+use common::writer;
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+/// Prints a line whenever a watched address receives or spends an output, as soon as the block is processed.
+/// Only the unspent outputs of watched addresses are kept in memory.
+/// Lines are ordered by height and by their position within the block, so replaying the deltas yields the balances.
+pub struct WatchAddresses {
+    watched: HashSet<String, BuildHasherDefault<XxHash>>,
+    outputs: HashMap<TxOutpoint, (String, u64), BuildHasherDefault<XxHash>>, // Address and value of unspent outputs paying a watched address
+    to_stderr: bool, // Prints the matches to stderr, so they aren't mixed with the log
+    emit_balances: bool, // Prints the balance after each change instead of the signed value (--emit balances)
+    balances: HashMap<String, u64, BuildHasherDefault<XxHash>>, // Balance of each watched address, only with --emit balances
+
+    start_height: usize,
+    n_credits: u64,
+    n_debits: u64,
+}
+
+impl WatchAddresses {
+    /// Reads one address per line, empty lines and lines starting with `#` are ignored
+    fn load_addresses(path: &Path) -> OpResult<Vec<String>> {
+        let mut addresses = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                addresses.push(String::from(line));
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Applies a credit or debit of `address` and prints it as signed value or as the resulting balance
+    fn record(&mut self, height: usize, address: &str, value: u64, credit: bool, txid: &[u8; 32]) {
+        let shown = if self.emit_balances {
+            let balance = self.balances.entry(String::from(address)).or_insert(0);
+            *balance = match credit {
+                true => balance.saturating_add(value),
+                false => balance.saturating_sub(value),
+            };
+            balance.to_string()
+        } else {
+            format!("{}{}", if credit { "+" } else { "-" }, value)
+        };
+        self.print_match(height, address, &shown, txid);
+    }
+
+    /// Writes and flushes a single match, so it shows up immediately even if the output is piped
+    fn print_match(&self, height: usize, address: &str, value: &str, txid: &[u8; 32]) {
+        let line = format!(
+            "{};{};{};{}\n",
+            writer::row_height(height),
+            address,
+            value,
+            arr_to_hex_swapped(txid)
+        );
+        let result = if self.to_stderr {
+            let stderr = io::stderr();
+            let mut handle = stderr.lock();
+            handle
+                .write_all(line.as_bytes())
+                .and_then(|_| handle.flush())
+        } else {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            handle
+                .write_all(line.as_bytes())
+                .and_then(|_| handle.flush())
+        };
+        if let Err(e) = result {
+            warn!(target: "WatchAddresses [on_block]", "Unable to print match: {}", e);
+        }
+    }
+}
+
+impl Callback for WatchAddresses {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("watchaddresses")
+            .about("Prints `height;address;+/-value;txid` whenever a watched address receives or spends funds")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(
+                Arg::with_name("address")
+                    .long("address")
+                    .value_name("ADDRESS")
+                    .help("Watches ADDRESS, may be given several times")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("addresses")
+                    .long("addresses")
+                    .value_name("FILE")
+                    .help("Watches all addresses in FILE, one per line")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("emit")
+                    .long("emit")
+                    .value_name("MODE")
+                    .help("Prints the signed value of each credit and debit or the balance after it (default: deltas)")
+                    .possible_values(&["deltas", "balances"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("stderr")
+                    .long("stderr")
+                    .help("Prints the matches to stderr instead of stdout, which also carries the log"),
+            )
+    }
+
+    fn new(matches: &ArgMatches, _: Option<&Path>) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let mut watched: HashSet<String, BuildHasherDefault<XxHash>> = Default::default();
+        if let Some(addresses) = matches.values_of("address") {
+            watched.extend(addresses.map(String::from));
+        }
+        if let Some(path) = matches.value_of("addresses") {
+            match WatchAddresses::load_addresses(Path::new(path)) {
+                Ok(addresses) => watched.extend(addresses),
+                Err(e) => return Err(tag_err!(e, "Unable to load addresses from {:?}", path)),
+            }
+        }
+        if watched.is_empty() {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("No addresses to watch, use --address or --addresses"));
+        }
+        Ok(WatchAddresses {
+            watched: watched,
+            outputs: Default::default(),
+            to_stderr: matches.is_present("stderr"),
+            emit_balances: matches.value_of("emit") == Some("balances"),
+            balances: Default::default(),
+            start_height: 0,
+            n_credits: 0,
+            n_debits: 0,
+        })
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "WatchAddresses [on_start]", "Using `WatchAddresses` with start block {}, watching {} addresses...",
+              self.start_height, self.watched.len());
+        if self.start_height > 0 {
+            warn!(target: "WatchAddresses [on_start]", "Outputs created before block {} are unknown, spending them is not reported!", self.start_height);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        for tx in &block.txs {
+            if !tx.value.is_coinbase() {
+                for input in &tx.value.inputs {
+                    if let Some((address, value)) = self.outputs.remove(&input.outpoint) {
+                        self.record(block_height, &address, value, false, &tx.hash);
+                        self.n_debits += 1;
+                    }
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let address = match output.script.address {
+                    Some(ref address) => address.to_string(),
+                    None => continue,
+                };
+                if !self.watched.contains(&address) {
+                    continue;
+                }
+                let value = output.out.value;
+                self.record(block_height, &address, value, true, &tx.hash);
+                self.n_credits += 1;
+                let outpoint = TxOutpoint {
+                    txid: tx.hash,
+                    index: i as u32,
+                };
+                self.outputs.insert(outpoint, (address, value));
+            }
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        let balance = self
+            .outputs
+            .values()
+            .fold(0u64, |sum, &(_, value)| sum.saturating_add(value));
+        info!(target: "WatchAddresses [on_complete]", "Done.\nWatched {} addresses from block {} to {}:\n\
+                                   \t-> received:      {:9}\n\
+                                   \t-> spent:         {:9}\n\
+                                   \t-> unspent value: {:.8} ({} units)",
+              self.watched.len(), self.start_height, block_height, self.n_credits, self.n_debits,
+              balance as f64 * 1E-8, balance);
+    }
+
+    fn memory_usage(&self) -> usize {
+        memory::hash_table_bytes::<(TxOutpoint, (String, u64))>(self.outputs.len())
+            + memory::hash_table_bytes::<(String, u64)>(self.balances.len())
+    }
+}
@@ -1,4 +1,6 @@
 extern crate chrono;
+extern crate ctrlc;
+extern crate flate2;
 
 pub mod logger {
     use common::chrono::prelude::*;
@@ -52,3 +54,947 @@ pub mod logger {
         }
     }
 }
+
+pub mod shutdown {
+    use common::ctrlc;
+    use errors::{OpError, OpErrorKind, OpResult};
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Exit status of a run stopped by `--max-runtime`, which may be continued later (EX_TEMPFAIL)
+    pub const EXIT_INCOMPLETE: i32 = 75;
+
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+    static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+    /// Installs a SIGINT handler which only raises a flag.
+    /// The dispatcher checks the flag between blocks and shuts down gracefully.
+    /// A second SIGINT terminates immediately.
+    pub fn init() -> Result<(), ctrlc::Error> {
+        ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                process::exit(130);
+            }
+        })
+    }
+
+    #[inline]
+    pub fn is_interrupted() -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+
+    /// Raises the same flag as SIGINT once `max_runtime` has passed, so the scan stops gracefully
+    pub fn stop_after(max_runtime: Duration) {
+        thread::spawn(move || {
+            thread::sleep(max_runtime);
+            TIMED_OUT.store(true, Ordering::SeqCst);
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        });
+    }
+
+    /// Returns true if the scan was stopped by `--max-runtime`
+    #[inline]
+    pub fn is_timed_out() -> bool {
+        TIMED_OUT.load(Ordering::SeqCst)
+    }
+
+    /// Parses `--max-runtime`, a number with an optional unit of s, m or h, e.g. `90m`. Plain numbers are seconds.
+    pub fn parse_duration(s: &str) -> OpResult<Duration> {
+        let (number, factor) = match s.chars().last() {
+            Some('s') => (&s[..s.len() - 1], 1),
+            Some('m') => (&s[..s.len() - 1], 60),
+            Some('h') => (&s[..s.len() - 1], 3600),
+            _ => (s, 1),
+        };
+        let secs = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(factor));
+        match secs {
+            Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+            _ => Err(
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                "Invalid duration `{}`, expected a positive number of seconds or e.g. 90m or 8h",
+                s
+            )),
+            ),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::parse_duration;
+        use std::time::Duration;
+
+        #[test]
+        fn test_parse_duration() {
+            assert_eq!(Duration::from_secs(45), parse_duration("45").unwrap());
+            assert_eq!(Duration::from_secs(45), parse_duration("45s").unwrap());
+            assert_eq!(Duration::from_secs(5400), parse_duration("90m").unwrap());
+            assert_eq!(Duration::from_secs(8 * 3600), parse_duration("8h").unwrap());
+            assert!(parse_duration("0h").is_err());
+            assert!(parse_duration("1d").is_err());
+            assert!(parse_duration("h").is_err());
+            assert!(parse_duration("18446744073709551615h").is_err());
+        }
+    }
+}
+
+pub mod profile {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::time::Instant;
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+    static NANOS: [AtomicU64; 5] = [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ];
+
+    /// Hot path phases measured with `--profile`.
+    /// The times are summed over all threads, so they can exceed the wall clock time.
+    #[derive(Clone, Copy, Debug)]
+    pub enum Phase {
+        Read,     // Reading blk files from disk
+        Parse,    // Everything the workers do, including Read and Classify
+        Classify, // Script evaluation of all outputs
+        Callback, // on_block and on_complete of the callback, including Write
+        Write,    // Writing rows of the callback
+    }
+
+    pub fn set_enabled(enabled: bool) {
+        ENABLED.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Starts a timer if profiling is enabled
+    #[inline]
+    pub fn start() -> Option<Instant> {
+        match ENABLED.load(Ordering::Relaxed) {
+            true => Some(Instant::now()),
+            false => None,
+        }
+    }
+
+    /// Adds the time elapsed since `start` to `phase`
+    #[inline]
+    pub fn record(phase: Phase, start: Option<Instant>) {
+        if let Some(start) = start {
+            let elapsed = start.elapsed();
+            let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+            NANOS[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the report of all phases and resets the times,
+    /// or None if profiling is disabled
+    pub fn take_report() -> Option<String> {
+        if !ENABLED.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut nanos = [0u64; 5];
+        for (i, n) in NANOS.iter().enumerate() {
+            nanos[i] = n.swap(0, Ordering::SeqCst);
+        }
+        Some(format_report(&nanos))
+    }
+
+    /// Exclusive phases are derived by subtracting the nested ones
+    fn format_report(nanos: &[u64; 5]) -> String {
+        let secs = |n: u64| n as f64 * 1e-9;
+        let read = nanos[Phase::Read as usize];
+        let classify = nanos[Phase::Classify as usize];
+        let deserialize = nanos[Phase::Parse as usize].saturating_sub(read + classify);
+        let write = nanos[Phase::Write as usize];
+        let process = nanos[Phase::Callback as usize].saturating_sub(write);
+        format!(
+            "Time per phase (summed over all threads):\n\
+             \t-> read:          {:9.2}s\n\
+             \t-> deserialize:   {:9.2}s\n\
+             \t-> classify:      {:9.2}s\n\
+             \t-> callback:      {:9.2}s (UTXO updates and other processing)\n\
+             \t-> write:         {:9.2}s",
+            secs(read),
+            secs(deserialize),
+            secs(classify),
+            secs(process),
+            secs(write)
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::format_report;
+
+        #[test]
+        fn test_format_report() {
+            let report = format_report(&[
+                1_000_000_000,
+                4_000_000_000,
+                500_000_000,
+                3_000_000_000,
+                250_000_000,
+            ]);
+            assert!(report.contains("read:               1.00s"));
+            assert!(report.contains("deserialize:        2.50s"));
+            assert!(report.contains("classify:           0.50s"));
+            assert!(report.contains("callback:           2.75s"));
+            assert!(report.contains("write:              0.25s"));
+        }
+    }
+}
+
+pub mod memory {
+    use std::mem;
+
+    /// Estimates the allocation of a hash map or set holding `len` entries of type `T`.
+    /// Each bucket stores the entry and a control byte, at most 7/8 of the buckets are used.
+    /// Heap data owned by the entries, like strings, is not included.
+    #[inline]
+    pub fn hash_table_bytes<T>(len: usize) -> usize {
+        len * (mem::size_of::<T>() + 1) / 7 * 8
+    }
+
+    /// Formats `bytes` as megabytes for log messages
+    pub fn format_mb(bytes: usize) -> String {
+        format!("{:.1} MB", bytes as f64 / 1E6)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::hash_table_bytes;
+
+        #[test]
+        fn test_hash_table_bytes() {
+            assert_eq!(0, hash_table_bytes::<u64>(0));
+            // 7 entries of 9 bytes occupy 8 buckets
+            assert_eq!(72, hash_table_bytes::<u64>(7));
+            assert!(hash_table_bytes::<([u8; 32], u32)>(1000000) > 36000000);
+        }
+    }
+}
+
+/// Scratch directories for tests
+#[cfg(test)]
+pub mod testdir {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Returns an empty directory for the test `name`, which no other test process uses
+    pub fn create(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rusty-blockparser-{}.{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+}
+
+pub mod writer {
+    use std::fs::{self, File};
+    use std::io::{self, BufWriter, Write};
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU8, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    #[cfg(test)]
+    use std::sync::MutexGuard;
+    use std::time::{Duration, Instant};
+
+    use callbacks::schema::Table;
+    use common::flate2::write::GzEncoder;
+    use common::flate2::Compression;
+    use common::profile::{self, Phase};
+    use errors::{OpError, OpErrorKind, OpResult};
+
+    /// Buffer size of each file written by a callback, unless set with `--write-buffer-size`
+    pub const DEFAULT_BUFFER_SIZE: usize = 4000000;
+
+    static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_SIZE);
+    static FLUSH_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+    static DELIMITER: AtomicU8 = AtomicU8::new(b';');
+    static HEADER_ROW: AtomicBool = AtomicBool::new(false);
+    static GZIP: AtomicBool = AtomicBool::new(false);
+    static CRLF: AtomicBool = AtomicBool::new(false);
+    static OVERWRITE: AtomicU8 = AtomicU8::new(Overwrite::Rename as u8);
+    static SUMMARY_ONLY: AtomicBool = AtomicBool::new(false);
+    static HEIGHT_ORIGIN: AtomicI64 = AtomicI64::new(0);
+    static TARGETS: Mutex<Vec<Target>> = Mutex::new(Vec::new());
+    #[cfg(test)]
+    static TEST_SETTINGS: Mutex<()> = Mutex::new(());
+
+    /// Resets all settings to their defaults and keeps other tests from changing them until the guard is dropped.
+    /// The settings are global, so tests which create writers or change a setting run one at a time.
+    #[cfg(test)]
+    pub fn test_defaults() -> MutexGuard<'static, ()> {
+        let guard = TEST_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+        BUFFER_SIZE.store(DEFAULT_BUFFER_SIZE, Ordering::SeqCst);
+        FLUSH_INTERVAL.store(0, Ordering::SeqCst);
+        DELIMITER.store(b';', Ordering::SeqCst);
+        HEADER_ROW.store(false, Ordering::SeqCst);
+        GZIP.store(false, Ordering::SeqCst);
+        CRLF.store(false, Ordering::SeqCst);
+        OVERWRITE.store(Overwrite::Rename as u8, Ordering::SeqCst);
+        SUMMARY_ONLY.store(false, Ordering::SeqCst);
+        HEIGHT_ORIGIN.store(0, Ordering::SeqCst);
+        TARGETS.lock().unwrap().clear();
+        guard
+    }
+
+    /// Line terminator of the CSV files written by the callbacks
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum LineEnding {
+        Lf,
+        CrLf,
+    }
+
+    impl FromStr for LineEnding {
+        type Err = OpError;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "lf" => Ok(LineEnding::Lf),
+                "crlf" => Ok(LineEnding::CrLf),
+                n => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg(&format!("Unknown line ending `{}`, expected lf or crlf", n))),
+            }
+        }
+    }
+
+    /// Sets the line ending for all writers created afterwards
+    pub fn set_line_ending(line_ending: LineEnding) {
+        CRLF.store(line_ending == LineEnding::CrLf, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn line_ending() -> LineEnding {
+        match CRLF.load(Ordering::SeqCst) {
+            true => LineEnding::CrLf,
+            false => LineEnding::Lf,
+        }
+    }
+
+    /// Sets the buffer size of all writers created afterwards
+    pub fn set_buffer_size(n_bytes: usize) {
+        BUFFER_SIZE.store(n_bytes, Ordering::SeqCst);
+    }
+
+    /// Flushes the rows of all writers created afterwards at least every `seconds`, 0 only flushes full buffers
+    pub fn set_flush_interval(seconds: usize) {
+        FLUSH_INTERVAL.store(seconds, Ordering::SeqCst);
+    }
+
+    /// Sets the column separator of all writers created afterwards
+    pub fn set_delimiter(delimiter: u8) {
+        DELIMITER.store(delimiter, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn delimiter() -> u8 {
+        DELIMITER.load(Ordering::SeqCst)
+    }
+
+    /// Parses `--delimiter`, which is a single printable ASCII character or `tab`
+    pub fn parse_delimiter(s: &str) -> OpResult<u8> {
+        match s.as_bytes() {
+            b"tab" | b"\\t" | b"\t" => Ok(b'\t'),
+            &[c] if c.is_ascii_graphic() && c != b'"' => Ok(c),
+            _ => Err(
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                    "Invalid delimiter `{}`, expected a single printable ASCII character or tab",
+                    s
+                )),
+            ),
+        }
+    }
+
+    /// Lets writers created afterwards start with the column names (`--header-row`)
+    pub fn set_header_row(header_row: bool) {
+        HEADER_ROW.store(header_row, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn is_header_row() -> bool {
+        HEADER_ROW.load(Ordering::SeqCst)
+    }
+
+    /// Compresses all files created afterwards with gzip, except the ones needed to resume (`--compress gzip`)
+    pub fn set_gzip(gzip: bool) {
+        GZIP.store(gzip, Ordering::SeqCst);
+    }
+
+    /// One of the files written for each row file of a callback, see `parse_targets`
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Target {
+        extension: &'static str, // Replaces `csv` in the file names
+        delimiter: u8,
+        gzip: bool,
+    }
+
+    /// Parses the targets of `--output-format`, a comma separated list of `FORMAT[:OPTION=VALUE]...`.
+    /// The formats are `csv` and `tsv`, the options `delimiter=<CHAR>` (`tab` or `comma` for these) and
+    /// `compress=gzip|none`. Options which aren't given default to `delimiter` and `gzip`.
+    pub fn parse_targets(s: &str, delimiter: u8, gzip: bool) -> OpResult<Vec<Target>> {
+        let invalid = |msg: String| OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg);
+        let mut targets: Vec<Target> = Vec::new();
+        for spec in s.split(',').filter(|spec| !spec.is_empty()) {
+            let mut parts = spec.split(':');
+            let format = parts.next().unwrap();
+            let mut target = match format {
+                "csv" => Target {
+                    extension: "csv",
+                    delimiter: delimiter,
+                    gzip: gzip,
+                },
+                "tsv" => Target {
+                    extension: "tsv",
+                    delimiter: b'\t',
+                    gzip: gzip,
+                },
+                "parquet" | "arrow" => {
+                    return Err(invalid(format!(
+                        "Output format `{}` is not supported yet, expected csv or tsv",
+                        format
+                    )))
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "Unknown output format `{}`, expected csv or tsv",
+                        format
+                    )))
+                }
+            };
+            for option in parts {
+                match option.split_at(option.find('=').unwrap_or(option.len())) {
+                    ("delimiter", "=comma") => target.delimiter = b',',
+                    ("delimiter", value) if !value.is_empty() => {
+                        target.delimiter = parse_delimiter(&value[1..])?
+                    }
+                    ("compress", "=gzip") => target.gzip = true,
+                    ("compress", "=none") => target.gzip = false,
+                    _ => {
+                        return Err(invalid(format!(
+                            "Invalid option `{}` of output format `{}`, expected delimiter=<CHAR> or compress=gzip|none",
+                            option, spec
+                        )))
+                    }
+                }
+            }
+            if targets
+                .iter()
+                .any(|t| t.extension == target.extension && t.gzip == target.gzip)
+            {
+                return Err(invalid(format!(
+                    "Output format `{}` would overwrite the files of an earlier one",
+                    spec
+                )));
+            }
+            targets.push(target);
+        }
+        if targets.is_empty() {
+            return Err(invalid(String::from(
+                "--output-format needs at least one format",
+            )));
+        }
+        Ok(targets)
+    }
+
+    /// Writes each row file created afterwards once per target, an empty list writes a single file as usual
+    pub fn set_targets(targets: Vec<Target>) {
+        *TARGETS.lock().unwrap() = targets;
+    }
+
+    /// Returns `path` with its `.csv` replaced by the extension of `target`, or appended if it has none
+    fn target_path(path: &Path, target: &Target) -> PathBuf {
+        if target.extension == "csv" {
+            return path.to_path_buf();
+        }
+        let name = path.file_name().unwrap().to_string_lossy();
+        let ext = format!(".{}", target.extension);
+        let name = match name.rfind(".csv") {
+            Some(i) => format!("{}{}{}", &name[..i], ext, &name[i + 4..]),
+            None => format!("{}{}", name, ext),
+        };
+        path.with_file_name(name)
+    }
+
+    /// File behind the writer of a callback
+    pub enum Output {
+        Plain(BufWriter<File>),
+        Gzip(GzEncoder<BufWriter<File>>),
+        Targets(Vec<(Target, RowWriter<Output>)>), // Receives all rows with `;` and `\n` and passes them to each target
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match *self {
+                Output::Plain(ref mut w) => w.write(buf),
+                Output::Gzip(ref mut w) => w.write(buf),
+                Output::Targets(ref mut targets) => {
+                    for &mut (_, ref mut w) in targets.iter_mut() {
+                        w.write_rows(buf)?;
+                    }
+                    Ok(buf.len())
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            match *self {
+                Output::Plain(ref mut w) => w.flush(),
+                Output::Gzip(ref mut w) => w.flush(),
+                Output::Targets(ref mut targets) => {
+                    for &mut (_, ref mut w) in targets.iter_mut() {
+                        w.flush()?;
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    impl Output {
+        /// Writes all buffered data, including the gzip trailer
+        fn finish(&mut self) -> io::Result<()> {
+            match *self {
+                Output::Plain(ref mut w) => w.flush(),
+                Output::Gzip(ref mut w) => {
+                    w.try_finish()?;
+                    w.get_mut().flush()
+                }
+                Output::Targets(ref mut targets) => {
+                    for &mut (_, ref mut w) in targets.iter_mut() {
+                        w.inner.finish()?;
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub type FileWriter = RowWriter<Output>;
+
+    /// Creates `path` for row output, or one file per target of `--output-format`
+    pub fn create(path: &Path) -> OpResult<FileWriter> {
+        let targets = TARGETS.lock().unwrap();
+        match targets.is_empty() {
+            true => Ok(RowWriter::new(open(path, GZIP.load(Ordering::SeqCst))?)),
+            false => create_targets(path, &targets),
+        }
+    }
+
+    fn create_targets(path: &Path, targets: &[Target]) -> OpResult<FileWriter> {
+        let mut writers = Vec::with_capacity(targets.len());
+        for target in targets {
+            let output = open(&target_path(path, target), target.gzip)?;
+            let writer = RowWriter::with(output, line_ending(), target.delimiter, false);
+            writers.push((target.clone(), writer));
+        }
+        // The targets convert the rows themselves
+        let header_row = is_header_row();
+        let mut writer = RowWriter::with(
+            Output::Targets(writers),
+            LineEnding::Lf,
+            b';',
+            is_summary_only(),
+        );
+        writer.header_row = header_row;
+        Ok(writer)
+    }
+
+    /// Creates `path` for a file which is loaded again to resume a scan, see `RowWriter::for_state`.
+    /// These are never compressed.
+    pub fn create_state(path: &Path) -> OpResult<FileWriter> {
+        Ok(RowWriter::for_state(open(path, false)?))
+    }
+
+    /// Creates `path` for binary output, see `RowWriter::binary`
+    pub fn create_binary(path: &Path) -> OpResult<FileWriter> {
+        Ok(RowWriter::binary(open(path, GZIP.load(Ordering::SeqCst))?))
+    }
+
+    fn open(path: &Path, gzip: bool) -> OpResult<Output> {
+        let file = match File::create(path) {
+            Ok(f) => BufWriter::with_capacity(BUFFER_SIZE.load(Ordering::SeqCst), f),
+            Err(err) => return Err(tag_err!(OpError::from(err), " while creating {:?}", path)),
+        };
+        Ok(match gzip {
+            true => Output::Gzip(GzEncoder::new(file, Compression::default())),
+            false => Output::Plain(file),
+        })
+    }
+
+    /// What happens if the final path of a finished file already exists
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Overwrite {
+        Rename,    // Leaves it to fs::rename, which replaces the file on Unix but fails on Windows
+        NoClobber, // Keeps the existing file and the temp file (--no-clobber)
+        Force,     // Removes the existing file if the rename fails (--force)
+    }
+
+    pub fn set_overwrite(overwrite: Overwrite) {
+        OVERWRITE.store(overwrite as u8, Ordering::SeqCst);
+    }
+
+    fn overwrite() -> Overwrite {
+        match OVERWRITE.load(Ordering::SeqCst) {
+            x if x == Overwrite::NoClobber as u8 => Overwrite::NoClobber,
+            x if x == Overwrite::Force as u8 => Overwrite::Force,
+            _ => Overwrite::Rename,
+        }
+    }
+
+    /// Moves a finished temp file to its final path, honoring `--no-clobber` and `--force`
+    pub fn rename<P: AsRef<Path>>(tmp_path: P, path: P) -> io::Result<()> {
+        rename_with(tmp_path.as_ref(), path.as_ref(), overwrite())
+    }
+
+    fn rename_with(tmp_path: &Path, path: &Path, overwrite: Overwrite) -> io::Result<()> {
+        if overwrite == Overwrite::NoClobber && path.exists() {
+            let msg = format!(
+                "{:?} already exists and --no-clobber is set, the new rows are kept in {:?}",
+                path, tmp_path
+            );
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+        }
+        match fs::rename(tmp_path, path) {
+            Err(_) if overwrite == Overwrite::Force && path.exists() => {
+                fs::remove_file(path)?;
+                fs::rename(tmp_path, path)
+            }
+            Err(e) if path.exists() => {
+                let msg = format!("{} (use --force to replace {:?})", e, path);
+                Err(io::Error::new(e.kind(), msg))
+            }
+            result => result,
+        }
+    }
+
+    /// Suppresses all row output for writers created afterwards, see `RowWriter::new`
+    pub fn set_summary_only(summary_only: bool) {
+        SUMMARY_ONLY.store(summary_only, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn is_summary_only() -> bool {
+        SUMMARY_ONLY.load(Ordering::SeqCst)
+    }
+
+    /// Sets the block height which is written as 0 in the height columns
+    pub fn set_height_origin(origin: usize) {
+        HEIGHT_ORIGIN.store(origin as i64, Ordering::SeqCst);
+    }
+
+    /// Converts `height` for the height columns of row output.
+    /// Files which are loaded again to resume a scan must keep the absolute height.
+    #[inline]
+    pub fn row_height(height: usize) -> i64 {
+        height as i64 - HEIGHT_ORIGIN.load(Ordering::Relaxed)
+    }
+
+    /// Wraps the writer of a callback and writes each row with the configured delimiter and line ending.
+    /// Callbacks always format their rows with `;` and `\n`.
+    pub struct RowWriter<W: Write> {
+        inner: W,
+        line_ending: LineEnding,
+        delimiter: u8,
+        header_row: bool,
+        discard: bool, // Drops all rows if only the summary is requested
+        flush_interval: Option<Duration>,
+        last_flush: Instant,
+    }
+
+    impl<W: Write> RowWriter<W> {
+        fn with(inner: W, line_ending: LineEnding, delimiter: u8, discard: bool) -> RowWriter<W> {
+            let flush_interval = match FLUSH_INTERVAL.load(Ordering::SeqCst) {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds as u64)),
+            };
+            RowWriter {
+                inner: inner,
+                line_ending: line_ending,
+                delimiter: delimiter,
+                header_row: HEADER_ROW.load(Ordering::SeqCst),
+                discard: discard,
+                flush_interval: flush_interval,
+                last_flush: Instant::now(),
+            }
+        }
+
+        /// Creates a writer for row output, which is discarded with `--summary-only`
+        pub fn new(inner: W) -> RowWriter<W> {
+            RowWriter::with(inner, line_ending(), delimiter(), is_summary_only())
+        }
+
+        /// Creates a writer for files which are loaded again to resume a scan.
+        /// These are always written and keep the `;` delimiter, so they can be parsed again.
+        pub fn for_state(inner: W) -> RowWriter<W> {
+            let mut writer = RowWriter::with(inner, line_ending(), b';', false);
+            writer.header_row = false;
+            writer
+        }
+
+        /// Creates a writer for binary output, which is written as is and discarded with `--summary-only`
+        pub fn binary(inner: W) -> RowWriter<W> {
+            let mut writer = RowWriter::with(inner, LineEnding::Lf, b';', is_summary_only());
+            writer.header_row = false;
+            writer
+        }
+
+        /// Writes the column names of `table` as first row if `--header-row` is set
+        pub fn write_header(&mut self, table: &Table) -> io::Result<()> {
+            if !self.header_row {
+                return Ok(());
+            }
+            let columns: Vec<&str> = table.columns.iter().map(|&(name, _)| name).collect();
+            let mut row = columns.join(";");
+            row.push('\n');
+            self.write_all(row.as_bytes())
+        }
+    }
+
+    impl RowWriter<Output> {
+        /// Flushes all rows and moves the temp file to its final path, which gets a `.gz` suffix if compressed.
+        /// If the rows were discarded, the empty temp file is removed instead.
+        pub fn finalize<P: AsRef<Path>>(&mut self, tmp_path: P, path: P) -> io::Result<()> {
+            self.inner.finish()?;
+            let (tmp_path, path) = (tmp_path.as_ref(), path.as_ref());
+            match self.inner {
+                Output::Targets(ref targets) => {
+                    for &(ref target, ref w) in targets {
+                        finalize_file(
+                            &w.inner,
+                            self.discard,
+                            &target_path(tmp_path, target),
+                            &target_path(path, target),
+                        )?;
+                    }
+                    Ok(())
+                }
+                ref output => finalize_file(output, self.discard, tmp_path, path),
+            }
+        }
+    }
+
+    fn finalize_file(
+        output: &Output,
+        discard: bool,
+        tmp_path: &Path,
+        path: &Path,
+    ) -> io::Result<()> {
+        if discard {
+            return fs::remove_file(tmp_path);
+        }
+        let path = match *output {
+            Output::Gzip(_) => {
+                let mut name = path.as_os_str().to_owned();
+                name.push(".gz");
+                PathBuf::from(name)
+            }
+            _ => path.to_path_buf(),
+        };
+        rename(tmp_path, &path)
+    }
+
+    impl<W: Write> RowWriter<W> {
+        fn write_rows(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let crlf = self.line_ending == LineEnding::CrLf;
+            if !crlf && self.delimiter == b';' {
+                self.inner.write_all(buf)?;
+                return Ok(buf.len());
+            }
+            let delimiter = [self.delimiter];
+            let mut start = 0;
+            for (i, &b) in buf.iter().enumerate() {
+                let replacement: &[u8] = match b {
+                    b'\n' if crlf => b"\r\n",
+                    b';' => &delimiter,
+                    _ => continue,
+                };
+                self.inner.write_all(&buf[start..i])?;
+                self.inner.write_all(replacement)?;
+                start = i + 1;
+            }
+            self.inner.write_all(&buf[start..])?;
+            Ok(buf.len())
+        }
+
+        /// Flushes the buffered rows if `--flush-interval` passed since the last flush
+        fn flush_due(&mut self) -> io::Result<()> {
+            match self.flush_interval {
+                Some(interval) if self.last_flush.elapsed() >= interval => {
+                    self.last_flush = Instant::now();
+                    self.inner.flush()
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl<W: Write> Write for RowWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.discard {
+                return Ok(buf.len());
+            }
+            let t_start = profile::start();
+            let result = self.write_rows(buf);
+            if result.is_ok() {
+                self.flush_due()?;
+            }
+            profile::record(Phase::Write, t_start);
+            result
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{
+            create_targets, parse_delimiter, parse_targets, rename_with, test_defaults, LineEnding,
+            Overwrite, RowWriter, Target,
+        };
+        use callbacks::schema::{ColumnType, Table};
+        use common::testdir;
+        use std::fs;
+        use std::io::Write;
+
+        #[test]
+        fn test_row_writer_line_endings() {
+            let mut writer = RowWriter::new(Vec::new());
+            writer.line_ending = LineEnding::CrLf;
+            writer.write_all(b"1;2\n3;4\n").unwrap();
+            writer.write_all(b"5;").unwrap();
+            writer.write_all(b"6\n").unwrap();
+            assert_eq!(writer.inner, b"1;2\r\n3;4\r\n5;6\r\n".to_vec());
+
+            let mut writer = RowWriter::new(Vec::new());
+            writer.line_ending = LineEnding::Lf;
+            writer.write_all(b"1;2\n3;4\n").unwrap();
+            assert_eq!(writer.inner, b"1;2\n3;4\n".to_vec());
+        }
+
+        #[test]
+        fn test_row_writer_delimiter() {
+            let mut writer = RowWriter::new(Vec::new());
+            writer.delimiter = b'\t';
+            writer.line_ending = LineEnding::CrLf;
+            writer.header_row = true;
+            let table = Table::new(
+                "burns",
+                "burns.csv",
+                &[
+                    ("height", ColumnType::Integer),
+                    ("value", ColumnType::BigInt),
+                ],
+            );
+            writer.write_header(&table).unwrap();
+            writer.write_all(b"1;2\n").unwrap();
+            assert_eq!(writer.inner, b"height\tvalue\r\n1\t2\r\n".to_vec());
+
+            // Without --header-row the header is left out
+            let mut writer = RowWriter::new(Vec::new());
+            writer.header_row = false;
+            writer.write_header(&table).unwrap();
+            assert!(writer.inner.is_empty());
+
+            assert_eq!(b'\t', parse_delimiter("tab").unwrap());
+            assert_eq!(b',', parse_delimiter(",").unwrap());
+            assert!(parse_delimiter(",;").is_err());
+            assert!(parse_delimiter(" ").is_err());
+        }
+
+        #[test]
+        fn test_row_writer_discard() {
+            let mut writer = RowWriter::for_state(Vec::new());
+            writer.discard = true;
+            writer.write_all(b"1;2\n").unwrap();
+            writer.flush().unwrap();
+            assert!(writer.inner.is_empty());
+        }
+
+        #[test]
+        fn test_parse_targets() {
+            let targets = parse_targets(
+                "csv,tsv:compress=gzip,csv:delimiter=comma:compress=gzip",
+                b';',
+                false,
+            )
+            .unwrap();
+            let expected = vec![
+                Target {
+                    extension: "csv",
+                    delimiter: b';',
+                    gzip: false,
+                },
+                Target {
+                    extension: "tsv",
+                    delimiter: b'\t',
+                    gzip: true,
+                },
+                Target {
+                    extension: "csv",
+                    delimiter: b',',
+                    gzip: true,
+                },
+            ];
+            assert_eq!(expected, targets);
+
+            // Both write the same uncompressed csv files
+            assert!(parse_targets("csv,csv:delimiter=tab", b';', false).is_err());
+            assert!(parse_targets("csv,parquet", b';', false).is_err());
+            assert!(parse_targets("csv:delimiter", b';', false).is_err());
+            assert!(parse_targets("csv:compress=zstd", b';', false).is_err());
+            assert!(parse_targets(",", b';', false).is_err());
+        }
+
+        #[test]
+        fn test_row_writer_targets() {
+            let _settings = test_defaults();
+            let folder = testdir::create("targets.test");
+            let targets = parse_targets("csv:delimiter=comma,tsv", b';', false).unwrap();
+            let mut writer = create_targets(&folder.join("rows.csv.tmp"), &targets).unwrap();
+            writer.write_all(b"1;2\n3;4\n").unwrap();
+            writer
+                .finalize(folder.join("rows.csv.tmp"), folder.join("rows-0-1.csv"))
+                .unwrap();
+
+            let csv = fs::read_to_string(folder.join("rows-0-1.csv")).unwrap();
+            assert_eq!("1,2\n3,4\n", csv);
+            let tsv = fs::read_to_string(folder.join("rows-0-1.tsv")).unwrap();
+            assert_eq!("1\t2\n3\t4\n", tsv);
+            assert!(!folder.join("rows.tsv.tmp").exists());
+            fs::remove_dir_all(&folder).unwrap();
+        }
+
+        #[test]
+        fn test_rename_existing() {
+            let folder = testdir::create("rename.test");
+            let tmp_path = folder.join("rename.test.csv.tmp");
+            let path = folder.join("rename.test.csv");
+            fs::write(&path, "old").unwrap();
+
+            // The existing file and the temp file are kept
+            fs::write(&tmp_path, "new").unwrap();
+            assert!(rename_with(&tmp_path, &path, Overwrite::NoClobber).is_err());
+            assert_eq!("old", fs::read_to_string(&path).unwrap());
+            assert!(tmp_path.exists());
+
+            assert!(rename_with(&tmp_path, &path, Overwrite::Force).is_ok());
+            assert_eq!("new", fs::read_to_string(&path).unwrap());
+            assert!(!tmp_path.exists());
+
+            // Without a file at the final path --no-clobber renames as usual
+            fs::remove_file(&path).unwrap();
+            fs::write(&tmp_path, "new").unwrap();
+            assert!(rename_with(&tmp_path, &path, Overwrite::NoClobber).is_ok());
+            assert_eq!("new", fs::read_to_string(&path).unwrap());
+            fs::remove_dir_all(&folder).unwrap();
+        }
+    }
+}
@@ -24,23 +24,52 @@ use std::boxed::Box;
 use std::fs;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::process;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use log::LogLevelFilter;
 
 use blockchain::parser::chain;
+use blockchain::parser::reorder;
+use blockchain::parser::shards::ShardOptions;
 use blockchain::parser::types::{Bitcoin, CoinType};
-use blockchain::parser::{BlockchainParser, ParseMode};
+use blockchain::parser::{self, BlockchainParser, ParseMode};
 use blockchain::utils;
 use blockchain::utils::blkfile::BlkFile;
+use blockchain::utils::throttle::RateLimiter;
+use callbacks::addressreuse::AddressReuse;
+use callbacks::blockstats::BlockStats;
+use callbacks::burndump::BurnDump;
 use callbacks::clusterizer::Clusterizer;
+use callbacks::coinbasedump::CoinbaseDump;
+use callbacks::coinjoindump::CoinJoinDump;
+use callbacks::cpfpdump::CpfpDump;
 use callbacks::csvdump::CsvDump;
+use callbacks::firstpaymentdump::FirstPaymentDump;
+use callbacks::fragmentation::Fragmentation;
+use callbacks::headerdump::HeaderDump;
+use callbacks::outputcountdump::OutputCountDump;
+use callbacks::protocoldump::ProtocolDump;
+use callbacks::realizedcap::RealizedCap;
+use callbacks::schema::{self, Dialect};
+use callbacks::scripthashdump::ScriptHashDump;
+use callbacks::spenddump::SpendDump;
 use callbacks::stats::SimpleStats;
+use callbacks::taprootadoption::TaprootAdoption;
+use callbacks::txdepthdump::TxDepthDump;
 use callbacks::txodump::TXODump;
+use callbacks::utxodelta::UTXODelta;
 use callbacks::utxodump::UTXODump;
+use callbacks::verifysnapshot::VerifySnapshot;
+use callbacks::versionrolling::VersionRolling;
+use callbacks::watchaddresses::WatchAddresses;
 use callbacks::Callback;
 use common::logger::SimpleLogger;
+use common::profile;
+use common::shutdown;
+use common::writer::{self, LineEnding, Overwrite};
 use errors::{OpError, OpErrorKind, OpResult};
 
 /// Holds all available user arguments
@@ -48,32 +77,251 @@ pub struct ParserOptions {
     callback: Box<Callback>, /* Name of the callback which gets executed for each block. (See callbacks/mod.rs)                      */
     coin_type: CoinType, /* Holds the name of the coin we want to parse                                                          */
     verify_merkle_root: bool, /* Enable this if you want to check the merkle root of each block. Aborts if something is fishy.        */
+    checksum_verify_blocks: bool, /* Compares the reserialized size of each block to its declared size (--checksum-verify-blocks)  */
+    parallel_hash: bool, /* Runs the merkle root and size checks on the worker threads (--parallel-hash)                 */
+    validate_difficulty: bool, /* Recomputes the difficulty bits at each retarget boundary (--validate-difficulty)                */
+    strict: bool, /* Failed validations abort the scan instead of logging a warning (--strict)                            */
+    dump_failing_block: Option<PathBuf>, /* Where the block failing a --strict check is written to (--dump-failing-block) */
     thread_count: u8, /* Number of core threads. The callback gets sequentially called!                                       */
     resume: bool, /* Resumes from latest known hash in chain.json.                                                        */
+    resume_verify: bool, /* Checks that the latest processed block is still part of the longest chain (--resume-verify)     */
     reindex: bool, /* Forces reindexing                                                                                    */
     blockchain_dir: PathBuf, /* Path to directory where blk.dat files are stored                                                     */
+    scan_depth: usize, /* Levels of sub directories of blockchain_dir which are scanned for blk files (--recursive)           */
+    follow_new_files: bool, /* Indexes blk files written after the scan started (--follow-new-files)                         */
     chain_storage_path: PathBuf, /* Path to the longest-chain.json generated by initial header scan                                      */
     worker_backlog: usize, /* Maximum backlog for each thread. If the backlog is full the worker waits until there is some space.  */
     /* Usually this happens if the callback implementation is too slow or if we reached the I/O capabilites */
     log_level_filter: LogLevelFilter, /* Verbosity level, 0 = Error, 1 = Info, 2 = Debug, 3+ = Trace                                          */
+    txid_prefix: Option<String>, /* Only transactions whose txid starts with these hex nibbles are passed to the callback                */
+    read_limiter: Option<Arc<RateLimiter>>, /* Caps the blk file read bandwidth of all workers together (--max-read-mbps)           */
+    memory_limit: Option<usize>, /* Estimated callback memory in bytes which triggers degradation or an abort (--memory-limit)      */
+    show_tx_rate: bool, /* Adds transactions/sec and outputs/sec to the status line (--transactions-per-second)              */
+    single_file: Option<PathBuf>, /* Parses only this blk file in file order, without chain storage (--single-file)                   */
+    schema_file: Option<(PathBuf, Dialect)>, /* Where to write the DDL of the callback's CSV files and in which dialect (--write-schema-file) */
+    manifest_path: Option<PathBuf>, /* Where to write the manifest with the coin's value unit (--write-manifest)                         */
+    bench_loops: Option<usize>, /* Replays all blocks this many times from memory through the callback (--bench-loops)           */
+    block_sample: usize, /* Only every n-th block is passed to the callback (--block-sample)                            */
+    parallel_write: Option<ShardOptions>, /* Splits an order insensitive callback into shards with their own threads (--parallel-write) */
+    unknown_magic_coin: Option<CoinType>, /* Coin to parse blocks with a magic value of no supported coin as (--assume-coin-on-unknown) */
+    reorder_buffer_size: usize, /* Bytes of blocks which may arrive before their predecessor until the scan aborts (--reorder-buffer-size) */
+}
+
+/// A single (coin, blk-dir, out-dir) tuple given with `--run`
+struct RunSpec {
+    coin_type: CoinType,
+    blockchain_dir: PathBuf,
+    out_dir: PathBuf,
+}
+
+impl RunSpec {
+    /// Creates a spec from the three values of a `--run`.
+    /// They are separate arguments, so paths may contain any character, like the drive of a Windows path.
+    fn from_values(values: &[&str]) -> OpResult<Self> {
+        if values.len() != 3 || values.iter().any(|v| v.is_empty()) {
+            let msg = format!(
+                "Invalid --run `{}`, expected COIN BLKDIR OUTDIR",
+                values.join(" ")
+            );
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+        }
+        Ok(RunSpec {
+            coin_type: CoinType::from_str(values[0])?,
+            blockchain_dir: PathBuf::from(values[1]),
+            out_dir: PathBuf::from(values[2]),
+        })
+    }
 }
 
 fn main() {
-    // Init user args
-    let mut options = match parse_args() {
-        Ok(o) => o,
-        Err(desc) => {
-            // Init logger to print outstanding error message
-            SimpleLogger::init(LogLevelFilter::Debug).unwrap();
-            error!(target: "main", "{}", desc);
-            return;
+    let matches = build_app().get_matches();
+
+    // Runs before the logger is set up, so stdout holds nothing but the JSON
+    if let Some(sub_matches) = matches.subcommand_matches("print-params") {
+        let coin_type = value_t!(sub_matches, "coin", CoinType)
+            .or(value_t!(matches, "coin", CoinType))
+            .unwrap_or(CoinType::from(Bitcoin));
+        println!("{}", coin_type.params_json());
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("diff-chains") {
+        if let Err(e) = diff_chains(sub_matches) {
+            eprintln!("{}", e);
+            process::exit(1);
         }
-    };
+        return;
+    }
 
     // Apply log filter based on verbosity
-    SimpleLogger::init(options.log_level_filter).expect("Unable to initialize logger!");
+    SimpleLogger::init(get_log_level_filter(&matches)).expect("Unable to initialize logger!");
     info!(target: "main", "Starting rusty-blockparser v{} ...", env!("CARGO_PKG_VERSION"));
+    if let Err(e) = shutdown::init() {
+        warn!(target: "main", "Unable to install SIGINT handler: {}", e);
+    }
+    if let Some(max_runtime) = matches.value_of("max-runtime") {
+        match shutdown::parse_duration(max_runtime) {
+            Ok(max_runtime) => shutdown::stop_after(max_runtime),
+            Err(e) => {
+                error!(target: "main", "{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let specs = match matches.values_of("run") {
+        Some(values) => match values
+            .collect::<Vec<_>>()
+            .chunks(3)
+            .map(RunSpec::from_values)
+            .collect::<OpResult<Vec<_>>>()
+        {
+            Ok(specs) => specs,
+            Err(e) => {
+                error!(target: "main", "{}", e);
+                process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    // Single run configured by --coin and --blockchain-dir
+    if specs.is_empty() {
+        let result = parse_args(&matches).and_then(|mut options| run(&mut options));
+        match result {
+            Ok(()) => info!(target: "main", "Fin."),
+            Err(ref e) => error!(target: "main", "{}", e),
+        }
+        exit_if_timed_out();
+        if result.is_err() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let fail_fast = matches.is_present("fail-fast");
+    let mut results = Vec::with_capacity(specs.len());
+    for spec in &specs {
+        info!(target: "main", "Starting run for {} with blockchain dir {:?} and output dir {:?} ...",
+              spec.coin_type.name, spec.blockchain_dir, spec.out_dir);
+        let t_started = time::precise_time_s();
+        let result = parse_run_args(&matches, spec).and_then(|mut options| run(&mut options));
+        if let Err(ref e) = result {
+            error!(target: "main", "Run for {} failed: {}", spec.coin_type.name, e);
+        }
+        let failed = result.is_err();
+        results.push((spec, result, time::precise_time_s() - t_started));
+        if shutdown::is_interrupted() || (failed && fail_fast) {
+            break;
+        }
+    }
+
+    // Combined summary of all runs
+    let mut summary = String::new();
+    for &(spec, ref result, t_elapsed) in &results {
+        let status = match *result {
+            Ok(()) if shutdown::is_timed_out() => String::from("stopped by --max-runtime"),
+            Ok(()) if shutdown::is_interrupted() => String::from("interrupted"),
+            Ok(()) => String::from("ok"),
+            Err(ref e) => format!("failed ({})", e),
+        };
+        summary.push_str(&format!(
+            "\n\t-> {:10} {:?}: {} after {:.2} minutes",
+            spec.coin_type.name,
+            spec.out_dir,
+            status,
+            t_elapsed / 60.0
+        ));
+    }
+    for spec in specs.iter().skip(results.len()) {
+        summary.push_str(&format!(
+            "\n\t-> {:10} {:?}: skipped",
+            spec.coin_type.name, spec.out_dir
+        ));
+    }
+    let n_failed = results.iter().filter(|r| r.1.is_err()).count();
+    info!(target: "main", "Finished {} of {} runs ({} failed):{}",
+          results.len(), specs.len(), n_failed, summary);
+    info!(target: "main", "Fin.");
+    exit_if_timed_out();
+    if n_failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Exits with `EXIT_INCOMPLETE` if `--max-runtime` stopped the scan, so scheduled jobs notice the partial output
+fn exit_if_timed_out() {
+    if shutdown::is_timed_out() {
+        warn!(target: "main", "Stopped by --max-runtime, the output is incomplete. Run the same command again to continue.");
+        process::exit(shutdown::EXIT_INCOMPLETE);
+    }
+}
+
+/// Prints the common ancestor of two chain storages and the blocks after it on each side
+fn diff_chains(matches: &ArgMatches) -> OpResult<()> {
+    let (first, second) = (
+        matches.value_of("first").unwrap(),
+        matches.value_of("second").unwrap(),
+    );
+    let load = |path: &str| {
+        chain::ChainStorage::load(Path::new(path))
+            .map_err(|e| tag_err!(e, "Couldn't load chain storage {:?}", path))
+    };
+    let diff = load(first)?.diff(&load(second)?);
+
+    match diff.common_ancestor {
+        Some((height, hash)) => println!(
+            "Common ancestor: height {}, hash {}",
+            height,
+            utils::arr_to_hex_swapped(&hash)
+        ),
+        None => println!("Common ancestor: none, the chains differ from the first block on"),
+    }
+    if diff.ours.is_empty() && diff.theirs.is_empty() {
+        println!("Both chains are identical");
+        return Ok(());
+    }
+    println!(
+        "Divergent blocks: {} in {}, {} in {}",
+        diff.ours.len(),
+        first,
+        diff.theirs.len(),
+        second
+    );
+    println!("height;{};{}", first, second);
+    let hex = |hashes: &[[u8; 32]], i: usize| {
+        hashes
+            .get(i)
+            .map_or(String::new(), |hash| utils::arr_to_hex_swapped(hash))
+    };
+    for i in 0..diff.ours.len().max(diff.theirs.len()) {
+        println!(
+            "{};{};{}",
+            diff.fork_height() + i,
+            hex(&diff.ours, i),
+            hex(&diff.theirs, i)
+        );
+    }
+    Ok(())
+}
+
+/// Runs the indexing and parsing iterations for a single blockchain
+fn run(options: &mut ParserOptions) -> OpResult<()> {
     debug!(target: "main", "Using LogLevel {}", options.log_level_filter);
+    if let Some((ref path, dialect)) = options.schema_file {
+        write_schema_file(path, &*options.callback, dialect)?;
+    }
+    if let Some(ref path) = options.manifest_path {
+        schema::write_manifest(&options.coin_type, &options.callback.schema(), path)?;
+        info!(target: "main", "Wrote manifest to {:?}, all values are given in {} ({} decimals).",
+              path, options.coin_type.unit_name, options.coin_type.decimals);
+    }
+    if let Some(path) = options.single_file.clone() {
+        let blk_file = match BlkFile::from_file(path.clone()) {
+            Ok(file) => file,
+            Err(e) => return Err(tag_err!(e, "Cannot open blk file {:?}.", path)),
+        };
+        return parser::parse_single_file(options, blk_file);
+    }
     if options.reindex {
         fs::remove_file(options.chain_storage_path.clone()).ok();
     }
@@ -85,10 +333,7 @@ fn main() {
         // Load chain file into memory
         let chain_file = match load_chain_file(&options.chain_storage_path) {
             Ok(f) => f,
-            Err(desc) => {
-                error!(target: "main", "Can't load chain storage. {}", desc);
-                return;
-            }
+            Err(e) => return Err(tag_err!(e, "Can't load chain storage.")),
         };
 
         // Determine ParseMode based on existing chain file
@@ -107,40 +352,45 @@ fn main() {
         };
 
         // Load blk files from blockchain dir
-        let blk_files = match BlkFile::from_path(options.blockchain_dir.clone(), start_blk_idx) {
+        let blk_files = match BlkFile::from_path(
+            options.blockchain_dir.clone(),
+            start_blk_idx,
+            options.scan_depth,
+        ) {
             Ok(files) => files,
             Err(e) => {
-                error!(
-                    "Cannot load blockchain from: '{:?}' (start_blk_idx = {}). {}",
-                    &options.blockchain_dir, start_blk_idx, e
-                );
-                return;
+                return Err(tag_err!(
+                    e,
+                    "Cannot load blockchain from: '{:?}' (start_blk_idx = {}).",
+                    &options.blockchain_dir,
+                    start_blk_idx
+                ))
             }
         };
 
         if parse_mode == ParseMode::FullData && chain_file.remaining() == 0 {
             info!("All {} known blocks are processed! Try again with `--resume` to scan for new blocks, or force a full rescan with `--reindex`",
                   chain_file.get_cur_height());
-            return;
+            return Ok(());
         }
 
         {
             // Start parser
             let (tx, rx) = mpsc::sync_channel(options.worker_backlog);
             let mut parser =
-                BlockchainParser::new(&mut options, parse_mode.clone(), blk_files, chain_file);
+                BlockchainParser::new(options, parse_mode.clone(), blk_files, chain_file);
 
             // Start threads
             if let Some(err) = parser.start_worker(tx).err() {
                 error!(target: "parser", "{}", err);
             }
             // Dispatch thread messages
-            if let Some(err) = parser.dispatch(rx).err() {
-                error!(target: "dispatch", "{}", err);
-                return;
-            }
+            parser.dispatch(rx)?;
         }
 
+        if shutdown::is_interrupted() {
+            return Ok(());
+        }
         debug!(target: "main", "Iteration {} finished.", i + 1);
 
         // If last mode was FullData we can break
@@ -152,7 +402,7 @@ fn main() {
             resume = false;
         }
     }
-    info!(target: "main", "Fin.");
+    Ok(())
 }
 
 /// Initializes all required data
@@ -171,8 +421,8 @@ fn load_chain_file(path: &Path) -> OpResult<chain::ChainStorage> {
     }
 }
 
-/// Parses args or panics if some requirements are not met.
-fn parse_args() -> OpResult<ParserOptions> {
+/// Builds the command line interface with all callbacks
+fn build_app<'a, 'b>() -> App<'a, 'b> {
     let coins = &[
         "bitcoin",
         "testnet3",
@@ -182,22 +432,57 @@ fn parse_args() -> OpResult<ParserOptions> {
         "myriadcoin",
         "unobtanium",
     ];
-    let matches = App::new("Multithreaded Blockchain Parser written in Rust")
+    App::new("Multithreaded Blockchain Parser written in Rust")
         .version(crate_version!())
         .author("gcarq <michael.egger@tsn.at>")
         // Add flags
         .arg(Arg::with_name("verify-merkle-root")
             .long("verify-merkle-root")
             .help("Verifies the merkle root of each block"))
+        .arg(Arg::with_name("checksum-verify-blocks")
+            .long("checksum-verify-blocks")
+            .help("Verifies that each reserialized block has the size stored in front of it in the blk file"))
+        .arg(Arg::with_name("parallel-hash")
+            .long("parallel-hash")
+            .help("Runs --verify-merkle-root and --checksum-verify-blocks on the worker threads instead of before each callback"))
+        .arg(Arg::with_name("validate-difficulty")
+            .long("validate-difficulty")
+            .help("Verifies the difficulty bits at each retarget boundary against the retarget algorithm"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Aborts on an invalid merkle root or difficulty instead of logging a warning"))
         .arg(Arg::with_name("resume")
             .short("r")
             .long("resume")
             .help("Resume from latest known block"))
+        .arg(Arg::with_name("resume-verify")
+            .long("resume-verify")
+            .requires("resume")
+            .help("Aborts the resume if the latest processed block is no longer part of the longest chain in the blk files"))
         .arg(Arg::with_name("reindex")
             .short("n")
             .long("reindex")
             .conflicts_with("resume")
             .help("Force complete reindexing"))
+        .arg(Arg::with_name("header-row")
+            .long("header-row")
+            .help("Starts each written CSV file with a row of column names"))
+        .arg(Arg::with_name("summary-only")
+            .long("summary-only")
+            .help("Only logs the summary of the callback and suppresses all row output"))
+        .arg(Arg::with_name("no-clobber")
+            .long("no-clobber")
+            .conflicts_with("force")
+            .help("Keeps existing files in the dump folder, the new files are left as .tmp"))
+        .arg(Arg::with_name("force")
+            .long("force")
+            .help("Replaces existing files in the dump folder, also on Windows where renaming over them fails"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .help("Logs the time spent reading, deserializing, classifying, in the callback and writing"))
+        .arg(Arg::with_name("transactions-per-second")
+            .long("transactions-per-second")
+            .help("Adds the transactions and outputs processed per second to the status line"))
         .arg(Arg::with_name("verbosity")
             .short("v")
             .multiple(true)
@@ -210,11 +495,29 @@ fn parse_args() -> OpResult<ParserOptions> {
             .help("Specify blockchain coin (default: bitcoin)")
             .possible_values(coins)
             .takes_value(true))
+        .arg(Arg::with_name("assume-coin-on-unknown")
+            .long("assume-coin-on-unknown")
+            .value_name("NAME")
+            .help("Parses blocks whose magic value belongs to no supported coin as NAME instead of aborting, e.g. for forks")
+            .possible_values(coins)
+            .takes_value(true))
         .arg(Arg::with_name("blockchain-dir")
             .short("d")
             .long("blockchain-dir")
             .help("Sets blockchain directory which contains blk.dat files (default: ~/.bitcoin/blocks)")
             .takes_value(true))
+        .arg(Arg::with_name("recursive")
+            .long("recursive")
+            .help("Also scans the sub directories of the blockchain directory for blk files"))
+        .arg(Arg::with_name("follow-new-files")
+            .long("follow-new-files")
+            .help("Indexes blk files which appear while the index is built, e.g. written by a syncing node"))
+        .arg(Arg::with_name("max-depth")
+            .long("max-depth")
+            .value_name("LEVELS")
+            .help("Limits --recursive to LEVELS levels of sub directories (default: unlimited)")
+            .requires("recursive")
+            .takes_value(true))
         .arg(Arg::with_name("threads")
             .short("t")
             .long("threads")
@@ -231,47 +534,463 @@ fn parse_args() -> OpResult<ParserOptions> {
             .value_name("COUNT")
             .help("Sets maximum worker backlog (default: 100)")
             .takes_value(true))
+        .arg(Arg::with_name("reorder-buffer-size")
+            .long("reorder-buffer-size")
+            .value_name("MB")
+            .help("Aborts if blocks of more than MB megabytes arrive before their predecessor, which means the blk files \
+                   are missing a block (default: 256 per thread)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-read-mbps")
+            .long("max-read-mbps")
+            .value_name("MB/S")
+            .help("Limits the total read bandwidth of all workers to MB/S megabytes per second (default: unlimited)")
+            .takes_value(true))
+        .arg(Arg::with_name("single-file")
+            .long("single-file")
+            .value_name("FILE")
+            .help("Parses only the given blk file in file order, without building the chain. Meant for debugging corrupt files")
+            .conflicts_with_all(&["run", "resume", "reindex"])
+            .takes_value(true))
+        .arg(Arg::with_name("dump-failing-block")
+            .long("dump-failing-block")
+            .value_name("DIR")
+            .requires("strict")
+            .help("Writes the raw bytes and decoded fields of the block failing a --strict check into DIR before aborting")
+            .takes_value(true))
+        .arg(Arg::with_name("bench-loops")
+            .long("bench-loops")
+            .value_name("N")
+            .help("Keeps all blocks in memory and replays them N times through the callback, for profiling without I/O")
+            .hidden(true)
+            .takes_value(true))
+        .arg(Arg::with_name("parallel-write")
+            .long("parallel-write")
+            .value_name("N")
+            .help("Runs N instances of the callback in their own threads, each writing the blocks of every N-th height into \
+                   its own files, which are appended to the main files at the end. Only for csvdump, coinbasedump and burndump")
+            .conflicts_with_all(&["single-file", "bench-loops"])
+            .takes_value(true))
+        .arg(Arg::with_name("keep-shards")
+            .long("keep-shards")
+            .help("Leaves the files of --parallel-write in the shard-<i> sub folders of the dump folder instead of merging them")
+            .requires("parallel-write"))
+        .arg(Arg::with_name("block-sample")
+            .long("block-sample")
+            .value_name("N")
+            .help("Passes only blocks whose height is a multiple of N to the callback, for coarse time series. \
+                   Only for callbacks without state between blocks")
+            .takes_value(true))
+        .arg(Arg::with_name("memory-limit")
+            .long("memory-limit")
+            .value_name("BYTES")
+            .help("Lets the callback free memory, e.g. by evicting old outputs, once its estimated usage approaches BYTES. \
+                   Aborts if that is not enough (default: unlimited)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-runtime")
+            .long("max-runtime")
+            .value_name("DURATION")
+            .help("Stops gracefully like on SIGINT after DURATION, e.g. 90m or 8h, and exits with status 75 (default: unlimited)")
+            .takes_value(true))
+        .arg(Arg::with_name("line-ending")
+            .long("line-ending")
+            .value_name("STYLE")
+            .help("Line ending of all written CSV files (default: lf)")
+            .possible_values(&["lf", "crlf"])
+            .takes_value(true))
+        .arg(Arg::with_name("delimiter")
+            .long("delimiter")
+            .value_name("CHAR")
+            .help("Column separator of all written CSV files, `tab` for tabs (default: ;)")
+            .takes_value(true))
+        .arg(Arg::with_name("compress")
+            .long("compress")
+            .value_name("FORMAT")
+            .help("Compresses all written files except the ones needed to resume, adding a .gz suffix")
+            .possible_values(&["gzip"])
+            .conflicts_with("parallel-write")
+            .takes_value(true))
+        .arg(Arg::with_name("output-format")
+            .long("output-format")
+            .value_name("TARGETS")
+            .help("Writes each CSV file once per comma separated target FORMAT[:delimiter=<CHAR>][:compress=gzip|none], \
+                   FORMAT is csv or tsv (default: csv)")
+            .conflicts_with("parallel-write")
+            .takes_value(true))
+        .arg(Arg::with_name("write-buffer-size")
+            .long("write-buffer-size")
+            .value_name("BYTES")
+            .help("Buffer size of each written file (default: 4000000)")
+            .takes_value(true))
+        .arg(Arg::with_name("flush-interval")
+            .long("flush-interval")
+            .value_name("SECONDS")
+            .help("Flushes the written files at least every SECONDS, e.g. to follow them while parsing (default: only full buffers)")
+            .takes_value(true))
+        .arg(Arg::with_name("height-origin")
+            .long("height-origin")
+            .value_name("HEIGHT")
+            .help("Writes all block heights relative to HEIGHT, which may lead to negative heights (default: 0)")
+            .takes_value(true))
+        .arg(Arg::with_name("write-schema-file")
+            .long("write-schema-file")
+            .help("Writes the DDL of all CSV files as schema.sql into the dump folder"))
+        .arg(Arg::with_name("write-manifest")
+            .long("write-manifest")
+            .help("Writes manifest.json with the coin, the unit and decimals of all values and the CSV files into the dump folder"))
+        .arg(Arg::with_name("schema-dialect")
+            .long("schema-dialect")
+            .value_name("DIALECT")
+            .requires("write-schema-file")
+            .help("SQL dialect of the schema file (default: postgres)")
+            .possible_values(&["postgres", "sqlite"])
+            .takes_value(true))
+        .arg(Arg::with_name("txid-prefix")
+            .long("txid-prefix")
+            .value_name("HEX")
+            .help("Only passes transactions whose txid starts with HEX to the callback. \
+                   Each nibble reduces the sample to ~1/16")
+            .takes_value(true))
+        .arg(Arg::with_name("run")
+            .long("run")
+            .value_names(&["COIN", "BLKDIR", "OUTDIR"])
+            .help("Parses multiple blockchains back-to-back. The callback's dump folder \
+                   and the chain storage are placed in OUTDIR for each run")
+            .multiple(true)
+            .number_of_values(3)
+            .conflicts_with_all(&["coin", "blockchain-dir"])
+            .takes_value(true))
+        .arg(Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .requires("run")
+            .help("Aborts remaining runs if one of them fails"))
         // Add callbacks
         .subcommand(CsvDump::build_subcommand())
         .subcommand(UTXODump::build_subcommand())
         .subcommand(TXODump::build_subcommand())
         .subcommand(Clusterizer::build_subcommand())
         .subcommand(SimpleStats::build_subcommand())
-        .get_matches();
+        .subcommand(RealizedCap::build_subcommand())
+        .subcommand(CoinbaseDump::build_subcommand())
+        .subcommand(UTXODelta::build_subcommand())
+        .subcommand(AddressReuse::build_subcommand())
+        .subcommand(BurnDump::build_subcommand())
+        .subcommand(HeaderDump::build_subcommand())
+        .subcommand(BlockStats::build_subcommand())
+        .subcommand(VerifySnapshot::build_subcommand())
+        .subcommand(CoinJoinDump::build_subcommand())
+        .subcommand(ScriptHashDump::build_subcommand())
+        .subcommand(CpfpDump::build_subcommand())
+        .subcommand(ProtocolDump::build_subcommand())
+        .subcommand(Fragmentation::build_subcommand())
+        .subcommand(WatchAddresses::build_subcommand())
+        .subcommand(OutputCountDump::build_subcommand())
+        .subcommand(VersionRolling::build_subcommand())
+        .subcommand(FirstPaymentDump::build_subcommand())
+        .subcommand(TxDepthDump::build_subcommand())
+        .subcommand(SpendDump::build_subcommand())
+        .subcommand(TaprootAdoption::build_subcommand())
+        .subcommand(SubCommand::with_name("print-params")
+            .about("Prints the parameters of the selected coin as JSON and exits")
+            .arg(Arg::with_name("coin")
+                .short("c")
+                .long("coin")
+                .value_name("NAME")
+                .help("Specify blockchain coin (default: bitcoin)")
+                .possible_values(coins)
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("diff-chains")
+            .about("Compares two chain storages height by height and prints their fork point and divergent blocks")
+            .arg(Arg::with_name("first")
+                .help("Chain storage of the first chain, e.g. the chain.json of one node")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("second")
+                .help("Chain storage of the second chain")
+                .index(2)
+                .required(true)))
+}
+
+/// Creates the options for a single `--run` from the parsed command line.
+/// Each run gets its own callback instance, which writes into the run's output dir instead of the dump folder.
+fn parse_run_args(matches: &ArgMatches, spec: &RunSpec) -> OpResult<ParserOptions> {
+    fs::create_dir_all(&spec.out_dir)?;
+    let mut options = parse_args_with_dump_folder(matches, Some(&spec.out_dir))?;
+    let chain_storage_name = match options.chain_storage_path.file_name() {
+        Some(name) => PathBuf::from(name),
+        None => PathBuf::from("chain.json"),
+    };
+    options.chain_storage_path = spec.out_dir.join(chain_storage_name);
+    options.coin_type = spec.coin_type.clone();
+    options.blockchain_dir = spec.blockchain_dir.clone();
+    Ok(options)
+}
+
+/// Writes the DDL for the CSV files of the callback to `path`
+fn write_schema_file<C: Callback + ?Sized>(
+    path: &Path,
+    callback: &C,
+    dialect: Dialect,
+) -> OpResult<()> {
+    let tables = callback.schema();
+    if tables.is_empty() {
+        warn!(target: "main", "The callback writes no CSV files, skipping --write-schema-file.");
+        return Ok(());
+    }
+    schema::write_ddl(&tables, dialect, path)?;
+    info!(target: "main", "Wrote {:?} schema for {} tables to {:?}.", dialect, tables.len(), path);
+    Ok(())
+}
+
+/// Verbosity level, 0 = Info, 1 = Debug, 2+ = Trace
+fn get_log_level_filter(matches: &ArgMatches) -> LogLevelFilter {
+    match matches.occurrences_of("verbosity") {
+        0 => LogLevelFilter::Info,
+        1 => LogLevelFilter::Debug,
+        _ => LogLevelFilter::Trace,
+    }
+}
 
+/// Parses args or panics if some requirements are not met.
+fn parse_args(matches: &ArgMatches) -> OpResult<ParserOptions> {
+    parse_args_with_dump_folder(matches, None)
+}
+
+/// Parses args like `parse_args`, but the callback writes into `dump_folder` instead of its `dump-folder` argument
+fn parse_args_with_dump_folder(
+    matches: &ArgMatches,
+    dump_folder: Option<&Path>,
+) -> OpResult<ParserOptions> {
     // Set flags
     let verify_merkle_root = matches.is_present("verify-merkle-root");
+    let checksum_verify_blocks = matches.is_present("checksum-verify-blocks");
+    let parallel_hash = matches.is_present("parallel-hash");
+    let validate_difficulty = matches.is_present("validate-difficulty");
+    let strict = matches.is_present("strict");
     let resume = matches.is_present("resume");
+    let resume_verify = matches.is_present("resume-verify");
     let reindex = matches.is_present("reindex");
-    let log_level_filter = match matches.occurrences_of("verbosity") {
-        0 => LogLevelFilter::Info,
-        1 => LogLevelFilter::Debug,
-        _ => LogLevelFilter::Trace,
-    };
+    let log_level_filter = get_log_level_filter(matches);
 
     // Set options
     let coin_type = value_t!(matches, "coin", CoinType).unwrap_or(CoinType::from(Bitcoin));
+    let unknown_magic_coin = match matches.value_of("assume-coin-on-unknown") {
+        Some(coin) => Some(CoinType::from_str(coin)?),
+        None => None,
+    };
     let mut blockchain_path = utils::get_absolute_blockchain_dir(&coin_type);
     if matches.value_of("blockchain-dir").is_some() {
         blockchain_path = PathBuf::from(matches.value_of("blockchain-dir").unwrap());
     }
+    let scan_depth = match (matches.is_present("recursive"), matches.value_of("max-depth")) {
+        (false, _) => 0,
+        (true, Some(_)) => match value_t!(matches, "max-depth", usize) {
+            Ok(levels) if levels > 0 => levels,
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--max-depth must be a positive number of levels"))
+            }
+        },
+        (true, None) => usize::max_value(),
+    };
+    let follow_new_files = matches.is_present("follow-new-files");
     let thread_count = value_t!(matches, "threads", u8).unwrap_or(2);
     let chain_storage_path = matches.value_of("chain-storage").unwrap_or("chain.json");
     let worker_backlog = value_t!(matches, "backlog", usize).unwrap_or(100);
+    let reorder_buffer_size = match matches.value_of("reorder-buffer-size") {
+        Some(_) => match value_t!(matches, "reorder-buffer-size", usize) {
+            Ok(mb) if mb > 0 => mb * 1000000,
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--reorder-buffer-size must be a positive number of megabytes"))
+            }
+        },
+        None => thread_count.max(1) as usize * reorder::DEFAULT_BYTES_PER_THREAD,
+    };
+    let read_limiter = match matches.value_of("max-read-mbps") {
+        Some(_) => match value_t!(matches, "max-read-mbps", u64) {
+            Ok(mbps) if mbps > 0 => Some(Arc::new(RateLimiter::new(mbps * 1000000))),
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--max-read-mbps must be a positive number of megabytes per second"))
+            }
+        },
+        None => None,
+    };
+    let memory_limit = match matches.value_of("memory-limit") {
+        Some(_) => match value_t!(matches, "memory-limit", usize) {
+            Ok(bytes) if bytes > 0 => Some(bytes),
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--memory-limit must be a positive number of bytes"))
+            }
+        },
+        None => None,
+    };
+    let bench_loops = match matches.value_of("bench-loops") {
+        Some(_) => match value_t!(matches, "bench-loops", usize) {
+            Ok(loops) if loops > 0 => Some(loops),
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--bench-loops must be a positive number"))
+            }
+        },
+        None => None,
+    };
+    let n_shards = match matches.value_of("parallel-write") {
+        Some(_) => match value_t!(matches, "parallel-write", usize) {
+            Ok(n) if n >= 2 => Some(n),
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--parallel-write must be a number of at least 2"))
+            }
+        },
+        None => None,
+    };
+    let block_sample = match matches.value_of("block-sample") {
+        Some(_) => match value_t!(matches, "block-sample", usize) {
+            Ok(n) if n > 0 => n,
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--block-sample must be a positive number"))
+            }
+        },
+        None => 1,
+    };
+    let line_ending = match matches.value_of("line-ending") {
+        Some(line_ending) => LineEnding::from_str(line_ending)?,
+        None => LineEnding::Lf,
+    };
+    // Must be set before the callback creates its writers
+    writer::set_line_ending(line_ending);
+    if let Some(delimiter) = matches.value_of("delimiter") {
+        writer::set_delimiter(writer::parse_delimiter(delimiter)?);
+    }
+    writer::set_header_row(matches.is_present("header-row"));
+    writer::set_gzip(matches.value_of("compress") == Some("gzip"));
+    writer::set_targets(match matches.value_of("output-format") {
+        Some(targets) => writer::parse_targets(
+            targets,
+            writer::delimiter(),
+            matches.value_of("compress") == Some("gzip"),
+        )?,
+        None => Vec::new(),
+    });
+    match matches.value_of("write-buffer-size") {
+        Some(_) => match value_t!(matches, "write-buffer-size", usize) {
+            Ok(n) if n > 0 => writer::set_buffer_size(n),
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--write-buffer-size must be a positive number of bytes"))
+            }
+        },
+        None => (),
+    }
+    match matches.value_of("flush-interval") {
+        Some(_) => match value_t!(matches, "flush-interval", usize) {
+            Ok(seconds) if seconds > 0 => writer::set_flush_interval(seconds),
+            _ => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--flush-interval must be a positive number of seconds"))
+            }
+        },
+        None => (),
+    }
+    writer::set_summary_only(matches.is_present("summary-only"));
+    writer::set_overwrite(if matches.is_present("no-clobber") {
+        Overwrite::NoClobber
+    } else if matches.is_present("force") {
+        Overwrite::Force
+    } else {
+        Overwrite::Rename
+    });
+    profile::set_enabled(matches.is_present("profile"));
+    if matches.is_present("height-origin") {
+        match value_t!(matches, "height-origin", usize) {
+            Ok(origin) => writer::set_height_origin(origin),
+            Err(_) => {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg("--height-origin must be a block height"))
+            }
+        }
+    }
+    let txid_prefix = match matches.value_of("txid-prefix") {
+        Some(prefix) => {
+            if prefix.is_empty() || prefix.len() > 64 || !prefix.chars().all(|c| c.is_digit(16)) {
+                let msg = format!("Invalid --txid-prefix `{}`, expected 1 to 64 hex digits", prefix);
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg));
+            }
+            Some(prefix.to_lowercase())
+        }
+        None => None,
+    };
+    let single_file = matches.value_of("single-file").map(PathBuf::from);
+    let dump_failing_block = matches.value_of("dump-failing-block").map(PathBuf::from);
+    let dump_folder = match matches.subcommand() {
+        (_, Some(sub_matches)) => match sub_matches.value_of("dump-folder") {
+            Some(arg) => Some(dump_folder.map_or(PathBuf::from(arg), PathBuf::from)),
+            None => None,
+        },
+        _ => None,
+    };
 
     // Set callback
+    let folder = dump_folder.as_ref().map(PathBuf::as_path);
     let callback: Box<Callback>;
     if let Some(ref matches) = matches.subcommand_matches("simplestats") {
-        callback = Box::new(SimpleStats::new(matches)?);
+        callback = Box::new(SimpleStats::new(matches, folder)?);
     } else if let Some(ref matches) = matches.subcommand_matches("csvdump") {
-        callback = Box::new(CsvDump::new(matches)?);
+        callback = Box::new(CsvDump::new(matches, folder)?);
     } else if let Some(ref matches) = matches.subcommand_matches("clusterizer") {
-        callback = Box::new(Clusterizer::new(matches)?);
+        callback = Box::new(Clusterizer::new(matches, folder)?);
     } else if let Some(ref matches) = matches.subcommand_matches("utxodump") {
-        callback = Box::new(UTXODump::new(matches)?);
+        callback = Box::new(UTXODump::new(matches, folder)?);
     } else if let Some(ref matches) = matches.subcommand_matches("txodump") {
-        callback = Box::new(TXODump::new(matches)?);
+        if single_file.is_some() && !matches.is_present("max-output-age-tracking") {
+            let msg = "txodump fails on spends of outputs created in other files, use --max-output-age-tracking with --single-file";
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(msg));
+        }
+        callback = Box::new(TXODump::new(matches, folder)?);
         info!("TXODump can only be done synchronously in blockheight, defaults to 1 thread.");
+    } else if let Some(ref matches) = matches.subcommand_matches("realizedcap") {
+        callback = Box::new(RealizedCap::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("coinbasedump") {
+        callback = Box::new(CoinbaseDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("utxodelta") {
+        callback = Box::new(UTXODelta::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("addressreuse") {
+        callback = Box::new(AddressReuse::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("burndump") {
+        callback = Box::new(BurnDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("headerdump") {
+        callback = Box::new(HeaderDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("blockstats") {
+        callback = Box::new(BlockStats::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("verify-snapshot") {
+        callback = Box::new(VerifySnapshot::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("coinjoindump") {
+        callback = Box::new(CoinJoinDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("scripthashdump") {
+        callback = Box::new(ScriptHashDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("cpfpdump") {
+        callback = Box::new(CpfpDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("protocoldump") {
+        callback = Box::new(ProtocolDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("fragmentation") {
+        callback = Box::new(Fragmentation::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("watchaddresses") {
+        callback = Box::new(WatchAddresses::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("outputcountdump") {
+        callback = Box::new(OutputCountDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("versionrolling") {
+        callback = Box::new(VersionRolling::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("firstpaymentdump") {
+        callback = Box::new(FirstPaymentDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("txdepthdump") {
+        callback = Box::new(TxDepthDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("spenddump") {
+        callback = Box::new(SpendDump::new(matches, folder)?);
+    } else if let Some(ref matches) = matches.subcommand_matches("taprootadoption") {
+        callback = Box::new(TaprootAdoption::new(matches, folder)?);
     } else {
         clap::Error {
             message: String::from("error: No Callback specified.\nFor more information try --help"),
@@ -281,16 +1000,88 @@ fn parse_args() -> OpResult<ParserOptions> {
         .exit();
     }
 
+    // Both files are written by run(), once all args are checked and `--run` replaced the coin
+    let schema_file = match dump_folder {
+        Some(ref dump_folder) if matches.is_present("write-schema-file") => {
+            let dialect = match matches.value_of("schema-dialect") {
+                Some(dialect) => Dialect::from_str(dialect)?,
+                None => Dialect::Postgres,
+            };
+            Some((dump_folder.join("schema.sql"), dialect))
+        }
+        None if matches.is_present("write-schema-file") => {
+            warn!(target: "main", "The callback writes no CSV files, skipping --write-schema-file.");
+            None
+        }
+        _ => None,
+    };
+    let manifest_path = match dump_folder {
+        Some(ref dump_folder) if matches.is_present("write-manifest") => {
+            Some(dump_folder.join("manifest.json"))
+        }
+        None if matches.is_present("write-manifest") => {
+            warn!(target: "main", "The callback has no dump folder, skipping --write-manifest.");
+            None
+        }
+        _ => None,
+    };
+    if block_sample > 1 && !callback.allows_block_sampling() {
+        let msg = "--block-sample only works with callbacks which keep no state between blocks \
+                   (csvdump, coinbasedump, burndump, headerdump, outputcountdump, txdepthdump, spenddump, versionrolling with --no-miner-check)";
+        return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(msg));
+    }
+    if txid_prefix.is_some() && !callback.allows_tx_filter() {
+        let msg = "--txid-prefix only works with callbacks which don't follow outputs across transactions \
+                   (csvdump, coinbasedump, burndump, headerdump, outputcountdump, protocoldump, scripthashdump, coinjoindump, \
+                   versionrolling with --no-miner-check, simplestats without --separate-data-carriers)";
+        return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(msg));
+    }
+    let parallel_write = match n_shards {
+        Some(n_shards) => {
+            if !callback.order_insensitive() {
+                let msg = "--parallel-write only works with callbacks whose output doesn't depend on the block order \
+                           (csvdump, coinbasedump, burndump)";
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(msg));
+            }
+            Some(ShardOptions {
+                n_shards: n_shards,
+                dump_folder: dump_folder.clone().unwrap(),
+                keep_shards: matches.is_present("keep-shards"),
+            })
+        }
+        None => None,
+    };
+
     Ok(ParserOptions {
         coin_type: coin_type,
         callback: callback,
         verify_merkle_root: verify_merkle_root,
+        checksum_verify_blocks: checksum_verify_blocks,
+        parallel_hash: parallel_hash,
+        validate_difficulty: validate_difficulty,
+        strict: strict,
+        dump_failing_block: dump_failing_block,
         thread_count: thread_count,
         resume: resume,
+        resume_verify: resume_verify,
         reindex: reindex,
         blockchain_dir: blockchain_path,
+        scan_depth: scan_depth,
+        follow_new_files: follow_new_files,
         chain_storage_path: PathBuf::from(chain_storage_path),
         worker_backlog: worker_backlog,
         log_level_filter: log_level_filter,
+        txid_prefix: txid_prefix,
+        read_limiter: read_limiter,
+        memory_limit: memory_limit,
+        show_tx_rate: matches.is_present("transactions-per-second"),
+        single_file: single_file,
+        schema_file: schema_file,
+        manifest_path: manifest_path,
+        bench_loops: bench_loops,
+        block_sample: block_sample,
+        parallel_write: parallel_write,
+        unknown_magic_coin: unknown_magic_coin,
+        reorder_buffer_size: reorder_buffer_size,
     })
 }